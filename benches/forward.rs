@@ -0,0 +1,193 @@
+//! Benchmark of the TCP data path, which pushes payload through the send and receive caches of a
+//! TCP connection in a loopback, and through a `Redirector` over a `MemoryLink` with an echo
+//! proxy. The send path is compared against a baseline which copies the payload into the queue and
+//! out of the cache like the path before the payload was carried in `Bytes`.
+
+use bytes::Bytes;
+use ipnetwork::Ipv4Network;
+use pnet::packet::tcp::TcpFlags;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+use pcap2socks::pcap::{FrameBuilder, HardwareAddr, LinkBackend, MemoryLink};
+use pcap2socks::tcp::{TcpRxState, TcpTxState};
+use pcap2socks::{Config, Forwarder, ProxyConfig, Redirector};
+
+/// Represents the total size of the payload pushed in each direction.
+const TOTAL_SIZE: usize = 4 << 30;
+/// Represents the total size of the payload echoed through the `Redirector`.
+const REDIRECT_TOTAL_SIZE: usize = 256 << 20;
+/// Represents the number of segments sent before waiting for the echo.
+const REDIRECT_BATCH: usize = 32;
+/// Represents the size of a read from the proxy.
+const READ_SIZE: usize = u16::MAX as usize;
+/// Represents the MSS of the TCP connection.
+const MSS: usize = 1460;
+
+fn main() {
+    let src: SocketAddr = "10.6.0.1:10000".parse().unwrap();
+    let dst: SocketAddr = "1.1.1.1:80".parse().unwrap();
+    let mut config = Config::default();
+    config.enable_cc = false;
+    config.enable_rto_compute = false;
+
+    // Send, from the proxy to the source
+    let (baseline_size, baseline) = send(src, dst, &config, true);
+    report("send (baseline)", baseline_size, baseline);
+    let (size, elapsed) = send(src, dst, &config, false);
+    report("send", size, elapsed);
+    println!(
+        "send: {:.2}x of the baseline",
+        (size as f64 / elapsed.as_secs_f64()) / (baseline_size as f64 / baseline.as_secs_f64())
+    );
+
+    // Receive, from the source to the proxy
+    let mut state = TcpRxState::new(src, dst, 0, 8, true, None);
+    let frame = Bytes::from(vec![0u8; MSS]);
+    let now = Instant::now();
+    let mut size = 0;
+    while size < TOTAL_SIZE {
+        let sequence = state.recv_next();
+        if let Some(payload) = state.append_cache(sequence, frame.slice(..)).unwrap() {
+            state.add_recv_next(payload.len() as u32);
+            size += payload.len();
+        }
+    }
+    report("receive", size, now.elapsed());
+
+    // Redirect, from the source to the echo proxy and back
+    let runtime = Runtime::new().unwrap();
+    let (size, elapsed) = runtime.block_on(redirect());
+    report("redirect", size, elapsed);
+}
+
+/// Pushes the payload read from the proxy through the queue and the cache, and returns the size
+/// and the elapsed time. The baseline copies the payload into the queue and out of the cache, otherwise
+/// the payload is carried in `Bytes` and sliced out.
+fn send(src: SocketAddr, dst: SocketAddr, config: &Config, is_baseline: bool) -> (usize, Duration) {
+    let mut state = TcpTxState::new(
+        src,
+        dst,
+        0,
+        0,
+        u16::MAX,
+        Some(8),
+        true,
+        None,
+        None,
+        MSS,
+        config,
+    );
+    let read = Bytes::from(vec![0u8; READ_SIZE]);
+    let mut buffer = Vec::new();
+    let now = Instant::now();
+    let mut size = 0;
+    while size < TOTAL_SIZE {
+        match is_baseline {
+            true => state.append_queue(Bytes::copy_from_slice(&read)),
+            false => state.append_queue(read.clone()),
+        }
+        while state.queue_len() > 0 {
+            let n = MSS.min(state.queue_len());
+            state.append_cache(n).unwrap();
+            let sequence = state.sequence();
+            size += match is_baseline {
+                true => {
+                    state.cache().get_into(sequence, n, &mut buffer).unwrap();
+                    buffer.len()
+                }
+                false => state.cache().get_bytes(sequence, n).unwrap().len(),
+            };
+            state.add_sequence(n as u32);
+            state.acknowledge(state.sequence(), None);
+        }
+    }
+
+    (size, now.elapsed())
+}
+
+async fn redirect() -> (usize, Duration) {
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let builder = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:80".parse().unwrap(),
+    )
+    .unwrap();
+
+    // Handshake
+    peer.inject(builder.tcp(0, 0, TcpFlags::SYN, &[])).unwrap();
+    let (syn_ack, _) = peer.recv_tcp().await.unwrap();
+    let mut sequence = 1u32;
+    let mut acknowledgement = syn_ack.sequence().wrapping_add(1);
+    peer.inject(builder.tcp(sequence, acknowledgement, TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Echo
+    let payload = vec![0u8; MSS];
+    let now = Instant::now();
+    let mut size = 0;
+    while size < REDIRECT_TOTAL_SIZE {
+        for _ in 0..REDIRECT_BATCH {
+            peer.inject(builder.tcp(
+                sequence,
+                acknowledgement,
+                TcpFlags::ACK | TcpFlags::PSH,
+                &payload,
+            ))
+            .unwrap();
+            sequence = sequence.wrapping_add(MSS as u32);
+        }
+
+        let mut echoed = 0;
+        while echoed < REDIRECT_BATCH * MSS {
+            let (_, payload) = peer.recv_tcp().await.unwrap();
+            echoed += payload.len();
+        }
+        acknowledgement = acknowledgement.wrapping_add(echoed as u32);
+        peer.inject(builder.tcp(sequence, acknowledgement, TcpFlags::ACK, &[]))
+            .unwrap();
+        size += echoed;
+    }
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+
+    (size, now.elapsed())
+}
+
+fn report(name: &str, size: usize, elapsed: Duration) {
+    let elapsed = elapsed.as_secs_f64();
+    println!(
+        "{}: {} Bytes in {:.3} s, {:.3} GB/s",
+        name,
+        size,
+        elapsed,
+        size as f64 / elapsed / 1e9
+    );
+}
@@ -0,0 +1,227 @@
+//! Support for runtime configurations.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+
+use crate::tcp::TcpCcAlgorithms;
+
+/// Represents the maximum window scale of TCP ([RFC 7323](https://tools.ietf.org/html/rfc7323)).
+const MAX_WSCALE: u8 = 14;
+
+/// Represents the runtime configuration of protocol tunables.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Represents the wait time after a `TimedOut` `IoError` in ms.
+    pub timedout_wait: u64,
+    /// Represents if the receive-side silly window syndrome avoidance, Clark's algorithm, is
+    /// enabled.
+    pub enable_recv_sws_avoid: bool,
+    /// Represents if the send-side silly window syndrome avoidance, Clark's algorithm, is enabled.
+    pub enable_send_sws_avoid: bool,
+    /// Represents if the delayed ACK is enabled.
+    pub enable_delayed_ack: bool,
+    /// Represents if the TCP MSS option is enabled.
+    pub enable_mss: bool,
+    /// Represents if the TCP window scale option is enabled.
+    pub enable_wscale: bool,
+    /// Represents the max window scale of the receive window.
+    pub max_recv_wscale: u8,
+    /// Represents if the TCP selective acknowledgment option is enabled.
+    pub enable_sack: bool,
+    /// Represents if the TCP timestamps option is enabled.
+    pub enable_ts: bool,
+    /// Represents the threshold of TCP ACK duplicates before trigger a fast retransmission.
+    pub duplicates_threshold: usize,
+    /// Represents the cool down time between 2 retransmissions in ms.
+    pub retrans_cool_down: u64,
+    /// Represents the max limit of UDP port for binding in local.
+    pub max_udp_port: usize,
+    /// Represents the number of shards processing traffic in parallel. 0 means the number of
+    /// available CPUs.
+    pub shards: usize,
+    /// Represents the maximum size of extra cache in a TCP connection.
+    pub max_queue: usize,
+    /// Represents the maximum size of data queued to the proxy in a TCP connection.
+    pub max_proxy_queue: usize,
+    /// Represents if the RTO computation is enabled.
+    pub enable_rto_compute: bool,
+    /// Represents the initial timeout for a retransmission in a TCP connection in ms.
+    pub initial_rto: u64,
+    /// Represents the minimum timeout for a retransmission in a TCP connection in ms.
+    pub min_rto: u64,
+    /// Represents the maximum timeout for a retransmission in a TCP connection in ms.
+    pub max_rto: u64,
+    /// Represents if the congestion control is enabled.
+    pub enable_cc: bool,
+    /// Represents the congestion control algorithm.
+    pub cc_algorithm: TcpCcAlgorithms,
+    /// Represents the congestion control algorithms overriding the default one for specific
+    /// source devices.
+    pub cc_overrides: HashMap<IpAddr, TcpCcAlgorithms>,
+    /// Represents the wait time after receiving 0 byte from the stream in ms.
+    pub recv_zero_wait: u64,
+    /// Represents the maximum count of receiving 0 byte from the stream before closing it.
+    pub max_recv_zero: usize,
+    /// Represents the timeout of a delayed ACK in ms.
+    pub delayed_ack_timeout: u64,
+    /// Represents if the TCP keep-alive is enabled.
+    pub enable_keep_alive: bool,
+    /// Represents the idle time of the source before sending TCP keep-alive probes in ms.
+    pub keep_alive_idle: u64,
+    /// Represents the interval between TCP keep-alive probes in ms.
+    pub keep_alive_interval: u64,
+    /// Represents the count of unanswered TCP keep-alive probes before reaping the connection.
+    pub keep_alive_count: usize,
+    /// Represents the idle timeout of a TCP connection without any payload in ms. 0 means the idle
+    /// timeout is disabled.
+    pub idle_timeout: u64,
+    /// Represents the timeout of waiting for TCP connections to close in a graceful shutdown in
+    /// ms.
+    pub shutdown_timeout: u64,
+    /// Represents the timeout of connecting to an upstream in ms.
+    pub connect_timeout: u64,
+    /// Represents the interval of health checks of upstreams in ms.
+    pub health_check_interval: u64,
+    /// Represents the timeout of a health check of an upstream in ms.
+    pub health_check_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            timedout_wait: 20,
+            enable_recv_sws_avoid: true,
+            enable_send_sws_avoid: true,
+            enable_delayed_ack: true,
+            enable_mss: true,
+            enable_wscale: true,
+            max_recv_wscale: 8,
+            enable_sack: true,
+            enable_ts: true,
+            duplicates_threshold: 3,
+            retrans_cool_down: 200,
+            max_udp_port: 256,
+            shards: 0,
+            max_queue: 16777216,
+            max_proxy_queue: 4194304,
+            enable_rto_compute: true,
+            initial_rto: 1000,
+            min_rto: 1000,
+            max_rto: 60000,
+            enable_cc: true,
+            cc_algorithm: TcpCcAlgorithms::Reno,
+            cc_overrides: HashMap::new(),
+            recv_zero_wait: 100,
+            max_recv_zero: 3,
+            delayed_ack_timeout: 200,
+            enable_keep_alive: true,
+            keep_alive_idle: 60000,
+            keep_alive_interval: 10000,
+            keep_alive_count: 3,
+            idle_timeout: 0,
+            shutdown_timeout: 5000,
+            connect_timeout: 10000,
+            health_check_interval: 10000,
+            health_check_timeout: 5000,
+        }
+    }
+}
+
+impl Config {
+    /// Validates the configuration. Returns an error if any option is out of range, which may
+    /// stall the connections or lead to busy loops.
+    pub fn validate(&self) -> io::Result<()> {
+        // A whole segment received from the proxy must fit the queue
+        if self.max_queue < u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("max_queue must be at least {}", u16::MAX),
+            ));
+        }
+        for (name, value) in [
+            ("timedout_wait", self.timedout_wait),
+            ("recv_zero_wait", self.recv_zero_wait),
+            ("keep_alive_idle", self.keep_alive_idle),
+            ("keep_alive_interval", self.keep_alive_interval),
+            ("health_check_interval", self.health_check_interval),
+        ] {
+            if value == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} must be greater than 0", name),
+                ));
+            }
+        }
+        if self.min_rto > self.max_rto {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "min_rto must not be greater than max_rto",
+            ));
+        }
+        if self.max_recv_wscale > MAX_WSCALE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("max_recv_wscale must not be greater than {}", MAX_WSCALE),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the congestion control algorithm of the given source device.
+    pub fn cc_algorithm(&self, src: IpAddr) -> TcpCcAlgorithms {
+        *self.cc_overrides.get(&src).unwrap_or(&self.cc_algorithm)
+    }
+}
+
+#[test]
+fn config_from_toml() {
+    let config: Config = toml::from_str("max_udp_port = 512\ncc_algorithm = \"cubic\"").unwrap();
+    assert_eq!(config.max_udp_port, 512);
+    assert_eq!(config.cc_algorithm, TcpCcAlgorithms::Cubic);
+    assert_eq!(
+        config.delayed_ack_timeout,
+        Config::default().delayed_ack_timeout
+    );
+
+    assert!(toml::from_str::<Config>("max_udp_ports = 512").is_err());
+}
+
+#[test]
+fn config_cc_overrides() {
+    let config: Config =
+        toml::from_str("cc_algorithm = \"reno\"\n[cc_overrides]\n\"10.6.0.1\" = \"cubic\"")
+            .unwrap();
+    assert_eq!(
+        config.cc_algorithm("10.6.0.1".parse().unwrap()),
+        TcpCcAlgorithms::Cubic
+    );
+    assert_eq!(
+        config.cc_algorithm("10.6.0.2".parse().unwrap()),
+        TcpCcAlgorithms::Reno
+    );
+}
+
+#[test]
+fn config_validate() {
+    assert!(Config::default().validate().is_ok());
+
+    let invalids = [
+        "max_queue = 65534",
+        "recv_zero_wait = 0",
+        "keep_alive_interval = 0",
+        "health_check_interval = 0",
+        "min_rto = 2000\nmax_rto = 1000",
+        "max_recv_wscale = 15",
+    ];
+    for s in invalids.iter() {
+        let config: Config = toml::from_str(s).unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
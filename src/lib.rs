@@ -2,39 +2,51 @@
 
 //! Redirect traffic to a SOCKS proxy with pcap.
 
-use ipnetwork::Ipv4Network;
+use bytes::Bytes;
+use ipnetwork::{Ipv4Network, Ipv6Network};
 use log::{debug, info, trace, warn};
 use lru::LruCache;
 use rand::{self, Rng};
-use stat::Traffic;
+use stat::{Backpressure, Latency, Reaped, Traffic};
 use std::cmp::{max, min};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io;
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
 
+pub mod config;
 pub mod packet;
 pub mod pcap;
 pub mod proxy;
+pub mod rule;
+pub mod server;
 pub mod stat;
 pub mod tcp;
 
+pub use self::config::Config;
 pub use self::proxy::ProxyConfig;
-use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, StreamWorker};
+use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, ProxiedStream, StreamWorker};
+pub use self::rule::{Action, Router, Rule};
 use packet::layer::arp::Arp;
 use packet::layer::ethernet::Ethernet;
 use packet::layer::icmpv4::Icmpv4;
+use packet::layer::icmpv6::Icmpv6;
 use packet::layer::ipv4::Ipv4;
+use packet::layer::ipv6::Ipv6;
 use packet::layer::tcp::Tcp;
 use packet::layer::udp::Udp;
 use packet::layer::{Layer, LayerKinds, Layers};
 use packet::{Defraggler, Indicator};
 use pcap::Interface;
-use pcap::{HardwareAddr, Receiver, Sender};
-use tcp::{TcpRxState, TcpTxState};
+use pcap::{AsyncReceiver, Direction, Dumper, HardwareAddr, Receiver, Sender};
+use tcp::{TcpRxState, TcpTxState, TimerQueue};
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
@@ -66,35 +78,72 @@ pub fn interface(name: Option<String>) -> Option<Interface> {
 /// Represents the max distance of `u32` values between packets in an `u32` window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
-/// Represents the wait time after a `TimedOut` `IoError`.
-const TIMEDOUT_WAIT: u64 = 20;
-
-/// Represents if the receive-side silly window syndrome avoidance, Clark's algorithm, is enabled.
-const ENABLE_RECV_SWS_AVOID: bool = true;
-/// Represents if the send-side silly window syndrome avoidance, Clark's algorithm, is enabled.
-const ENABLE_SEND_SWS_AVOID: bool = true;
-
-/// Represents if the delayed ACK is enabled.
-const ENABLE_DELAYED_ACK: bool = true;
-
-/// Represents if the TCP MSS option is enabled.
-const ENABLE_MSS: bool = true;
-
 /// Represents the minimum frame size.
 /// Because all traffic is in Ethernet, and the 802.3 specifies the minimum is 64 Bytes.
 /// Exclude the 4 bytes used in FCS, the minimum frame size in pcap2socks is 60 Bytes.
 const MINIMUM_FRAME_SIZE: usize = 60;
 
+/// Represents the length of the TCP timestamps option, including paddings.
+const TCP_TS_LEN: usize = 12;
+
+/// Represents the interval of checking if the `Redirector` is running in ms.
+const MONITOR_INTERVAL: u64 = 100;
+
+/// Represents the interval of flushing the dumper in ms.
+const DUMP_FLUSH_INTERVAL: u64 = 1000;
+
+/// Represents the capacity of the channel between the dispatcher and a shard of the `Redirector`.
+const SHARD_CHANNEL_CAPACITY: usize = 1024;
+
+/// Represents the wait time after the frames in a capture file are exhausted before shutting down
+/// the `Redirector` in ms.
+const REPLAY_LINGER: u64 = 1000;
+
+/// Represents the minimum MTU of IPv6 links.
+const IPV6_MINIMUM_MTU: usize = 1280;
+
+/// Returns the minimum length of the IP header of the given IP address.
+fn ip_minimum_len(ip_addr: IpAddr) -> usize {
+    match ip_addr {
+        IpAddr::V4(_) => Ipv4::minimum_len(),
+        IpAddr::V6(_) => Ipv6::minimum_len(),
+    }
+}
+
+/// Returns the multicast hardware address of the given IPv6 multicast address.
+fn ipv6_multicast_hardware_addr(ip_addr: Ipv6Addr) -> HardwareAddr {
+    let octets = ip_addr.octets();
+
+    HardwareAddr::new(0x33, 0x33, octets[12], octets[13], octets[14], octets[15])
+}
+
+/// Represents the send half in pcap and the states of the link, which are shared by the
+/// `Forwarder`s of all shards.
+struct Link {
+    tx: Sender,
+    src_mtu_map: HashMap<IpAddr, usize>,
+    src_hardware_addr_map: HashMap<IpAddr, HardwareAddr>,
+    ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
+    ipv6_identification: u32,
+    dumper: Option<Arc<Mutex<Dumper>>>,
+}
+
 /// Represents a channel forward traffic to the source in pcap.
 pub struct Forwarder {
-    tx: Sender,
-    src_mtu_map: HashMap<Ipv4Addr, usize>,
+    link: Arc<Mutex<Link>>,
     local_mtu: usize,
-    src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
     local_hardware_addr: HardwareAddr,
     local_ip_addr: Ipv4Addr,
-    ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
-    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    local_ipv6_addr: Option<Ipv6Addr>,
+    states: HashMap<(SocketAddr, SocketAddr), TcpTxState>,
+    timers: TimerQueue,
+    timers_notify: Arc<Notify>,
+    reaped_streams: Vec<(SocketAddr, SocketAddr)>,
+    reaped: Reaped,
+    opened_windows: Vec<(SocketAddr, SocketAddr)>,
+    windows_notify: Arc<Notify>,
+    backpressure: Backpressure,
+    config: Config,
     traffic_size: Option<Arc<AtomicUsize>>,
     traffic_count: Option<Arc<AtomicUsize>>,
 }
@@ -127,46 +176,232 @@ impl Forwarder {
             None => None,
         };
         Forwarder {
-            tx,
-            src_mtu_map: HashMap::new(),
+            link: Arc::new(Mutex::new(Link {
+                tx,
+                src_mtu_map: HashMap::new(),
+                src_hardware_addr_map: HashMap::new(),
+                ipv4_identification_map: HashMap::new(),
+                ipv6_identification: 0,
+                dumper: None,
+            })),
             local_mtu: mtu,
-            src_hardware_addr_map: HashMap::new(),
             local_hardware_addr,
             local_ip_addr,
-            ipv4_identification_map: HashMap::new(),
+            local_ipv6_addr: None,
             states: HashMap::new(),
+            timers: TimerQueue::new(),
+            timers_notify: Arc::new(Notify::new()),
+            reaped_streams: Vec::new(),
+            reaped: Reaped::new(),
+            opened_windows: Vec::new(),
+            windows_notify: Arc::new(Notify::new()),
+            backpressure: Backpressure::new(),
+            config: Config::default(),
             traffic_size: size,
             traffic_count: count,
         }
     }
 
+    /// Creates a new `Forwarder` of a shard. The new `Forwarder` shares the send half in pcap, the
+    /// source MTUs and hardware addresses, the statistics and the configuration with this one, but
+    /// tracks its own TCP connections.
+    pub fn new_shard(&self) -> Forwarder {
+        Forwarder {
+            link: Arc::clone(&self.link),
+            local_mtu: self.local_mtu,
+            local_hardware_addr: self.local_hardware_addr,
+            local_ip_addr: self.local_ip_addr,
+            local_ipv6_addr: self.local_ipv6_addr,
+            states: HashMap::new(),
+            timers: TimerQueue::new(),
+            timers_notify: Arc::new(Notify::new()),
+            reaped_streams: Vec::new(),
+            reaped: self.reaped.clone(),
+            opened_windows: Vec::new(),
+            windows_notify: Arc::new(Notify::new()),
+            backpressure: self.backpressure.clone(),
+            config: self.config.clone(),
+            traffic_size: self.traffic_size.clone(),
+            traffic_count: self.traffic_count.clone(),
+        }
+    }
+
+    /// Spawns the driver of the timers of the `Forwarder`. The driver sleeps until the earliest
+    /// deadline of TCP connections, and then retransmits their timed out data and sends their
+    /// delayed ACKs.
+    pub fn spawn_timer(forwarder: &Arc<Mutex<Forwarder>>) {
+        let notify = Arc::clone(&forwarder.lock().unwrap().timers_notify);
+        let forwarder = Arc::downgrade(forwarder);
+        tokio::spawn(async move {
+            loop {
+                let deadline = {
+                    let forwarder = match forwarder.upgrade() {
+                        Some(forwarder) => forwarder,
+                        None => return,
+                    };
+                    let mut forwarder_locked = forwarder.lock().unwrap();
+                    forwarder_locked.fire_timers();
+
+                    forwarder_locked.timers.next()
+                };
+
+                match deadline {
+                    Some(deadline) => {
+                        let sleep_fut = time::sleep_until(time::Instant::from_std(deadline));
+                        let notify_fut = notify.notified();
+
+                        tokio::pin!(sleep_fut, notify_fut);
+
+                        tokio::select! {
+                            _ = sleep_fut => {},
+                            _ = notify_fut => {}
+                        }
+                    }
+                    None => notify.notified().await,
+                }
+            }
+        });
+    }
+
+    /// Triggers the timed events of TCP connections which are expired.
+    fn fire_timers(&mut self) {
+        for (dst, src) in self.timers.pop_expired(Instant::now()) {
+            // Keep-alive
+            let mut result = match self.keep_alive_tcp(dst, src) {
+                Ok(true) => continue,
+                Ok(false) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            // Retransmit
+            if result.is_ok() {
+                result = self.retransmit_tcp_timedout(dst, src);
+            }
+
+            // Paced sending, the data waiting for the pacing is sent once it is released
+            if result.is_ok() {
+                let is_queued = match self.get_state(dst, src) {
+                    Some(state) => state.cache_syn().is_none() && state.queue_len() > 0,
+                    None => false,
+                };
+                if is_queued {
+                    result = self.send_tcp(dst, src);
+                }
+            }
+
+            // Zero window probe
+            if result.is_ok() {
+                let is_persist_timedout = match self.get_state(dst, src) {
+                    Some(state) => state.persist().map_or(false, |timer| timer.is_timedout()),
+                    None => false,
+                };
+                if is_persist_timedout {
+                    result = self.send_tcp(dst, src);
+                }
+            }
+
+            match result {
+                Ok(_) => self.schedule_timer(dst, src),
+                Err(ref e) => {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        continue;
+                    }
+                    warn!("handle timeout: {}: {} -> {}: {}", "TCP", dst, src, e);
+
+                    // Retry after an RTO
+                    if let Some(state) = self.get_state(dst, src) {
+                        let deadline = Instant::now() + Duration::from_millis(state.rto());
+                        self.timers.schedule(dst, src, deadline);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Schedules the timer of a TCP connection according to its next timed event.
+    fn schedule_timer(&mut self, dst: SocketAddr, src: SocketAddr) {
+        let deadline = match self.get_state(dst, src).and_then(|state| state.deadline()) {
+            Some(deadline) => deadline,
+            None => return,
+        };
+
+        if self.timers.schedule(dst, src, deadline) {
+            self.timers_notify.notify_one();
+        }
+    }
+
+    /// Sets the runtime configuration.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Sets the dumper which dumps the frames sent. The dumper is shared by the `Forwarder`s of
+    /// all shards.
+    pub fn set_dumper(&mut self, dumper: Arc<Mutex<Dumper>>) {
+        self.link.lock().unwrap().dumper = Some(dumper);
+    }
+
+    /// Returns the statistics of reaped connections.
+    pub fn reaped(&self) -> Reaped {
+        self.reaped.clone()
+    }
+
+    /// Takes the TCP connections reaped since the last call, in `(dst, src)` pairs.
+    pub fn take_reaped_streams(&mut self) -> Vec<(SocketAddr, SocketAddr)> {
+        std::mem::take(&mut self.reaped_streams)
+    }
+
+    /// Takes the TCP connections whose windows are opened since the last call, in `(dst, src)`
+    /// pairs.
+    pub fn take_opened_windows(&mut self) -> Vec<(SocketAddr, SocketAddr)> {
+        std::mem::take(&mut self.opened_windows)
+    }
+
+    /// Returns the notification which is notified when the window of any TCP connection is
+    /// opened.
+    pub fn windows_notify(&self) -> Arc<Notify> {
+        Arc::clone(&self.windows_notify)
+    }
+
     /// Sets the source MTU.
-    pub fn set_src_mtu(&mut self, src_ip_addr: Ipv4Addr, mtu: usize) -> bool {
-        let prev_mtu = *self
+    pub fn set_src_mtu(&mut self, src_ip_addr: IpAddr, mtu: usize) -> bool {
+        let mut link_locked = self.link.lock().unwrap();
+        let prev_mtu = *link_locked
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu);
 
-        self.src_mtu_map
+        link_locked
+            .src_mtu_map
             .insert(src_ip_addr, min(self.local_mtu, mtu));
         trace!("set source MTU of {} to {}", src_ip_addr, mtu);
 
-        return *self
+        return *link_locked
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu)
             != prev_mtu;
     }
 
-    /// Sets the source hardware address.
-    pub fn set_src_hardware_addr(&mut self, src_ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
-        self.src_hardware_addr_map
+    /// Sets the source hardware address. Returns if the source is new.
+    pub fn set_src_hardware_addr(
+        &mut self,
+        src_ip_addr: IpAddr,
+        hardware_addr: HardwareAddr,
+    ) -> bool {
+        let prev_hardware_addr = self
+            .link
+            .lock()
+            .unwrap()
+            .src_hardware_addr_map
             .insert(src_ip_addr, hardware_addr);
         trace!(
             "set source hardware address of {} to {}",
             src_ip_addr,
             hardware_addr
         );
+
+        prev_hardware_addr.is_none()
     }
 
     /// Sets the local IP address.
@@ -175,11 +410,20 @@ impl Forwarder {
         trace!("set local IP address to {}", ip_addr);
     }
 
-    fn increase_ipv4_identification(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) {
-        let entry = self
+    /// Sets the local IPv6 address.
+    pub fn set_local_ipv6_addr(&mut self, ip_addr: Ipv6Addr) {
+        self.local_ipv6_addr = Some(ip_addr);
+        trace!("set local IPv6 address to {}", ip_addr);
+    }
+
+    /// Returns the IPv4 identification and increases it.
+    fn next_ipv4_identification(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) -> u16 {
+        let mut link_locked = self.link.lock().unwrap();
+        let entry = link_locked
             .ipv4_identification_map
             .entry((src_ip_addr, dst_ip_addr))
             .or_insert(0);
+        let identification = *entry;
         *entry = entry.checked_add(1).unwrap_or(0);
         trace!(
             "increase IPv4 identification of {} -> {} to {}",
@@ -187,55 +431,86 @@ impl Forwarder {
             src_ip_addr,
             entry
         );
+
+        identification
+    }
+
+    /// Returns the IPv6 identification and increases it.
+    fn next_ipv6_identification(&mut self) -> u32 {
+        let mut link_locked = self.link.lock().unwrap();
+        let identification = link_locked.ipv6_identification;
+        link_locked.ipv6_identification = identification.checked_add(1).unwrap_or(0);
+
+        identification
     }
 
     /// Sets the state of a TCP connection.
-    pub fn set_state(&mut self, dst: SocketAddrV4, src: SocketAddrV4, state: TcpTxState) {
+    pub fn set_state(&mut self, dst: SocketAddr, src: SocketAddr, state: TcpTxState) {
         let key = (src, dst);
 
         self.states.insert(key, state);
     }
 
     /// Removes all information related to a TCP connection.
-    pub fn clean_up(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
+    pub fn clean_up(&mut self, dst: SocketAddr, src: SocketAddr) {
         let key = (src, dst);
 
         self.states.remove(&key);
+        self.timers.cancel(dst, src);
+    }
+
+    /// Returns if a TCP connection is closed, which means its FIN is acknowledged by the source or
+    /// it does not exist.
+    pub fn is_closed(&self, dst: SocketAddr, src: SocketAddr) -> bool {
+        match self.get_state(dst, src) {
+            Some(state) => state.fin_acknowledged(),
+            None => true,
+        }
     }
 
     /// Returns the source MTU.
-    pub fn get_src_mtu(&self, src_ip_addr: Ipv4Addr) -> usize {
+    pub fn get_src_mtu(&self, src_ip_addr: IpAddr) -> usize {
         *self
+            .link
+            .lock()
+            .unwrap()
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu)
     }
 
+    /// Returns the source hardware address.
+    pub fn get_src_hardware_addr(&self, src_ip_addr: IpAddr) -> HardwareAddr {
+        *self
+            .link
+            .lock()
+            .unwrap()
+            .src_hardware_addr_map
+            .get(&src_ip_addr)
+            .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED)
+    }
+
     /// Returns the state of a TCP connection.
-    pub fn get_state(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<&TcpTxState> {
+    pub fn get_state(&self, dst: SocketAddr, src: SocketAddr) -> Option<&TcpTxState> {
         let key = (src, dst);
 
         self.states.get(&key)
     }
 
     /// Returns the mutable state of a TCP connection.
-    pub fn get_state_mut(
-        &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
-    ) -> Option<&mut TcpTxState> {
+    pub fn get_state_mut(&mut self, dst: SocketAddr, src: SocketAddr) -> Option<&mut TcpTxState> {
         let key = (src, dst);
 
         self.states.get_mut(&key)
     }
 
-    fn get_tcp_window(&self, dst: SocketAddrV4, src: SocketAddrV4) -> u16 {
+    fn get_tcp_window(&self, dst: SocketAddr, src: SocketAddr) -> u16 {
         let key = (src, dst);
 
         let state = self.states.get(&key).unwrap();
 
         // Avoid SWS
-        if ENABLE_RECV_SWS_AVOID {
+        if self.config.enable_recv_sws_avoid {
             let thresh = min(state.half_max_window() as usize, self.local_mtu);
 
             if (state.window() as usize) < thresh {
@@ -249,12 +524,12 @@ impl Forwarder {
     }
 
     /// Returns the size of the cache and the queue of a TCP connection.
-    pub fn get_cache_size(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+    pub fn get_cache_size(&mut self, dst: SocketAddr, src: SocketAddr) -> usize {
         let key = (src, dst);
 
         let state = self.states.get(&key).unwrap();
 
-        state.cache().len() + state.queue().len()
+        state.cache().len() + state.queue_len()
     }
 
     /// Sends an ARP reply packet.
@@ -263,10 +538,7 @@ impl Forwarder {
         let arp = Arp::new_reply(
             self.local_hardware_addr,
             self.local_ip_addr,
-            *self
-                .src_hardware_addr_map
-                .get(&src_ip_addr)
-                .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+            self.get_src_hardware_addr(IpAddr::V4(src_ip_addr)),
             src_ip_addr,
         );
 
@@ -283,6 +555,40 @@ impl Forwarder {
         self.send_ethernet(pcap::HARDWARE_ADDR_BROADCAST, Layers::Arp(arp), None, None)
     }
 
+    /// Sends an ICMPv6 neighbor advertisement packet.
+    pub fn send_neighbor_advertisement(&mut self, src_ip_addr: Ipv6Addr) -> io::Result<()> {
+        let local_ip_addr = self
+            .local_ipv6_addr
+            .ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+        // ICMPv6
+        let icmpv6 =
+            Icmpv6::new_neighbor_advertisement(local_ip_addr, self.local_hardware_addr, true);
+
+        self.send_ipv6(local_ip_addr, src_ip_addr, Layers::Icmpv6(icmpv6), None)
+    }
+
+    /// Sends an unsolicited ICMPv6 neighbor advertisement packet.
+    pub fn send_unsolicited_neighbor_advertisement(&mut self) -> io::Result<()> {
+        let local_ip_addr = self
+            .local_ipv6_addr
+            .ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+        // ICMPv6
+        let icmpv6 =
+            Icmpv6::new_neighbor_advertisement(local_ip_addr, self.local_hardware_addr, false);
+
+        // All-nodes multicast address
+        let all_nodes_ip_addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+        self.send_ipv6(
+            local_ip_addr,
+            all_nodes_ip_addr,
+            Layers::Icmpv6(icmpv6),
+            None,
+        )
+    }
+
     /// Sends an ICMPv4 echo reply packet.
     pub fn send_icmpv4_echo_reply(
         &mut self,
@@ -323,12 +629,84 @@ impl Forwarder {
         self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
     }
 
+    /// Sends an ICMPv6 destination port unreachable packet.
+    pub fn send_icmpv6_destination_port_unreachable(
+        &mut self,
+        dst_ip_addr: Ipv6Addr,
+        src_ip_addr: Ipv6Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv6
+        let icmpv6 = Icmpv6::new_destination_port_unreachable(payload);
+
+        self.send_ipv6(dst_ip_addr, src_ip_addr, Layers::Icmpv6(icmpv6), None)
+    }
+
+    /// Sends an ICMP destination port unreachable packet in reply to a UDP datagram from the
+    /// source.
+    pub fn send_udp_port_unreachable(
+        &mut self,
+        dst: SocketAddr,
+        src: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // Rebuild the original datagram
+        let mut udp = Udp::new(src.port(), dst.port());
+        let ip = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src_ip_addr), IpAddr::V4(dst_ip_addr)) => {
+                let ipv4 = Ipv4::new(0, LayerKinds::Udp, src_ip_addr, dst_ip_addr).unwrap();
+                udp.set_ipv4_layer(&ipv4);
+
+                Layers::Ipv4(ipv4)
+            }
+            (IpAddr::V6(src_ip_addr), IpAddr::V6(dst_ip_addr)) => {
+                let ipv6 = Ipv6::new(LayerKinds::Udp, src_ip_addr, dst_ip_addr).unwrap();
+                udp.set_ipv6_layer(&ipv6);
+
+                Layers::Ipv6(ipv6)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "IP version mismatched",
+                ))
+            }
+        };
+        let n = ip.len() + udp.len() + payload.len();
+        let mut buffer = vec![0u8; n];
+        ip.serialize(buffer.as_mut_slice(), n)?;
+        udp.serialize_with_payload(&mut buffer[ip.len()..], payload, n - ip.len())?;
+
+        // Send
+        match (dst.ip(), src.ip()) {
+            (IpAddr::V4(dst_ip_addr), IpAddr::V4(src_ip_addr)) => {
+                // The IPv4 header and the first 8 Bytes of the original datagram
+                let size = ip.len() + udp.len();
+                self.send_icmpv4_destination_port_unreachable(
+                    dst_ip_addr,
+                    src_ip_addr,
+                    &buffer[..size],
+                )
+            }
+            (IpAddr::V6(dst_ip_addr), IpAddr::V6(src_ip_addr)) => {
+                // As much of the original packet as possible without exceeding the minimum MTU
+                let size = min(n, IPV6_MINIMUM_MTU - Ipv6::minimum_len() - 8);
+                self.send_icmpv6_destination_port_unreachable(
+                    dst_ip_addr,
+                    src_ip_addr,
+                    &buffer[..size],
+                )
+            }
+            _ => unreachable!(),
+        }
+    }
+
     /// Appends TCP payload to the queue.
     pub fn queue_tcp(
         &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
-        payload: &[u8],
+        dst: SocketAddr,
+        src: SocketAddr,
+        payload: Bytes,
     ) -> io::Result<()> {
         // Append to queue
         let state = self
@@ -342,8 +720,8 @@ impl Forwarder {
     /// Retransmits TCP packets from the cache. This method is used for fast retransmission.
     pub fn retransmit_tcp(
         &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
+        dst: SocketAddr,
+        src: SocketAddr,
         sacks: Option<Vec<(u32, u32)>>,
     ) -> io::Result<()> {
         let state = self
@@ -384,7 +762,7 @@ impl Forwarder {
             let state = self
                 .get_state(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-            let payload = state.cache().get(range.0, size)?;
+            let payload = state.cache().get_bytes(range.0, size)?;
             if payload.len() > 0 {
                 if range.1 == recv_next && state.cache_fin().is_some() {
                     // ACK/FIN
@@ -397,7 +775,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), true)?;
+                    self.send_tcp_ack(dst, src, range.0, &payload, true)?;
                 } else {
                     // ACK
                     trace!(
@@ -409,7 +787,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), false)?;
+                    self.send_tcp_ack(dst, src, range.0, &payload, false)?;
                 }
             }
         }
@@ -429,23 +807,78 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Checks the liveness of a TCP connection. A keep-alive probe is sent if the source has been
+    /// silent for a while, and the connection is reaped with a RST if the probes are not answered
+    /// or the connection is idle for too long. Returns if the connection is reaped.
+    fn keep_alive_tcp(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<bool> {
+        let now = Instant::now();
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let is_idle = state
+            .idle_deadline()
+            .map_or(false, |deadline| deadline <= now);
+        let is_keep_alive = state
+            .keep_alive_deadline()
+            .map_or(false, |deadline| deadline <= now);
+        let is_dead = is_keep_alive && state.keep_alive_probes() >= self.config.keep_alive_count;
+
+        if is_idle || is_dead {
+            // Reap
+            match is_idle {
+                true => {
+                    debug!("reap TCP {} -> {} due to idle timeout", dst, src);
+                    self.reaped.idle().fetch_add(1, Ordering::Relaxed);
+                }
+                false => {
+                    debug!("reap TCP {} -> {} due to keep-alive", dst, src);
+                    self.reaped.keep_alive().fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            // Send ACK/RST
+            let result = self.send_tcp_ack_rst(dst, src);
+
+            // Clean up
+            self.clean_up(dst, src);
+            self.reaped_streams.push((dst, src));
+
+            return result.map(|_| true);
+        }
+
+        if is_keep_alive {
+            trace!("probe TCP keep-alive {} -> {}", dst, src);
+
+            // Send
+            self.send_tcp_probe(dst, src)?;
+
+            let state = self
+                .get_state_mut(dst, src)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            state.add_keep_alive_probe();
+        }
+
+        Ok(false)
+    }
+
     /// Retransmits timed out TCP packets from the cache. This method is used for transmitting
     /// timed out data.
-    pub fn retransmit_tcp_timedout(
-        &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
-    ) -> io::Result<()> {
+    pub fn retransmit_tcp_timedout(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         let next_rto = state.next_rto();
-        let payload = state.cache_mut().get_timed_out_and_update(next_rto);
+        let timedout_size = state.cache_mut().update_timed_out(next_rto);
         let sequence = state.cache().sequence();
         let size = state.cache().len();
 
         if size > 0 {
-            if payload.len() > 0 {
+            if timedout_size > 0 {
+                let state = self
+                    .get_state_mut(dst, src)
+                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+                let payload = state.cache().get_bytes(sequence, timedout_size)?;
+
                 // Double RTO
                 state.double_rto();
 
@@ -467,7 +900,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, sequence, payload.as_slice(), true)?;
+                    self.send_tcp_ack(dst, src, sequence, &payload, true)?;
                 } else {
                     // ACK
                     trace!(
@@ -479,7 +912,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, sequence, payload.as_slice(), false)?;
+                    self.send_tcp_ack(dst, src, sequence, &payload, false)?;
                 }
             }
         } else {
@@ -509,7 +942,7 @@ impl Forwarder {
     }
 
     /// Sends TCP packets from the queue.
-    pub fn send_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         // Retransmit unhandled SYN
         let state = self
             .get_state(dst, src)
@@ -522,13 +955,14 @@ impl Forwarder {
             // TCP sequence
             let sent_size = state.cache().len();
             let remain_size = state.send_window().checked_sub(sent_size).unwrap_or(0);
+            let remain_size = min(remain_size, state.pacing_window());
             let remain_size = min(remain_size, u16::MAX as usize) as u16;
 
-            let mut size = min(remain_size as usize, state.queue().len());
+            let mut size = min(remain_size as usize, state.queue_len());
             // Avoid SWS
-            if ENABLE_SEND_SWS_AVOID {
-                let mtu = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu);
-                let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+            if self.config.enable_send_sws_avoid {
+                let mtu = self.get_src_mtu(src.ip());
+                let mss = mtu - (ip_minimum_len(src.ip()) + Tcp::minimum_len());
 
                 if size < mss && !state.cache().is_empty() {
                     size = 0;
@@ -539,26 +973,44 @@ impl Forwarder {
                 let state = self
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-                let payload = state.append_cache(size)?;
+                state.append_cache(size)?;
+                let sequence = state.sequence();
+                let payload = state.cache().get_bytes(sequence, size)?;
 
                 // If the queue is empty and a FIN is in the queue, pop it
-                if state.queue().is_empty() && state.queue_fin() {
+                if state.queue_len() == 0 && state.queue_fin() {
                     // ACK/FIN
                     state.append_cache_fin();
 
                     // Send
-                    let state = self
-                        .get_state(dst, src)
-                        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-                    let sequence = state.sequence();
                     self.send_tcp_ack(dst, src, sequence, &payload, true)?;
                 } else {
                     // ACK
+                    self.send_tcp_ack(dst, src, sequence, &payload, false)?;
+                }
+            }
+        } else if state.cache().is_empty() && state.queue_len() > 0 {
+            // Zero window
+            match state.persist() {
+                Some(timer) => {
+                    if timer.is_timedout() {
+                        trace!("probe TCP zero window {} -> {}", dst, src);
+
+                        // Send
+                        self.send_tcp_probe(dst, src)?;
+
+                        // Back off
+                        let state = self
+                            .get_state_mut(dst, src)
+                            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+                        state.update_persist_timer();
+                    }
+                }
+                None => {
                     let state = self
-                        .get_state(dst, src)
+                        .get_state_mut(dst, src)
                         .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-                    let sequence = state.sequence();
-                    self.send_tcp_ack(dst, src, sequence, &payload, false)?;
+                    state.update_persist_timer();
                 }
             }
         }
@@ -578,20 +1030,29 @@ impl Forwarder {
             }
         }
 
+        // Timer
+        self.schedule_timer(dst, src);
+
         Ok(())
     }
 
     fn send_tcp_ack(
         &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
+        dst: SocketAddr,
+        src: SocketAddr,
         sequence: u32,
         payload: &[u8],
         is_fin: bool,
     ) -> io::Result<()> {
         // Segmentation
-        let mss = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu)
-            - (Ipv4::minimum_len() + Tcp::minimum_len());
+        let mut mss = self.get_src_mtu(src.ip()) - (ip_minimum_len(src.ip()) + Tcp::minimum_len());
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if state.ts().is_some() {
+            mss -= TCP_TS_LEN;
+        }
+        let mss = mss;
         let mut i = 0;
         while mss * i < payload.len() {
             let state = self
@@ -616,7 +1077,7 @@ impl Forwarder {
                     sequence,
                     state.acknowledgement(),
                     self.get_tcp_window(dst, src),
-                    None,
+                    state.ts(),
                 );
                 recv_next = recv_next.checked_add(1).unwrap_or(0);
             } else {
@@ -628,23 +1089,19 @@ impl Forwarder {
                     state.acknowledgement(),
                     self.get_tcp_window(dst, src),
                     None,
-                    None,
+                    state.ts(),
                 );
             }
 
             // Send
-            self.send_ipv4(
-                dst.ip().clone(),
-                src.ip().clone(),
-                Layers::Tcp(tcp),
-                Some(payload),
-            )?;
+            self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), Some(payload))?;
 
             // Clear TCP delayed ACK
             let state = self
                 .get_state_mut(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
             state.clear_delayed_ack();
+            state.set_ack_sent();
 
             // Update TCP sequence
             let record_sequence = state.sequence();
@@ -662,8 +1119,8 @@ impl Forwarder {
     }
 
     /// Sends an TCP delayed ACK packet without payload.
-    pub fn send_tcp_delay_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        if ENABLE_DELAYED_ACK {
+    pub fn send_tcp_delay_ack_0(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
+        if self.config.enable_delayed_ack {
             let state = self
                 .get_state_mut(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
@@ -672,6 +1129,9 @@ impl Forwarder {
                 self.send_tcp_ack_0(dst, src)?;
             } else {
                 state.set_delayed_ack();
+
+                // Timer
+                self.schedule_timer(dst, src);
             }
         } else {
             self.send_tcp_ack_0(dst, src)?;
@@ -680,8 +1140,37 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Sends an TCP probe. The probe is an ACK packet without payload using the last acknowledged
+    /// sequence, which forces the source to report its window. Used in probing the zero window and
+    /// the keep-alive.
+    fn send_tcp_probe(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
+        // TCP
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let tcp = Tcp::new_ack(
+            dst.port(),
+            src.port(),
+            state.cache().sequence().checked_sub(1).unwrap_or(u32::MAX),
+            state.acknowledgement(),
+            self.get_tcp_window(dst, src),
+            state.sacks().clone(),
+            state.ts(),
+        );
+
+        // Send
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)?;
+
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        state.set_ack_sent();
+
+        Ok(())
+    }
+
     /// Sends an TCP ACK packet without payload.
-    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp_ack_0(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
@@ -693,25 +1182,26 @@ impl Forwarder {
             state.acknowledgement(),
             self.get_tcp_window(dst, src),
             state.sacks().clone(),
-            None,
+            state.ts(),
         );
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)?;
 
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         state.clear_delayed_ack();
+        state.set_ack_sent();
 
         Ok(())
     }
 
-    fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        let mss = match ENABLE_MSS {
+    fn send_tcp_ack_syn(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
+        let mss = match self.config.enable_mss {
             true => {
-                let mss = self.local_mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+                let mss = self.local_mtu - (ip_minimum_len(src.ip()) + Tcp::minimum_len());
                 let mss = if mss > u16::MAX as usize {
                     u16::MAX
                 } else {
@@ -736,23 +1226,24 @@ impl Forwarder {
             mss,
             state.src_wscale(),
             state.sack_perm(),
-            None,
+            state.ts(),
         );
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)?;
 
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         state.clear_delayed_ack();
+        state.set_ack_sent();
 
         Ok(())
     }
 
     /// Sends an TCP ACK/RST packet.
-    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
@@ -767,13 +1258,14 @@ impl Forwarder {
         );
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)?;
 
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         state.clear_delayed_ack();
+        state.set_ack_sent();
 
         Ok(())
     }
@@ -781,32 +1273,46 @@ impl Forwarder {
     /// Sends an TCP ACK/RST packet of an untracked connection.
     pub fn send_tcp_ack_rst_untracked(
         &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
+        dst: SocketAddr,
+        src: SocketAddr,
         sequence: u32,
     ) -> io::Result<()> {
         // TCP
         let tcp = Tcp::new_ack_rst(dst.port(), src.port(), sequence, 0, 0, None);
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP ACK/RST packet refusing an TCP SYN packet of an untracked connection.
+    pub fn send_tcp_ack_rst_refused(
+        &mut self,
+        dst: SocketAddr,
+        src: SocketAddr,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        // TCP
+        let tcp = Tcp::new_ack_rst(dst.port(), src.port(), 0, acknowledgement, 0, None);
+
+        // Send
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)
     }
 
     /// Sends an TCP RST packet.
     pub fn send_tcp_rst(
         &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
+        dst: SocketAddr,
+        src: SocketAddr,
         sequence: u32,
     ) -> io::Result<()> {
         // TCP
         let tcp = Tcp::new_rst(dst.port(), src.port(), sequence, 0, 0, None);
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)
     }
 
-    fn send_tcp_fin(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    fn send_tcp_fin(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
@@ -817,29 +1323,47 @@ impl Forwarder {
             state.sequence(),
             state.acknowledgement(),
             self.get_tcp_window(dst, src),
-            None,
+            state.ts(),
         );
 
         // Send
-        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+        self.send_ip(dst.ip(), src.ip(), Layers::Tcp(tcp), None)?;
+
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        state.set_ack_sent();
+
+        Ok(())
     }
 
     /// Sends UDP packets.
-    pub fn send_udp(
-        &mut self,
-        dst: SocketAddrV4,
-        src: SocketAddrV4,
-        payload: &[u8],
-    ) -> io::Result<()> {
+    pub fn send_udp(&mut self, dst: SocketAddr, src: SocketAddr, payload: &[u8]) -> io::Result<()> {
         // UDP
         let udp = Udp::new(dst.port(), src.port());
 
-        self.send_ipv4(
-            dst.ip().clone(),
-            src.ip().clone(),
-            Layers::Udp(udp),
-            Some(payload),
-        )
+        self.send_ip(dst.ip(), src.ip(), Layers::Udp(udp), Some(payload))
+    }
+
+    fn send_ip(
+        &mut self,
+        dst_ip_addr: IpAddr,
+        src_ip_addr: IpAddr,
+        transport: Layers,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        match (dst_ip_addr, src_ip_addr) {
+            (IpAddr::V4(dst_ip_addr), IpAddr::V4(src_ip_addr)) => {
+                self.send_ipv4(dst_ip_addr, src_ip_addr, transport, payload)
+            }
+            (IpAddr::V6(dst_ip_addr), IpAddr::V6(src_ip_addr)) => {
+                self.send_ipv6(dst_ip_addr, src_ip_addr, transport, payload)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IP version mismatched",
+            )),
+        }
     }
 
     fn send_ipv4(
@@ -855,23 +1379,12 @@ impl Forwarder {
                 Some(payload) => payload.len(),
                 None => 0,
             };
-        let mss = *self
-            .src_mtu_map
-            .get(&src_ip_addr)
-            .unwrap_or(&self.local_mtu)
-            - Ipv4::minimum_len();
+        let mss = self.get_src_mtu(IpAddr::V4(src_ip_addr)) - Ipv4::minimum_len();
+        let identification = self.next_ipv4_identification(dst_ip_addr, src_ip_addr);
         if size <= mss {
             // IPv4
-            let ipv4 = Ipv4::new(
-                *self
-                    .ipv4_identification_map
-                    .get(&(src_ip_addr, dst_ip_addr))
-                    .unwrap_or(&0),
-                transport.kind(),
-                dst_ip_addr,
-                src_ip_addr,
-            )
-            .unwrap();
+            let ipv4 =
+                Ipv4::new(identification, transport.kind(), dst_ip_addr, src_ip_addr).unwrap();
 
             // Set IPv4 layer for checksum
             match transport {
@@ -882,10 +1395,7 @@ impl Forwarder {
 
             // Send
             self.send_ethernet(
-                *self
-                    .src_hardware_addr_map
-                    .get(&src_ip_addr)
-                    .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                self.get_src_hardware_addr(IpAddr::V4(src_ip_addr)),
                 Layers::Ipv4(ipv4),
                 Some(transport),
                 payload,
@@ -931,10 +1441,7 @@ impl Forwarder {
                 // IPv4
                 let ipv4 = if remain > 0 {
                     Ipv4::new_more_fragment(
-                        *self
-                            .ipv4_identification_map
-                            .get(&(src_ip_addr, dst_ip_addr))
-                            .unwrap_or(&0),
+                        identification,
                         transport.kind(),
                         (n / 8) as u16,
                         dst_ip_addr,
@@ -943,10 +1450,7 @@ impl Forwarder {
                     .unwrap()
                 } else {
                     Ipv4::new_last_fragment(
-                        *self
-                            .ipv4_identification_map
-                            .get(&(src_ip_addr, dst_ip_addr))
-                            .unwrap_or(&0),
+                        identification,
                         transport.kind(),
                         (n / 8) as u16,
                         dst_ip_addr,
@@ -957,10 +1461,7 @@ impl Forwarder {
 
                 // Send
                 self.send_ethernet(
-                    *self
-                        .src_hardware_addr_map
-                        .get(&src_ip_addr)
-                        .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                    self.get_src_hardware_addr(IpAddr::V4(src_ip_addr)),
                     Layers::Ipv4(ipv4),
                     None,
                     Some(&buffer[n..n + length]),
@@ -970,43 +1471,140 @@ impl Forwarder {
             }
         }
 
-        // Update IPv4 identification
-        self.increase_ipv4_identification(dst_ip_addr, src_ip_addr);
-
         Ok(())
     }
 
-    fn send_ethernet(
+    fn send_ipv6(
         &mut self,
-        src_hardware_addr: HardwareAddr,
-        network: Layers,
-        transport: Option<Layers>,
+        dst_ip_addr: Ipv6Addr,
+        src_ip_addr: Ipv6Addr,
+        mut transport: Layers,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
-        // Ethernet
-        let ethernet =
-            Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
-
-        // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
-
-        // Send
-        match payload {
-            Some(payload) => self.send_with_payload(&indicator, payload),
-            None => self.send(&indicator),
-        }
-    }
+        let hardware_addr = if src_ip_addr.is_multicast() {
+            ipv6_multicast_hardware_addr(src_ip_addr)
+        } else {
+            self.get_src_hardware_addr(IpAddr::V6(src_ip_addr))
+        };
+
+        // Fragmentation
+        let size = &transport.len()
+            + match payload {
+                Some(payload) => payload.len(),
+                None => 0,
+            };
+        let mss = self.get_src_mtu(IpAddr::V6(src_ip_addr)) - Ipv6::minimum_len();
+        if size <= mss {
+            // IPv6
+            let ipv6 = match transport {
+                Layers::Icmpv6(ref icmpv6) if icmpv6.is_ndp() => {
+                    Ipv6::new_ndp(dst_ip_addr, src_ip_addr)
+                }
+                _ => Ipv6::new(transport.kind(), dst_ip_addr, src_ip_addr).unwrap(),
+            };
+
+            // Set IPv6 layer for checksum
+            match transport {
+                Layers::Icmpv6(ref mut icmpv6) => icmpv6.set_ipv6_layer(&ipv6),
+                Layers::Tcp(ref mut tcp) => tcp.set_ipv6_layer(&ipv6),
+                Layers::Udp(ref mut udp) => udp.set_ipv6_layer(&ipv6),
+                _ => {}
+            }
+
+            // Send
+            self.send_ethernet(hardware_addr, Layers::Ipv6(ipv6), Some(transport), payload)?;
+        } else {
+            // Pseudo header
+            let ipv6 = Ipv6::new(transport.kind(), dst_ip_addr, src_ip_addr).unwrap();
+
+            // Set IPv6 layer for checksum
+            match &mut transport {
+                Layers::Icmpv6(icmpv6) => icmpv6.set_ipv6_layer(&ipv6),
+                Layers::Tcp(tcp) => tcp.set_ipv6_layer(&ipv6),
+                Layers::Udp(udp) => udp.set_ipv6_layer(&ipv6),
+                _ => {}
+            }
+
+            // Payload
+            let mut buffer = vec![0u8; size];
+            match payload {
+                Some(payload) => transport.serialize_with_payload(
+                    buffer.as_mut_slice(),
+                    payload,
+                    transport.len() + payload.len(),
+                )?,
+                None => transport.serialize(buffer.as_mut_slice(), transport.len())?,
+            };
+
+            // The fragment extension header takes 8 Bytes
+            let identification = self.next_ipv6_identification();
+            let mss = (mss - 8) / 8 * 8;
+            let mut n = 0;
+            while n < size {
+                let length = min(size - n, mss);
+                let remain = size - n - length;
+
+                // IPv6
+                let ipv6 = Ipv6::new_fragment(
+                    identification,
+                    transport.kind(),
+                    (n / 8) as u16,
+                    remain > 0,
+                    dst_ip_addr,
+                    src_ip_addr,
+                )
+                .unwrap();
+
+                // Send
+                self.send_ethernet(
+                    hardware_addr,
+                    Layers::Ipv6(ipv6),
+                    None,
+                    Some(&buffer[n..n + length]),
+                )?;
+
+                n += length;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_ethernet(
+        &mut self,
+        src_hardware_addr: HardwareAddr,
+        network: Layers,
+        transport: Option<Layers>,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        // Ethernet
+        let ethernet =
+            Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
+
+        // Indicator
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+
+        // Send
+        match payload {
+            Some(payload) => self.send_with_payload(&indicator, payload),
+            None => self.send(&indicator),
+        }
+    }
 
     fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
         // Serialize and send
         let size = indicator.len();
         let buffer_size = max(size, MINIMUM_FRAME_SIZE);
         let mut result = None;
-        self.tx.build_and_send(1, buffer_size, &mut |buffer| {
-            if let Err(e) = indicator.serialize(&mut buffer[..size]) {
-                result = Some(e);
-            }
+        let mut link = self.link.lock().unwrap();
+        let Link { tx, dumper, .. } = &mut *link;
+        tx.build_and_send(1, buffer_size, &mut |buffer| match indicator
+            .serialize(&mut buffer[..size])
+        {
+            Ok(_) => dump(dumper, buffer, Direction::Outbound),
+            Err(e) => result = Some(e),
         });
+        drop(link);
         match result {
             Some(e) => return Err(e),
             None => debug!("send to pcap: {} ({} Bytes)", indicator.brief(), size),
@@ -1028,15 +1626,16 @@ impl Forwarder {
         let size = indicator.len();
         let buffer_size = max(size + payload.len(), MINIMUM_FRAME_SIZE);
         let mut result = None;
-        self.tx
-            .build_and_send(1, buffer_size, &mut |buffer| {
-                if let Err(e) =
-                    indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)
-                {
-                    result = Some(e);
-                }
-            })
-            .unwrap_or(Ok(()))?;
+        let mut link = self.link.lock().unwrap();
+        let Link { tx, dumper, .. } = &mut *link;
+        tx.build_and_send(1, buffer_size, &mut |buffer| match indicator
+            .serialize_with_payload(&mut buffer[..size + payload.len()], payload)
+        {
+            Ok(_) => dump(dumper, buffer, Direction::Outbound),
+            Err(e) => result = Some(e),
+        })
+        .unwrap_or(Ok(()))?;
+        drop(link);
         match result {
             Some(e) => return Err(e),
             None => debug!(
@@ -1060,7 +1659,7 @@ impl Forwarder {
 }
 
 impl ForwardStream for Forwarder {
-    fn open(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    fn open(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         self.send_tcp_ack_syn(dst, src)?;
 
         let state = self
@@ -1071,7 +1670,7 @@ impl ForwardStream for Forwarder {
         Ok(())
     }
 
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
+    fn forward(&mut self, dst: SocketAddr, src: SocketAddr, payload: Bytes) -> io::Result<()> {
         let state = self
             .get_state(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
@@ -1082,11 +1681,7 @@ impl ForwardStream for Forwarder {
         self.queue_tcp(dst, src, payload)
     }
 
-    fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        self.retransmit_tcp_timedout(dst, src)
-    }
-
-    fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    fn close(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()> {
         let state = match self.get_state_mut(dst, src) {
             Some(state) => state,
             None => return Ok(()),
@@ -1096,16 +1691,32 @@ impl ForwardStream for Forwarder {
         self.send_tcp(dst, src)
     }
 
-    fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize> {
+    fn check(&self, dst: SocketAddr, src: SocketAddr) -> io::Result<usize> {
         let state = self
             .get_state(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         Ok(state.queue_remaining())
     }
+
+    fn notify(&self, dst: SocketAddr, src: SocketAddr) -> io::Result<Arc<Notify>> {
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(state.queue_notify())
+    }
+
+    fn backpressure(&self) -> Backpressure {
+        self.backpressure.clone()
+    }
+
+    fn open_window(&mut self, dst: SocketAddr, src: SocketAddr) {
+        self.opened_windows.push((dst, src));
+        self.windows_notify.notify_one();
+    }
 }
 
 impl ForwardDatagram for Forwarder {
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
+    fn forward(&mut self, dst: SocketAddr, src: SocketAddr, payload: &[u8]) -> io::Result<()> {
         self.send_udp(dst, src, payload)
     }
 }
@@ -1161,38 +1772,119 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
     vector
 }
 
-/// Represents the threshold of TCP ACK duplicates before trigger a fast retransmission.
-const DUPLICATES_THRESHOLD: usize = 3;
-/// Represents the cool down time between 2 retransmissions.
-const RETRANS_COOL_DOWN: u128 = 200;
+/// Dumps a frame if the dumper is set. Errors in dumping are logged only, so they will not interrupt
+/// the redirection.
+fn dump(dumper: &Option<Arc<Mutex<Dumper>>>, frame: &[u8], direction: Direction) {
+    if let Some(dumper) = dumper {
+        if let Err(ref e) = dumper.lock().unwrap().dump(frame, direction) {
+            warn!("dump: {}", e);
+        }
+    }
+}
 
-/// Represents if the TCP selective acknowledgment option is enabled.
-const ENABLE_SACK: bool = true;
+/// Flushes the dumper if it is set.
+fn flush_dump(dumper: &Option<Arc<Mutex<Dumper>>>) {
+    if let Some(dumper) = dumper {
+        if let Err(ref e) = dumper.lock().unwrap().flush() {
+            warn!("dump: {}", e);
+        }
+    }
+}
 
-/// Represents if the TCP window scale option is enabled.
-const ENABLE_WSCALE: bool = true;
-/// Represents the max window scale of the receive window.
-const MAX_RECV_WSCALE: u8 = 8;
+/// Represents a frame dispatched to a shard of the `Redirector`.
+struct ShardFrame {
+    frame: Bytes,
+    /// Represents the transport layer and the payload of the reassembled IPv4 fragmentation if the
+    /// frame is the fragment completing it.
+    frag: Option<(Option<Layers>, Bytes)>,
+}
 
-/// Represents the max limit of UDP port for binding in local.
-const MAX_UDP_PORT: usize = 256;
+/// Represents a guard counting a running shard of the `Redirector`, the count is decreased when the
+/// shard is finished, returns an error or panics.
+struct ShardGuard(Arc<AtomicUsize>);
+
+impl Drop for ShardGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Represents a proxied stream connected for a TCP connection, or the error in connecting.
+type Connected = ((SocketAddr, SocketAddr), io::Result<ProxiedStream>);
+
+/// Returns the index of the shard handling the flow of the given transport layer. TCP segments and
+/// UDP datagrams are dispatched by their flow (the source, the destination and the protocol), and
+/// ICMP port unreachable messages are dispatched by the flow quoted in them, which is in reply to
+/// the source, so they are handled by the shard owning the flow. Others are handled in the first
+/// shard.
+fn flow_shard(transport: &Layers, shards: usize) -> usize {
+    let flow = match transport {
+        Layers::Tcp(tcp) => (
+            LayerKinds::Tcp,
+            SocketAddr::new(tcp.src_ip_addr(), tcp.src()),
+            SocketAddr::new(tcp.dst_ip_addr(), tcp.dst()),
+        ),
+        Layers::Udp(udp) => (
+            LayerKinds::Udp,
+            SocketAddr::new(udp.src_ip_addr(), udp.src()),
+            SocketAddr::new(udp.dst_ip_addr(), udp.dst()),
+        ),
+        Layers::Icmpv4(icmpv4) if icmpv4.is_destination_port_unreachable() => {
+            match (icmpv4.next_level_layer_kind(), icmpv4.dst(), icmpv4.src()) {
+                (Some(kind), Some(src), Some(dst)) => (kind, src, dst),
+                _ => return 0,
+            }
+        }
+        Layers::Icmpv6(icmpv6) if icmpv6.is_destination_port_unreachable() => {
+            match (icmpv6.next_level_layer_kind(), icmpv6.dst(), icmpv6.src()) {
+                (Some(kind), Some(src), Some(dst)) => (kind, src, dst),
+                _ => return 0,
+            }
+        }
+        _ => return 0,
+    };
+
+    let mut hasher = DefaultHasher::new();
+    flow.hash(&mut hasher);
+
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Returns the receive window of a TCP connection, which is limited by both the cache and the queue
+/// to the proxy.
+fn tcp_recv_window(state: &TcpRxState, stream: &StreamWorker) -> u16 {
+    (min(state.cache().remaining(), stream.tx_remaining()) >> state.wscale() as usize) as u16
+}
 
 /// Represents a channel redirect traffic to the proxy or loopback to the source in pcap.
 pub struct Redirector {
     tx: Arc<Mutex<Forwarder>>,
-    tx_src_hardware_addr_set_ip_addr_set: HashSet<Ipv4Addr>,
+    tx_src_hardware_addr_set_ip_addr_set: HashSet<IpAddr>,
     src_ip_addr: Ipv4Network,
     local_ip_addr: Ipv4Addr,
     gw_ip_addr: Option<Ipv4Addr>,
-    proxy: ProxyConfig,
-    streams: HashMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
-    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
+    src_ipv6_addr: Option<Ipv6Network>,
+    local_ipv6_addr: Option<Ipv6Addr>,
+    gw_ipv6_addr: Option<Ipv6Addr>,
+    proxy: Arc<ProxyConfig>,
+    proxies: HashMap<String, Arc<ProxyConfig>>,
+    direct: Arc<ProxyConfig>,
+    router: Router,
+    streams: HashMap<(SocketAddr, SocketAddr), StreamWorker>,
+    states: HashMap<(SocketAddr, SocketAddr), TcpRxState>,
+    /// Represents the states of the TCP connections whose proxied streams are connecting.
+    connecting: HashMap<(SocketAddr, SocketAddr), TcpRxState>,
+    connected_tx: mpsc::UnboundedSender<Connected>,
+    connected_rx: mpsc::UnboundedReceiver<Connected>,
     datagrams: HashMap<u16, DatagramWorker>,
-    /// Represents the map mapping a source port to a local port.
-    datagram_map: HashMap<SocketAddrV4, u16>,
-    /// Represents the LRU mapping a local port to a source port.
-    udp_lru: LruCache<u16, SocketAddrV4>,
+    /// Represents the map mapping a source port and its action to a local port.
+    datagram_map: HashMap<(SocketAddr, Action), u16>,
+    /// Represents the LRU mapping a local port to a source port and its action.
+    udp_lru: LruCache<u16, (SocketAddr, Action)>,
     defrag: Defraggler,
+    is_shutting_down: bool,
+    config: Config,
+    dumper: Option<Arc<Mutex<Dumper>>>,
     traffic_size: Option<Arc<AtomicUsize>>,
     traffic_count: Option<Arc<AtomicUsize>>,
 }
@@ -1215,19 +1907,32 @@ impl Redirector {
             Some(traffic) => Some(traffic.count()),
             None => None,
         };
+        let (connected_tx, connected_rx) = mpsc::unbounded_channel();
         let redirector = Redirector {
             tx,
             tx_src_hardware_addr_set_ip_addr_set: HashSet::new(),
             src_ip_addr,
             local_ip_addr,
             gw_ip_addr,
-            proxy,
+            src_ipv6_addr: None,
+            local_ipv6_addr: None,
+            gw_ipv6_addr: None,
+            proxy: Arc::new(proxy),
+            proxies: HashMap::new(),
+            direct: Arc::new(ProxyConfig::Direct),
+            router: Router::new(),
             streams: HashMap::new(),
             states: HashMap::new(),
+            connecting: HashMap::new(),
+            connected_tx,
+            connected_rx,
             datagrams: HashMap::new(),
             datagram_map: HashMap::new(),
-            udp_lru: LruCache::new(MAX_UDP_PORT),
+            udp_lru: LruCache::new(Config::default().max_udp_port),
             defrag: Defraggler::new(),
+            is_shutting_down: false,
+            config: Config::default(),
+            dumper: None,
             traffic_size: size,
             traffic_count: count,
         };
@@ -1238,58 +1943,438 @@ impl Redirector {
         redirector
     }
 
+    /// Creates a new shard of the `Redirector`. The shard shares the proxies, the rules and the
+    /// configuration with this one, but tracks its own connections in a `Forwarder` of a shard. The
+    /// limit of local UDP ports is divided evenly across the given number of shards.
+    fn new_shard(&self, shards: usize) -> Redirector {
+        let (connected_tx, connected_rx) = mpsc::unbounded_channel();
+        Redirector {
+            tx: Arc::new(Mutex::new(self.tx.lock().unwrap().new_shard())),
+            tx_src_hardware_addr_set_ip_addr_set: HashSet::new(),
+            src_ip_addr: self.src_ip_addr,
+            local_ip_addr: self.local_ip_addr,
+            gw_ip_addr: self.gw_ip_addr,
+            src_ipv6_addr: self.src_ipv6_addr,
+            local_ipv6_addr: self.local_ipv6_addr,
+            gw_ipv6_addr: self.gw_ipv6_addr,
+            proxy: Arc::clone(&self.proxy),
+            proxies: self.proxies.clone(),
+            direct: Arc::clone(&self.direct),
+            router: self.router.clone(),
+            streams: HashMap::new(),
+            states: HashMap::new(),
+            connecting: HashMap::new(),
+            connected_tx,
+            connected_rx,
+            datagrams: HashMap::new(),
+            datagram_map: HashMap::new(),
+            udp_lru: LruCache::new(max(1, self.config.max_udp_port / shards)),
+            defrag: Defraggler::new(),
+            is_shutting_down: false,
+            config: self.config.clone(),
+            dumper: self.dumper.clone(),
+            traffic_size: self.traffic_size.clone(),
+            traffic_count: self.traffic_count.clone(),
+        }
+    }
+
+    /// Sets the runtime configuration. The configuration is also applied to the `Forwarder`.
+    pub fn set_config(&mut self, config: Config) {
+        self.udp_lru.resize(config.max_udp_port);
+        self.tx.lock().unwrap().set_config(config.clone());
+        self.config = config;
+    }
+
+    /// Sets the dumper which dumps the frames received from the source and the frames sent to the
+    /// source. The dumper is also applied to the `Forwarder`.
+    pub fn set_dumper(&mut self, dumper: Dumper) {
+        let dumper = Arc::new(Mutex::new(dumper));
+        self.tx.lock().unwrap().set_dumper(Arc::clone(&dumper));
+        self.dumper = Some(dumper);
+    }
+
+    /// Adds a named proxy which can be used in rules.
+    pub fn add_proxy(&mut self, name: String, proxy: ProxyConfig) {
+        self.proxies.insert(name, Arc::new(proxy));
+    }
+
+    /// Appends a rule for routing. Rules are evaluated in order, and the traffic not matching any
+    /// rule will be forwarded through the default proxy.
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.router.push(rule);
+    }
+
+    /// Returns the statistics of reaped connections.
+    pub fn reaped(&self) -> Reaped {
+        self.tx.lock().unwrap().reaped()
+    }
+
+    /// Returns the backpressure statistics of the proxied streams.
+    pub fn backpressure(&self) -> Backpressure {
+        self.tx.lock().unwrap().backpressure()
+    }
+
+    /// Returns the latency statistics of the upstreams of the default proxy and the named
+    /// proxies.
+    pub fn latencies(&self) -> Vec<(String, Latency)> {
+        let mut latencies = self.proxy.latencies();
+        for proxy in self.proxies.values() {
+            latencies.extend(proxy.latencies());
+        }
+
+        latencies
+    }
+
+    /// Enables redirecting IPv6 traffic from the given source network.
+    pub fn set_ipv6(
+        &mut self,
+        src_ip_addr: Ipv6Network,
+        local_ip_addr: Option<Ipv6Addr>,
+        gw_ip_addr: Option<Ipv6Addr>,
+    ) {
+        self.src_ipv6_addr = Some(src_ip_addr);
+        self.local_ipv6_addr = local_ip_addr;
+        self.gw_ipv6_addr = gw_ip_addr;
+        if let Some(ip_addr) = gw_ip_addr.or(local_ip_addr) {
+            self.tx.lock().unwrap().set_local_ipv6_addr(ip_addr);
+        }
+    }
+
     /// Opens an `Interface` for redirection.
-    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+    pub async fn open(&mut self, rx: Receiver) -> io::Result<()> {
         self.open_monitored(rx, None).await
     }
 
-    /// Opens an `Interface` for redirection and monitoring.
+    /// Opens an `Interface` for redirection and monitoring. The `Receiver` is moved into a
+    /// dedicated capture thread, and the captured frames are dispatched by flow to shards running
+    /// in their own tasks. The `Receiver` may also be a `FileReceiver` replaying a capture file or
+    /// the receive half of a `MemoryLink`, and the redirection is shut down after the frames in
+    /// the file are exhausted or the `MemoryPeer` is dropped.
     pub async fn open_monitored(
         &mut self,
-        rx: &mut Receiver,
+        rx: Receiver,
         is_running: Option<Arc<AtomicBool>>,
     ) -> io::Result<()> {
+        let mut rx = AsyncReceiver::new(rx, Duration::from_millis(self.config.timedout_wait))?;
+        let is_running = is_running.unwrap_or_else(|| Arc::new(AtomicBool::new(true)));
+
         // Send gratuitous ARP
         if self.gw_ip_addr.is_some() {
             self.tx.lock().unwrap().send_gratuitous_arp()?;
         }
+        // Send unsolicited neighbor advertisement
+        if self.gw_ipv6_addr.is_some() {
+            self.tx
+                .lock()
+                .unwrap()
+                .send_unsolicited_neighbor_advertisement()?;
+        }
+
+        // Check upstreams
+        self.proxy.spawn_health_check(&self.config);
+        for proxy in self.proxies.values() {
+            proxy.spawn_health_check(&self.config);
+        }
+
+        // Shard
+        let shards = match self.config.shards {
+            0 => thread::available_parallelism().map_or(1, |shards| shards.get()),
+            shards => shards,
+        };
+        let running_shards = Arc::new(AtomicUsize::new(shards));
+        let mut shard_txs = Vec::with_capacity(shards);
+        for _ in 0..shards {
+            let (shard_tx, mut shard_rx) = mpsc::channel(SHARD_CHANNEL_CAPACITY);
+            let mut shard = self.new_shard(shards);
+            let is_running = Some(Arc::clone(&is_running));
+            let guard = ShardGuard(Arc::clone(&running_shards));
+            tokio::spawn(async move {
+                // The shard is counted as shut down even if it panics
+                let _guard = guard;
+                if let Err(ref e) = shard.run(&mut shard_rx, is_running).await {
+                    warn!("handle shard: {}", e);
+                }
+            });
+            shard_txs.push(shard_tx);
+        }
+        debug!("dispatch to {} shards", shards);
+
+        // Dispatch until all the shards are shut down
+        let mut flushed_at = Instant::now();
+        let mut dropped_frames = 0usize;
+        while running_shards.load(Ordering::Relaxed) > 0 {
+            // Flush the dumper periodically
+            if flushed_at.elapsed() >= Duration::from_millis(DUMP_FLUSH_INTERVAL) {
+                flush_dump(&self.dumper);
+                flushed_at = Instant::now();
+
+                if dropped_frames > 0 {
+                    warn!("Drop {} frames due to busy shards", dropped_frames);
+                    dropped_frames = 0;
+                }
+            }
+
+            // Receive, return periodically for monitoring
+            let frame =
+                match time::timeout(Duration::from_millis(MONITOR_INTERVAL), rx.next()).await {
+                    Ok(Ok(frame)) => frame,
+                    Ok(Err(e)) => {
+                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                            return Err(e);
+                        }
+
+                        // The capture file is exhausted, shut down after the responses are
+                        // forwarded
+                        info!("Replay finished, shut down in {} ms", REPLAY_LINGER);
+                        time::sleep(Duration::from_millis(REPLAY_LINGER)).await;
+                        is_running.store(false, Ordering::Relaxed);
+                        while running_shards.load(Ordering::Relaxed) > 0 {
+                            time::sleep(Duration::from_millis(MONITOR_INTERVAL)).await;
+                        }
+
+                        break;
+                    }
+                    Err(_) => continue,
+                };
+
+            if let Some((index, shard_frame)) = self.dispatch(frame, shards) {
+                // Drop the frame instead of blocking other shards if the shard is busy, the shard
+                // may also have been shut down
+                if let Err(mpsc::error::TrySendError::Full(_)) =
+                    shard_txs[index].try_send(shard_frame)
+                {
+                    trace!("drop frame to busy shard {}", index);
+                    dropped_frames = dropped_frames + 1;
+                }
+            }
+        }
+        flush_dump(&self.dumper);
+
+        Ok(())
+    }
+
+    /// Dispatches a frame to a shard by its flow. Returns the index of the shard and the frame to
+    /// be sent, or `None` if the frame is invalid or an incomplete IPv4 fragment.
+    fn dispatch(&mut self, frame: Bytes, shards: usize) -> Option<(usize, ShardFrame)> {
+        let indicator = Indicator::from(frame.as_ref())?;
+
+        // Dump
+        if self.is_from_src(&indicator) {
+            dump(&self.dumper, &frame, Direction::Inbound);
+        }
+
+        // Fragmentation
+        if let Some(ipv4) = indicator.ipv4() {
+            if ipv4.is_fragment() {
+                let frag = self
+                    .defrag
+                    .add(&indicator, &frame[..indicator.content_len()])?;
+                let (transport, payload) = frag.into_concatenated();
+                let index = match transport {
+                    Some(ref transport) => flow_shard(transport, shards),
+                    None => 0,
+                };
+
+                return Some((
+                    index,
+                    ShardFrame {
+                        frame,
+                        frag: Some((transport, payload)),
+                    },
+                ));
+            }
+        }
+
+        let index = match indicator.transport() {
+            Some(transport) => flow_shard(transport, shards),
+            None => 0,
+        };
+
+        Some((index, ShardFrame { frame, frag: None }))
+    }
+
+    /// Returns if the frame is sent from the source.
+    fn is_from_src(&self, indicator: &Indicator) -> bool {
+        if let Some(arp) = indicator.arp() {
+            return arp.src() != self.local_ip_addr && self.src_ip_addr.contains(arp.src());
+        }
+        if let Some(ipv4) = indicator.ipv4() {
+            return ipv4.src() != self.local_ip_addr && self.src_ip_addr.contains(ipv4.src());
+        }
+        if let Some(ipv6) = indicator.ipv6() {
+            return Some(ipv6.src()) != self.local_ipv6_addr
+                && self
+                    .src_ipv6_addr
+                    .map_or(false, |src_ip_addr| src_ip_addr.contains(ipv6.src()));
+        }
+
+        false
+    }
+
+    /// Runs a shard of the redirection, which handles the frames dispatched to it.
+    async fn run(
+        &mut self,
+        rx: &mut mpsc::Receiver<ShardFrame>,
+        is_running: Option<Arc<AtomicBool>>,
+    ) -> io::Result<()> {
+        // Drive timers
+        Forwarder::spawn_timer(&self.tx);
 
         loop {
             // Monitor
             if let Some(is_running) = &is_running {
                 if !is_running.load(Ordering::Relaxed) {
-                    return Ok(());
+                    return self.shutdown(rx).await;
                 }
             }
-            match rx.next() {
-                Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
-                        if let Some(t) = indicator.network_kind() {
-                            match t {
-                                LayerKinds::Arp => {
-                                    if let Err(ref e) = self.handle_arp(indicator) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                LayerKinds::Ipv4 => {
-                                    if let Err(ref e) = self.handle_ipv4(indicator, frame).await {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                    };
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                        continue;
+
+            self.handle_next(rx).await?;
+        }
+    }
+
+    /// Shuts down the redirection gracefully. New TCP connections are refused, and the existing
+    /// ones are closed with FINs after the data in their queues and caches is flushed to the
+    /// source. The method returns after all the final ACKs are received, or the remaining
+    /// connections will be reset if the shutdown timeout is reached.
+    async fn shutdown(&mut self, rx: &mut mpsc::Receiver<ShardFrame>) -> io::Result<()> {
+        self.is_shutting_down = true;
+        let deadline = Instant::now() + Duration::from_millis(self.config.shutdown_timeout);
+
+        // Refuse the connections in connecting
+        let keys = self.connecting.keys().cloned().collect::<Vec<_>>();
+        for (src, dst) in keys {
+            if let Err(ref e) = self.reset_tcp_syn(src, dst) {
+                warn!("handle shutdown: {}: {} -> {}: {}", "TCP", dst, src, e);
+            }
+        }
+
+        // Close streams from the proxy, the FINs will be sent after flushing
+        for stream in self.streams.values_mut() {
+            stream.shutdown(Shutdown::Read);
+        }
+        info!("Wait for {} connections to close", self.streams.len());
+
+        loop {
+            // Clean up closed connections
+            let closed_streams = {
+                let tx_locked = self.tx.lock().unwrap();
+                self.streams
+                    .keys()
+                    .filter(|(src, dst)| tx_locked.is_closed(*dst, *src))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            for (src, dst) in closed_streams {
+                self.clean_up(src, dst);
+            }
+
+            if self.streams.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "Reset {} connections due to shutdown timeout",
+                    self.streams.len()
+                );
+
+                let keys = self.streams.keys().cloned().collect::<Vec<_>>();
+                for (src, dst) in keys {
+                    // Send ACK/RST
+                    if let Err(ref e) = self.tx.lock().unwrap().send_tcp_ack_rst(dst, src) {
+                        warn!("handle shutdown: {}: {} -> {}: {}", "TCP", dst, src, e);
                     }
-                    return Err(e);
+
+                    // Clean up
+                    self.clean_up(src, dst);
                 }
-            };
+                break;
+            }
+
+            self.handle_next(rx).await?;
         }
+
+        Ok(())
+    }
+
+    async fn handle_next(&mut self, rx: &mut mpsc::Receiver<ShardFrame>) -> io::Result<()> {
+        // Reap
+        let reaped_streams = self.tx.lock().unwrap().take_reaped_streams();
+        for (dst, src) in reaped_streams {
+            self.clean_up(src, dst);
+        }
+
+        // Update windows
+        let opened_windows = self.tx.lock().unwrap().take_opened_windows();
+        for (dst, src) in opened_windows {
+            if let Err(ref e) = self.update_window(src, dst) {
+                warn!("handle window: {}: {} -> {}: {}", "TCP", dst, src, e);
+            }
+        }
+
+        // Receive, return periodically for monitoring, if any window is opened or if any proxied
+        // stream is connected
+        let windows_notify = self.tx.lock().unwrap().windows_notify();
+        let mut shard_frame = None;
+        let mut connected = None;
+        {
+            let rx_fut = time::timeout(Duration::from_millis(MONITOR_INTERVAL), rx.recv());
+            let windows_notify_fut = windows_notify.notified();
+            let connected_rx_fut = self.connected_rx.recv();
+
+            tokio::pin!(rx_fut, windows_notify_fut, connected_rx_fut);
+
+            tokio::select! {
+                r = rx_fut => match r {
+                    Ok(Some(this_shard_frame)) => shard_frame = Some(this_shard_frame),
+                    Ok(None) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "dispatcher stopped",
+                        ))
+                    }
+                    Err(_) => return Ok(()),
+                },
+                r = connected_rx_fut => connected = r,
+                _ = windows_notify_fut => return Ok(())
+            }
+        }
+
+        // Connected
+        if let Some((key, stream)) = connected {
+            if let Err(ref e) = self.handle_tcp_connected(key, stream) {
+                warn!("handle connect: {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+            }
+        }
+
+        let ShardFrame { frame, frag } = match shard_frame {
+            Some(shard_frame) => shard_frame,
+            None => return Ok(()),
+        };
+
+        if let Some(ref indicator) = Indicator::from(frame.as_ref()) {
+            if let Some(t) = indicator.network_kind() {
+                match t {
+                    LayerKinds::Arp => {
+                        if let Err(ref e) = self.handle_arp(indicator) {
+                            warn!("handle {}: {}", indicator.brief(), e);
+                        }
+                    }
+                    LayerKinds::Ipv4 => {
+                        if let Err(ref e) = self.handle_ipv4(indicator, &frame, frag).await {
+                            warn!("handle {}: {}", indicator.brief(), e);
+                        }
+                    }
+                    LayerKinds::Ipv6 => {
+                        if let Err(ref e) = self.handle_ipv6(indicator, &frame).await {
+                            warn!("handle {}: {}", indicator.brief(), e);
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        };
+
+        Ok(())
     }
 
     fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
@@ -1308,7 +2393,7 @@ impl Redirector {
                     );
 
                     // Set forwarder's hardware address
-                    self.set_tx_hardware_addr(src, arp.src_hardware_addr());
+                    self.set_tx_hardware_addr(IpAddr::V4(src), arp.src_hardware_addr());
 
                     // Send
                     self.tx.lock().unwrap().send_arp_reply(src)?;
@@ -1327,7 +2412,12 @@ impl Redirector {
         Ok(())
     }
 
-    async fn handle_ipv4(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+    async fn handle_ipv4(
+        &mut self,
+        indicator: &Indicator,
+        frame: &Bytes,
+        frag: Option<(Option<Layers>, Bytes)>,
+    ) -> io::Result<()> {
         if let Some(ipv4) = indicator.ipv4() {
             let src = ipv4.src();
             if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
@@ -1339,21 +2429,21 @@ impl Redirector {
                     indicator.content_len() - indicator.len()
                 );
                 // Set forwarder's hardware address
-                self.set_tx_hardware_addr(src, indicator.ethernet().unwrap().src());
+                self.set_tx_hardware_addr(IpAddr::V4(src), indicator.ethernet().unwrap().src());
 
                 let frame_without_padding = &frame[..indicator.content_len()];
                 if ipv4.is_fragment() {
-                    // Fragmentation
-                    let frag = match self.defrag.add(indicator, frame_without_padding) {
+                    // Fragmentation, which is reassembled in the dispatcher
+                    let frag = match frag {
                         Some(frag) => frag,
                         None => return Ok(()),
                     };
-                    let (transport, payload) = frag.concatenate();
+                    let (transport, payload) = frag;
 
                     if let Some(transport) = transport {
                         match transport {
                             Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, &payload).await?,
+                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, payload).await?,
                             Layers::Udp(ref udp) => self.handle_udp(udp, &payload).await?,
                             _ => unreachable!(),
                         }
@@ -1363,8 +2453,11 @@ impl Redirector {
                         match transport {
                             Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
                             Layers::Tcp(tcp) => {
-                                self.handle_tcp(tcp, &frame_without_padding[indicator.len()..])
-                                    .await?
+                                self.handle_tcp(
+                                    tcp,
+                                    frame.slice(indicator.len()..indicator.content_len()),
+                                )
+                                .await?
                             }
                             Layers::Udp(udp) => {
                                 self.handle_udp(udp, &frame_without_padding[indicator.len()..])
@@ -1388,47 +2481,195 @@ impl Redirector {
         Ok(())
     }
 
-    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
-        if icmpv4.is_destination_port_unreachable() {
+    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
+        if icmpv4.is_destination_port_unreachable() {
+            // Destination port unreachable
+            let kind = match icmpv4.next_level_layer_kind() {
+                Some(kind) => kind,
+                None => return Ok(()),
+            };
+            match kind {
+                LayerKinds::Udp => {
+                    let dst = icmpv4.dst().unwrap();
+                    self.unbind_local_udp_port(dst);
+                }
+                _ => {}
+            }
+        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
+            // Fragmentation required, and DF flag set
+            let mtu = icmpv4.next_hop_mtu().unwrap();
+            if self
+                .tx
+                .lock()
+                .unwrap()
+                .set_src_mtu(IpAddr::V4(icmpv4.dst_ip_addr().unwrap()), mtu as usize)
+            {
+                info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_ipv6(&mut self, indicator: &Indicator, frame: &Bytes) -> io::Result<()> {
+        let src_ip_addr = match self.src_ipv6_addr {
+            Some(src_ip_addr) => src_ip_addr,
+            None => return Ok(()),
+        };
+        if let Some(ipv6) = indicator.ipv6() {
+            // Neighbor solicitation
+            if let Some(icmpv6) = indicator.icmpv6() {
+                if icmpv6.is_neighbor_solicitation() {
+                    return self.handle_neighbor_solicitation(indicator, icmpv6);
+                }
+            }
+
+            let src = ipv6.src();
+            if Some(src) != self.local_ipv6_addr && src_ip_addr.contains(src) {
+                // Drop truncated or malformed frames whose payload length does not fit
+                if indicator.len() > indicator.content_len()
+                    || indicator.content_len() > frame.len()
+                {
+                    trace!(
+                        "drop malformed {} ({} + {} Bytes)",
+                        indicator.brief(),
+                        indicator.len(),
+                        frame.len().saturating_sub(indicator.len())
+                    );
+
+                    return Ok(());
+                }
+
+                debug!(
+                    "receive from pcap: {} ({} + {} Bytes)",
+                    indicator.brief(),
+                    indicator.len(),
+                    indicator.content_len() - indicator.len()
+                );
+                // Set forwarder's hardware address
+                self.set_tx_hardware_addr(IpAddr::V6(src), indicator.ethernet().unwrap().src());
+
+                let frame_without_padding = &frame[..indicator.content_len()];
+                if ipv6.is_fragment() {
+                    // Fragmentation
+                    trace!("drop IPv6 fragment {} -> {}", ipv6.src(), ipv6.dst());
+                } else if let Some(transport) = indicator.transport() {
+                    match transport {
+                        Layers::Icmpv6(icmpv6) => self.handle_icmpv6(icmpv6)?,
+                        Layers::Tcp(tcp) => {
+                            self.handle_tcp(
+                                tcp,
+                                frame.slice(indicator.len()..indicator.content_len()),
+                            )
+                            .await?
+                        }
+                        Layers::Udp(udp) => {
+                            self.handle_udp(udp, &frame_without_padding[indicator.len()..])
+                                .await?
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+
+                // Monitor
+                if let Some(size) = &self.traffic_size {
+                    size.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                }
+                if let Some(count) = &self.traffic_count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_neighbor_solicitation(
+        &mut self,
+        indicator: &Indicator,
+        icmpv6: &Icmpv6,
+    ) -> io::Result<()> {
+        if let (Some(gw_ip_addr), Some(src_ip_addr)) = (self.gw_ipv6_addr, self.src_ipv6_addr) {
+            let src = indicator.ipv6().unwrap().src();
+            // Addresses in duplicate address detection are ignored, and link-local addresses are
+            // always accepted because neighbor discovery usually happens on them
+            let is_link_local = src.segments()[0] & 0xffc0 == 0xfe80;
+            if !src.is_unspecified()
+                && Some(src) != self.local_ipv6_addr
+                && (src_ip_addr.contains(src) || is_link_local)
+                && icmpv6.target_addr() == Some(gw_ip_addr)
+            {
+                debug!(
+                    "receive from pcap: {} ({} Bytes)",
+                    indicator.brief(),
+                    indicator.content_len()
+                );
+
+                // Set forwarder's hardware address
+                let hardware_addr = icmpv6
+                    .src_hardware_addr()
+                    .unwrap_or_else(|| indicator.ethernet().unwrap().src());
+                self.set_tx_hardware_addr(IpAddr::V6(src), hardware_addr);
+
+                // Send
+                self.tx.lock().unwrap().send_neighbor_advertisement(src)?;
+
+                // Monitor
+                if let Some(size) = &self.traffic_size {
+                    size.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                }
+                if let Some(count) = &self.traffic_count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_icmpv6(&mut self, icmpv6: &Icmpv6) -> io::Result<()> {
+        if icmpv6.is_destination_port_unreachable() {
             // Destination port unreachable
-            let kind = match icmpv4.next_level_layer_kind() {
+            let kind = match icmpv6.next_level_layer_kind() {
                 Some(kind) => kind,
                 None => return Ok(()),
             };
             match kind {
                 LayerKinds::Udp => {
-                    let dst = icmpv4.dst().unwrap();
-                    self.unbind_local_udp_port(dst);
+                    if let Some(dst) = icmpv6.dst() {
+                        self.unbind_local_udp_port(dst);
+                    }
                 }
                 _ => {}
             }
-        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
-            // Fragmentation required, and DF flag set
-            let mtu = icmpv4.next_hop_mtu().unwrap();
-            if self
-                .tx
-                .lock()
-                .unwrap()
-                .set_src_mtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
-            {
-                info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
+        } else if icmpv6.is_packet_too_big() {
+            // Packet too big
+            if let (Some(mtu), Some(dst_ip_addr)) = (icmpv6.mtu(), icmpv6.dst_ip_addr()) {
+                if self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .set_src_mtu(IpAddr::V6(dst_ip_addr), mtu as usize)
+                {
+                    info!("Update MTU of {} to {}", dst_ip_addr, mtu);
+                }
             }
         }
 
         Ok(())
     }
 
-    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+    async fn handle_tcp(&mut self, tcp: &Tcp, payload: Bytes) -> io::Result<()> {
         if tcp.is_rst() {
             self.handle_tcp_rst(tcp);
         } else if tcp.is_ack() {
             self.handle_tcp_ack(tcp, payload)?;
         } else if tcp.is_syn() {
             // Pure TCP SYN
-            self.handle_tcp_syn(tcp).await?;
+            self.handle_tcp_syn(tcp)?;
         } else if tcp.is_fin() {
             // Pure TCP FIN
-            self.handle_tcp_fin(tcp, payload)?;
+            self.handle_tcp_fin(tcp, &payload)?;
         } else {
             unreachable!();
         }
@@ -1436,9 +2677,9 @@ impl Redirector {
         Ok(())
     }
 
-    fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+    fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: Bytes) -> io::Result<()> {
+        let src = SocketAddr::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddr::new(tcp.dst_ip_addr(), tcp.dst());
         let key = (src, dst);
         let is_exist = self.streams.get(&key).is_some();
         let is_writable = match self.streams.get(&key) {
@@ -1452,6 +2693,31 @@ impl Redirector {
                 .states
                 .get_mut(&key)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            // PAWS
+            if state.is_paws_rejected(tcp.ts()) {
+                trace!(
+                    "TCP PAWS rejected of {} -> {} at {}",
+                    src,
+                    dst,
+                    tcp.sequence()
+                );
+
+                // Send ACK0
+                self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+
+                return Ok(());
+            }
+            if let Some(ts) = tcp.ts() {
+                let last_ack_sent = self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .get_state(dst, src)
+                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                    .last_ack_sent();
+                state.update_ts_recent(ts, tcp.sequence(), last_ack_sent);
+            }
+
             if tcp.sequence() != state.recv_next() {
                 trace!(
                     "TCP out of order of {} -> {} at {}",
@@ -1466,15 +2732,38 @@ impl Redirector {
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-                tx_state.acknowledge(tcp.acknowledgement());
+                tx_state.acknowledge(tcp.acknowledgement(), tcp.ts_ecr());
                 tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
+                if let Some(ts) = state.ts_recent() {
+                    tx_state.set_ts_recent(ts);
+                }
             }
 
             if payload.len() > 0 {
                 // ACK
                 if is_writable {
+                    // Drop the data beyond the queue to the proxy
+                    let tx_remaining = self
+                        .streams
+                        .get(&key)
+                        .map_or(0, |stream| stream.tx_remaining());
+                    let offset = tcp.sequence().wrapping_sub(state.recv_next()) as usize;
+                    if offset <= MAX_U32_WINDOW_SIZE && offset + payload.len() > tx_remaining {
+                        trace!(
+                            "TCP out of window of {} -> {} at {}",
+                            src,
+                            dst,
+                            tcp.sequence()
+                        );
+
+                        // Send ACK0
+                        self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+
+                        return Ok(());
+                    }
+
                     // Append to cache
-                    let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+                    let cont_payload = state.append_cache(tcp.sequence(), payload.clone())?;
 
                     // SACK
                     if state.sack_perm() {
@@ -1497,9 +2786,7 @@ impl Redirector {
                             let size = payload.len();
                             match stream.send(payload) {
                                 Ok(_) => {
-                                    let cache_remaining_size = (state.cache().remaining()
-                                        >> state.wscale() as usize)
-                                        as u16;
+                                    let window = tcp_recv_window(state, stream);
 
                                     state.add_recv_next(size as u32);
 
@@ -1509,7 +2796,7 @@ impl Redirector {
                                         .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
                                     // Update window size
-                                    tx_state.set_window(cache_remaining_size);
+                                    tx_state.set_window(window);
 
                                     // Update TCP acknowledgement
                                     tx_state.add_acknowledgement(size as u32);
@@ -1531,8 +2818,10 @@ impl Redirector {
                         }
                         None => {
                             // Retransmission or unordered
-                            let cache_remaining_size =
-                                (state.cache().remaining() >> state.wscale() as usize) as u16;
+                            let window = match self.streams.get(&key) {
+                                Some(stream) => tcp_recv_window(state, stream),
+                                None => 0,
+                            };
 
                             // Update window size
                             let mut tx_locked = self.tx.lock().unwrap();
@@ -1540,7 +2829,7 @@ impl Redirector {
                                 .get_state_mut(dst, src)
                                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-                            tx_state.set_window(cache_remaining_size);
+                            tx_state.set_window(window);
 
                             // Send ACK0
                             tx_locked.send_tcp_ack_0(dst, src)?;
@@ -1568,9 +2857,12 @@ impl Redirector {
                 } else {
                     // Duplicate ACK
                     state.admit(tcp.acknowledgement());
-                    if state.duplicate() >= DUPLICATES_THRESHOLD {
+                    if state.duplicate() >= self.config.duplicates_threshold {
                         let is_cooled_down = match state.last_retrans() {
-                            Some(ref instant) => instant.elapsed().as_millis() < RETRANS_COOL_DOWN,
+                            Some(ref instant) => {
+                                instant.elapsed().as_millis()
+                                    < self.config.retrans_cool_down as u128
+                            }
                             None => false,
                         };
 
@@ -1607,8 +2899,11 @@ impl Redirector {
 
             // FIN
             if tcp.is_fin() || state.fin_sequence().is_some() {
-                self.handle_tcp_fin(tcp, payload)?;
+                self.handle_tcp_fin(tcp, &payload)?;
             }
+        } else if self.connecting.contains_key(&key) {
+            // The SYN/ACK is not sent yet
+            trace!("drop TCP {} -> {} in connecting", src, dst);
         } else {
             // Send ACK/RST
             self.tx
@@ -1620,28 +2915,45 @@ impl Redirector {
         Ok(())
     }
 
-    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+    fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
+        let src = SocketAddr::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddr::new(tcp.dst_ip_addr(), tcp.dst());
         let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+        let is_exist = self.streams.get(&key).is_some() || self.connecting.contains_key(&key);
 
-        // Connect if not connected, drop if established
+        // Refuse new connections in shutting down
+        if self.is_shutting_down && !is_exist {
+            trace!("refuse TCP SYN of {} -> {} due to shutdown", src, dst);
+
+            // Send ACK/RST
+            return self.tx.lock().unwrap().send_tcp_ack_rst_refused(
+                dst,
+                src,
+                tcp.sequence().checked_add(1).unwrap_or(0),
+            );
+        }
+
+        // Connect if not connected, drop if established or connecting
         if !is_exist {
             // Clean up
             self.clean_up(src, dst);
 
             // Admit SYN
-            let wscale = match ENABLE_WSCALE {
+            let wscale = match self.config.enable_wscale {
                 true => tcp.wscale(),
                 false => None,
             };
             let recv_wscale = match wscale {
-                Some(wscale) => Some(min(wscale, MAX_RECV_WSCALE)),
+                Some(wscale) => Some(min(wscale, self.config.max_recv_wscale)),
                 None => None,
             };
-            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
-            let state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+            let sack_perm = self.config.enable_sack && tcp.is_sack_perm();
+            let ts = match self.config.enable_ts {
+                true => tcp.ts(),
+                false => None,
+            };
+            let state =
+                TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm, ts);
 
             {
                 let mut tx_locked = self.tx.lock().unwrap();
@@ -1650,13 +2962,13 @@ impl Redirector {
                 let sequence = rng.gen::<u32>();
                 let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
                 if let Some(mss) = tcp.mss() {
-                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
+                    let mtu = ip_minimum_len(src.ip()) + Tcp::minimum_len() + mss as usize;
                     if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
                         info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
                     }
                 }
 
-                let tx_state = TcpTxState::new(
+                let mut tx_state = TcpTxState::new(
                     src,
                     dst,
                     sequence,
@@ -1664,48 +2976,112 @@ impl Redirector {
                     tcp.window(),
                     recv_wscale,
                     sack_perm,
+                    ts,
                     wscale,
                     tx_locked.get_src_mtu(tcp.src_ip_addr())
-                        - (Ipv4::minimum_len() + Tcp::minimum_len()),
+                        - (ip_minimum_len(src.ip()) + Tcp::minimum_len()),
+                    &self.config,
+                );
+
+                // Limit the window by the queue to the proxy
+                let window = min(
+                    tx_state.window() as usize,
+                    self.config.max_proxy_queue >> wscale.unwrap_or(0) as usize,
                 );
+                tx_state.set_window(window as u16);
+
                 tx_locked.set_state(dst, src, tx_state);
             }
 
-            // Connect
-            let stream = StreamWorker::connect(self.get_tx(), src, dst, &self.proxy).await;
-
-            let stream = match stream {
-                Ok(stream) => stream,
+            // Route
+            let action = self.router.route(LayerKinds::Tcp, src, dst);
+            let proxy = match self.get_proxy(&action) {
+                Ok(proxy) => proxy,
                 Err(e) => {
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked
-                            .get_state_mut(dst, src)
-                            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+                    self.reset_tcp_syn(src, dst)?;
 
-                        tx_state.add_acknowledgement(1);
+                    // Blocked SYNs are expected and would flood the log
+                    if matches!(action, Action::Block) {
+                        trace!("reject TCP {} -> {} ({})", src, dst, action);
 
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, src)?;
+                        return Ok(());
                     }
 
-                    // Clean up
-                    self.clean_up(src, dst);
-
                     return Err(e);
                 }
             };
 
-            self.states.insert(key, state);
-            self.streams.insert(key, stream);
+            // Connect in a task, the SYN/ACK will be sent after the proxied stream is connected
+            self.connecting.insert(key, state);
+            let connected_tx = self.connected_tx.clone();
+            let connect_timeout = self.config.connect_timeout;
+            tokio::spawn(async move {
+                let stream = StreamWorker::connect_proxy(dst, &proxy, connect_timeout).await;
+                let _ = connected_tx.send((key, stream));
+            });
+        }
+
+        Ok(())
+    }
+
+    fn handle_tcp_connected(
+        &mut self,
+        key: (SocketAddr, SocketAddr),
+        stream: io::Result<ProxiedStream>,
+    ) -> io::Result<()> {
+        let (src, dst) = key;
+
+        // Drop if the connection was reset or cleaned up in connecting
+        let state = match self.connecting.remove(&key) {
+            Some(state) => state,
+            None => {
+                trace!("drop connected stream {} -> {}", src, dst);
+
+                return Ok(());
+            }
+        };
+
+        // Open
+        let stream =
+            stream.and_then(|stream| StreamWorker::open(self.get_tx(), src, stream, &self.config));
+        match stream {
+            Ok(stream) => {
+                self.states.insert(key, state);
+                self.streams.insert(key, stream);
+
+                Ok(())
+            }
+            Err(e) => {
+                self.reset_tcp_syn(src, dst)?;
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Refuses the TCP connection whose SYN was admitted but not yet replied.
+    fn reset_tcp_syn(&mut self, src: SocketAddr, dst: SocketAddr) -> io::Result<()> {
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            let tx_state = tx_locked
+                .get_state_mut(dst, src)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+            tx_state.add_acknowledgement(1);
+
+            // Send ACK/RST
+            tx_locked.send_tcp_ack_rst(dst, src)?;
         }
 
+        // Clean up
+        self.clean_up(src, dst);
+
         Ok(())
     }
 
     fn handle_tcp_rst(&mut self, tcp: &Tcp) {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let src = SocketAddr::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddr::new(tcp.dst_ip_addr(), tcp.dst());
         let key = (src, dst);
 
         if tcp.is_ack() {
@@ -1730,8 +3106,8 @@ impl Redirector {
     }
 
     fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let src = SocketAddr::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddr::new(tcp.dst_ip_addr(), tcp.dst());
         let key = (src, dst);
         let is_exist = self.streams.get(&key).is_some();
         let (is_writable, is_readable) = match self.streams.get(&key) {
@@ -1803,6 +3179,9 @@ impl Redirector {
                 // Send ACK0
                 self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
             }
+        } else if self.connecting.contains_key(&key) {
+            // The SYN/ACK is not sent yet
+            trace!("drop TCP {} -> {} in connecting", src, dst);
         } else {
             // Send ACK/RST
             self.tx
@@ -1814,35 +3193,71 @@ impl Redirector {
         Ok(())
     }
 
-    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+    fn update_window(&mut self, src: SocketAddr, dst: SocketAddr) -> io::Result<()> {
+        let key = (src, dst);
+
+        let window = match (self.states.get(&key), self.streams.get(&key)) {
+            (Some(state), Some(stream)) => tcp_recv_window(state, stream),
+            _ => return Ok(()),
+        };
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        let tx_state = tx_locked
+            .get_state_mut(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+        // Update window size
+        tx_state.set_window(window);
+
+        // Send window update
+        tx_locked.send_tcp_ack_0(dst, src)
+    }
+
+    fn clean_up(&mut self, src: SocketAddr, dst: SocketAddr) {
         let key = (src, dst);
 
         self.streams.remove(&key);
         self.states.remove(&key);
+        self.connecting.remove(&key);
 
         self.tx.lock().unwrap().clean_up(dst, src);
     }
 
     async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let src = SocketAddr::new(udp.src_ip_addr(), udp.src());
+        let dst = SocketAddr::new(udp.dst_ip_addr(), udp.dst());
+
+        // Blocked or unsupported
+        let action = self.router.route(LayerKinds::Udp, src, dst);
+        let is_supported = match self.get_proxy(&action) {
+            Ok(proxy) => proxy.is_datagram_supported(),
+            Err(_) => false,
+        };
+        if !is_supported {
+            trace!("reject UDP {} -> {} ({})", src, dst, action);
+
+            return self
+                .tx
+                .lock()
+                .unwrap()
+                .send_udp_port_unreachable(dst, src, payload);
+        }
 
         // Bind
-        let port = self.bind_local_udp_port(src).await?;
+        let port = self.bind_local_udp_port(src, action).await?;
 
         // Send
         self.datagrams
             .get_mut(&port)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?
-            .send_to(
-                payload.to_vec(),
-                SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
-            )?;
+            .send_to(payload.to_vec(), dst)?;
 
         Ok(())
     }
 
-    async fn bind_local_udp_port(&mut self, src: SocketAddrV4) -> io::Result<u16> {
-        let local_port = self.datagram_map.get(&src);
+    async fn bind_local_udp_port(&mut self, src: SocketAddr, action: Action) -> io::Result<u16> {
+        let key = (src, action);
+        let local_port = self.datagram_map.get(&key);
         match local_port {
             Some(&local_port) => {
                 // Update LRU
@@ -1852,20 +3267,7 @@ impl Redirector {
             }
             None => {
                 let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
-                    match DatagramWorker::bind(self.get_tx(), src, &self.proxy).await {
-                        Ok((worker, port)) => {
-                            self.datagrams.insert(port, worker);
-
-                            // Update map and LRU
-                            self.datagram_map.insert(src, port);
-                            self.udp_lru.put(port, src);
-
-                            trace!("bind UDP port {} = {}", port, src);
-
-                            Ok(port)
-                        }
-                        Err(e) => Err(e),
-                    }
+                    self.bind_datagram_worker(key.clone()).await
                 } else {
                     Err(io::Error::new(io::ErrorKind::Other, "cannot bind UDP port"))
                 };
@@ -1878,18 +3280,34 @@ impl Redirector {
                         } else {
                             let pair = self.udp_lru.pop_lru().unwrap();
                             let port = pair.0;
-                            let prev_src = pair.1;
-
-                            // Reuse
-                            self.datagram_map.remove(&prev_src);
-                            trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
-                            self.datagram_map.insert(src.clone(), port);
-                            self.datagrams.get_mut(&port).unwrap().set_src(&src);
-
-                            // Update LRU
-                            self.udp_lru.put(port, src.clone());
-
-                            Ok(port)
+                            let prev_key = pair.1;
+                            self.datagram_map.remove(&prev_key);
+
+                            // The upstream of a group is picked in binding, so the worker of a group
+                            // is not reused for another source
+                            let is_reusable = prev_key.1 == key.1
+                                && match self.get_proxy(&key.1) {
+                                    Ok(proxy) => !matches!(*proxy, ProxyConfig::Group(_)),
+                                    Err(_) => false,
+                                };
+                            if is_reusable {
+                                // Reuse
+                                trace!("reuse UDP port {} = {} to {}", port, prev_key.0, src);
+                                self.datagram_map.insert(key.clone(), port);
+                                self.datagrams.get_mut(&port).unwrap().set_src(&src);
+
+                                // Update LRU
+                                self.udp_lru.put(port, key);
+
+                                Ok(port)
+                            } else {
+                                // Rebind since the previous worker is in another action, or the
+                                // upstream should be picked again
+                                self.datagrams.remove(&port);
+                                trace!("unbind UDP port {} = {}", port, prev_key.0);
+
+                                self.bind_datagram_worker(key).await
+                            }
                         }
                     }
                 }
@@ -1897,17 +3315,52 @@ impl Redirector {
         }
     }
 
-    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
-        let local_port = self.datagram_map.get(&src);
-        match local_port {
-            Some(&local_port) => {
+    async fn bind_datagram_worker(&mut self, key: (SocketAddr, Action)) -> io::Result<u16> {
+        let src = key.0;
+        let (worker, port) = {
+            let proxy = self.get_proxy(&key.1)?;
+            DatagramWorker::bind(self.get_tx(), src, &proxy, &self.config).await?
+        };
+        self.datagrams.insert(port, worker);
+
+        // Update map and LRU
+        trace!("bind UDP port {} = {} ({})", port, src, key.1);
+        self.datagram_map.insert(key.clone(), port);
+        self.udp_lru.put(port, key);
+
+        Ok(port)
+    }
+
+    fn unbind_local_udp_port(&mut self, src: SocketAddr) {
+        let keys = self
+            .datagram_map
+            .keys()
+            .filter(|key| key.0 == src)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some(local_port) = self.datagram_map.remove(&key) {
                 self.datagrams.remove(&local_port);
                 self.udp_lru.pop(&local_port);
-                self.datagram_map.remove(&src);
 
                 trace!("unbind UDP port {} = {}", local_port, src);
             }
-            None => {}
+        }
+    }
+
+    fn get_proxy(&self, action: &Action) -> io::Result<Arc<ProxyConfig>> {
+        match action {
+            Action::Proxy(None) => Ok(Arc::clone(&self.proxy)),
+            Action::Proxy(Some(name)) => self
+                .proxies
+                .get(name)
+                .map(Arc::clone)
+                .ok_or(io::Error::new(io::ErrorKind::NotFound, "proxy not found")),
+            Action::Direct => Ok(Arc::clone(&self.direct)),
+            Action::Block => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "blocked by rule",
+            )),
         }
     }
 
@@ -1915,14 +3368,877 @@ impl Redirector {
         Arc::clone(&self.tx)
     }
 
-    fn set_tx_hardware_addr(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
+    fn set_tx_hardware_addr(&mut self, ip_addr: IpAddr, hardware_addr: HardwareAddr) {
         if !self.tx_src_hardware_addr_set_ip_addr_set.contains(&ip_addr) {
-            self.tx
+            let is_new = self
+                .tx
                 .lock()
                 .unwrap()
                 .set_src_hardware_addr(ip_addr, hardware_addr);
             self.tx_src_hardware_addr_set_ip_addr_set.insert(ip_addr);
-            info!("Device {} ({}) joined the network", ip_addr, hardware_addr);
+            if is_new {
+                info!("Device {} ({}) joined the network", ip_addr, hardware_addr);
+            }
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_tcp_echo() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink, MemoryPeer};
+    use pnet::packet::tcp::TcpFlags;
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let builder = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:80".parse().unwrap(),
+    )
+    .unwrap();
+
+    async fn recv_tcp(peer: &mut MemoryPeer) -> (Tcp, Vec<u8>) {
+        time::timeout(Duration::from_secs(5), peer.recv_tcp())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // Handshake
+    peer.inject(builder.tcp(100, 0, TcpFlags::SYN, &[]))
+        .unwrap();
+    let (syn_ack, _) = recv_tcp(&mut peer).await;
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    assert_eq!(syn_ack.acknowledgement(), 101);
+    let sequence = syn_ack.sequence().wrapping_add(1);
+    peer.inject(builder.tcp(101, sequence, TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Echo
+    peer.inject(builder.tcp(101, sequence, TcpFlags::ACK | TcpFlags::PSH, b"hello"))
+        .unwrap();
+    loop {
+        let (tcp, payload) = recv_tcp(&mut peer).await;
+        if !payload.is_empty() {
+            assert_eq!(tcp.sequence(), sequence);
+            assert_eq!(payload, b"hello");
+            break;
+        }
+    }
+
+    // Close, the FIN is echoed after the stub sees the end of the stream
+    let acknowledgement = sequence.wrapping_add(5);
+    peer.inject(builder.tcp(106, acknowledgement, TcpFlags::ACK | TcpFlags::FIN, &[]))
+        .unwrap();
+    loop {
+        let (tcp, _) = recv_tcp(&mut peer).await;
+        if tcp.is_fin() {
+            assert!(tcp.is_ack());
+            assert_eq!(tcp.sequence(), acknowledgement);
+            assert_eq!(tcp.acknowledgement(), 107);
+            break;
+        }
+    }
+    peer.inject(builder.tcp(107, acknowledgement.wrapping_add(1), TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_tcp_connect_stalled() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink};
+    use pnet::packet::tcp::TcpFlags;
+    use tokio::net::TcpListener;
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    // The upstream accepts the connection but never replies
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            streams.push(stream);
+        }
+    });
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    redirector.add_proxy("stalled".to_string(), ProxyConfig::new_socks4(remote, None));
+    let mut rule = Rule::new(Action::Proxy(Some("stalled".to_string())));
+    rule.set_dst_ports(81, 81);
+    redirector.add_rule(rule);
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    config.connect_timeout = 500;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let syn = |dst: &str| {
+        FrameBuilder::new(
+            src_hardware_addr,
+            local_hardware_addr,
+            "10.6.0.1:10000".parse().unwrap(),
+            dst.parse().unwrap(),
+        )
+        .unwrap()
+        .tcp(100, 0, TcpFlags::SYN, &[])
+    };
+    // The stalled connection does not block the following ones
+    peer.inject(syn("203.0.113.1:81")).unwrap();
+    peer.inject(syn("203.0.113.1:80")).unwrap();
+    let mut tcps = Vec::new();
+    while tcps.len() < 2 {
+        let (tcp, _) = time::timeout(Duration::from_secs(5), peer.recv_tcp())
+            .await
+            .unwrap()
+            .unwrap();
+        tcps.push(tcp);
+    }
+    let syn_ack = &tcps[0];
+    assert_eq!(syn_ack.src(), 80);
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+
+    // The stalled connection is refused after the connect timeout
+    let rst = &tcps[1];
+    assert_eq!(rst.src(), 81);
+    assert!(rst.is_rst() && rst.is_ack());
+    assert_eq!(rst.acknowledgement(), 101);
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_tcp_shutdown() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink, MemoryPeer};
+    use pnet::packet::tcp::TcpFlags;
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 10000;
+    redirector.set_config(config);
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    let handle =
+        tokio::spawn(async move { redirector.open_monitored(rx, Some(is_running_cloned)).await });
+
+    let builder = |src: &str| {
+        FrameBuilder::new(
+            src_hardware_addr,
+            local_hardware_addr,
+            src.parse().unwrap(),
+            "203.0.113.1:80".parse().unwrap(),
+        )
+        .unwrap()
+    };
+    let existing = builder("10.6.0.1:10000");
+    let refused = builder("10.6.0.1:10001");
+
+    async fn recv_tcp(peer: &mut MemoryPeer) -> Tcp {
+        let (tcp, _) = time::timeout(Duration::from_secs(5), peer.recv_tcp())
+            .await
+            .unwrap()
+            .unwrap();
+        tcp
+    }
+
+    // Handshake
+    peer.inject(existing.tcp(100, 0, TcpFlags::SYN, &[]))
+        .unwrap();
+    let syn_ack = recv_tcp(&mut peer).await;
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    let sequence = syn_ack.sequence().wrapping_add(1);
+    peer.inject(existing.tcp(101, sequence, TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // The existing connection is closed with a FIN
+    is_running.store(false, Ordering::Relaxed);
+    let fin = recv_tcp(&mut peer).await;
+    assert_eq!(fin.dst(), 10000);
+    assert!(fin.is_fin() && !fin.is_rst());
+    assert_eq!(fin.sequence(), sequence);
+
+    // New connections are refused
+    peer.inject(refused.tcp(200, 0, TcpFlags::SYN, &[]))
+        .unwrap();
+    let rst = recv_tcp(&mut peer).await;
+    assert_eq!(rst.dst(), 10001);
+    assert!(rst.is_rst() && rst.is_ack());
+    assert_eq!(rst.acknowledgement(), 201);
+
+    // The shutdown finishes after the FIN is acknowledged, before the shutdown timeout
+    peer.inject(existing.tcp(101, sequence.wrapping_add(1), TcpFlags::ACK, &[]))
+        .unwrap();
+    time::timeout(Duration::from_secs(5), handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_replay() {
+    use pcap::{FileReceiver, FileSender, FrameBuilder};
+    use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+    use pnet::packet::tcp::TcpFlags;
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let replay = dir.join(format!("pcap2socks-{}-replay.pcap", id));
+    let output = dir.join(format!("pcap2socks-{}-output.pcap", id));
+    let dump = dir.join(format!("pcap2socks-{}-dump.pcapng", id));
+
+    // Capture a TCP SYN and an UDP datagram from the source
+    let tcp = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:80".parse().unwrap(),
+    )
+    .unwrap();
+    let udp = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:53".parse().unwrap(),
+    )
+    .unwrap();
+    let frames = vec![tcp.tcp(100, 0, TcpFlags::SYN, &[]), udp.udp(b"hello")];
+    let mut sender = FileSender::create(&replay).unwrap();
+    for frame in &frames {
+        sender.send_to(frame, None).unwrap().unwrap();
+    }
+    drop(sender);
+
+    // Replay, the redirector shuts down after the capture file is exhausted
+    let rx = FileReceiver::open(&replay, false).unwrap();
+    let tx = FileSender::create(&output).unwrap();
+    let forwarder = Forwarder::new(Box::new(tx), 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    redirector.set_config(config);
+    redirector.set_dumper(Dumper::create(&dump).unwrap());
+    time::timeout(Duration::from_secs(10), redirector.open(Box::new(rx)))
+        .await
+        .unwrap()
+        .unwrap();
+    drop(redirector);
+
+    let read_all = |path: &std::path::Path| {
+        let mut rx = FileReceiver::open(path, false).unwrap();
+        let mut frames = Vec::new();
+        loop {
+            match rx.next() {
+                Ok(frame) => frames.push(frame.to_vec()),
+                Err(e) => {
+                    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+                    break frames;
+                }
+            }
         }
+    };
+
+    // The SYN is answered and the datagram is echoed
+    let outputs = read_all(output.as_path());
+    let syn_ack = outputs
+        .iter()
+        .filter_map(|frame| Indicator::from(frame.as_slice()).and_then(|i| i.tcp().cloned()))
+        .next()
+        .unwrap();
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    assert_eq!(syn_ack.acknowledgement(), 101);
+    let echo = outputs
+        .iter()
+        .find_map(|frame| {
+            let indicator = Indicator::from(frame.as_slice())?;
+            let udp = indicator.udp()?;
+            assert_eq!((udp.src(), udp.dst()), (53, 10000));
+            Some(frame[indicator.len()..indicator.content_len()].to_vec())
+        })
+        .unwrap();
+    assert_eq!(echo, b"hello");
+
+    // The dump holds both the replayed frames and the output frames
+    let dumps = read_all(dump.as_path());
+    assert_eq!(dumps.len(), frames.len() + outputs.len());
+    for frame in frames.iter().chain(outputs.iter()) {
+        assert!(dumps.contains(frame));
+    }
+
+    for path in &[replay, output, dump] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_tcp_retransmit() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink, MemoryPeer};
+    use pnet::packet::tcp::TcpFlags;
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    config.initial_rto = 200;
+    config.min_rto = 200;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let builder = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:80".parse().unwrap(),
+    )
+    .unwrap();
+
+    async fn recv_tcp(peer: &mut MemoryPeer) -> (Tcp, Vec<u8>) {
+        time::timeout(Duration::from_secs(5), peer.recv_tcp())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // Handshake
+    peer.inject(builder.tcp(100, 0, TcpFlags::SYN, &[]))
+        .unwrap();
+    let (syn_ack, _) = recv_tcp(&mut peer).await;
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    let sequence = syn_ack.sequence().wrapping_add(1);
+    peer.inject(builder.tcp(101, sequence, TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Echo, the segment is lost and never acknowledged
+    peer.inject(builder.tcp(101, sequence, TcpFlags::ACK | TcpFlags::PSH, b"hello"))
+        .unwrap();
+    let instant = loop {
+        let (tcp, payload) = recv_tcp(&mut peer).await;
+        if !payload.is_empty() {
+            assert_eq!(tcp.sequence(), sequence);
+            assert_eq!(payload, b"hello");
+            break Instant::now();
+        }
+    };
+
+    // Retransmit after an RTO
+    loop {
+        let (tcp, payload) = recv_tcp(&mut peer).await;
+        if !payload.is_empty() {
+            assert_eq!(tcp.sequence(), sequence);
+            assert_eq!(payload, b"hello");
+            assert!(instant.elapsed() >= Duration::from_millis(100));
+            break;
+        }
+    }
+
+    // Close, the FIN is echoed after the stub sees the end of the stream
+    let acknowledgement = sequence.wrapping_add(5);
+    peer.inject(builder.tcp(106, acknowledgement, TcpFlags::ACK | TcpFlags::FIN, &[]))
+        .unwrap();
+    loop {
+        let (tcp, _) = recv_tcp(&mut peer).await;
+        if tcp.is_fin() {
+            assert_eq!(tcp.sequence(), acknowledgement);
+            assert_eq!(tcp.acknowledgement(), 107);
+            break;
+        }
+    }
+    peer.inject(builder.tcp(107, acknowledgement.wrapping_add(1), TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_udp_echo() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink};
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::Echo,
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let builder = FrameBuilder::new(
+        src_hardware_addr,
+        local_hardware_addr,
+        "10.6.0.1:10000".parse().unwrap(),
+        "203.0.113.1:53".parse().unwrap(),
+    )
+    .unwrap();
+
+    // Round trip, each datagram is echoed back from the destination
+    for payload in &[&b"hello"[..], &b"world"[..]] {
+        peer.inject(builder.udp(payload)).unwrap();
+        let (udp, echo) = time::timeout(Duration::from_secs(5), peer.recv_udp())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(udp.src_ip_addr(), IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)));
+        assert_eq!(udp.dst_ip_addr(), IpAddr::V4(src_ip_addr));
+        assert_eq!((udp.src(), udp.dst()), (53, 10000));
+        assert_eq!(echo, *payload);
+    }
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_socks_server() {
+    use pcap::{FrameBuilder, LinkBackend, MemoryLink, MemoryPeer};
+    use pnet::packet::tcp::TcpFlags;
+    use server::SocksServer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 2);
+    let local_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+
+    // The destinations echo the data back
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp_remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => stream.write_all(&buffer[..size]).await.unwrap(),
+                    }
+                }
+            });
+        }
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp_remote = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 1024];
+        loop {
+            let (size, addr) = socket.recv_from(&mut buffer).await.unwrap();
+            socket.send_to(&buffer[..size], addr).await.unwrap();
+        }
+    });
+
+    // The embedded server is the upstream
+    let server = SocksServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap()
+        .spawn()
+        .unwrap();
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, local_ip_addr);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        local_ip_addr,
+        None,
+        ProxyConfig::new_socks(server, false, false, None),
+        None,
+    );
+    let mut config = Config::default();
+    config.shards = 1;
+    config.shutdown_timeout = 100;
+    redirector.set_config(config);
+    let handle = tokio::spawn(async move { redirector.open(rx).await });
+
+    let builder = |dst: SocketAddr| {
+        FrameBuilder::new(
+            src_hardware_addr,
+            local_hardware_addr,
+            "10.6.0.1:10000".parse().unwrap(),
+            dst,
+        )
+        .unwrap()
+    };
+    let tcp = builder(tcp_remote);
+    let udp = builder(udp_remote);
+
+    async fn recv_tcp(peer: &mut MemoryPeer) -> (Tcp, Vec<u8>) {
+        time::timeout(Duration::from_secs(5), peer.recv_tcp())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // Handshake, the SYN is answered after the server connects to the destination
+    peer.inject(tcp.tcp(100, 0, TcpFlags::SYN, &[])).unwrap();
+    let (syn_ack, _) = recv_tcp(&mut peer).await;
+    assert_eq!(syn_ack.src(), tcp_remote.port());
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    assert_eq!(syn_ack.acknowledgement(), 101);
+    let sequence = syn_ack.sequence().wrapping_add(1);
+    peer.inject(tcp.tcp(101, sequence, TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Echo through the server
+    peer.inject(tcp.tcp(101, sequence, TcpFlags::ACK | TcpFlags::PSH, b"hello"))
+        .unwrap();
+    loop {
+        let (segment, payload) = recv_tcp(&mut peer).await;
+        if !payload.is_empty() {
+            assert_eq!(segment.sequence(), sequence);
+            assert_eq!(payload, b"hello");
+            break;
+        }
+    }
+
+    // Relay through the UDP association of the server
+    peer.inject(udp.udp(b"world")).unwrap();
+    let (datagram, echo) = time::timeout(Duration::from_secs(5), peer.recv_udp())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(datagram.src_ip_addr(), udp_remote.ip());
+    assert_eq!((datagram.src(), datagram.dst()), (udp_remote.port(), 10000));
+    assert_eq!(echo, b"world");
+
+    // Close, the FIN is passed through after the destination closes the stream
+    let acknowledgement = sequence.wrapping_add(5);
+    peer.inject(tcp.tcp(106, acknowledgement, TcpFlags::ACK | TcpFlags::FIN, &[]))
+        .unwrap();
+    loop {
+        let (segment, _) = recv_tcp(&mut peer).await;
+        if segment.is_fin() {
+            assert_eq!(segment.sequence(), acknowledgement);
+            assert_eq!(segment.acknowledgement(), 107);
+            break;
+        }
+    }
+    peer.inject(tcp.tcp(107, acknowledgement.wrapping_add(1), TcpFlags::ACK, &[]))
+        .unwrap();
+
+    // Shut down after the link is closed
+    drop(peer);
+    handle.await.unwrap().unwrap();
+}
+
+#[test]
+fn forwarder_ipv6_fragment() {
+    use pcap::{LinkBackend, MemoryLink};
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv6::Ipv6Packet;
+    use pnet::packet::Packet;
+
+    let src_ip_addr: Ipv6Addr = "fd00::1".parse().unwrap();
+    let dst_ip_addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+
+    let (link, mut peer) = MemoryLink::new();
+    let (tx, _rx) = link.open().unwrap();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        HardwareAddr::new(2, 0, 0, 0, 0, 1),
+        Ipv4Addr::new(10, 6, 0, 254),
+    );
+    forwarder.set_src_mtu(IpAddr::V6(src_ip_addr), IPV6_MINIMUM_MTU);
+
+    let payload = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+    forwarder
+        .send_udp(
+            SocketAddr::new(IpAddr::V6(dst_ip_addr), 53),
+            SocketAddr::new(IpAddr::V6(src_ip_addr), 10000),
+            &payload,
+        )
+        .unwrap();
+
+    let mut identification = None;
+    let mut content = Vec::new();
+    let mut is_more_fragment = true;
+    while let Some(frame) = peer.try_recv() {
+        assert!(is_more_fragment);
+        let packet = Ipv6Packet::new(&frame[EthernetPacket::minimum_packet_size()..]).unwrap();
+        assert_eq!(packet.get_next_header(), IpNextHeaderProtocols::Ipv6Frag);
+        assert!(Ipv6::minimum_len() + packet.get_payload_length() as usize <= IPV6_MINIMUM_MTU);
+
+        let header = &packet.payload()[..8];
+        assert_eq!(header[0], IpNextHeaderProtocols::Udp.0);
+        let offset_and_flag = u16::from_be_bytes([header[2], header[3]]);
+        assert_eq!((offset_and_flag >> 3) as usize * 8, content.len());
+        is_more_fragment = offset_and_flag & 1 != 0;
+        let fragment_identification = &header[4..8];
+        assert_eq!(
+            *identification.get_or_insert(fragment_identification.to_vec()),
+            fragment_identification
+        );
+
+        let fragment = &packet.payload()[8..packet.get_payload_length() as usize];
+        if is_more_fragment {
+            assert_eq!(fragment.len() % 8, 0);
+        }
+        content.extend_from_slice(fragment);
     }
+    assert!(!is_more_fragment);
+    assert_eq!(content.len(), Udp::minimum_len() + payload.len());
+    assert_eq!(&content[Udp::minimum_len()..], payload.as_slice());
+}
+
+#[tokio::test]
+async fn redirector_ipv6_truncated() {
+    use pcap::{LinkBackend, MemoryLink};
+
+    let local_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 1);
+    let src_ip_addr: Ipv6Addr = "fd00::1".parse().unwrap();
+    let dst_ip_addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+
+    let (link, _peer) = MemoryLink::new();
+    let (tx, _rx) = link.open().unwrap();
+    let forwarder = Forwarder::new(tx, 1500, local_hardware_addr, Ipv4Addr::new(10, 6, 0, 254));
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap(),
+        Ipv4Addr::new(10, 6, 0, 254),
+        None,
+        ProxyConfig::Discard,
+        None,
+    );
+    redirector.set_ipv6(Ipv6Network::new(src_ip_addr, 128).unwrap(), None, None);
+
+    let ipv6 = Ipv6::new(LayerKinds::Udp, src_ip_addr, dst_ip_addr).unwrap();
+    let mut udp = Udp::new(10000, 53);
+    udp.set_ipv6_layer(&ipv6);
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv6,
+        HardwareAddr::new(2, 0, 0, 0, 0, 2),
+        local_hardware_addr,
+    )
+    .unwrap();
+    let indicator = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv6(ipv6)),
+        Some(Layers::Udp(udp)),
+    );
+    let payload = [0u8; 100];
+    let mut buffer = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut buffer, &payload)
+        .unwrap();
+
+    // The payload length claims more than the truncated frame carries
+    buffer.truncate(indicator.len() + 10);
+    let frame = Bytes::from(buffer);
+    let indicator = Indicator::from(frame.as_ref()).unwrap();
+    assert!(indicator.content_len() > frame.len());
+    redirector.handle_ipv6(&indicator, &frame).await.unwrap();
+}
+
+#[test]
+fn flow_shard_by_flow() {
+    use pnet::packet::tcp::{self as pnet_tcp, TcpFlags};
+
+    // Serializes the network and the UDP layer quoted in an ICMP port unreachable message
+    let quote = |network: Layers, udp: Udp| {
+        let ethernet = Ethernet::new(
+            network.kind(),
+            HardwareAddr::new(2, 0, 0, 0, 0, 1),
+            HardwareAddr::new(2, 0, 0, 0, 0, 2),
+        )
+        .unwrap();
+        let offset = ethernet.len();
+        let indicator = Indicator::new(
+            Layers::Ethernet(ethernet),
+            Some(network),
+            Some(Layers::Udp(udp)),
+        );
+        let mut buffer = vec![0u8; indicator.len()];
+        indicator.serialize(&mut buffer).unwrap();
+
+        buffer.split_off(offset)
+    };
+
+    let remote_ip_addr = Ipv4Addr::new(203, 0, 113, 1);
+    let remote_ipv6_addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let mut shards = HashSet::new();
+    for port in 10000..10016 {
+        // IPv4
+        let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+        let src = SocketAddr::new(IpAddr::V4(src_ip_addr), port);
+
+        let ipv4 = Ipv4::new(0, LayerKinds::Tcp, src_ip_addr, remote_ip_addr).unwrap();
+        let mut tcp = Tcp::from(pnet_tcp::Tcp {
+            source: port,
+            destination: 80,
+            sequence: 0,
+            acknowledgement: 0,
+            data_offset: 5,
+            reserved: 0,
+            flags: TcpFlags::SYN,
+            window: 65535,
+            checksum: 0,
+            urgent_ptr: 0,
+            options: vec![],
+            payload: vec![],
+        });
+        tcp.set_ipv4_layer(&ipv4);
+
+        let ipv4 = Ipv4::new(0, LayerKinds::Udp, src_ip_addr, remote_ip_addr).unwrap();
+        let mut udp = Udp::new(port, 53);
+        udp.set_ipv4_layer(&ipv4);
+
+        let ipv4 = Ipv4::new(0, LayerKinds::Udp, remote_ip_addr, src_ip_addr).unwrap();
+        let mut reply = Udp::new(53, port);
+        reply.set_ipv4_layer(&ipv4);
+        let icmpv4 = Icmpv4::new_destination_port_unreachable(&quote(Layers::Ipv4(ipv4), reply));
+        assert_eq!(icmpv4.dst(), Some(src));
+
+        // The ICMP message is dispatched with the flow it quotes
+        let shard = flow_shard(&Layers::Udp(udp), 8);
+        assert_eq!(flow_shard(&Layers::Icmpv4(icmpv4), 8), shard);
+        shards.insert(flow_shard(&Layers::Tcp(tcp), 8));
+
+        // IPv6
+        let src_ipv6_addr: Ipv6Addr = "fd00::1".parse().unwrap();
+        let src = SocketAddr::new(IpAddr::V6(src_ipv6_addr), port);
+
+        let ipv6 = Ipv6::new(LayerKinds::Udp, src_ipv6_addr, remote_ipv6_addr).unwrap();
+        let mut udp = Udp::new(port, 53);
+        udp.set_ipv6_layer(&ipv6);
+
+        let ipv6 = Ipv6::new(LayerKinds::Udp, remote_ipv6_addr, src_ipv6_addr).unwrap();
+        let mut reply = Udp::new(53, port);
+        reply.set_ipv6_layer(&ipv6);
+        let icmpv6 = Icmpv6::new_destination_port_unreachable(&quote(Layers::Ipv6(ipv6), reply));
+        assert_eq!(icmpv6.dst(), Some(src));
+
+        assert_eq!(
+            flow_shard(&Layers::Icmpv6(icmpv6), 8),
+            flow_shard(&Layers::Udp(udp), 8)
+        );
+    }
+
+    // The flows of a source are spread across shards
+    assert!(shards.len() > 1);
+}
+
+#[tokio::test]
+async fn shard_guard_panic() {
+    let running_shards = Arc::new(AtomicUsize::new(2));
+
+    let guard = ShardGuard(Arc::clone(&running_shards));
+    let _ = tokio::spawn(async move {
+        let _guard = guard;
+    })
+    .await;
+    assert_eq!(running_shards.load(Ordering::Relaxed), 1);
+
+    // A panicking shard is also counted as shut down
+    let guard = ShardGuard(Arc::clone(&running_shards));
+    let result = tokio::spawn(async move {
+        let _guard = guard;
+        panic!("shard panicked");
+    })
+    .await;
+    assert!(result.unwrap_err().is_panic());
+    assert_eq!(running_shards.load(Ordering::Relaxed), 0);
 }
@@ -1,26 +1,58 @@
 use env_logger::fmt::{Color, Formatter, Target};
-use ipnetwork::Ipv4Network;
+use ipnetwork::{Ipv4Network, Ipv6Network};
 use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
 use std::clone::Clone;
 use std::fmt::Display;
+use std::fs;
 use std::io::{self, Write};
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
+use tokio::signal;
 
-use pcap2socks::{self as lib, Forwarder, ProxyConfig, Redirector};
+use pcap2socks::pcap::{BlackHole, Dumper, FileReceiver, FileSender, Interface, Receiver, Sender};
+use pcap2socks::server::SocksServer;
+use pcap2socks::tcp::TcpCcAlgorithms;
+use pcap2socks::{self as lib, Action, Config, Forwarder, ProxyConfig, Redirector, Rule};
+
+/// Represents the MTU of the virtual interface in replaying if it is not set.
+const REPLAY_MTU: usize = 1500;
 
 #[tokio::main]
 async fn main() {
     // Parse arguments
-    let flags = Flags::from_args();
+    let mut flags = Flags::from_args();
 
     // Log
     set_logger(flags.verbose);
 
+    // Configuration
+    let config = match apply_config(&mut flags) {
+        Ok(config) => config,
+        Err(ref e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if flags.src.is_none() && flags.preset.is_none() {
+        error!("The source is required. Please use -s <ADDRESS> to set");
+        return;
+    }
+
     // Interface
-    let inter = match lib::interface(flags.inter) {
+    let inter = match flags.replay {
+        // Replay on a virtual interface, which uses the publishing address as its address
+        Some(_) => Some(Interface::new_virtual(
+            flags.publish.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            flags.mtu.unwrap_or(REPLAY_MTU),
+        )),
+        None => lib::interface(flags.inter),
+    };
+    let inter = match inter {
         Some(inter) => inter,
         None => {
             error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
@@ -98,8 +130,39 @@ async fn main() {
     // Instructions
     show_info(src, gw, mtu);
 
+    // IPv6
+    let gw6 = match flags.src6 {
+        Some(src6) => {
+            if let Some(publish6) = flags.publish6 {
+                info!("Publish for {}", publish6);
+            }
+
+            let gw6 = match flags.publish6.or(inter.ipv6_addr()) {
+                Some(gw6) => gw6,
+                None => {
+                    error!(
+                        "Cannot obtain the IPv6 address. Please use --publish6 <ADDRESS> to set"
+                    );
+                    return;
+                }
+            };
+            if src6.size() == 1 && src6.network() == gw6 {
+                error!("The IPv6 source cannot be the same with the gateway (publish)");
+                return;
+            }
+            show_info_ipv6(src6, gw6);
+
+            Some(gw6)
+        }
+        None => None,
+    };
+
     // Proxy
-    let (tx, mut rx) = match inter.open() {
+    let channel = match flags.replay {
+        Some(ref replay) => open_replay(replay, flags.output.as_ref()),
+        None => inter.open(),
+    };
+    let (tx, rx) = match channel {
         Ok((tx, rx)) => (tx, rx),
         Err(ref e) => {
             error!("{}", e);
@@ -107,32 +170,394 @@ async fn main() {
         }
     };
     let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
-    let auth = match flags.username {
-        Some(ref username) => Some((username.clone(), flags.password.unwrap())),
-        None => None,
+    if flags.embedded {
+        let addr = match serve_embedded().await {
+            Ok(addr) => addr,
+            Err(ref e) => {
+                error!("Cannot serve the embedded SOCKS5 server: {}", e);
+                return;
+            }
+        };
+        flags.dst = vec![ResolvableSocketAddr {
+            addr,
+            alias: Some(String::from("embedded")),
+        }];
+        flags.protocol = Some(String::from("socks5"));
+    }
+    let proxy = match flags.direct {
+        true => ProxyConfig::Direct,
+        false => {
+            let mut proxies = Vec::new();
+            for dst in flags.dst.iter() {
+                let proxy = match new_proxy(
+                    flags.protocol.as_ref().unwrap().as_str(),
+                    dst.addr(),
+                    flags.username.clone(),
+                    flags.password.clone(),
+                    flags.method.as_ref().unwrap().as_str(),
+                    flags.force_associate_dst,
+                    flags.force_associate_bind_addr,
+                ) {
+                    Some(proxy) => proxy,
+                    None => return,
+                };
+                proxies.push(proxy);
+            }
+            match proxies.len() {
+                1 => proxies.pop().unwrap(),
+                _ => ProxyConfig::new_group(proxies),
+            }
+        }
     };
     let mut redirector = Redirector::new(
         Arc::new(Mutex::new(forwarder)),
         src,
         gw,
         publish,
-        ProxyConfig::new_socks(
-            flags.dst.addr(),
-            flags.force_associate_dst,
-            flags.force_associate_bind_addr,
-            auth,
-        ),
+        proxy,
         None,
     );
+    redirector.set_config(config);
+    if let Some(ref dump) = flags.dump {
+        match Dumper::create(dump) {
+            Ok(dumper) => redirector.set_dumper(dumper),
+            Err(ref e) => {
+                error!("Cannot create {}: {}", dump, e);
+                return;
+            }
+        }
+        info!("Dump to {}", dump);
+    }
+    if let (Some(src6), Some(_)) = (flags.src6, gw6) {
+        redirector.set_ipv6(src6, inter.ipv6_addr(), flags.publish6);
+    }
+
+    // Rules
+    let mut named_proxies: Vec<(String, Vec<ProxyConfig>)> = Vec::new();
+    for named_proxy in flags.proxies.iter() {
+        let proxy = match new_proxy(
+            named_proxy.protocol.as_str(),
+            named_proxy.addr.addr(),
+            named_proxy.username.clone(),
+            named_proxy.password.clone(),
+            named_proxy
+                .username
+                .as_ref()
+                .map(|method| method.as_str())
+                .unwrap_or(""),
+            false,
+            false,
+        ) {
+            Some(proxy) => proxy,
+            None => return,
+        };
+        info!("Add proxy {} to {}", named_proxy.name, named_proxy.addr);
+        match named_proxies
+            .iter_mut()
+            .find(|(name, _)| name == &named_proxy.name)
+        {
+            Some((_, proxies)) => proxies.push(proxy),
+            None => named_proxies.push((named_proxy.name.clone(), vec![proxy])),
+        }
+    }
+    for (name, mut proxies) in named_proxies {
+        let proxy = match proxies.len() {
+            1 => proxies.pop().unwrap(),
+            _ => ProxyConfig::new_group(proxies),
+        };
+        redirector.add_proxy(name, proxy);
+    }
+    for rule in flags.rules.iter() {
+        if let Action::Proxy(Some(name)) = rule.action() {
+            if flags.proxies.iter().all(|proxy| &proxy.name != name) {
+                error!("The proxy {} in rule {} is not available", name, rule);
+                return;
+            }
+        }
+        info!("Add rule {}", rule);
+        redirector.add_rule(rule.clone());
+    }
+    let dst = match flags.direct {
+        true => String::from("direct"),
+        false => flags
+            .dst
+            .iter()
+            .map(|dst| dst.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
     match flags.username {
-        Some(username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
-        None => info!("Proxy {} to {}", src, flags.dst),
+        Some(username) => info!("Proxy {} to {}@{}", src, username, dst),
+        None => info!("Proxy {} to {}", src, dst),
     }
-    if let Err(ref e) = redirector.open(&mut rx).await {
+
+    // Shutdown
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutting down, press Ctrl-C again to exit immediately");
+        is_running_cloned.store(false, Ordering::Relaxed);
+
+        wait_for_signal().await;
+        process::exit(1);
+    });
+
+    if let Err(ref e) = redirector.open_monitored(rx, Some(is_running)).await {
         error!("{}", e);
     }
 }
 
+/// Opens a capture file for replaying. Frames sent are written to the output capture file if any,
+/// or they are discarded.
+fn open_replay(replay: &str, output: Option<&String>) -> io::Result<(Sender, Receiver)> {
+    let rx = FileReceiver::open(replay, true)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot open {}: {}", replay, e)))?;
+    let tx: Sender =
+        match output {
+            Some(output) => Box::new(FileSender::create(output).map_err(|e| {
+                io::Error::new(e.kind(), format!("Cannot create {}: {}", output, e))
+            })?),
+            None => Box::new(BlackHole::new()),
+        };
+    info!("Replay {}", replay);
+
+    Ok((tx, Box::new(rx)))
+}
+
+/// Serves the embedded SOCKS5 server in the loopback. Returns the local address of the server.
+async fn serve_embedded() -> io::Result<SocketAddr> {
+    let server = SocksServer::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+
+    server.spawn()
+}
+
+/// Waits for a SIGINT or a SIGTERM.
+#[cfg(unix)]
+async fn wait_for_signal() {
+    let mut sigterm = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(ref e) => {
+            warn!("{}", e);
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Waits for a SIGINT.
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = signal::ctrl_c().await;
+}
+
+/// Represents the configuration file in TOML. Options set in flags take precedence over the ones in
+/// the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    interface: Option<String>,
+    mtu: Option<usize>,
+    preset: Option<String>,
+    source: Option<String>,
+    publish: Option<String>,
+    destination: Vec<String>,
+    source6: Option<String>,
+    publish6: Option<String>,
+    protocol: Option<String>,
+    method: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    proxies: Vec<String>,
+    rules: Vec<String>,
+    options: Config,
+}
+
+/// Loads the configuration file if any, fills the unset flags and returns the runtime
+/// configuration.
+fn apply_config(flags: &mut Flags) -> Result<Config, String> {
+    fn parse<T>(key: &str, value: Option<String>) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match value {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(format!("The {} {} is not available: {}", key, value, e)),
+            },
+            None => Ok(None),
+        }
+    }
+    fn parse_vec<T>(key: &str, values: Vec<String>) -> Result<Vec<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        values
+            .into_iter()
+            .map(|value| Ok(parse(key, Some(value))?.unwrap()))
+            .collect()
+    }
+
+    let mut config = Config::default();
+    if let Some(ref path) = flags.config {
+        let s = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read the configuration file {}: {}", path, e))?;
+        let file: FileConfig = toml::from_str(s.as_str())
+            .map_err(|e| format!("Cannot parse the configuration file {}: {}", path, e))?;
+
+        flags.inter = flags.inter.take().or(file.interface);
+        flags.mtu = flags.mtu.or(file.mtu);
+        flags.preset = flags.preset.take().or(file.preset);
+        if flags.src.is_none() {
+            flags.src = parse("source", file.source)?;
+        }
+        if flags.publish.is_none() {
+            flags.publish = parse("publish", file.publish)?;
+        }
+        if flags.dst.is_empty() {
+            flags.dst = parse_vec("destination", file.destination)?;
+        }
+        if flags.src6.is_none() {
+            flags.src6 = parse("source6", file.source6)?;
+        }
+        if flags.publish6.is_none() {
+            flags.publish6 = parse("publish6", file.publish6)?;
+        }
+        flags.protocol = flags.protocol.take().or(file.protocol);
+        flags.method = flags.method.take().or(file.method);
+        flags.username = flags.username.take().or(file.username);
+        flags.password = flags.password.take().or(file.password);
+        if flags.proxies.is_empty() {
+            flags.proxies = parse_vec("proxy", file.proxies)?;
+        }
+        if flags.rules.is_empty() {
+            flags.rules = parse_vec("rule", file.rules)?;
+        }
+        config = file.options;
+    }
+
+    // The destination is replaced with the embedded server or the direct connection, drop the
+    // options from the file which conflict with them
+    if flags.embedded || flags.direct {
+        flags.dst.clear();
+        flags.protocol = None;
+        flags.username = None;
+        flags.password = None;
+    }
+
+    // Default values
+    if flags.dst.is_empty() {
+        flags.dst.push("127.0.0.1:1080".parse().unwrap());
+    }
+    if flags.protocol.is_none() {
+        flags.protocol = Some(String::from("socks5"));
+    }
+    if flags.method.is_none() {
+        flags.method = Some(String::from("chacha20-ietf-poly1305"));
+    }
+    if let Some(cc) = flags.cc {
+        config.cc_algorithm = cc;
+    }
+    for device_cc in flags.device_ccs.iter() {
+        config
+            .cc_overrides
+            .insert(device_cc.addr, device_cc.algorithm);
+    }
+    if flags.publish6.is_some() && flags.src6.is_none() {
+        return Err(String::from(
+            "The IPv6 source is required when publishing for IPv6. Please use --source6 <ADDRESS> to set",
+        ));
+    }
+    config
+        .validate()
+        .map_err(|e| format!("The options are not available: {}", e))?;
+
+    Ok(config)
+}
+
+fn new_proxy(
+    protocol: &str,
+    dst: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+    method: &str,
+    force_associate_dst: bool,
+    force_associate_bind_addr: bool,
+) -> Option<ProxyConfig> {
+    let proxy = match protocol {
+        "socks5" => {
+            let auth = match username {
+                Some(username) => match password {
+                    Some(password) => Some((username, password)),
+                    None => {
+                        error!("The password is required in SOCKS5. Please use --password <VALUE> to set");
+                        return None;
+                    }
+                },
+                None => None,
+            };
+
+            ProxyConfig::new_socks(dst, force_associate_dst, force_associate_bind_addr, auth)
+        }
+        "socks4" => {
+            warn!("UDP is not supported in SOCKS4, and the UDP traffic will be rejected");
+
+            ProxyConfig::new_socks4(dst, username)
+        }
+        "http" => {
+            let auth = match username {
+                Some(username) => Some((username, password.unwrap_or_default())),
+                None => None,
+            };
+            warn!("UDP is not supported in HTTP, and the UDP traffic will be rejected");
+
+            ProxyConfig::new_http_connect(dst, auth)
+        }
+        "echo" => ProxyConfig::Echo,
+        "discard" => ProxyConfig::Discard,
+        "ss" | "shadowsocks" => {
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    error!("The password is required in Shadowsocks. Please use --password <VALUE> to set");
+                    return None;
+                }
+            };
+
+            match ProxyConfig::new_shadowsocks(dst, method, &password) {
+                Ok(proxy) => proxy,
+                Err(_) => {
+                    error!("The method {} is not available", method);
+                    return None;
+                }
+            }
+        }
+        _ => {
+            error!("The protocol {} is not available", protocol);
+            return None;
+        }
+    };
+
+    Some(proxy)
+}
+
+fn show_info_ipv6(src: Ipv6Network, gw: Ipv6Addr) {
+    let src_str = format!("{}/{}", src.network(), src.prefix());
+    let width = std::cmp::max(src_str.len(), gw.to_string().len());
+    info!("Please set the IPv6 network of your device with the following parameters:");
+    info!("    ┌─────────────{:─>w$}─┐", "", w = width);
+    info!("    │ IP Address  {:>w$} │", src_str, w = width);
+    info!("    │ Gateway     {:>w$} │", gw, w = width);
+    info!("    └─────────────{:─>w$}─┘", "", w = width);
+}
+
 fn show_info(src: Ipv4Network, gw: Ipv4Addr, mtu: usize) {
     macro_rules! max {
         ($x: expr) => ($x);
@@ -217,7 +642,7 @@ struct Flags {
         short,
         help = "Source",
         value_name = "ADDRESS",
-        required_unless("preset"),
+        required_unless_one(&["preset", "config"]),
         display_order(3)
     )]
     pub src: Option<Ipv4Network>,
@@ -234,10 +659,39 @@ struct Flags {
         short,
         help = "Destination",
         value_name = "ADDRESS",
-        default_value = "127.0.0.1:1080",
+        number_of_values = 1,
         display_order(5)
     )]
-    pub dst: ResolvableSocketAddrV4,
+    pub dst: Vec<ResolvableSocketAddr>,
+    #[structopt(
+        long,
+        help = "Protocol of the destination",
+        value_name = "PROTOCOL",
+        display_order(8)
+    )]
+    pub protocol: Option<String>,
+    #[structopt(
+        long,
+        help = "Shadowsocks method",
+        value_name = "METHOD",
+        display_order(9)
+    )]
+    pub method: Option<String>,
+    #[structopt(
+        long = "source6",
+        help = "IPv6 source",
+        value_name = "ADDRESS",
+        display_order(6)
+    )]
+    pub src6: Option<Ipv6Network>,
+    #[structopt(
+        long,
+        help = "NDP publishing address",
+        value_name = "ADDRESS",
+        requires("src6"),
+        display_order(7)
+    )]
+    pub publish6: Option<Ipv6Addr>,
     #[structopt(
         long = "force-associate-destination",
         help = "Force to associate with the destination",
@@ -251,22 +705,85 @@ struct Flags {
         display_order(1001)
     )]
     pub force_associate_bind_addr: bool,
+    #[structopt(long, help = "Username", value_name = "VALUE", display_order(1000))]
+    pub username: Option<String>,
+    #[structopt(long, help = "Password", value_name = "VALUE", display_order(1001))]
+    pub password: Option<String>,
+    #[structopt(
+        long = "proxy",
+        help = "Named proxy",
+        value_name = "NAME=URL",
+        number_of_values = 1,
+        display_order(1002)
+    )]
+    pub proxies: Vec<NamedProxy>,
+    #[structopt(
+        long = "rule",
+        help = "Routing rule",
+        value_name = "RULE",
+        number_of_values = 1,
+        display_order(1003)
+    )]
+    pub rules: Vec<Rule>,
     #[structopt(
         long,
-        help = "Username",
-        value_name = "VALUE",
-        requires("password"),
-        display_order(1000)
+        help = "TCP congestion control algorithm",
+        value_name = "ALGORITHM",
+        display_order(1004)
     )]
-    pub username: Option<String>,
+    pub cc: Option<TcpCcAlgorithms>,
+    #[structopt(
+        long = "device-cc",
+        help = "TCP congestion control algorithm of a source device",
+        value_name = "ADDRESS=ALGORITHM",
+        number_of_values = 1,
+        display_order(1005)
+    )]
+    pub device_ccs: Vec<DeviceCc>,
     #[structopt(
         long,
-        help = "Password",
-        value_name = "VALUE",
-        requires("username"),
-        display_order(1001)
+        help = "Configuration file",
+        value_name = "FILE",
+        display_order(1006)
     )]
-    pub password: Option<String>,
+    pub config: Option<String>,
+    #[structopt(
+        long,
+        help = "Replay frames from a capture file",
+        value_name = "FILE",
+        conflicts_with("inter"),
+        display_order(1007)
+    )]
+    pub replay: Option<String>,
+    #[structopt(
+        long,
+        help = "Capture file for frames sent in replaying",
+        value_name = "FILE",
+        requires("replay"),
+        display_order(1008)
+    )]
+    pub output: Option<String>,
+    #[structopt(
+        long,
+        help = "Capture file for frames processed",
+        value_name = "FILE",
+        display_order(1009)
+    )]
+    pub dump: Option<String>,
+    #[structopt(
+        long,
+        help = "Use the embedded SOCKS5 server as the destination",
+        conflicts_with_all(&["dst", "protocol", "username", "password"]),
+        display_order(1010)
+    )]
+    pub embedded: bool,
+    #[structopt(
+        long,
+        help = "Connect to the destination directly",
+        conflicts_with_all(&["dst", "protocol", "username", "password", "embedded"]),
+        display_order(1011)
+    )]
+    pub direct: bool,
 }
 
 /// Represents a logger.
@@ -372,18 +889,18 @@ impl From<io::Error> for ResolvableAddrParseError {
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-struct ResolvableSocketAddrV4 {
-    addr: SocketAddrV4,
+struct ResolvableSocketAddr {
+    addr: SocketAddr,
     alias: Option<String>,
 }
 
-impl ResolvableSocketAddrV4 {
-    fn addr(&self) -> SocketAddrV4 {
+impl ResolvableSocketAddr {
+    fn addr(&self) -> SocketAddr {
         self.addr
     }
 }
 
-impl Display for ResolvableSocketAddrV4 {
+impl Display for ResolvableSocketAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.alias {
             Some(alias) => write!(f, "{} ({})", alias, self.addr),
@@ -392,7 +909,7 @@ impl Display for ResolvableSocketAddrV4 {
     }
 }
 
-impl FromStr for ResolvableSocketAddrV4 {
+impl FromStr for ResolvableSocketAddr {
     type Err = ResolvableAddrParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let has_alias;
@@ -416,14 +933,12 @@ impl FromStr for ResolvableSocketAddrV4 {
                 };
                 let ip = match dns_lookup::lookup_host(v[0]) {
                     Ok(addrs) => {
-                        let mut ip = None;
-
-                        for addr in addrs {
-                            if let IpAddr::V4(addr) = addr {
-                                ip = Some(addr);
-                                break;
-                            }
-                        }
+                        // Prefer IPv4 addresses
+                        let ip = addrs
+                            .iter()
+                            .find(|addr| addr.is_ipv4())
+                            .or(addrs.first())
+                            .cloned();
 
                         match ip {
                             Some(ip) => ip,
@@ -433,7 +948,7 @@ impl FromStr for ResolvableSocketAddrV4 {
                     Err(e) => return Err(ResolvableAddrParseError::from(e)),
                 };
 
-                SocketAddrV4::new(ip, port)
+                SocketAddr::new(ip, port)
             }
         };
 
@@ -441,6 +956,81 @@ impl FromStr for ResolvableSocketAddrV4 {
             true => Some(String::from_str(s).unwrap()),
             false => None,
         };
-        Ok(ResolvableSocketAddrV4 { addr, alias })
+        Ok(ResolvableSocketAddr { addr, alias })
+    }
+}
+
+/// Represents a named proxy in the form of `<NAME>=<PROTOCOL>://[<USERNAME>[:<PASSWORD>]@]<ADDRESS>`.
+/// The username and the password are the method and the password in Shadowsocks.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct NamedProxy {
+    name: String,
+    protocol: String,
+    addr: ResolvableSocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl FromStr for NamedProxy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut v = s.splitn(2, '=');
+        let name = v.next().unwrap();
+        let url = v.next().ok_or(format!("invalid proxy {}", s))?;
+        if name.is_empty() {
+            return Err(format!("invalid proxy {}", s));
+        }
+
+        let mut v = url.splitn(2, "://");
+        let protocol = v.next().unwrap();
+        let rest = v.next().ok_or(format!("invalid proxy {}", s))?;
+
+        let (userinfo, addr) = match rest.rfind('@') {
+            Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+            None => (None, rest),
+        };
+        let (username, password) = match userinfo {
+            Some(userinfo) => match userinfo.find(':') {
+                Some(i) => (
+                    Some(String::from(&userinfo[..i])),
+                    Some(String::from(&userinfo[i + 1..])),
+                ),
+                None => (Some(String::from(userinfo)), None),
+            },
+            None => (None, None),
+        };
+        let addr = addr.parse().map_err(|e| format!("{}", e))?;
+
+        Ok(NamedProxy {
+            name: String::from(name),
+            protocol: String::from(protocol),
+            addr,
+            username,
+            password,
+        })
+    }
+}
+
+/// Represents a TCP congestion control algorithm of a source device in the form of
+/// `<ADDRESS>=<ALGORITHM>`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct DeviceCc {
+    addr: IpAddr,
+    algorithm: TcpCcAlgorithms,
+}
+
+impl FromStr for DeviceCc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut v = s.splitn(2, '=');
+        let addr = v.next().unwrap();
+        let algorithm = v
+            .next()
+            .ok_or(format!("invalid device congestion control {}", s))?;
+
+        Ok(DeviceCc {
+            addr: addr.parse().map_err(|e| format!("{}", e))?,
+            algorithm: algorithm.parse()?,
+        })
     }
 }
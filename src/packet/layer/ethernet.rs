@@ -19,6 +19,7 @@ impl Ethernet {
         let ethertype = match t {
             LayerKinds::Arp => EtherTypes::Arp,
             LayerKinds::Ipv4 => EtherTypes::Ipv4,
+            LayerKinds::Ipv6 => EtherTypes::Ipv6,
             _ => return None,
         };
         let ethernet = ethernet::Ethernet {
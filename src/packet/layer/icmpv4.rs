@@ -13,7 +13,7 @@ use pnet::packet::{FromPacket, Packet};
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr};
 
 use super::ipv4::Ipv4;
 use super::tcp::Tcp;
@@ -178,15 +178,15 @@ impl Icmpv4 {
     }
 
     /// Returns the source in the payload of the layer.
-    pub fn src(&self) -> Option<SocketAddrV4> {
+    pub fn src(&self) -> Option<SocketAddr> {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
             let (_, transport) = self.parse_payload().unwrap();
             match transport {
                 Some(transport) => match transport {
-                    Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.src_ip_addr(), tcp.src())),
-                    Layers::Udp(ref udp) => Some(SocketAddrV4::new(udp.src_ip_addr(), udp.src())),
+                    Layers::Tcp(ref tcp) => Some(SocketAddr::new(tcp.src_ip_addr(), tcp.src())),
+                    Layers::Udp(ref udp) => Some(SocketAddr::new(udp.src_ip_addr(), udp.src())),
                     _ => None,
                 },
                 None => None,
@@ -197,15 +197,15 @@ impl Icmpv4 {
     }
 
     /// Returns the destination in the payload of the layer.
-    pub fn dst(&self) -> Option<SocketAddrV4> {
+    pub fn dst(&self) -> Option<SocketAddr> {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
             let (_, transport) = self.parse_payload().unwrap();
             match transport {
                 Some(transport) => match transport {
-                    Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst())),
-                    Layers::Udp(ref udp) => Some(SocketAddrV4::new(udp.dst_ip_addr(), udp.dst())),
+                    Layers::Tcp(ref tcp) => Some(SocketAddr::new(tcp.dst_ip_addr(), tcp.dst())),
+                    Layers::Udp(ref udp) => Some(SocketAddr::new(udp.dst_ip_addr(), udp.dst())),
                     _ => None,
                 },
                 None => None,
@@ -0,0 +1,447 @@
+//! Support for serializing and deserializing the ICMPv6 layer.
+
+use super::{Layer, LayerKind, LayerKinds};
+use pnet::packet::icmpv6::{
+    self, Icmpv6Code, Icmpv6Packet, Icmpv6Type, Icmpv6Types, MutableIcmpv6Packet,
+};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::{FromPacket, Packet};
+use pnet::util::MacAddr;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+
+use super::ipv6::Ipv6;
+use super::tcp::Tcp;
+use super::udp::Udp;
+use super::Layers;
+
+/// Represents the code of the ICMPv6 destination port unreachable.
+const DESTINATION_PORT_UNREACHABLE: Icmpv6Code = Icmpv6Code(4);
+
+/// Represents the flag router in the ICMPv6 neighbor advertisement.
+const FLAG_ROUTER: u8 = 0x80;
+/// Represents the flag solicited in the ICMPv6 neighbor advertisement.
+const FLAG_SOLICITED: u8 = 0x40;
+/// Represents the flag override in the ICMPv6 neighbor advertisement.
+const FLAG_OVERRIDE: u8 = 0x20;
+
+/// Represents the type of the NDP source link-layer address option.
+const OPTION_SOURCE_LINK_LAYER_ADDR: u8 = 1;
+/// Represents the type of the NDP target link-layer address option.
+const OPTION_TARGET_LINK_LAYER_ADDR: u8 = 2;
+
+/// Represents an ICMPv6 layer.
+#[derive(Clone, Debug)]
+pub struct Icmpv6 {
+    layer: icmpv6::Icmpv6,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+}
+
+impl Icmpv6 {
+    /// Creates an `Icmpv6` represents an ICMPv6 neighbor advertisement.
+    pub fn new_neighbor_advertisement(
+        target: Ipv6Addr,
+        hardware_addr: MacAddr,
+        is_solicited: bool,
+    ) -> Icmpv6 {
+        let mut payload = vec![0u8; 28];
+        // Flags
+        payload[0] = FLAG_ROUTER | FLAG_OVERRIDE;
+        if is_solicited {
+            payload[0] |= FLAG_SOLICITED;
+        }
+        // Target address
+        &payload[4..20].copy_from_slice(&target.octets());
+        // Target link-layer address option
+        payload[20] = OPTION_TARGET_LINK_LAYER_ADDR;
+        payload[21] = 1;
+        payload[22] = hardware_addr.0;
+        payload[23] = hardware_addr.1;
+        payload[24] = hardware_addr.2;
+        payload[25] = hardware_addr.3;
+        payload[26] = hardware_addr.4;
+        payload[27] = hardware_addr.5;
+        let d_icmpv6 = icmpv6::Icmpv6 {
+            icmpv6_type: Icmpv6Types::NeighborAdvert,
+            icmpv6_code: Icmpv6Code(0),
+            checksum: 0,
+            payload,
+        };
+        Icmpv6::from(d_icmpv6)
+    }
+
+    /// Creates an `Icmpv6` represents an ICMPv6 destination port unreachable.
+    pub fn new_destination_port_unreachable(payload: &[u8]) -> Icmpv6 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        &next_payload[4..].copy_from_slice(payload);
+        let d_icmpv6 = icmpv6::Icmpv6 {
+            icmpv6_type: Icmpv6Types::DestinationUnreachable,
+            icmpv6_code: DESTINATION_PORT_UNREACHABLE,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv6::from(d_icmpv6)
+    }
+
+    /// Creates an `Icmpv6` according to the given `Icmpv6`.
+    pub fn from(d_icmpv6: icmpv6::Icmpv6) -> Icmpv6 {
+        Icmpv6 {
+            layer: d_icmpv6,
+            src: Ipv6Addr::UNSPECIFIED,
+            dst: Ipv6Addr::UNSPECIFIED,
+        }
+    }
+
+    /// Creates an `Icmpv6` according to the given ICMPv6 packet and the `Ipv6`.
+    pub fn parse(packet: &Icmpv6Packet, ipv6: &Ipv6) -> Icmpv6 {
+        let mut icmpv6 = Icmpv6::from(packet.from_packet());
+        icmpv6.set_ipv6_layer(ipv6);
+
+        icmpv6
+    }
+
+    /// Sets the source and destination IP address for the layer with the given `Ipv6`.
+    pub fn set_ipv6_layer(&mut self, ipv6: &Ipv6) {
+        self.src = ipv6.src();
+        self.dst = ipv6.dst();
+    }
+
+    /// Returns the string represents the description of the layer.
+    pub fn description(&self) -> String {
+        if self.is_neighbor_solicitation() {
+            String::from("Neighbor solicitation")
+        } else if self.is_neighbor_advertisement() {
+            String::from("Neighbor advertisement")
+        } else if self.is_destination_port_unreachable() {
+            String::from("Destination port unreachable")
+        } else if self.is_packet_too_big() {
+            String::from("Packet too big")
+        } else if self.is_echo_request() {
+            String::from("Echo request")
+        } else if self.is_echo_reply() {
+            String::from("Echo reply")
+        } else {
+            format!(
+                "Type = {}, Code = {}",
+                self.layer.icmpv6_type.0, self.layer.icmpv6_code.0
+            )
+        }
+    }
+
+    /// Returns the target address of the layer.
+    pub fn target_addr(&self) -> Option<Ipv6Addr> {
+        if (self.is_neighbor_solicitation() || self.is_neighbor_advertisement())
+            && self.layer.payload.len() >= 20
+        {
+            let mut buffer = [0u8; 16];
+            buffer.copy_from_slice(&self.layer.payload[4..20]);
+            Some(Ipv6Addr::from(buffer))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the source link-layer address in the options of the layer.
+    pub fn src_hardware_addr(&self) -> Option<MacAddr> {
+        if !self.is_neighbor_solicitation() {
+            return None;
+        }
+
+        // NDP options
+        let mut i = 20;
+        while i + 2 <= self.layer.payload.len() {
+            let kind = self.layer.payload[i];
+            let length = self.layer.payload[i + 1] as usize * 8;
+            if length == 0 || i + length > self.layer.payload.len() {
+                break;
+            }
+            if kind == OPTION_SOURCE_LINK_LAYER_ADDR && length >= 8 {
+                let b = &self.layer.payload[i + 2..i + 8];
+                return Some(MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5]));
+            }
+            i += length;
+        }
+
+        None
+    }
+
+    /// Returns the MTU of the layer.
+    pub fn mtu(&self) -> Option<u32> {
+        if self.is_packet_too_big() && self.layer.payload.len() >= 4 {
+            let buffer = [
+                self.layer.payload[0],
+                self.layer.payload[1],
+                self.layer.payload[2],
+                self.layer.payload[3],
+            ];
+            Some(u32::from_be_bytes(buffer))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the destination IP address in the payload of the layer.
+    pub fn dst_ip_addr(&self) -> Option<Ipv6Addr> {
+        if self.is_destination_port_unreachable() || self.is_packet_too_big() {
+            match self.parse_payload() {
+                Some((ipv6, _)) => Some(ipv6.dst()),
+                None => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Returns the next level layer kind in the payload of the layer.
+    pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
+        if self.is_destination_port_unreachable() || self.is_packet_too_big() {
+            match self.parse_payload() {
+                Some((ipv6, _)) => ipv6.next_level_layer_kind(),
+                None => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Returns the source in the payload of the layer.
+    pub fn src(&self) -> Option<SocketAddr> {
+        if self.is_destination_port_unreachable() || self.is_packet_too_big() {
+            match self.parse_payload() {
+                Some((_, Some(transport))) => match transport {
+                    Layers::Tcp(ref tcp) => Some(SocketAddr::new(tcp.src_ip_addr(), tcp.src())),
+                    Layers::Udp(ref udp) => Some(SocketAddr::new(udp.src_ip_addr(), udp.src())),
+                    _ => None,
+                },
+                _ => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Returns the destination in the payload of the layer.
+    pub fn dst(&self) -> Option<SocketAddr> {
+        if self.is_destination_port_unreachable() || self.is_packet_too_big() {
+            match self.parse_payload() {
+                Some((_, Some(transport))) => match transport {
+                    Layers::Tcp(ref tcp) => Some(SocketAddr::new(tcp.dst_ip_addr(), tcp.dst())),
+                    Layers::Udp(ref udp) => Some(SocketAddr::new(udp.dst_ip_addr(), udp.dst())),
+                    _ => None,
+                },
+                _ => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    fn parse_payload(&self) -> Option<(Ipv6, Option<Layers>)> {
+        if self.layer.payload.len() < 4 {
+            return None;
+        }
+        let payload = &self.layer.payload[4..];
+        match Ipv6Packet::new(payload) {
+            Some(ref ipv6_packet) => {
+                let ipv6 = Ipv6::parse(ipv6_packet);
+                // Fragment
+                if !ipv6.is_fragment() {
+                    let transport = match ipv6_packet.get_next_header() {
+                        IpNextHeaderProtocols::Tcp => match TcpPacket::new(ipv6_packet.payload()) {
+                            Some(ref tcp_packet) => {
+                                Some(Layers::Tcp(Tcp::parse_ipv6(tcp_packet, &ipv6)))
+                            }
+                            None => None,
+                        },
+                        IpNextHeaderProtocols::Udp => match UdpPacket::new(ipv6_packet.payload()) {
+                            Some(ref udp_packet) => {
+                                Some(Layers::Udp(Udp::parse_ipv6(udp_packet, &ipv6)))
+                            }
+                            None => None,
+                        },
+                        _ => None,
+                    };
+
+                    Some((ipv6, transport))
+                } else {
+                    Some((ipv6, None))
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn is_type(&self, t: Icmpv6Type) -> bool {
+        self.layer.icmpv6_type == t
+    }
+
+    /// Returns if the layer is an ICMPv6 neighbor solicitation.
+    pub fn is_neighbor_solicitation(&self) -> bool {
+        self.is_type(Icmpv6Types::NeighborSolicit)
+    }
+
+    /// Returns if the layer is an ICMPv6 neighbor advertisement.
+    pub fn is_neighbor_advertisement(&self) -> bool {
+        self.is_type(Icmpv6Types::NeighborAdvert)
+    }
+
+    /// Returns if the layer is an ICMPv6 destination port unreachable.
+    pub fn is_destination_port_unreachable(&self) -> bool {
+        self.is_type(Icmpv6Types::DestinationUnreachable)
+            && self.layer.icmpv6_code == DESTINATION_PORT_UNREACHABLE
+    }
+
+    /// Returns if the layer is an ICMPv6 packet too big.
+    pub fn is_packet_too_big(&self) -> bool {
+        self.is_type(Icmpv6Types::PacketTooBig)
+    }
+
+    /// Returns if the layer is an ICMPv6 echo request.
+    pub fn is_echo_request(&self) -> bool {
+        self.is_type(Icmpv6Types::EchoRequest)
+    }
+
+    /// Returns if the layer is an ICMPv6 echo reply.
+    pub fn is_echo_reply(&self) -> bool {
+        self.is_type(Icmpv6Types::EchoReply)
+    }
+
+    /// Returns if the layer is an ICMPv6 neighbor discovery message.
+    pub fn is_ndp(&self) -> bool {
+        self.is_type(Icmpv6Types::RouterSolicit)
+            || self.is_type(Icmpv6Types::RouterAdvert)
+            || self.is_neighbor_solicitation()
+            || self.is_neighbor_advertisement()
+            || self.is_type(Icmpv6Types::Redirect)
+    }
+}
+
+impl Display for Icmpv6 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", LayerKinds::Icmpv6, self.description())
+    }
+}
+
+impl Layer for Icmpv6 {
+    fn kind(&self) -> LayerKind {
+        LayerKinds::Icmpv6
+    }
+
+    fn len(&self) -> usize {
+        Icmpv6Packet::packet_size(&self.layer)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+        let mut packet = MutableIcmpv6Packet::new(buffer)
+            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = icmpv6::checksum(&packet.to_immutable(), &self.src, &self.dst);
+        packet.set_checksum(checksum);
+
+        Ok(self.len())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+        self.serialize(buffer, n)
+    }
+}
+
+#[test]
+fn icmpv6_checksum() {
+    let src: Ipv6Addr = "fe80::1".parse().unwrap();
+    let dst: Ipv6Addr = "fe80::2".parse().unwrap();
+    let mut icmpv6 = Icmpv6::new_neighbor_advertisement(src, MacAddr::new(2, 0, 0, 0, 0, 1), false);
+    icmpv6.set_ipv6_layer(&Ipv6::new_ndp(src, dst));
+
+    let mut buffer = vec![0u8; icmpv6.len()];
+    let n = buffer.len();
+    icmpv6.serialize(&mut buffer, n).unwrap();
+
+    // The one's complement sum over the pseudo header and the message is 0xffff
+    let mut pseudo = Vec::new();
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, IpNextHeaderProtocols::Icmpv6.0]);
+    pseudo.extend_from_slice(&buffer);
+    let mut sum = pseudo
+        .chunks(2)
+        .map(|b| u16::from_be_bytes([b[0], *b.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(sum, 0xffff);
+}
+
+#[test]
+fn icmpv6_neighbor_advertisement() {
+    let target: Ipv6Addr = "fe80::1".parse().unwrap();
+    let hardware_addr = MacAddr::new(2, 0, 0, 0, 0, 1);
+
+    let icmpv6 = Icmpv6::new_neighbor_advertisement(target, hardware_addr, true);
+    assert!(icmpv6.is_neighbor_advertisement());
+    assert!(icmpv6.is_ndp());
+    assert_eq!(icmpv6.len(), 32);
+    assert_eq!(
+        icmpv6.layer.payload[0],
+        FLAG_ROUTER | FLAG_SOLICITED | FLAG_OVERRIDE
+    );
+    assert_eq!(icmpv6.target_addr(), Some(target));
+    assert_eq!(
+        &icmpv6.layer.payload[20..],
+        &[OPTION_TARGET_LINK_LAYER_ADDR, 1, 2, 0, 0, 0, 0, 1]
+    );
+
+    let icmpv6 = Icmpv6::new_neighbor_advertisement(target, hardware_addr, false);
+    assert_eq!(icmpv6.layer.payload[0], FLAG_ROUTER | FLAG_OVERRIDE);
+}
+
+#[test]
+fn icmpv6_ndp_options() {
+    let target: Ipv6Addr = "fe80::1".parse().unwrap();
+    let solicitation = |options: &[u8]| {
+        let mut payload = vec![0u8; 20];
+        payload[4..20].copy_from_slice(&target.octets());
+        payload.extend_from_slice(options);
+        Icmpv6::from(icmpv6::Icmpv6 {
+            icmpv6_type: Icmpv6Types::NeighborSolicit,
+            icmpv6_code: Icmpv6Code(0),
+            checksum: 0,
+            payload,
+        })
+    };
+
+    // The source link-layer address option follows a nonce option
+    let options = [
+        [14, 1, 1, 2, 3, 4, 5, 6],
+        [OPTION_SOURCE_LINK_LAYER_ADDR, 1, 2, 0, 0, 0, 0, 2],
+    ]
+    .concat();
+    let icmpv6 = solicitation(&options);
+    assert_eq!(icmpv6.target_addr(), Some(target));
+    assert_eq!(
+        icmpv6.src_hardware_addr(),
+        Some(MacAddr::new(2, 0, 0, 0, 0, 2))
+    );
+
+    // No option
+    assert_eq!(solicitation(&[]).src_hardware_addr(), None);
+
+    // Zero length option
+    let icmpv6 = solicitation(&[OPTION_SOURCE_LINK_LAYER_ADDR, 0, 2, 0, 0, 0, 0, 2]);
+    assert_eq!(icmpv6.src_hardware_addr(), None);
+
+    // Truncated option
+    let icmpv6 = solicitation(&[OPTION_SOURCE_LINK_LAYER_ADDR, 1, 2, 0, 0]);
+    assert_eq!(icmpv6.src_hardware_addr(), None);
+}
@@ -0,0 +1,311 @@
+//! Support for serializing and deserializing the IPv6 layer.
+
+use super::{Layer, LayerKind, LayerKinds};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv6::{self, Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv6Addr;
+
+/// Represents the hop limit in the sent packets.
+const HOP_LIMIT: u8 = 128;
+/// Represents the hop limit in the sent neighbor discovery packets.
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// Represents the length of the IPv6 fragment extension header.
+const FRAGMENT_HEADER_LEN: usize = 8;
+
+/// Represents the IPv6 fragment extension header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct FragmentHeader {
+    next_header: IpNextHeaderProtocol,
+    fragment_offset: u16,
+    is_more_fragment: bool,
+    identification: u32,
+}
+
+/// Represents an IPv6 layer.
+#[derive(Clone, Debug)]
+pub struct Ipv6 {
+    layer: ipv6::Ipv6,
+    fragment: Option<FragmentHeader>,
+}
+
+impl Ipv6 {
+    /// Creates an `Ipv6`.
+    pub fn new(t: LayerKind, src: Ipv6Addr, dst: Ipv6Addr) -> Option<Ipv6> {
+        let next_header = match t {
+            LayerKinds::Icmpv6 => IpNextHeaderProtocols::Icmpv6,
+            LayerKinds::Tcp => IpNextHeaderProtocols::Tcp,
+            LayerKinds::Udp => IpNextHeaderProtocols::Udp,
+            _ => return None,
+        };
+        let d_ipv6 = ipv6::Ipv6 {
+            version: 6,
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: 0,
+            next_header,
+            hop_limit: HOP_LIMIT,
+            source: src,
+            destination: dst,
+            payload: vec![],
+        };
+        Some(Ipv6::from(d_ipv6))
+    }
+
+    /// Creates an `Ipv6` represents an IPv6 neighbor discovery packet.
+    pub fn new_ndp(src: Ipv6Addr, dst: Ipv6Addr) -> Ipv6 {
+        let mut ipv6 = Ipv6::new(LayerKinds::Icmpv6, src, dst).unwrap();
+        ipv6.layer.hop_limit = NDP_HOP_LIMIT;
+
+        ipv6
+    }
+
+    /// Creates an `Ipv6` represents an IPv6 fragment. The fragment offset is in 8-octet units.
+    pub fn new_fragment(
+        identification: u32,
+        t: LayerKind,
+        fragment_offset: u16,
+        is_more_fragment: bool,
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+    ) -> Option<Ipv6> {
+        let ipv6 = Ipv6::new(t, src, dst);
+        if let Some(mut ipv6) = ipv6 {
+            let fragment = FragmentHeader {
+                next_header: ipv6.layer.next_header,
+                fragment_offset,
+                is_more_fragment,
+                identification,
+            };
+            ipv6.layer.next_header = IpNextHeaderProtocols::Ipv6Frag;
+            ipv6.fragment = Some(fragment);
+            return Some(ipv6);
+        };
+
+        None
+    }
+
+    /// Creates an `Ipv6` according to the given `Ipv6`.
+    pub fn from(ipv6: ipv6::Ipv6) -> Ipv6 {
+        Ipv6 {
+            layer: ipv6,
+            fragment: None,
+        }
+    }
+
+    /// Creates an `Ipv6` according to the given IPv6 packet.
+    pub fn parse(packet: &Ipv6Packet) -> Ipv6 {
+        let d_ipv6 = ipv6::Ipv6 {
+            version: packet.get_version(),
+            traffic_class: packet.get_traffic_class(),
+            flow_label: packet.get_flow_label(),
+            payload_length: packet.get_payload_length(),
+            next_header: packet.get_next_header(),
+            hop_limit: packet.get_hop_limit(),
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            payload: vec![],
+        };
+        let mut ipv6 = Ipv6::from(d_ipv6);
+
+        // Fragment extension header
+        if ipv6.layer.next_header == IpNextHeaderProtocols::Ipv6Frag {
+            let payload = packet.payload();
+            if payload.len() >= FRAGMENT_HEADER_LEN {
+                let offset_and_flag = u16::from_be_bytes([payload[2], payload[3]]);
+                ipv6.fragment = Some(FragmentHeader {
+                    next_header: IpNextHeaderProtocol::new(payload[0]),
+                    fragment_offset: offset_and_flag >> 3,
+                    is_more_fragment: offset_and_flag & 1 != 0,
+                    identification: u32::from_be_bytes([
+                        payload[4], payload[5], payload[6], payload[7],
+                    ]),
+                });
+            }
+        }
+
+        ipv6
+    }
+
+    /// Returns the minimum of the layer when converted into a byte-array.
+    pub fn minimum_len() -> usize {
+        40
+    }
+
+    /// Returns the payload length of the layer.
+    pub fn payload_length(&self) -> u16 {
+        self.layer.payload_length
+    }
+
+    /// Returns the hop limit of the layer.
+    pub fn hop_limit(&self) -> u8 {
+        self.layer.hop_limit
+    }
+
+    /// Returns if the layer is a IPv6 fragment.
+    pub fn is_fragment(&self) -> bool {
+        self.layer.next_header == IpNextHeaderProtocols::Ipv6Frag
+    }
+
+    /// Returns the next header of the layer.
+    pub fn next_header(&self) -> IpNextHeaderProtocol {
+        self.layer.next_header
+    }
+
+    /// Returns the next level layer kind of the layer.
+    pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
+        let next_header = match self.fragment {
+            Some(fragment) => fragment.next_header,
+            None => self.layer.next_header,
+        };
+        match next_header {
+            IpNextHeaderProtocols::Icmpv6 => Some(LayerKinds::Icmpv6),
+            IpNextHeaderProtocols::Tcp => Some(LayerKinds::Tcp),
+            IpNextHeaderProtocols::Udp => Some(LayerKinds::Udp),
+            _ => None,
+        }
+    }
+
+    /// Returns the source of the layer.
+    pub fn src(&self) -> Ipv6Addr {
+        self.layer.source
+    }
+
+    /// Returns the destination of the layer.
+    pub fn dst(&self) -> Ipv6Addr {
+        self.layer.destination
+    }
+
+    fn serialize_fragment_header(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        if let Some(fragment) = self.fragment {
+            if buffer.len() < FRAGMENT_HEADER_LEN {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+            }
+
+            let offset_and_flag = (fragment.fragment_offset << 3)
+                | match fragment.is_more_fragment {
+                    true => 1,
+                    false => 0,
+                };
+            buffer[0] = fragment.next_header.0;
+            buffer[1] = 0;
+            buffer[2..4].copy_from_slice(&offset_and_flag.to_be_bytes());
+            buffer[4..8].copy_from_slice(&fragment.identification.to_be_bytes());
+
+            return Ok(FRAGMENT_HEADER_LEN);
+        }
+
+        Ok(0)
+    }
+}
+
+impl Display for Ipv6 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut fragment = String::new();
+        if let Some(header) = self.fragment {
+            fragment = format!(", Fragment = {}", header.fragment_offset as usize * 8);
+        }
+
+        write!(
+            f,
+            "{}: {} -> {}, Length = {}{}",
+            LayerKinds::Ipv6,
+            self.layer.source,
+            self.layer.destination,
+            self.layer.payload_length,
+            fragment
+        )
+    }
+}
+
+impl Layer for Ipv6 {
+    fn kind(&self) -> LayerKind {
+        LayerKinds::Ipv6
+    }
+
+    fn len(&self) -> usize {
+        match self.fragment {
+            Some(_) => Ipv6::minimum_len() + FRAGMENT_HEADER_LEN,
+            None => Ipv6::minimum_len(),
+        }
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
+        let header_length = self.len();
+        if buffer.len() < header_length {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        {
+            let mut packet = MutableIpv6Packet::new(buffer)
+                .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+
+            packet.populate(&self.layer);
+
+            // Fix length
+            let payload_length = n.checked_sub(Ipv6::minimum_len()).unwrap_or(0);
+            if payload_length > u16::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "length too big",
+                ));
+            }
+            packet.set_payload_length(payload_length as u16);
+        }
+
+        // Fragment extension header
+        self.serialize_fragment_header(&mut buffer[Ipv6::minimum_len()..])?;
+
+        Ok(header_length)
+    }
+
+    fn serialize_with_payload(
+        &self,
+        buffer: &mut [u8],
+        payload: &[u8],
+        n: usize,
+    ) -> io::Result<usize> {
+        let header_length = self.serialize(buffer, n)?;
+
+        // Copy payload
+        if buffer.len() < header_length + payload.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        buffer[header_length..header_length + payload.len()].copy_from_slice(payload);
+
+        Ok(header_length)
+    }
+}
+
+#[test]
+fn ipv6_round_trip() {
+    let src: Ipv6Addr = "fd00::1".parse().unwrap();
+    let dst: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let ipv6 = Ipv6::new_fragment(0x12345678, LayerKinds::Udp, 185, true, src, dst).unwrap();
+    assert_eq!(ipv6.len(), Ipv6::minimum_len() + FRAGMENT_HEADER_LEN);
+
+    let payload = [0xffu8; 16];
+    let mut buffer = vec![0u8; ipv6.len() + payload.len()];
+    let n = buffer.len();
+    ipv6.serialize_with_payload(&mut buffer, &payload, n)
+        .unwrap();
+
+    let packet = Ipv6Packet::new(&buffer).unwrap();
+    assert_eq!(packet.get_version(), 6);
+    assert_eq!(
+        packet.get_payload_length() as usize,
+        FRAGMENT_HEADER_LEN + payload.len()
+    );
+    assert_eq!(&buffer[ipv6.len()..], &payload);
+
+    let parsed = Ipv6::parse(&packet);
+    assert_eq!(parsed.src(), src);
+    assert_eq!(parsed.dst(), dst);
+    assert_eq!(parsed.hop_limit(), HOP_LIMIT);
+    assert!(parsed.is_fragment());
+    assert_eq!(parsed.next_level_layer_kind(), Some(LayerKinds::Udp));
+    assert_eq!(parsed.fragment, ipv6.fragment);
+}
@@ -9,7 +9,9 @@ use std::io;
 pub mod arp;
 pub mod ethernet;
 pub mod icmpv4;
+pub mod icmpv6;
 pub mod ipv4;
+pub mod ipv6;
 pub mod tcp;
 pub mod udp;
 
@@ -29,6 +31,8 @@ impl Display for LayerKind {
                 LayerKinds::Icmpv4 => "ICMPv4",
                 LayerKinds::Tcp => "TCP",
                 LayerKinds::Udp => "UDP",
+                LayerKinds::Ipv6 => "IPv6",
+                LayerKinds::Icmpv6 => "ICMPv6",
                 _ => "unknown",
             }
         )
@@ -53,6 +57,10 @@ pub mod LayerKinds {
     pub const Tcp: LayerKind = LayerKind(4);
     /// Represents the layer kind of UDP.
     pub const Udp: LayerKind = LayerKind(5);
+    /// Represents the layer kind of IPv6.
+    pub const Ipv6: LayerKind = LayerKind(6);
+    /// Represents the layer kind of ICMPv6.
+    pub const Icmpv6: LayerKind = LayerKind(7);
 }
 
 /// Represents a layer.
@@ -90,6 +98,10 @@ pub enum Layers {
     Tcp(tcp::Tcp),
     /// Represents the UDP layer.
     Udp(udp::Udp),
+    /// Represents the IPv6 layer.
+    Ipv6(ipv6::Ipv6),
+    /// Represents the ICMPv6 layer.
+    Icmpv6(icmpv6::Icmpv6),
 }
 
 impl Display for Layers {
@@ -101,6 +113,8 @@ impl Display for Layers {
             Layers::Icmpv4(ref layer) => layer.fmt(f),
             Layers::Tcp(ref layer) => layer.fmt(f),
             Layers::Udp(ref layer) => layer.fmt(f),
+            Layers::Ipv6(ref layer) => layer.fmt(f),
+            Layers::Icmpv6(ref layer) => layer.fmt(f),
         }
     }
 }
@@ -114,6 +128,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.kind(),
             Layers::Tcp(ref layer) => layer.kind(),
             Layers::Udp(ref layer) => layer.kind(),
+            Layers::Ipv6(ref layer) => layer.kind(),
+            Layers::Icmpv6(ref layer) => layer.kind(),
         }
     }
 
@@ -125,6 +141,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.len(),
             Layers::Tcp(ref layer) => layer.len(),
             Layers::Udp(ref layer) => layer.len(),
+            Layers::Ipv6(ref layer) => layer.len(),
+            Layers::Icmpv6(ref layer) => layer.len(),
         }
     }
 
@@ -136,6 +154,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize(buffer, n),
             Layers::Tcp(ref layer) => layer.serialize(buffer, n),
             Layers::Udp(ref layer) => layer.serialize(buffer, n),
+            Layers::Ipv6(ref layer) => layer.serialize(buffer, n),
+            Layers::Icmpv6(ref layer) => layer.serialize(buffer, n),
         }
     }
 
@@ -152,6 +172,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Tcp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Udp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Ipv6(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Icmpv6(ref layer) => layer.serialize_with_payload(buffer, payload, n),
         }
     }
 }
@@ -1,6 +1,7 @@
 //! Support for serializing and deserializing the TCP layer.
 
 use super::ipv4::Ipv4;
+use super::ipv6::Ipv6;
 use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::tcp::{
     self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
@@ -10,14 +11,14 @@ use std::clone::Clone;
 use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 /// Represents a TCP packet.
 #[derive(Clone, Debug)]
 pub struct Tcp {
     layer: tcp::Tcp,
-    src: Ipv4Addr,
-    dst: Ipv4Addr,
+    src: IpAddr,
+    dst: IpAddr,
 }
 
 impl Tcp {
@@ -205,8 +206,8 @@ impl Tcp {
     pub fn from(tcp: tcp::Tcp) -> Tcp {
         Tcp {
             layer: tcp,
-            src: Ipv4Addr::UNSPECIFIED,
-            dst: Ipv4Addr::UNSPECIFIED,
+            src: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            dst: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
         }
     }
 
@@ -232,6 +233,28 @@ impl Tcp {
         tcp
     }
 
+    /// Creates a `Tcp` according to the given TCP packet and the `Ipv6`.
+    pub fn parse_ipv6(packet: &TcpPacket, ipv6: &Ipv6) -> Tcp {
+        let d_tcp = tcp::Tcp {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            sequence: packet.get_sequence(),
+            acknowledgement: packet.get_acknowledgement(),
+            data_offset: packet.get_data_offset(),
+            reserved: packet.get_reserved(),
+            flags: packet.get_flags(),
+            window: packet.get_window(),
+            checksum: packet.get_checksum(),
+            urgent_ptr: packet.get_urgent_ptr(),
+            options: packet.get_options(),
+            payload: vec![],
+        };
+        let mut tcp = Tcp::from(d_tcp);
+        tcp.set_ipv6_layer(ipv6);
+
+        tcp
+    }
+
     /// Returns the minimum of the layer when converted into a byte-array.
     pub fn minimum_len() -> usize {
         20
@@ -239,17 +262,23 @@ impl Tcp {
 
     /// Sets the source and destination IP address for the layer with the given `Ipv4`.
     pub fn set_ipv4_layer(&mut self, ipv4: &Ipv4) {
-        self.src = ipv4.src();
-        self.dst = ipv4.dst();
+        self.src = IpAddr::V4(ipv4.src());
+        self.dst = IpAddr::V4(ipv4.dst());
+    }
+
+    /// Sets the source and destination IP address for the layer with the given `Ipv6`.
+    pub fn set_ipv6_layer(&mut self, ipv6: &Ipv6) {
+        self.src = IpAddr::V6(ipv6.src());
+        self.dst = IpAddr::V6(ipv6.dst());
     }
 
     /// Returns the source IP address of the layer.
-    pub fn src_ip_addr(&self) -> Ipv4Addr {
+    pub fn src_ip_addr(&self) -> IpAddr {
         self.src
     }
 
     /// Returns the destination IP address of the layer.
-    pub fn dst_ip_addr(&self) -> Ipv4Addr {
+    pub fn dst_ip_addr(&self) -> IpAddr {
         self.dst
     }
 
@@ -449,6 +478,14 @@ impl Tcp {
 
         false
     }
+
+    fn checksum(&self, packet: &TcpPacket) -> u16 {
+        match (self.src, self.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => tcp::ipv4_checksum(packet, &src, &dst),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => tcp::ipv6_checksum(packet, &src, &dst),
+            _ => 0,
+        }
+    }
 }
 
 impl Display for Tcp {
@@ -494,11 +531,7 @@ impl Layer for Tcp {
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.src_ip_addr(),
-            &self.dst_ip_addr(),
-        );
+        let checksum = self.checksum(&packet.to_immutable());
         packet.set_checksum(checksum);
 
         Ok(header_length)
@@ -526,11 +559,7 @@ impl Layer for Tcp {
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.src_ip_addr(),
-            &self.dst_ip_addr(),
-        );
+        let checksum = self.checksum(&packet.to_immutable());
         packet.set_checksum(checksum);
 
         Ok(header_length + n)
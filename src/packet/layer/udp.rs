@@ -1,19 +1,20 @@
 //! Support for serializing and deserializing the UDP layer.
 
 use super::ipv4::Ipv4;
+use super::ipv6::Ipv6;
 use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 /// Represents an UDP packet.
 #[derive(Clone, Debug)]
 pub struct Udp {
     layer: udp::Udp,
-    src: Ipv4Addr,
-    dst: Ipv4Addr,
+    src: IpAddr,
+    dst: IpAddr,
 }
 
 impl Udp {
@@ -33,8 +34,8 @@ impl Udp {
     pub fn from(udp: udp::Udp) -> Udp {
         Udp {
             layer: udp,
-            src: Ipv4Addr::UNSPECIFIED,
-            dst: Ipv4Addr::UNSPECIFIED,
+            src: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            dst: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
         }
     }
 
@@ -53,6 +54,21 @@ impl Udp {
         udp
     }
 
+    /// Creates an `Udp` according to the given UDP packet and the `Ipv6`
+    pub fn parse_ipv6(packet: &UdpPacket, ipv6: &Ipv6) -> Udp {
+        let d_udp = udp::Udp {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            length: packet.get_length(),
+            checksum: packet.get_checksum(),
+            payload: vec![],
+        };
+        let mut udp = Udp::from(d_udp);
+        udp.set_ipv6_layer(ipv6);
+
+        udp
+    }
+
     /// Returns the minimum of the layer when converted into a byte-array.
     pub fn minimum_len() -> usize {
         8
@@ -60,17 +76,23 @@ impl Udp {
 
     /// Sets the source and destination IP address for the layer with the given `Ipv4`.
     pub fn set_ipv4_layer(&mut self, ipv4: &Ipv4) {
-        self.src = ipv4.src();
-        self.dst = ipv4.dst();
+        self.src = IpAddr::V4(ipv4.src());
+        self.dst = IpAddr::V4(ipv4.dst());
+    }
+
+    /// Sets the source and destination IP address for the layer with the given `Ipv6`.
+    pub fn set_ipv6_layer(&mut self, ipv6: &Ipv6) {
+        self.src = IpAddr::V6(ipv6.src());
+        self.dst = IpAddr::V6(ipv6.dst());
     }
 
     /// Returns the source IP address of the layer.
-    pub fn src_ip_addr(&self) -> Ipv4Addr {
+    pub fn src_ip_addr(&self) -> IpAddr {
         self.src
     }
 
     /// Returns the destination IP address of the layer.
-    pub fn dst_ip_addr(&self) -> Ipv4Addr {
+    pub fn dst_ip_addr(&self) -> IpAddr {
         self.dst
     }
 
@@ -88,6 +110,14 @@ impl Udp {
     pub fn length(&self) -> u16 {
         self.layer.length
     }
+
+    fn checksum(&self, packet: &UdpPacket) -> u16 {
+        match (self.src, self.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => udp::ipv4_checksum(packet, &src, &dst),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => udp::ipv6_checksum(packet, &src, &dst),
+            _ => 0,
+        }
+    }
 }
 
 impl Display for Udp {
@@ -128,11 +158,7 @@ impl Layer for Udp {
         packet.set_length(n as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.src_ip_addr(),
-            &self.dst_ip_addr(),
-        );
+        let checksum = self.checksum(&packet.to_immutable());
         packet.set_checksum(checksum);
 
         Ok(self.len())
@@ -162,11 +188,7 @@ impl Layer for Udp {
         packet.set_length(n as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.src_ip_addr(),
-            &self.dst_ip_addr(),
-        );
+        let checksum = self.checksum(&packet.to_immutable());
         packet.set_checksum(checksum);
 
         Ok(self.len() + n)
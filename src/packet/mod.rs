@@ -1,10 +1,13 @@
 //! Support for serializing and deserializing packets.
 
+use bytes::Bytes;
 use pnet::packet::arp::ArpPacket;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
 use pnet::packet::Packet;
@@ -18,7 +21,9 @@ pub mod layer;
 use layer::arp::Arp;
 use layer::ethernet::Ethernet;
 use layer::icmpv4::Icmpv4;
+use layer::icmpv6::Icmpv6;
 use layer::ipv4::Ipv4;
+use layer::ipv6::Ipv6;
 use layer::tcp::Tcp;
 use layer::udp::Udp;
 use layer::{Layer, LayerKind, Layers};
@@ -89,6 +94,44 @@ impl Indicator {
                 }
                 None => None,
             },
+            EtherTypes::Ipv6 => match Ipv6Packet::new(packet.payload()) {
+                Some(ref ipv6_packet) => {
+                    let ipv6 = Ipv6::parse(ipv6_packet);
+                    // Fragment
+                    if !ipv6.is_fragment() {
+                        transport = match ipv6_packet.get_next_header() {
+                            IpNextHeaderProtocols::Icmpv6 => {
+                                match Icmpv6Packet::new(ipv6_packet.payload()) {
+                                    Some(ref icmpv6_packet) => {
+                                        Some(Layers::Icmpv6(Icmpv6::parse(icmpv6_packet, &ipv6)))
+                                    }
+                                    None => None,
+                                }
+                            }
+                            IpNextHeaderProtocols::Tcp => {
+                                match TcpPacket::new(ipv6_packet.payload()) {
+                                    Some(ref tcp_packet) => {
+                                        Some(Layers::Tcp(Tcp::parse_ipv6(tcp_packet, &ipv6)))
+                                    }
+                                    None => None,
+                                }
+                            }
+                            IpNextHeaderProtocols::Udp => {
+                                match UdpPacket::new(ipv6_packet.payload()) {
+                                    Some(ref udp_packet) => {
+                                        Some(Layers::Udp(Udp::parse_ipv6(udp_packet, &ipv6)))
+                                    }
+                                    None => None,
+                                }
+                            }
+                            _ => None,
+                        };
+                    }
+
+                    Some(Layers::Ipv6(ipv6))
+                }
+                None => None,
+            },
             _ => None,
         };
 
@@ -143,6 +186,37 @@ impl Indicator {
                     },
                     None => format!("{}", ipv4),
                 },
+                Layers::Ipv6(ipv6) => match self.transport() {
+                    Some(transport) => match transport {
+                        Layers::Icmpv6(icmpv6) => format!(
+                            "{}: {} -> {}, {}",
+                            icmpv6.kind(),
+                            ipv6.src(),
+                            ipv6.dst(),
+                            icmpv6.description()
+                        ),
+                        Layers::Tcp(tcp) => format!(
+                            "{}: [{}]:{} -> [{}]:{} {}",
+                            tcp.kind(),
+                            tcp.src_ip_addr(),
+                            tcp.src(),
+                            tcp.dst_ip_addr(),
+                            tcp.dst(),
+                            tcp.flag_string(),
+                        ),
+                        Layers::Udp(udp) => format!(
+                            "{}: [{}]:{} -> [{}]:{}, Length = {}",
+                            udp.kind(),
+                            udp.src_ip_addr(),
+                            udp.src(),
+                            udp.dst_ip_addr(),
+                            udp.dst(),
+                            udp.length(),
+                        ),
+                        _ => unreachable!(),
+                    },
+                    None => format!("{}", ipv6),
+                },
                 _ => unreachable!(),
             },
             None => match self.link() {
@@ -177,6 +251,9 @@ impl Indicator {
                 Some(network) => match network {
                     Layers::Arp(arp) => ethernet.len() + arp.len(),
                     Layers::Ipv4(ipv4) => ethernet.len() + ipv4.total_length() as usize,
+                    Layers::Ipv6(ipv6) => {
+                        ethernet.len() + Ipv6::minimum_len() + ipv6.payload_length() as usize
+                    }
                     _ => unreachable!(),
                 },
                 None => ethernet.len(),
@@ -294,6 +371,17 @@ impl Indicator {
         None
     }
 
+    /// Returns the IPv6 layer.
+    pub fn ipv6(&self) -> Option<&Ipv6> {
+        if let Some(layer) = self.network() {
+            if let Layers::Ipv6(layer) = layer {
+                return Some(layer);
+            }
+        }
+
+        None
+    }
+
     /// Returns the transport layer.
     pub fn transport(&self) -> Option<&Layers> {
         if let Some(layer) = &self.transport {
@@ -323,6 +411,17 @@ impl Indicator {
         None
     }
 
+    /// Returns the ICMPv6 layer.
+    pub fn icmpv6(&self) -> Option<&Icmpv6> {
+        if let Some(layer) = self.transport() {
+            if let Layers::Icmpv6(layer) = layer {
+                return Some(layer);
+            }
+        }
+
+        None
+    }
+
     /// Returns the TCP layer.
     pub fn tcp(&self) -> Option<&Tcp> {
         if let Some(layer) = self.transport() {
@@ -445,6 +544,19 @@ impl Fragmentation {
         (transport, &self.buffer[header_size..self.length])
     }
 
+    /// Concatenates fragmentations and returns the transport layer and the payload. The payload
+    /// takes over the buffer of the fragmentations without copying.
+    pub fn into_concatenated(self) -> (Option<Layers>, Bytes) {
+        let (transport, payload) = self.concatenate();
+        let header_size = self.length - payload.len();
+        let length = self.length;
+
+        (
+            transport,
+            Bytes::from(self.buffer).slice(header_size..length),
+        )
+    }
+
     /// Returns if the fragmentation is completed.
     pub fn is_completed(&self) -> bool {
         match self.total_length {
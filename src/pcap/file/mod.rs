@@ -0,0 +1,608 @@
+//! Support for reading and writing capture files.
+
+use pnet::datalink::{self, DataLinkReceiver, DataLinkSender};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Represents the magic number of pcap files with timestamps in microseconds.
+const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+/// Represents the magic number of pcap files with timestamps in nanoseconds.
+const PCAP_MAGIC_NANO: u32 = 0xA1B23C4D;
+/// Represents the byte-order magic in pcapng section header blocks.
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+/// Represents the type of pcapng section header blocks.
+const PCAPNG_SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+/// Represents the type of pcapng interface description blocks.
+const PCAPNG_INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+/// Represents the type of pcapng simple packet blocks.
+const PCAPNG_SIMPLE_PACKET_BLOCK: u32 = 0x00000003;
+/// Represents the type of pcapng enhanced packet blocks.
+const PCAPNG_ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+/// Represents the option code of the timestamp resolution in pcapng interface description blocks.
+const PCAPNG_IF_TSRESOL: u16 = 9;
+/// Represents the option code of the flags in pcapng enhanced packet blocks.
+const PCAPNG_EPB_FLAGS: u16 = 2;
+
+/// Represents the link type of Ethernet.
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Represents the snapshot length of written pcap files, and the maximum length of frames read from
+/// capture files without a snapshot length.
+const SNAPLEN: u32 = u16::MAX as u32;
+/// Represents the maximum length of pcapng blocks which are read.
+const MAX_BLOCK_LEN: usize = 128 * 1024;
+/// Represents the buffer size of the `Dumper`.
+const DUMP_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Represents a receive half which reads frames from a pcap or pcapng file. Only Ethernet frames
+/// are supported.
+#[derive(Debug)]
+pub struct FileReceiver {
+    reader: BufReader<File>,
+    is_pcapng: bool,
+    is_big_endian: bool,
+    /// Represents the timestamp ticks per second of each interface.
+    ticks: Vec<u64>,
+    /// Represents the snapshot length of each interface.
+    snaplens: Vec<usize>,
+    block: Vec<u8>,
+    buffer: Vec<u8>,
+    is_paced: bool,
+    origin: Option<(Duration, Instant)>,
+}
+
+impl FileReceiver {
+    /// Opens a pcap or pcapng file. If the receiver is paced, frames are returned in the
+    /// intervals recorded in their timestamps, or they are returned as fast as possible.
+    pub fn open<P: AsRef<Path>>(path: P, is_paced: bool) -> io::Result<FileReceiver> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let mut receiver = FileReceiver {
+            reader,
+            is_pcapng: false,
+            is_big_endian: false,
+            ticks: Vec::new(),
+            snaplens: Vec::new(),
+            block: Vec::new(),
+            buffer: Vec::new(),
+            is_paced,
+            origin: None,
+        };
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER_BLOCK {
+            // pcapng, the section header block is handled as other blocks
+            receiver.is_pcapng = true;
+            receiver.read_section_header()?;
+        } else {
+            // pcap
+            let (is_big_endian, ticks) =
+                match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                    (PCAP_MAGIC, _) => (false, 1_000_000),
+                    (PCAP_MAGIC_NANO, _) => (false, 1_000_000_000),
+                    (_, PCAP_MAGIC) => (true, 1_000_000),
+                    (_, PCAP_MAGIC_NANO) => (true, 1_000_000_000),
+                    _ => return Err(invalid_data("unknown capture file format")),
+                };
+
+            let mut header = [0u8; 20];
+            receiver.reader.read_exact(&mut header)?;
+            if read_u32(&header[16..], is_big_endian) != LINKTYPE_ETHERNET {
+                return Err(invalid_data("unsupported link type"));
+            }
+
+            receiver.is_big_endian = is_big_endian;
+            receiver.ticks.push(ticks);
+            receiver
+                .snaplens
+                .push(snaplen(read_u32(&header[12..16], is_big_endian)));
+        }
+
+        Ok(receiver)
+    }
+
+    /// Reads the next frame into the buffer, and returns its timestamp if any.
+    fn read_next(&mut self) -> io::Result<Option<Duration>> {
+        match self.is_pcapng {
+            true => self.read_next_pcapng(),
+            false => self.read_next_pcap(),
+        }
+    }
+
+    fn read_next_pcap(&mut self) -> io::Result<Option<Duration>> {
+        let mut header = [0u8; 16];
+        self.reader.read_exact(&mut header)?;
+
+        let sec = read_u32(&header[..4], self.is_big_endian) as u64;
+        let frac = read_u32(&header[4..8], self.is_big_endian) as u64;
+        let size = read_u32(&header[8..12], self.is_big_endian) as usize;
+        if size > self.snaplens[0] {
+            return Err(invalid_data("invalid record length"));
+        }
+        self.buffer.resize(size, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+
+        Ok(Some(timestamp(sec * self.ticks[0] + frac, self.ticks[0])))
+    }
+
+    fn read_next_pcapng(&mut self) -> io::Result<Option<Duration>> {
+        loop {
+            let mut header = [0u8; 4];
+            self.reader.read_exact(&mut header)?;
+            let kind = read_u32(&header, self.is_big_endian);
+            if kind == PCAPNG_SECTION_HEADER_BLOCK {
+                self.read_section_header()?;
+                continue;
+            }
+
+            // Block, including the trailing length
+            self.reader.read_exact(&mut header)?;
+            let len = read_u32(&header, self.is_big_endian) as usize;
+            if len > MAX_BLOCK_LEN {
+                return Err(invalid_data("invalid block length"));
+            }
+            let body_len = len
+                .checked_sub(12)
+                .ok_or(invalid_data("invalid block length"))?;
+            self.block.resize(len - 8, 0);
+            self.reader.read_exact(&mut self.block)?;
+            let body = &self.block[..body_len];
+
+            match kind {
+                PCAPNG_INTERFACE_DESCRIPTION_BLOCK => {
+                    if body.len() < 8 {
+                        return Err(invalid_data("invalid interface description block"));
+                    }
+                    if read_u16(&body[..2], self.is_big_endian) as u32 != LINKTYPE_ETHERNET {
+                        return Err(invalid_data("unsupported link type"));
+                    }
+
+                    let ticks = read_tsresol(&body[8..], self.is_big_endian)?;
+                    self.ticks.push(ticks);
+                    self.snaplens
+                        .push(snaplen(read_u32(&body[4..8], self.is_big_endian)));
+                }
+                PCAPNG_ENHANCED_PACKET_BLOCK => {
+                    if body.len() < 20 {
+                        return Err(invalid_data("invalid enhanced packet block"));
+                    }
+                    let interface = read_u32(&body[..4], self.is_big_endian) as usize;
+                    let high = read_u32(&body[4..8], self.is_big_endian) as u64;
+                    let low = read_u32(&body[8..12], self.is_big_endian) as u64;
+                    let size = read_u32(&body[12..16], self.is_big_endian) as usize;
+                    if 20 + size > body.len() {
+                        return Err(invalid_data("invalid enhanced packet block"));
+                    }
+                    let ticks = *self
+                        .ticks
+                        .get(interface)
+                        .ok_or(invalid_data("unknown interface"))?;
+                    if size > self.snaplens[interface] {
+                        return Err(invalid_data("invalid record length"));
+                    }
+
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&body[20..20 + size]);
+
+                    return Ok(Some(timestamp((high << 32) | low, ticks)));
+                }
+                PCAPNG_SIMPLE_PACKET_BLOCK => {
+                    if body.len() < 4 {
+                        return Err(invalid_data("invalid simple packet block"));
+                    }
+                    let size = read_u32(&body[..4], self.is_big_endian) as usize;
+                    let size = size.min(body.len() - 4);
+
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&body[4..4 + size]);
+
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the rest of a pcapng section header block after its type. Interfaces of the previous
+    /// section are dropped.
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 8];
+        self.reader.read_exact(&mut header)?;
+        self.is_big_endian = match u32::from_le_bytes([header[4], header[5], header[6], header[7]])
+        {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(invalid_data("unknown byte order")),
+        };
+        let len = read_u32(&header[..4], self.is_big_endian) as usize;
+        if len > MAX_BLOCK_LEN {
+            return Err(invalid_data("invalid block length"));
+        }
+        let len = len
+            .checked_sub(12)
+            .ok_or(invalid_data("invalid block length"))?;
+        self.block.resize(len, 0);
+        self.reader.read_exact(&mut self.block)?;
+        self.ticks.clear();
+        self.snaplens.clear();
+
+        Ok(())
+    }
+
+    /// Waits until the frame with the given timestamp is due.
+    fn pace(&mut self, ts: Duration) {
+        match self.origin {
+            Some((origin_ts, origin)) => {
+                let due = origin + ts.checked_sub(origin_ts).unwrap_or_default();
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+            }
+            None => self.origin = Some((ts, Instant::now())),
+        }
+    }
+}
+
+impl DataLinkReceiver for FileReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let ts = self.read_next()?;
+        if self.is_paced {
+            if let Some(ts) = ts {
+                self.pace(ts);
+            }
+        }
+
+        Ok(&self.buffer)
+    }
+}
+
+/// Represents a send half which writes frames into a pcap file.
+#[derive(Debug)]
+pub struct FileSender {
+    writer: BufWriter<File>,
+}
+
+impl FileSender {
+    /// Creates a pcap file, the file will be truncated if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSender> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        // Header
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        writer.flush()?;
+
+        Ok(FileSender { writer })
+    }
+
+    /// Writes a frame with the current timestamp.
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let size = frame.len().min(SNAPLEN as usize);
+
+        self.writer
+            .write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&ts.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(size as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&frame[..size])?;
+        self.writer.flush()
+    }
+}
+
+impl DataLinkSender for FileSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+            if let Err(e) = self.write(&buffer) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        Some(self.write(packet))
+    }
+}
+
+/// Represents the direction of a frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// Represents a frame received from the source.
+    Inbound,
+    /// Represents a frame sent to the source.
+    Outbound,
+}
+
+/// Represents a writer which dumps frames with their directions into a pcapng file. The frames are
+/// buffered, and they are written into the file only when the buffer is full, the `Dumper` is
+/// flushed or dropped.
+#[derive(Debug)]
+pub struct Dumper {
+    writer: BufWriter<File>,
+}
+
+impl Dumper {
+    /// Creates a pcapng file, the file will be truncated if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Dumper> {
+        let mut writer = BufWriter::with_capacity(DUMP_BUFFER_SIZE, File::create(path)?);
+
+        // Section header block
+        writer.write_all(&PCAPNG_SECTION_HEADER_BLOCK.to_le_bytes())?;
+        writer.write_all(&28u32.to_le_bytes())?;
+        writer.write_all(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&(-1i64).to_le_bytes())?;
+        writer.write_all(&28u32.to_le_bytes())?;
+
+        // Interface description block, timestamps are in microseconds by default
+        writer.write_all(&PCAPNG_INTERFACE_DESCRIPTION_BLOCK.to_le_bytes())?;
+        writer.write_all(&20u32.to_le_bytes())?;
+        writer.write_all(&(LINKTYPE_ETHERNET as u16).to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&20u32.to_le_bytes())?;
+        writer.flush()?;
+
+        Ok(Dumper { writer })
+    }
+
+    /// Dumps a frame with the current timestamp and the given direction.
+    pub fn dump(&mut self, frame: &[u8], direction: Direction) -> io::Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let padding = (4 - frame.len() % 4) % 4;
+        // Header, data, the flags option, the end of options and the trailing length
+        let len = 28 + frame.len() + padding + 8 + 4 + 4;
+        let flags: u32 = match direction {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+
+        // Enhanced packet block
+        self.writer
+            .write_all(&PCAPNG_ENHANCED_PACKET_BLOCK.to_le_bytes())?;
+        self.writer.write_all(&(len as u32).to_le_bytes())?;
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        self.writer.write_all(&((ts >> 32) as u32).to_le_bytes())?;
+        self.writer.write_all(&(ts as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(frame)?;
+        self.writer.write_all(&[0u8; 3][..padding])?;
+        self.writer.write_all(&PCAPNG_EPB_FLAGS.to_le_bytes())?;
+        self.writer.write_all(&4u16.to_le_bytes())?;
+        self.writer.write_all(&flags.to_le_bytes())?;
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        self.writer.write_all(&(len as u32).to_le_bytes())
+    }
+
+    /// Writes the buffered frames into the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn invalid_data(error: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn read_u16(bytes: &[u8], is_big_endian: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    match is_big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes),
+    }
+}
+
+fn read_u32(bytes: &[u8], is_big_endian: bool) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    match is_big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    }
+}
+
+/// Returns the timestamp ticks per second in the options of a pcapng interface description block.
+/// The default resolution is in microseconds.
+fn read_tsresol(mut options: &[u8], is_big_endian: bool) -> io::Result<u64> {
+    while options.len() >= 4 {
+        let code = read_u16(&options[..2], is_big_endian);
+        let len = read_u16(&options[2..4], is_big_endian) as usize;
+        let padded_len = (len + 3) / 4 * 4;
+        if 4 + padded_len > options.len() {
+            return Err(invalid_data("invalid option"));
+        }
+
+        if code == PCAPNG_IF_TSRESOL && len >= 1 {
+            let tsresol = options[4];
+            return match tsresol & 0x80 {
+                0 => 10u64
+                    .checked_pow(tsresol as u32)
+                    .ok_or(invalid_data("invalid timestamp resolution")),
+                _ => 1u64
+                    .checked_shl((tsresol & 0x7F) as u32)
+                    .filter(|ticks| *ticks > 0)
+                    .ok_or(invalid_data("invalid timestamp resolution")),
+            };
+        }
+        if code == 0 {
+            break;
+        }
+
+        options = &options[4 + padded_len..];
+    }
+
+    Ok(1_000_000)
+}
+
+/// Returns the maximum length of frames of the given snapshot length, a snapshot length of 0
+/// stands for no limit and is capped at `SNAPLEN`.
+fn snaplen(snaplen: u32) -> usize {
+    match snaplen {
+        0 => SNAPLEN as usize,
+        _ => snaplen as usize,
+    }
+}
+
+/// Returns the timestamp of the given ticks.
+fn timestamp(ts: u64, ticks: u64) -> Duration {
+    let nanos = (ts % ticks) as u128 * 1_000_000_000 / ticks as u128;
+
+    Duration::new(ts / ticks, nanos as u32)
+}
+
+#[test]
+fn file_write_and_read() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-{}.pcap", std::process::id()));
+
+    let mut tx = FileSender::create(&path).unwrap();
+    tx.send_to(&[0, 1, 2, 3], None).unwrap().unwrap();
+    tx.build_and_send(2, 3, &mut |buffer: &mut [u8]| {
+        buffer.copy_from_slice(&[4, 5, 6])
+    })
+    .unwrap()
+    .unwrap();
+    drop(tx);
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.next().unwrap(), &[0, 1, 2, 3]);
+    assert_eq!(rx.next().unwrap(), &[4, 5, 6]);
+    assert_eq!(rx.next().unwrap(), &[4, 5, 6]);
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn file_read_pcapng() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-{}.pcapng", std::process::id()));
+
+    let mut file = Vec::new();
+    // Section header block
+    file.extend_from_slice(&PCAPNG_SECTION_HEADER_BLOCK.to_le_bytes());
+    file.extend_from_slice(&28u32.to_le_bytes());
+    file.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+    file.extend_from_slice(&[1, 0, 0, 0]);
+    file.extend_from_slice(&u64::MAX.to_le_bytes());
+    file.extend_from_slice(&28u32.to_le_bytes());
+    // Interface description block in nanoseconds
+    file.extend_from_slice(&PCAPNG_INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+    file.extend_from_slice(&32u32.to_le_bytes());
+    file.extend_from_slice(&[1, 0, 0, 0]);
+    file.extend_from_slice(&SNAPLEN.to_le_bytes());
+    file.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0]);
+    file.extend_from_slice(&32u32.to_le_bytes());
+    // Enhanced packet block
+    file.extend_from_slice(&PCAPNG_ENHANCED_PACKET_BLOCK.to_le_bytes());
+    file.extend_from_slice(&40u32.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&1_500_000_000u32.to_le_bytes());
+    file.extend_from_slice(&5u32.to_le_bytes());
+    file.extend_from_slice(&5u32.to_le_bytes());
+    file.extend_from_slice(&[0, 1, 2, 3, 4, 0, 0, 0]);
+    file.extend_from_slice(&40u32.to_le_bytes());
+    std::fs::write(&path, &file).unwrap();
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.read_next().unwrap(), Some(Duration::from_millis(1500)));
+    assert_eq!(rx.buffer, vec![0, 1, 2, 3, 4]);
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn file_dump_and_read() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-{}-dump.pcapng", std::process::id()));
+
+    let mut dumper = Dumper::create(&path).unwrap();
+    dumper.dump(&[0, 1, 2], Direction::Inbound).unwrap();
+    dumper.dump(&[3, 4, 5, 6], Direction::Outbound).unwrap();
+    drop(dumper);
+
+    // Flags of the enhanced packet blocks, following the section header block, the interface
+    // description block, the header of the block, the padded data and the option header
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(read_u32(&file[84..88], false), 1);
+    assert_eq!(read_u32(&file[132..136], false), 2);
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.next().unwrap(), &[0, 1, 2]);
+    assert_eq!(rx.next().unwrap(), &[3, 4, 5, 6]);
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn file_read_malformed() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-{}-malformed", std::process::id()));
+
+    // pcap record longer than the snapshot length
+    let mut file = Vec::new();
+    file.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    file.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    file.extend_from_slice(&4u32.to_le_bytes());
+    file.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    file.extend_from_slice(&[0u8; 8]);
+    file.extend_from_slice(&5u32.to_le_bytes());
+    file.extend_from_slice(&5u32.to_le_bytes());
+    file.extend_from_slice(&[0, 1, 2, 3, 4]);
+    std::fs::write(&path, &file).unwrap();
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // pcap record longer than the maximum snapshot length
+    file[16..20].copy_from_slice(&0u32.to_le_bytes());
+    file[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, &file).unwrap();
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // pcapng block longer than the maximum block length
+    let mut file = Vec::new();
+    file.extend_from_slice(&PCAPNG_SECTION_HEADER_BLOCK.to_le_bytes());
+    file.extend_from_slice(&28u32.to_le_bytes());
+    file.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+    file.extend_from_slice(&[1, 0, 0, 0]);
+    file.extend_from_slice(&u64::MAX.to_le_bytes());
+    file.extend_from_slice(&28u32.to_le_bytes());
+    file.extend_from_slice(&PCAPNG_ENHANCED_PACKET_BLOCK.to_le_bytes());
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, &file).unwrap();
+
+    let mut rx = FileReceiver::open(&path, false).unwrap();
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    std::fs::remove_file(&path).unwrap();
+}
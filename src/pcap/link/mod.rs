@@ -0,0 +1,305 @@
+//! Support for link backends other than pcap devices.
+
+use pnet::datalink::{self, DataLinkReceiver, DataLinkSender};
+use std::io;
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{Interface, Receiver, Sender};
+
+#[cfg(target_os = "linux")]
+use std::fs::{File, OpenOptions};
+#[cfg(target_os = "linux")]
+use std::io::{Read, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+mod support;
+pub use support::FrameBuilder;
+
+/// Represents the path of the clone device of TUN/TAP interfaces.
+#[cfg(target_os = "linux")]
+const TUN_CLONE_DEVICE: &str = "/dev/net/tun";
+/// Represents the ioctl request which attaches a file to a TUN/TAP interface.
+#[cfg(target_os = "linux")]
+const TUNSETIFF: libc::c_ulong = 0x400454CA;
+/// Represents the flag of TAP interfaces.
+#[cfg(target_os = "linux")]
+const IFF_TAP: libc::c_short = 0x0002;
+/// Represents the flag of TUN/TAP interfaces which do not prepend packet information.
+#[cfg(target_os = "linux")]
+const IFF_NO_PI: libc::c_short = 0x1000;
+/// Represents the size of the receive buffer of TAP interfaces.
+#[cfg(target_os = "linux")]
+const TAP_BUFFER_SIZE: usize = 65536;
+
+/// Represents a link backend which can be opened for sending and receiving Ethernet frames.
+pub trait LinkBackend {
+    /// Opens the link for sending and receiving data.
+    fn open(&self) -> io::Result<(Sender, Receiver)>;
+}
+
+impl LinkBackend for Interface {
+    fn open(&self) -> io::Result<(Sender, Receiver)> {
+        Interface::open(self)
+    }
+}
+
+/// Represents an in-memory link. Frames injected by the `MemoryPeer` are received from the link,
+/// and frames sent to the link are received by the `MemoryPeer`. The link can only be opened
+/// once.
+#[derive(Debug)]
+pub struct MemoryLink {
+    halves: Mutex<Option<(MemorySender, MemoryReceiver)>>,
+}
+
+impl MemoryLink {
+    /// Creates a new `MemoryLink` and its `MemoryPeer`.
+    pub fn new() -> (MemoryLink, MemoryPeer) {
+        let (tx, peer_rx) = mpsc::unbounded_channel();
+        let (peer_tx, rx) = mpsc::unbounded_channel();
+
+        let link = MemoryLink {
+            halves: Mutex::new(Some((
+                MemorySender { tx },
+                MemoryReceiver {
+                    rx,
+                    buffer: Vec::new(),
+                },
+            ))),
+        };
+        let peer = MemoryPeer {
+            tx: peer_tx,
+            rx: peer_rx,
+        };
+
+        (link, peer)
+    }
+}
+
+impl LinkBackend for MemoryLink {
+    fn open(&self) -> io::Result<(Sender, Receiver)> {
+        let (tx, rx) = self.halves.lock().unwrap().take().ok_or(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "link already opened",
+        ))?;
+
+        Ok((Box::new(tx), Box::new(rx)))
+    }
+}
+
+/// Represents the other end of a `MemoryLink`. Dropping the peer closes the link, and the receive
+/// half of the link will return an `UnexpectedEof` `IoError`.
+#[derive(Debug)]
+pub struct MemoryPeer {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryPeer {
+    /// Injects a frame into the link.
+    pub fn inject(&self, frame: Vec<u8>) -> io::Result<()> {
+        self.tx
+            .send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "link closed"))
+    }
+
+    /// Receives the next frame sent to the link. Returns `None` if the send half of the link is
+    /// dropped.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await
+    }
+
+    /// Attempts to receive the next frame sent to the link without waiting.
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+#[derive(Debug)]
+struct MemorySender {
+    tx: UnboundedSender<Vec<u8>>,
+}
+
+impl MemorySender {
+    fn send(&self, frame: Vec<u8>) -> io::Result<()> {
+        self.tx
+            .send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "peer closed"))
+    }
+}
+
+impl DataLinkSender for MemorySender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0u8; packet_size];
+            func(&mut buffer);
+            if let Err(e) = self.send(buffer) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        Some(self.send(packet.to_vec()))
+    }
+}
+
+#[derive(Debug)]
+struct MemoryReceiver {
+    rx: UnboundedReceiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkReceiver for MemoryReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        self.buffer = self
+            .rx
+            .blocking_recv()
+            .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        Ok(&self.buffer)
+    }
+}
+
+/// Represents a TAP interface in Linux. The interface is created if it does not exist, and it is
+/// removed after it is closed unless it is persistent.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct TapLink {
+    name: String,
+}
+
+#[cfg(target_os = "linux")]
+impl TapLink {
+    /// Creates a new `TapLink` with the given interface name.
+    pub fn new(name: &str) -> TapLink {
+        TapLink {
+            name: String::from(name),
+        }
+    }
+
+    /// Returns the name of the interface.
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl LinkBackend for TapLink {
+    fn open(&self) -> io::Result<(Sender, Receiver)> {
+        // struct ifreq, the name is followed by the flags
+        let mut ifreq = [0u8; 40];
+        if self.name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        ifreq[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        ifreq[libc::IFNAMSIZ..libc::IFNAMSIZ + 2]
+            .copy_from_slice(&(IFF_TAP | IFF_NO_PI).to_ne_bytes());
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_CLONE_DEVICE)?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, ifreq.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let tx = TapSender {
+            file: file.try_clone()?,
+        };
+        let rx = TapReceiver {
+            file,
+            buffer: vec![0u8; TAP_BUFFER_SIZE],
+        };
+
+        Ok((Box::new(tx), Box::new(rx)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct TapSender {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+impl DataLinkSender for TapSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+            if let Err(e) = self.file.write_all(&buffer) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        Some(self.file.write_all(packet))
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct TapReceiver {
+    file: File,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl DataLinkReceiver for TapReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        // Each read returns exactly one frame
+        let size = self.file.read(&mut self.buffer)?;
+
+        Ok(&self.buffer[..size])
+    }
+}
+
+#[test]
+fn memory_link_inject_and_send() {
+    let (link, mut peer) = MemoryLink::new();
+    let (mut tx, mut rx) = link.open().unwrap();
+    assert!(link.open().is_err());
+
+    peer.inject(vec![1, 2, 3]).unwrap();
+    assert_eq!(rx.next().unwrap(), &[1, 2, 3]);
+
+    tx.send_to(&[4, 5], None).unwrap().unwrap();
+    tx.build_and_send(2, 3, &mut |buffer: &mut [u8]| buffer.fill(6))
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer.try_recv(), Some(vec![4, 5]));
+    assert_eq!(peer.try_recv(), Some(vec![6, 6, 6]));
+    assert_eq!(peer.try_recv(), Some(vec![6, 6, 6]));
+    assert_eq!(peer.try_recv(), None);
+
+    drop(peer);
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
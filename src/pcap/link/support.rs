@@ -0,0 +1,151 @@
+//! Support for driving a `MemoryLink` as the source in tests and benchmarks.
+
+use pnet::packet::tcp as pnet_tcp;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use super::super::HardwareAddr;
+use super::MemoryPeer;
+use crate::packet::layer::ethernet::Ethernet;
+use crate::packet::layer::ipv4::Ipv4;
+use crate::packet::layer::ipv6::Ipv6;
+use crate::packet::layer::tcp::Tcp;
+use crate::packet::layer::udp::Udp;
+use crate::packet::layer::{Layer, LayerKind, LayerKinds, Layers};
+use crate::packet::Indicator;
+
+/// Represents a builder of the frames sent from a source to a destination, which can be injected
+/// into a `MemoryLink` by its `MemoryPeer` or written into a capture file for replaying.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameBuilder {
+    src_hardware_addr: HardwareAddr,
+    dst_hardware_addr: HardwareAddr,
+    src: SocketAddr,
+    dst: SocketAddr,
+}
+
+impl FrameBuilder {
+    /// Creates a new `FrameBuilder`. The source and the destination must be in the same IP
+    /// version.
+    pub fn new(
+        src_hardware_addr: HardwareAddr,
+        dst_hardware_addr: HardwareAddr,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> io::Result<FrameBuilder> {
+        if src.is_ipv4() != dst.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination in different IP versions",
+            ));
+        }
+
+        Ok(FrameBuilder {
+            src_hardware_addr,
+            dst_hardware_addr,
+            src,
+            dst,
+        })
+    }
+
+    /// Returns a frame of a TCP segment with the given sequence, acknowledgement, flags and
+    /// payload. The window is always the max one without the window scale.
+    pub fn tcp(&self, sequence: u32, acknowledgement: u32, flags: u16, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = Tcp::from(pnet_tcp::Tcp {
+            source: self.src.port(),
+            destination: self.dst.port(),
+            sequence,
+            acknowledgement,
+            data_offset: 5,
+            reserved: 0,
+            flags,
+            window: u16::MAX,
+            checksum: 0,
+            urgent_ptr: 0,
+            options: vec![],
+            payload: vec![],
+        });
+        let network = self.network(LayerKinds::Tcp);
+        match network {
+            Layers::Ipv4(ref ipv4) => tcp.set_ipv4_layer(ipv4),
+            Layers::Ipv6(ref ipv6) => tcp.set_ipv6_layer(ipv6),
+            _ => unreachable!(),
+        }
+
+        self.serialize(network, Layers::Tcp(tcp), payload)
+    }
+
+    /// Returns a frame of an UDP datagram with the given payload.
+    pub fn udp(&self, payload: &[u8]) -> Vec<u8> {
+        let mut udp = Udp::new(self.src.port(), self.dst.port());
+        let network = self.network(LayerKinds::Udp);
+        match network {
+            Layers::Ipv4(ref ipv4) => udp.set_ipv4_layer(ipv4),
+            Layers::Ipv6(ref ipv6) => udp.set_ipv6_layer(ipv6),
+            _ => unreachable!(),
+        }
+
+        self.serialize(network, Layers::Udp(udp), payload)
+    }
+
+    fn network(&self, t: LayerKind) -> Layers {
+        match (self.src.ip(), self.dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => Layers::Ipv4(Ipv4::new(0, t, src, dst).unwrap()),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => Layers::Ipv6(Ipv6::new(t, src, dst).unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn serialize(&self, network: Layers, transport: Layers, payload: &[u8]) -> Vec<u8> {
+        let ethernet = Ethernet::new(
+            network.kind(),
+            self.src_hardware_addr,
+            self.dst_hardware_addr,
+        )
+        .unwrap();
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), Some(transport));
+
+        let mut buffer = vec![0u8; indicator.len() + payload.len()];
+        indicator
+            .serialize_with_payload(&mut buffer, payload)
+            .unwrap();
+
+        buffer
+    }
+}
+
+impl MemoryPeer {
+    /// Receives the next TCP segment sent to the link and its payload, frames of other kinds are
+    /// skipped. Returns `None` if the send half of the link is dropped.
+    pub async fn recv_tcp(&mut self) -> Option<(Tcp, Vec<u8>)> {
+        loop {
+            let frame = self.recv().await?;
+            let indicator = match Indicator::from(frame.as_slice()) {
+                Some(indicator) => indicator,
+                None => continue,
+            };
+            if let Some(tcp) = indicator.tcp() {
+                let payload = frame[indicator.len()..indicator.content_len()].to_vec();
+
+                return Some((tcp.clone(), payload));
+            }
+        }
+    }
+
+    /// Receives the next UDP datagram sent to the link and its payload, frames of other kinds
+    /// are skipped. Returns `None` if the send half of the link is dropped.
+    pub async fn recv_udp(&mut self) -> Option<(Udp, Vec<u8>)> {
+        loop {
+            let frame = self.recv().await?;
+            let indicator = match Indicator::from(frame.as_slice()) {
+                Some(indicator) => indicator,
+                None => continue,
+            };
+            if let Some(udp) = indicator.udp() {
+                let payload = frame[indicator.len()..indicator.content_len()].to_vec();
+
+                return Some((udp.clone(), payload));
+            }
+        }
+    }
+}
@@ -1,10 +1,15 @@
 //! Support for handling pcap interfaces.
 
+use bytes::{Bytes, BytesMut};
 use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
 use std::clone::Clone;
+use std::cmp::max;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
 #[cfg(windows)]
 use netifs;
@@ -12,6 +17,13 @@ use netifs;
 #[cfg(not(windows))]
 use interfaces as c_interfaces;
 
+mod file;
+pub use file::{Direction, Dumper, FileReceiver, FileSender};
+mod link;
+#[cfg(target_os = "linux")]
+pub use link::TapLink;
+pub use link::{FrameBuilder, LinkBackend, MemoryLink, MemoryPeer};
+
 /// Represents the hardware address MAC in an Ethernet network.
 pub type HardwareAddr = pnet::datalink::MacAddr;
 
@@ -28,6 +40,11 @@ pub type Receiver = Box<dyn DataLinkReceiver>;
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
 
+/// Represents the capacity of the channel between the capture thread and the `AsyncReceiver`.
+const CAPTURE_CHANNEL_CAPACITY: usize = 1024;
+/// Represents the size of the buffer pool of the capture thread.
+const CAPTURE_POOL_SIZE: usize = 1024 * 1024;
+
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Interface {
@@ -35,6 +52,7 @@ pub struct Interface {
     alias: Option<String>,
     hardware_addr: MacAddr,
     ip_addrs: Vec<Ipv4Addr>,
+    ipv6_addrs: Vec<Ipv6Addr>,
     mtu: usize,
     is_up: bool,
     is_loopback: bool,
@@ -48,12 +66,28 @@ impl Interface {
             alias: None,
             hardware_addr: MacAddr::zero(),
             ip_addrs: vec![],
+            ipv6_addrs: vec![],
             mtu: 0,
             is_up: false,
             is_loopback: false,
         }
     }
 
+    /// Constructs a new virtual `Interface` which is not backed by any device. The virtual
+    /// interface is used in replaying capture files, and cannot be opened.
+    pub fn new_virtual(ip_addr: Ipv4Addr, mtu: usize) -> Interface {
+        Interface {
+            name: String::from("replay"),
+            alias: None,
+            hardware_addr: HARDWARE_ADDR_UNSPECIFIED,
+            ip_addrs: vec![ip_addr],
+            ipv6_addrs: vec![],
+            mtu,
+            is_up: true,
+            is_loopback: false,
+        }
+    }
+
     /// Opens the network interface for sending and receiving data.
     pub fn open(&self) -> io::Result<(Sender, Receiver)> {
         let inters = datalink::interfaces();
@@ -102,6 +136,16 @@ impl Interface {
         }
     }
 
+    /// Returns the first IPv6 address of the interface. Global addresses are preferred over
+    /// link-local addresses.
+    pub fn ipv6_addr(&self) -> Option<Ipv6Addr> {
+        self.ipv6_addrs
+            .iter()
+            .find(|ip_addr| ip_addr.segments()[0] & 0xffc0 != 0xfe80)
+            .or(self.ipv6_addrs.first())
+            .cloned()
+    }
+
     /// Returns the MTU of the interface.
     pub fn mtu(&self) -> usize {
         self.mtu
@@ -130,6 +174,11 @@ impl Display for Interface {
             self.ip_addrs
                 .iter()
                 .map(|ip_addr| { ip_addr.to_string() })
+                .chain(
+                    self.ipv6_addrs
+                        .iter()
+                        .map(|ip_addr| { ip_addr.to_string() })
+                )
                 .collect::<Vec<_>>()
                 .join(", ")
         );
@@ -175,6 +224,15 @@ pub fn interfaces() -> Vec<Interface> {
                 })
                 .filter_map(Result::ok)
                 .collect();
+            i.ipv6_addrs = inter
+                .ips
+                .iter()
+                .map(|ip| match ip {
+                    ipnetwork::IpNetwork::V6(ref ipv6) => Ok(ipv6.ip()),
+                    _ => Err(()),
+                })
+                .filter_map(Result::ok)
+                .collect();
 
             // Exclude interface without any IPv4 address
             if i.ip_addrs.len() <= 0 {
@@ -229,6 +287,69 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
     ifs
 }
 
+/// Represents the receive half of a pcap device which receives frames asynchronously. The frames
+/// are captured in a dedicated thread and fed into a bounded channel, so the blocking capture will
+/// not occupy the threads of the async runtime. The frames are copied into a buffer pool and handed
+/// out as shared buffers, so slicing them later does not allocate or copy.
+#[derive(Debug)]
+pub struct AsyncReceiver {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+}
+
+impl AsyncReceiver {
+    /// Creates a new `AsyncReceiver` by moving the `Receiver` into a dedicated capture thread. The
+    /// capture thread will sleep for the given wait time after a `TimedOut` `IoError`.
+    pub fn new(mut rx: Receiver, timedout_wait: Duration) -> io::Result<AsyncReceiver> {
+        let (tx, frame_rx) = mpsc::channel(CAPTURE_CHANNEL_CAPACITY);
+
+        thread::Builder::new()
+            .name(String::from("capture"))
+            .spawn(move || {
+                let mut pool = BytesMut::with_capacity(CAPTURE_POOL_SIZE);
+                loop {
+                    let result = match rx.next() {
+                        Ok(frame) => {
+                            // Reclaim the pool if all the frames in it are dropped, or allocate a
+                            // new one
+                            if pool.capacity() < frame.len() {
+                                pool.reserve(max(frame.len(), CAPTURE_POOL_SIZE));
+                            }
+                            pool.extend_from_slice(frame);
+
+                            Ok(pool.split().freeze())
+                        }
+                        Err(e) => {
+                            if e.kind() == io::ErrorKind::TimedOut {
+                                thread::sleep(timedout_wait);
+                                continue;
+                            }
+                            Err(e)
+                        }
+                    };
+                    let is_err = result.is_err();
+
+                    // Send, stop capturing if the receiver is dropped or the capture failed
+                    if tx.blocking_send(result).is_err() || is_err {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(AsyncReceiver { rx: frame_rx })
+    }
+
+    /// Receives the next frame.
+    pub async fn next(&mut self) -> io::Result<Bytes> {
+        match self.rx.recv().await {
+            Some(result) => result,
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "capture thread stopped",
+            )),
+        }
+    }
+}
+
 /// Represents a virtual send half which will discard all incoming traffic.
 #[derive(Debug)]
 pub struct BlackHole {}
@@ -0,0 +1,251 @@
+//! Support for handling HTTP proxies.
+
+use std::net::SocketAddr;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Represents the username and the password of the Basic authentication connecting to an HTTP
+/// proxy.
+#[derive(Clone, Debug)]
+pub struct HttpAuth {
+    username: String,
+    password: String,
+}
+
+impl HttpAuth {
+    /// Creates a `HttpAuth`.
+    pub fn new(username: String, password: String) -> HttpAuth {
+        HttpAuth { username, password }
+    }
+
+    fn credentials(&self) -> String {
+        base64_encode(format!("{}:{}", self.username, self.password).as_bytes())
+    }
+}
+
+/// Represents the options connecting to an HTTP proxy.
+#[derive(Clone, Debug)]
+pub struct HttpOption {
+    auth: Option<HttpAuth>,
+}
+
+impl HttpOption {
+    /// Creates a `HttpOption`.
+    pub fn new(auth: Option<HttpAuth>) -> HttpOption {
+        HttpOption { auth }
+    }
+}
+
+/// Represents the maximum size of the response header of the HTTP proxy.
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// Connects to a target server through an HTTP proxy using the CONNECT method.
+pub async fn connect(
+    remote: SocketAddr,
+    dst: SocketAddr,
+    options: &HttpOption,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(remote).await?;
+
+    // Request
+    let request = new_request(
+        format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", dst, dst),
+        options,
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Response
+    let status = read_status_code(&mut stream).await?;
+    if status / 100 != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP CONNECT rejected ({})", status),
+        ));
+    }
+
+    Ok(stream)
+}
+
+/// Sends an OPTIONS request to an HTTP proxy without connecting to any target server. This method
+/// is used for checking if the proxy is available, any response except an authentication failure
+/// is accepted.
+pub async fn handshake(remote: SocketAddr, options: &HttpOption) -> io::Result<()> {
+    let mut stream = TcpStream::connect(remote).await?;
+
+    // Request
+    let request = new_request(
+        format!("OPTIONS * HTTP/1.1\r\nHost: {}\r\n", remote),
+        options,
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Response
+    let status = read_status_code(&mut stream).await?;
+    if status == 407 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "HTTP proxy authentication required",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Completes the request header with the authentication.
+fn new_request(mut request: String, options: &HttpOption) -> String {
+    if let Some(ref auth) = options.auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            auth.credentials()
+        ));
+    }
+    request.push_str("\r\n");
+
+    request
+}
+
+/// Reads the response header and returns its status code. The header is read byte by byte from
+/// the unbuffered stream so the tunneled data which may arrive together with the response header
+/// stays in the stream.
+async fn read_status_code(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP response too long",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    parse_status_code(&response).ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected HTTP response",
+    ))
+}
+
+fn parse_status_code(response: &[u8]) -> Option<u16> {
+    let line = response.split(|&b| b == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+
+    parts.next()?.parse().ok()
+}
+
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let v = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        output.push(BASE64_TABLE[(v >> 18) as usize & 0x3f] as char);
+        output.push(BASE64_TABLE[(v >> 12) as usize & 0x3f] as char);
+        match chunk.len() {
+            1 => output.push_str("=="),
+            2 => {
+                output.push(BASE64_TABLE[(v >> 6) as usize & 0x3f] as char);
+                output.push('=');
+            }
+            _ => {
+                output.push(BASE64_TABLE[(v >> 6) as usize & 0x3f] as char);
+                output.push(BASE64_TABLE[v as usize & 0x3f] as char);
+            }
+        }
+    }
+
+    output
+}
+
+#[test]
+fn base64_encode_padding() {
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foo"), "Zm9v");
+    assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+}
+
+#[test]
+fn parse_status_code_line() {
+    assert_eq!(
+        parse_status_code(b"HTTP/1.1 200 Connection established\r\n\r\n"),
+        Some(200)
+    );
+    assert_eq!(
+        parse_status_code(b"HTTP/1.0 407 Proxy Authentication Required\r\n\r\n"),
+        Some(407)
+    );
+    assert_eq!(parse_status_code(b"SSH-2.0-OpenSSH\r\n\r\n"), None);
+}
+
+#[tokio::test]
+async fn connect_keeps_tunneled_data() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        assert!(request.starts_with(b"CONNECT 203.0.113.1:80 HTTP/1.1\r\n"));
+
+        // The response header and the tunneled data arrive in one segment
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+            .await
+            .unwrap();
+    });
+
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut stream = connect(remote, dst, &HttpOption::new(None)).await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+}
+
+#[tokio::test]
+async fn handshake_status() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        for response in [
+            &b"HTTP/1.1 405 Method Not Allowed\r\n\r\n"[..],
+            &b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"[..],
+            &b"SSH-2.0-OpenSSH\r\n\r\n"[..],
+        ] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            assert!(request.starts_with(b"OPTIONS * HTTP/1.1\r\n"));
+
+            stream.write_all(response).await.unwrap();
+        }
+    });
+
+    let options = HttpOption::new(None);
+    assert!(handshake(remote, &options).await.is_ok());
+    assert_eq!(
+        handshake(remote, &options).await.unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        handshake(remote, &options).await.unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
@@ -1,29 +1,61 @@
 //! Support for handling proxies.
 
-use log::{debug, trace, warn};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use bytes::{Bytes, BytesMut};
+use log::{debug, info, trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
 use tokio::{self, io, time};
 
+use crate::config::Config;
+use crate::stat::{Backpressure, Latency};
+
+mod http;
+use http::{HttpAuth, HttpOption};
+
+mod shadowsocks;
+use shadowsocks::{ShadowsocksOption, ShadowsocksRecvHalf, ShadowsocksSendHalf};
+use shadowsocks::{ShadowsocksStreamRecvHalf, ShadowsocksStreamSendHalf};
+
 mod socks;
-use socks::SocksSendHalf;
-use socks::{SocksAuth, SocksOption};
+use socks::{Socks4Option, SocksAuth, SocksOption};
+use socks::{SocksRecvHalf, SocksSendHalf};
+
+/// Represents the buffer size of stand-in streams.
+const STUB_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Represents the configuration of the proxy.
 pub enum ProxyConfig {
     /// Represents the SOCKS proxy configuration.
-    Socks(SocketAddrV4, SocksOption),
+    Socks(SocketAddr, SocksOption),
+    /// Represents the SOCKS4 proxy configuration.
+    Socks4(SocketAddr, Socks4Option),
+    /// Represents the HTTP proxy configuration using the CONNECT method.
+    HttpConnect(SocketAddr, HttpOption),
+    /// Represents the Shadowsocks proxy configuration.
+    Shadowsocks(SocketAddr, ShadowsocksOption),
+    /// Represents connecting to the destination directly using OS sockets.
+    Direct,
+    /// Represents a stand-in upstream which sends all the data back to the source without
+    /// touching the network.
+    Echo,
+    /// Represents a stand-in upstream which drops all the data without touching the network.
+    Discard,
+    /// Represents a group of upstream proxies with failover.
+    Group(ProxyGroup),
 }
 
 impl ProxyConfig {
     /// Creates a new SOCKS `ProxyConfig`.
     pub fn new_socks(
-        remote: SocketAddrV4,
+        remote: SocketAddr,
         force_associate_remote: bool,
         force_associate_bind_addr: bool,
         auth: Option<(String, String)>,
@@ -40,43 +72,577 @@ impl ProxyConfig {
             ),
         )
     }
+
+    /// Creates a new SOCKS4 `ProxyConfig`.
+    pub fn new_socks4(remote: SocketAddr, user_id: Option<String>) -> ProxyConfig {
+        ProxyConfig::Socks4(remote, Socks4Option::new(user_id))
+    }
+
+    /// Creates a new HTTP `ProxyConfig` using the CONNECT method.
+    pub fn new_http_connect(remote: SocketAddr, auth: Option<(String, String)>) -> ProxyConfig {
+        ProxyConfig::HttpConnect(
+            remote,
+            HttpOption::new(match auth {
+                Some((username, password)) => Some(HttpAuth::new(username, password)),
+                None => None,
+            }),
+        )
+    }
+
+    /// Creates a new Shadowsocks `ProxyConfig`. Available methods are `chacha20-ietf-poly1305`
+    /// and `aes-256-gcm`.
+    pub fn new_shadowsocks(
+        remote: SocketAddr,
+        method: &str,
+        password: &str,
+    ) -> io::Result<ProxyConfig> {
+        Ok(ProxyConfig::Shadowsocks(
+            remote,
+            ShadowsocksOption::new(method.parse()?, password),
+        ))
+    }
+
+    /// Creates a new `ProxyConfig` of a group of upstream proxies. Upstreams are tried in order,
+    /// and the unavailable ones are skipped. Nested groups are not supported.
+    pub fn new_group(proxies: Vec<ProxyConfig>) -> ProxyConfig {
+        ProxyConfig::Group(ProxyGroup::new(proxies))
+    }
+
+    /// Returns if the proxy supports forwarding datagrams.
+    pub fn is_datagram_supported(&self) -> bool {
+        match self {
+            ProxyConfig::Socks(_, _) => true,
+            ProxyConfig::Socks4(_, _) => false,
+            ProxyConfig::HttpConnect(_, _) => false,
+            ProxyConfig::Shadowsocks(_, _) => true,
+            ProxyConfig::Direct => true,
+            ProxyConfig::Echo | ProxyConfig::Discard => true,
+            ProxyConfig::Group(group) => group
+                .upstreams
+                .iter()
+                .any(|upstream| upstream.proxy.is_datagram_supported()),
+        }
+    }
+
+    /// Spawns a task checking the availability of the upstreams periodically if the proxy is a
+    /// group. The task stops when the proxy is dropped.
+    pub fn spawn_health_check(&self, config: &Config) {
+        if let ProxyConfig::Group(group) = self {
+            let health_check_interval = config.health_check_interval;
+            let health_check_timeout = config.health_check_timeout;
+
+            let upstreams = Arc::downgrade(&group.upstreams);
+            tokio::spawn(async move {
+                loop {
+                    {
+                        let upstreams = match upstreams.upgrade() {
+                            Some(upstreams) => upstreams,
+                            None => return,
+                        };
+                        for upstream in upstreams.iter() {
+                            let is_alive = match time::timeout(
+                                Duration::from_millis(health_check_timeout),
+                                probe(&upstream.proxy),
+                            )
+                            .await
+                            {
+                                Ok(Ok(rtt)) => {
+                                    upstream.latency.update(rtt);
+                                    true
+                                }
+                                Ok(Err(ref e)) => {
+                                    trace!("check upstream {}: {}", upstream.proxy, e);
+                                    upstream.latency.reset();
+                                    false
+                                }
+                                Err(_) => {
+                                    trace!("check upstream {}: timed out", upstream.proxy);
+                                    upstream.latency.reset();
+                                    false
+                                }
+                            };
+                            if upstream.is_alive.swap(is_alive, Ordering::Relaxed) != is_alive {
+                                match is_alive {
+                                    true => info!("Upstream {} is available", upstream.proxy),
+                                    false => warn!("Upstream {} is unavailable", upstream.proxy),
+                                }
+                            }
+                        }
+                    }
+
+                    time::sleep(Duration::from_millis(health_check_interval)).await;
+                }
+            });
+        }
+    }
+
+    /// Returns the latency statistics of the upstreams if the proxy is a group. The latency is
+    /// measured by the health check.
+    pub fn latencies(&self) -> Vec<(String, Latency)> {
+        match self {
+            ProxyConfig::Group(group) => group
+                .upstreams
+                .iter()
+                .map(|upstream| (upstream.proxy.to_string(), upstream.latency.clone()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Display for ProxyConfig {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ProxyConfig::Socks(remote, _) => write!(f, "socks5://{}", remote),
+            ProxyConfig::Socks4(remote, _) => write!(f, "socks4://{}", remote),
+            ProxyConfig::HttpConnect(remote, _) => write!(f, "http://{}", remote),
+            ProxyConfig::Shadowsocks(remote, _) => write!(f, "ss://{}", remote),
+            ProxyConfig::Direct => write!(f, "direct"),
+            ProxyConfig::Echo => write!(f, "echo"),
+            ProxyConfig::Discard => write!(f, "discard"),
+            ProxyConfig::Group(group) => write!(
+                f,
+                "[{}]",
+                group
+                    .upstreams
+                    .iter()
+                    .map(|upstream| upstream.proxy.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Represents an upstream proxy in a group.
+struct Upstream {
+    proxy: ProxyConfig,
+    is_alive: AtomicBool,
+    latency: Latency,
+}
+
+/// Represents a group of upstream proxies.
+pub struct ProxyGroup {
+    upstreams: Arc<Vec<Upstream>>,
+}
+
+impl ProxyGroup {
+    /// Creates a new `ProxyGroup`.
+    fn new(proxies: Vec<ProxyConfig>) -> ProxyGroup {
+        let upstreams = proxies
+            .into_iter()
+            .map(|proxy| Upstream {
+                proxy,
+                is_alive: AtomicBool::new(true),
+                latency: Latency::new(),
+            })
+            .collect();
+
+        ProxyGroup {
+            upstreams: Arc::new(upstreams),
+        }
+    }
+
+    /// Returns the upstreams to be tried in order. All the upstreams will be returned if none of
+    /// them is available.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let alive = self
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.is_alive.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+
+        match alive.is_empty() {
+            true => self.upstreams.iter().collect(),
+            false => alive,
+        }
+    }
+
+    /// Returns the upstreams supporting datagrams to be tried in the order of latency. The
+    /// upstreams whose latency is not measured are placed last in order.
+    fn datagram_candidates(&self) -> Vec<&Upstream> {
+        let mut candidates = self
+            .candidates()
+            .into_iter()
+            .filter(|upstream| upstream.proxy.is_datagram_supported())
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|upstream| match upstream.latency.rtt() {
+            Some(rtt) => (false, rtt),
+            None => (true, Duration::default()),
+        });
+
+        candidates
+    }
+}
+
+/// Checks if the proxy is available at the protocol level, and returns the latency of the proxy.
+async fn probe(proxy: &ProxyConfig) -> io::Result<Duration> {
+    let instant = Instant::now();
+    match proxy {
+        ProxyConfig::Socks(remote, options) => socks::handshake(remote.clone(), options).await?,
+        ProxyConfig::Socks4(remote, _) => {
+            TcpStream::connect(remote).await?;
+        }
+        ProxyConfig::HttpConnect(remote, options) => {
+            http::handshake(remote.clone(), options).await?
+        }
+        ProxyConfig::Shadowsocks(remote, options) => {
+            // The server is waited for a rejection, so only the connection is timed
+            return shadowsocks::handshake(remote.clone(), options).await;
+        }
+        ProxyConfig::Direct | ProxyConfig::Echo | ProxyConfig::Discard => {}
+        ProxyConfig::Group(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nested group is not supported",
+            ))
+        }
+    }
+
+    Ok(instant.elapsed())
+}
+
+/// Represents the receive half of a proxied stream.
+enum StreamRecvHalf {
+    Plain(OwnedReadHalf),
+    Shadowsocks(ShadowsocksStreamRecvHalf),
+    Stub(ReadHalf<DuplexStream>),
+}
+
+impl StreamRecvHalf {
+    /// Pulls some bytes from the stream into the specified buffer, returning how many bytes were
+    /// read.
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StreamRecvHalf::Plain(stream_rx) => stream_rx.read(buffer).await,
+            StreamRecvHalf::Shadowsocks(stream_rx) => stream_rx.read(buffer).await,
+            StreamRecvHalf::Stub(stream_rx) => stream_rx.read(buffer).await,
+        }
+    }
+}
+
+/// Represents the send half of a proxied stream.
+enum StreamSendHalf {
+    Plain(OwnedWriteHalf),
+    Shadowsocks(ShadowsocksStreamSendHalf),
+    Stub(WriteHalf<DuplexStream>),
+}
+
+impl StreamSendHalf {
+    /// Writes an entire buffer into the stream.
+    async fn write_all(&mut self, payload: &[u8]) -> io::Result<()> {
+        match self {
+            StreamSendHalf::Plain(stream_tx) => stream_tx.write_all(payload).await,
+            StreamSendHalf::Shadowsocks(stream_tx) => stream_tx.write_all(payload).await,
+            StreamSendHalf::Stub(stream_tx) => stream_tx.write_all(payload).await,
+        }
+    }
+
+    /// Destroys the send half, but don't close the write half of the stream. The stub stream is
+    /// shut down instead, so the stub sees the end of the stream.
+    fn forget(self) {
+        match self {
+            StreamSendHalf::Plain(stream_tx) => stream_tx.forget(),
+            StreamSendHalf::Shadowsocks(stream_tx) => stream_tx.forget(),
+            StreamSendHalf::Stub(mut stream_tx) => {
+                tokio::spawn(async move {
+                    let _ = stream_tx.shutdown().await;
+                });
+            }
+        }
+    }
+}
+
+/// Connects to a target server through the proxy. Upstreams in a group are tried in order until
+/// one succeeds, and each attempt times out after the given timeout.
+async fn connect(
+    dst: SocketAddr,
+    proxy: &ProxyConfig,
+    timeout: Duration,
+) -> io::Result<(StreamRecvHalf, StreamSendHalf)> {
+    match proxy {
+        ProxyConfig::Group(group) => {
+            let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no upstream");
+            for upstream in group.candidates() {
+                match connect_upstream_timeout(dst, &upstream.proxy, timeout).await {
+                    Ok(halves) => return Ok(halves),
+                    Err(e) => {
+                        debug!("connect to upstream {}: {}", upstream.proxy, e);
+                        last_error = e;
+                    }
+                }
+            }
+
+            Err(last_error)
+        }
+        _ => connect_upstream_timeout(dst, proxy, timeout).await,
+    }
+}
+
+async fn connect_upstream_timeout(
+    dst: SocketAddr,
+    proxy: &ProxyConfig,
+    timeout: Duration,
+) -> io::Result<(StreamRecvHalf, StreamSendHalf)> {
+    match time::timeout(timeout, connect_upstream(dst, proxy)).await {
+        Ok(r) => r,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connect to upstream timed out",
+        )),
+    }
+}
+
+async fn connect_upstream(
+    dst: SocketAddr,
+    proxy: &ProxyConfig,
+) -> io::Result<(StreamRecvHalf, StreamSendHalf)> {
+    let stream = match proxy {
+        ProxyConfig::Socks(remote, options) => socks::connect(remote.clone(), dst, options)
+            .await?
+            .into_inner(),
+        ProxyConfig::Socks4(remote, options) => {
+            socks::connect4(remote.clone(), dst, options).await?
+        }
+        ProxyConfig::HttpConnect(remote, options) => {
+            http::connect(remote.clone(), dst, options).await?
+        }
+        ProxyConfig::Shadowsocks(remote, options) => {
+            let (stream_rx, stream_tx) = shadowsocks::connect(remote.clone(), dst, options).await?;
+
+            return Ok((
+                StreamRecvHalf::Shadowsocks(stream_rx),
+                StreamSendHalf::Shadowsocks(stream_tx),
+            ));
+        }
+        ProxyConfig::Direct => {
+            let stream = TcpStream::connect(dst).await?;
+            let (stream_rx, stream_tx) = stream.into_split();
+
+            return Ok((
+                StreamRecvHalf::Plain(stream_rx),
+                StreamSendHalf::Plain(stream_tx),
+            ));
+        }
+        ProxyConfig::Echo | ProxyConfig::Discard => {
+            let (stream, stub) = tokio::io::duplex(STUB_BUFFER_SIZE);
+            let is_echo = matches!(proxy, ProxyConfig::Echo);
+            tokio::spawn(async move {
+                let (mut stub_rx, mut stub_tx) = tokio::io::split(stub);
+                let _ = match is_echo {
+                    true => tokio::io::copy(&mut stub_rx, &mut stub_tx).await,
+                    false => tokio::io::copy(&mut stub_rx, &mut tokio::io::sink()).await,
+                };
+            });
+            let (stream_rx, stream_tx) = tokio::io::split(stream);
+
+            return Ok((
+                StreamRecvHalf::Stub(stream_rx),
+                StreamSendHalf::Stub(stream_tx),
+            ));
+        }
+        ProxyConfig::Group(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nested group is not supported",
+            ))
+        }
+    };
+    let (stream_rx, stream_tx) = stream.into_split();
+
+    Ok((
+        StreamRecvHalf::Plain(stream_rx),
+        StreamSendHalf::Plain(stream_tx),
+    ))
+}
+
+/// Represents the receive half of a proxied datagram.
+enum DatagramRecvHalf {
+    Socks(SocksRecvHalf),
+    Shadowsocks(ShadowsocksRecvHalf),
+    Direct(Arc<UdpSocket>),
+    Stub(UnboundedReceiver<(Vec<u8>, SocketAddr)>),
+}
+
+impl DatagramRecvHalf {
+    /// Receives a single datagram message on the socket.
+    async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            DatagramRecvHalf::Socks(socks_rx) => socks_rx.recv_from(buffer).await,
+            DatagramRecvHalf::Shadowsocks(ss_rx) => ss_rx.recv_from(buffer).await,
+            DatagramRecvHalf::Direct(socket) => socket.recv_from(buffer).await,
+            DatagramRecvHalf::Stub(stub_rx) => {
+                let (payload, addr) = stub_rx
+                    .recv()
+                    .await
+                    .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))?;
+                let size = payload.len().min(buffer.len());
+                buffer[..size].copy_from_slice(&payload[..size]);
+
+                Ok((size, addr))
+            }
+        }
+    }
+}
+
+/// Represents the send half of a proxied datagram.
+enum DatagramSendHalf {
+    Socks(SocksSendHalf),
+    Shadowsocks(ShadowsocksSendHalf),
+    Direct(Arc<UdpSocket>),
+    /// Represents a stand-in datagram, the datagrams are sent back if it is an echo.
+    Stub(UnboundedSender<(Vec<u8>, SocketAddr)>, bool),
+}
+
+impl DatagramSendHalf {
+    /// Sends data on the socket to the given address.
+    async fn send_to(&mut self, payload: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        match self {
+            DatagramSendHalf::Socks(socks_tx) => socks_tx.send_to(payload, dst).await,
+            DatagramSendHalf::Shadowsocks(ss_tx) => ss_tx.send_to(payload, dst).await,
+            DatagramSendHalf::Direct(socket) => socket.send_to(payload, dst).await,
+            DatagramSendHalf::Stub(stub_tx, is_echo) => {
+                if *is_echo {
+                    stub_tx
+                        .send((payload.to_vec(), dst))
+                        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+                }
+
+                Ok(payload.len())
+            }
+        }
+    }
+}
+
+/// Binds a local address to the proxy. The local address is in the same address family as the
+/// source when connecting directly. Upstreams in a group supporting datagrams are tried in the
+/// order of latency until one succeeds, so a newly bound port always prefers the fastest upstream.
+async fn bind(
+    src: SocketAddr,
+    proxy: &ProxyConfig,
+) -> io::Result<(DatagramRecvHalf, DatagramSendHalf, u16)> {
+    match proxy {
+        ProxyConfig::Group(group) => {
+            let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no upstream");
+            for upstream in group.datagram_candidates() {
+                match bind_upstream(src, &upstream.proxy).await {
+                    Ok(halves) => {
+                        trace!("bind {} to upstream {}", src, upstream.proxy);
+
+                        return Ok(halves);
+                    }
+                    Err(e) => {
+                        debug!("bind to upstream {}: {}", upstream.proxy, e);
+                        last_error = e;
+                    }
+                }
+            }
+
+            Err(last_error)
+        }
+        _ => bind_upstream(src, proxy).await,
+    }
+}
+
+async fn bind_upstream(
+    src: SocketAddr,
+    proxy: &ProxyConfig,
+) -> io::Result<(DatagramRecvHalf, DatagramSendHalf, u16)> {
+    match proxy {
+        ProxyConfig::Socks(remote, options) => {
+            let (socks_rx, socks_tx, local_port) = socks::bind(remote.clone(), options).await?;
+
+            Ok((
+                DatagramRecvHalf::Socks(socks_rx),
+                DatagramSendHalf::Socks(socks_tx),
+                local_port,
+            ))
+        }
+        ProxyConfig::Shadowsocks(remote, options) => {
+            let (ss_rx, ss_tx, local_port) = shadowsocks::bind(remote.clone(), options).await?;
+
+            Ok((
+                DatagramRecvHalf::Shadowsocks(ss_rx),
+                DatagramSendHalf::Shadowsocks(ss_tx),
+                local_port,
+            ))
+        }
+        ProxyConfig::Direct => {
+            let local = match src {
+                SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            let local_port = socket.local_addr()?.port();
+
+            let a_socket = Arc::new(socket);
+            let a_socket_cloned = Arc::clone(&a_socket);
+
+            Ok((
+                DatagramRecvHalf::Direct(a_socket),
+                DatagramSendHalf::Direct(a_socket_cloned),
+                local_port,
+            ))
+        }
+        ProxyConfig::Echo | ProxyConfig::Discard => {
+            let (stub_tx, stub_rx) = mpsc::unbounded_channel();
+
+            Ok((
+                DatagramRecvHalf::Stub(stub_rx),
+                DatagramSendHalf::Stub(stub_tx, matches!(proxy, ProxyConfig::Echo)),
+                0,
+            ))
+        }
+        ProxyConfig::Socks4(_, _) | ProxyConfig::HttpConnect(_, _) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "datagram is not supported by the proxy",
+        )),
+        ProxyConfig::Group(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nested group is not supported",
+        )),
+    }
 }
 
 /// Trait for forwarding a stream.
 pub trait ForwardStream: Send {
     /// Opens a stream connection.
-    fn open(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    fn open(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()>;
 
-    /// Forwards stream.
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()>;
-
-    /// Triggers a timed event. Used in retransmitting timed out data.
-    fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    /// Forwards stream. The payload is passed without being copied.
+    fn forward(&mut self, dst: SocketAddr, src: SocketAddr, payload: Bytes) -> io::Result<()>;
 
     /// Closes a stream connection.
-    fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    fn close(&mut self, dst: SocketAddr, src: SocketAddr) -> io::Result<()>;
 
     /// Checks the stream.
-    fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize>;
-}
+    fn check(&self, dst: SocketAddr, src: SocketAddr) -> io::Result<usize>;
 
-/// Represents the wait time after a `TimedOut` `IoError`.
-const TIMEDOUT_WAIT: u64 = 20;
-/// Represents the wait time after a queue full event.
-const QUEUE_FULL_WAIT: u64 = 200;
+    /// Returns the notification of the stream which is notified when the stream may have space
+    /// for forwarding.
+    fn notify(&self, dst: SocketAddr, src: SocketAddr) -> io::Result<Arc<Notify>>;
 
-/// Represents the wait time after receiving 0 byte from the stream.
-const RECV_ZERO_WAIT: u64 = 100;
-/// Represents the maximum count of receiving 0 byte from the stream before closing it.
-const MAX_RECV_ZERO: usize = 3;
+    /// Returns the backpressure statistics.
+    fn backpressure(&self) -> Backpressure;
 
-/// Represents the interval of a tick.
-const TICK_INTERVAL: u64 = 500;
+    /// Opens the window of the stream after the proxied stream has space again.
+    fn open_window(&mut self, dst: SocketAddr, src: SocketAddr);
+}
+
+/// Represents a stream connected to a target server through the proxy, which is not opened
+/// toward the source yet.
+pub struct ProxiedStream {
+    dst: SocketAddr,
+    stream_rx: StreamRecvHalf,
+    stream_tx: StreamSendHalf,
+}
 
 /// Represents a worker of a proxied TCP stream.
 pub struct StreamWorker {
-    dst: SocketAddrV4,
-    tx_tx: UnboundedSender<Vec<u8>>,
+    dst: SocketAddr,
+    tx_tx: UnboundedSender<Bytes>,
+    tx_queued: Arc<AtomicUsize>,
+    max_tx_queue: usize,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
     tx_close_tx: Sender<()>,
@@ -87,25 +653,63 @@ impl StreamWorker {
     /// Opens a new `StreamWorker`.
     pub async fn connect(
         tx: Arc<Mutex<dyn ForwardStream>>,
-        src: SocketAddrV4,
-        dst: SocketAddrV4,
+        src: SocketAddr,
+        dst: SocketAddr,
         proxy: &ProxyConfig,
+        config: &Config,
     ) -> io::Result<StreamWorker> {
-        let tx_cloned = Arc::clone(&tx);
+        let stream = StreamWorker::connect_proxy(dst, proxy, config.connect_timeout).await?;
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(remote.clone(), dst, options).await?
-            }
-        };
-        let stream = stream.into_inner();
-        let (mut stream_rx, mut stream_tx) = stream.into_split();
+        StreamWorker::open(tx, src, stream, config)
+    }
+
+    /// Connects to a target server through the proxy without opening the stream toward the
+    /// source. The connection times out after the given timeout in ms, or each attempt of the
+    /// upstreams times out if the proxy is a group.
+    pub async fn connect_proxy(
+        dst: SocketAddr,
+        proxy: &ProxyConfig,
+        timeout: u64,
+    ) -> io::Result<ProxiedStream> {
+        let (stream_rx, stream_tx) = connect(dst, proxy, Duration::from_millis(timeout)).await?;
+
+        Ok(ProxiedStream {
+            dst,
+            stream_rx,
+            stream_tx,
+        })
+    }
+
+    /// Opens a new `StreamWorker` on a stream connected through the proxy.
+    pub fn open(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddr,
+        stream: ProxiedStream,
+        config: &Config,
+    ) -> io::Result<StreamWorker> {
+        let timedout_wait = config.timedout_wait;
+        let recv_zero_wait = config.recv_zero_wait;
+        let max_recv_zero = config.max_recv_zero;
+        let max_tx_queue = config.max_proxy_queue;
+
+        let ProxiedStream {
+            dst,
+            mut stream_rx,
+            mut stream_tx,
+        } = stream;
 
         // Open
-        tx.lock().unwrap().open(dst, src)?;
+        let (notify, backpressure) = {
+            let mut tx_locked = tx.lock().unwrap();
+            tx_locked.open(dst, src)?;
 
-        let (tx_tx, mut tx_rx): (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>) =
+            (tx_locked.notify(dst, src)?, tx_locked.backpressure())
+        };
+
+        let (tx_tx, mut tx_rx): (UnboundedSender<Bytes>, UnboundedReceiver<Bytes>) =
             mpsc::unbounded_channel();
+        let tx_queued = Arc::new(AtomicUsize::new(0));
+        let tx_queued_cloned = Arc::clone(&tx_queued);
         let is_tx_closed = Arc::new(AtomicBool::new(false));
         let is_tx_closed_cloned = Arc::clone(&is_tx_closed);
         let is_rx_closed = Arc::new(AtomicBool::new(false));
@@ -114,6 +718,7 @@ impl StreamWorker {
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
 
         // Send
+        let tx_cloned = Arc::clone(&tx);
         tokio::spawn(async move {
             loop {
                 let is_close;
@@ -128,13 +733,22 @@ impl StreamWorker {
                     tokio::select! {
                         r = tx_rx_fut => match r {
                             Some(payload) => {
-                                match stream_tx.write_all(payload.as_slice()).await {
+                                match stream_tx.write_all(&payload).await {
                                     Ok(_) => {
                                         debug!(
                                             "send to proxy: {}: {} -> {} ({} Bytes)",
                                             "TCP", 0, dst, payload.len()
                                         );
 
+                                        // Open the window if the queue drops below the half
+                                        let queued = tx_queued_cloned
+                                            .fetch_sub(payload.len(), Ordering::Relaxed);
+                                        if queued >= max_tx_queue / 2
+                                            && queued - payload.len() < max_tx_queue / 2
+                                        {
+                                            tx_cloned.lock().unwrap().open_window(dst, src);
+                                        }
+
                                         is_close = false
                                     },
                                     Err(ref e) => {
@@ -161,13 +775,15 @@ impl StreamWorker {
 
         // Receive
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; u16::MAX as usize];
+            // The payload is split from the buffer, so it is passed along without being copied
+            let mut buffer = BytesMut::new();
             let mut recv_zero: usize = 0;
             loop {
                 let size;
 
                 // Select
                 {
+                    buffer.resize(u16::MAX as usize, 0);
                     let stream_rx_fut = stream_rx.read(&mut buffer);
                     let rx_close_rx_fut = rx_close_rx.recv();
 
@@ -184,16 +800,16 @@ impl StreamWorker {
                                 size = this_size;
                             } else {
                                 recv_zero = recv_zero.checked_add(1).unwrap_or(usize::MAX);
-                                if recv_zero > MAX_RECV_ZERO {
+                                if recv_zero > max_recv_zero {
                                     size = 0;
                                 } else {
-                                    time::sleep(Duration::from_millis(RECV_ZERO_WAIT)).await;
+                                    time::sleep(Duration::from_millis(recv_zero_wait)).await;
                                     continue;
                                 }
                             },
                             Err(ref e) => {
                                 if e.kind() == io::ErrorKind::TimedOut {
-                                    time::sleep(Duration::from_millis(TIMEDOUT_WAIT)).await;
+                                    time::sleep(Duration::from_millis(timedout_wait)).await;
                                     continue;
                                 }
 
@@ -207,8 +823,12 @@ impl StreamWorker {
                 }
 
                 if size > 0 {
+                    buffer.truncate(size);
+                    let payload = buffer.split().freeze();
+
                     // Loop until the data was transferred to the forwarder
                     let mut is_sent = false;
+                    let mut blocked = None;
                     loop {
                         {
                             let mut tx_locked = tx.lock().unwrap();
@@ -217,7 +837,7 @@ impl StreamWorker {
                                     // If the queue remains size
                                     if remaining >= size {
                                         if let Err(ref e) =
-                                            tx_locked.forward(dst, src, &buffer[..size])
+                                            tx_locked.forward(dst, src, payload.clone())
                                         {
                                             warn!(
                                                 "handle receive: {}: {} -> {}: {}",
@@ -237,10 +857,16 @@ impl StreamWorker {
                         if is_sent {
                             break;
                         } else {
-                            // Pause if the queue is full
-                            time::sleep(Duration::from_millis(QUEUE_FULL_WAIT)).await;
+                            // Wait until the queue is drained if the queue is full
+                            blocked.get_or_insert_with(Instant::now);
+                            notify.notified().await;
                         }
                     }
+
+                    // Backpressure
+                    if let Some(blocked) = blocked {
+                        backpressure.add(blocked.elapsed());
+                    }
                 } else {
                     // Close
                     is_rx_closed_cloned.store(true, Ordering::Relaxed);
@@ -255,25 +881,13 @@ impl StreamWorker {
             }
         });
 
-        // Timeout
-        tokio::spawn(async move {
-            loop {
-                time::sleep(Duration::from_millis(TICK_INTERVAL)).await;
-                // Send
-                if let Err(ref e) = tx_cloned.lock().unwrap().tick(dst, src) {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        return;
-                    }
-                    warn!("handle timeout: {}: {} -> {}: {}", "TCP", dst, 0, e);
-                }
-            }
-        });
-
         trace!("open stream {} -> {}", 0, dst);
 
         Ok(StreamWorker {
             dst,
             tx_tx,
+            tx_queued,
+            max_tx_queue,
             is_tx_closed,
             is_rx_closed,
             tx_close_tx,
@@ -281,16 +895,34 @@ impl StreamWorker {
         })
     }
 
-    /// Sends data on the proxied stream in TCP to the destination.
-    pub fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+    /// Sends data on the proxied stream in TCP to the destination. The data is queued until it is
+    /// written to the proxy, and the size of the queue is bounded.
+    pub fn send(&mut self, payload: Bytes) -> io::Result<()> {
+        if payload.len() > self.tx_remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "proxy queue is full",
+            ));
+        }
+
         // Send
+        let size = payload.len();
+        self.tx_queued.fetch_add(size, Ordering::Relaxed);
         if let Err(_) = self.tx_tx.send(payload) {
+            self.tx_queued.fetch_sub(size, Ordering::Relaxed);
             return Err(io::Error::from(io::ErrorKind::NotConnected));
         }
 
         Ok(())
     }
 
+    /// Returns the remaining size of the queue to the proxy.
+    pub fn tx_remaining(&self) -> usize {
+        self.max_tx_queue
+            .checked_sub(self.tx_queued.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
     /// Shuts down the read, write, or both halves of the worker.
     pub fn shutdown(&mut self, how: Shutdown) {
         match how {
@@ -337,8 +969,8 @@ impl Drop for StreamWorker {
 /// Represents a worker of a proxied TCP stream. Comparing with `StreamWorker`, `StreamWorker2` do
 /// not require the ownership of the sent payload, but have to wait until the payload was sent.
 pub struct StreamWorker2 {
-    dst: SocketAddrV4,
-    stream_tx: Option<OwnedWriteHalf>,
+    dst: SocketAddr,
+    stream_tx: Option<StreamSendHalf>,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
     rx_close_tx: Sender<()>,
@@ -348,19 +980,17 @@ impl StreamWorker2 {
     /// Opens a new `StreamWorker2`.
     pub async fn connect(
         tx: Arc<Mutex<dyn ForwardStream>>,
-        src: SocketAddrV4,
-        dst: SocketAddrV4,
+        src: SocketAddr,
+        dst: SocketAddr,
         proxy: &ProxyConfig,
+        config: &Config,
     ) -> io::Result<StreamWorker2> {
-        let tx_cloned = Arc::clone(&tx);
+        let timedout_wait = config.timedout_wait;
+        let recv_zero_wait = config.recv_zero_wait;
+        let max_recv_zero = config.max_recv_zero;
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(remote.clone(), dst, options).await?
-            }
-        };
-        let stream = stream.into_inner();
-        let (mut stream_rx, stream_tx) = stream.into_split();
+        let (mut stream_rx, stream_tx) =
+            connect(dst, proxy, Duration::from_millis(config.connect_timeout)).await?;
 
         // Open
         tx.lock().unwrap().open(dst, src)?;
@@ -372,13 +1002,15 @@ impl StreamWorker2 {
 
         // Receive
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; u16::MAX as usize];
+            // The payload is split from the buffer, so it is passed along without being copied
+            let mut buffer = BytesMut::new();
             let mut recv_zero: usize = 0;
             loop {
                 let size;
 
                 // Select
                 {
+                    buffer.resize(u16::MAX as usize, 0);
                     let stream_rx_fut = stream_rx.read(&mut buffer);
                     let rx_close_rx_fut = rx_close_rx.recv();
 
@@ -395,16 +1027,16 @@ impl StreamWorker2 {
                                 size = this_size;
                             } else {
                                 recv_zero = recv_zero.checked_add(1).unwrap_or(usize::MAX);
-                                if recv_zero > MAX_RECV_ZERO {
+                                if recv_zero > max_recv_zero {
                                     size = 0;
                                 } else {
-                                    time::sleep(Duration::from_millis(RECV_ZERO_WAIT)).await;
+                                    time::sleep(Duration::from_millis(recv_zero_wait)).await;
                                     continue;
                                 }
                             },
                             Err(ref e) => {
                                 if e.kind() == io::ErrorKind::TimedOut {
-                                    time::sleep(Duration::from_millis(TIMEDOUT_WAIT)).await;
+                                    time::sleep(Duration::from_millis(timedout_wait)).await;
                                     continue;
                                 }
 
@@ -418,7 +1050,9 @@ impl StreamWorker2 {
                 }
 
                 if size > 0 {
-                    if let Err(ref e) = tx.lock().unwrap().forward(dst, src, &buffer[..size]) {
+                    buffer.truncate(size);
+                    let payload = buffer.split().freeze();
+                    if let Err(ref e) = tx.lock().unwrap().forward(dst, src, payload) {
                         warn!("handle receive: {}: {} -> {}: {}", "TCP", dst, 0, e);
                     }
                 } else {
@@ -435,20 +1069,6 @@ impl StreamWorker2 {
             }
         });
 
-        // Timeout
-        tokio::spawn(async move {
-            loop {
-                time::sleep(Duration::from_millis(TICK_INTERVAL)).await;
-                // Send
-                if let Err(ref e) = tx_cloned.lock().unwrap().tick(dst, src) {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        return;
-                    }
-                    warn!("handle timeout: {}: {} -> {}: {}", "TCP", dst, 0, e);
-                }
-            }
-        });
-
         trace!("open stream {} -> {}", 0, dst);
 
         Ok(StreamWorker2 {
@@ -526,14 +1146,14 @@ impl Drop for StreamWorker2 {
 /// Trait for forwarding a datagram.
 pub trait ForwardDatagram: Send {
     /// Forwards datagram.
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()>;
+    fn forward(&mut self, dst: SocketAddr, src: SocketAddr, payload: &[u8]) -> io::Result<()>;
 }
 
 /// Represents a worker of a proxied UDP datagram.
 pub struct DatagramWorker {
-    src: Arc<AtomicU64>,
+    src: Arc<Mutex<SocketAddr>>,
     local_port: u16,
-    tx_tx: UnboundedSender<(Vec<u8>, SocketAddrV4)>,
+    tx_tx: UnboundedSender<(Vec<u8>, SocketAddr)>,
     is_closed: Arc<AtomicBool>,
     close_tx: Sender<()>,
     close_tx2: Sender<()>,
@@ -543,18 +1163,19 @@ impl DatagramWorker {
     /// Creates a new `DatagramWorker`.
     pub async fn bind(
         tx: Arc<Mutex<dyn ForwardDatagram>>,
-        src: SocketAddrV4,
+        src: SocketAddr,
         proxy: &ProxyConfig,
+        config: &Config,
     ) -> io::Result<(DatagramWorker, u16)> {
-        let (mut socks_rx, mut socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
-        };
+        let timedout_wait = config.timedout_wait;
+
+        let (mut datagram_rx, mut datagram_tx, local_port) = bind(src, proxy).await?;
 
         let (tx_tx, mut tx_rx): (
-            UnboundedSender<(Vec<u8>, SocketAddrV4)>,
-            UnboundedReceiver<(Vec<u8>, SocketAddrV4)>,
+            UnboundedSender<(Vec<u8>, SocketAddr)>,
+            UnboundedReceiver<(Vec<u8>, SocketAddr)>,
         ) = mpsc::unbounded_channel();
-        let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
+        let a_src = Arc::new(Mutex::new(src));
         let a_src_cloned = Arc::clone(&a_src);
         let is_closed = Arc::new(AtomicBool::new(false));
         let is_closed_cloned = Arc::clone(&is_closed);
@@ -576,7 +1197,7 @@ impl DatagramWorker {
                     tokio::select! {
                         r = tx_rx_fut => match r {
                             Some((payload, dst)) => {
-                                match socks_tx.send_to(payload.as_slice(), dst).await {
+                                match datagram_tx.send_to(payload.as_slice(), dst).await {
                                     Ok(size) => {
                                         debug!(
                                             "send to proxy: {}: {} -> {} ({} Bytes)",
@@ -607,18 +1228,18 @@ impl DatagramWorker {
             let mut buffer = vec![0u8; u16::MAX as usize];
             loop {
                 let size;
-                let mut addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+                let mut addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
 
                 // Select
                 {
-                    let socks_rx_fut = socks_rx.recv_from(&mut buffer);
+                    let datagram_rx_fut = datagram_rx.recv_from(&mut buffer);
                     let close_rx_fut = close_rx2.recv();
 
-                    tokio::pin!(socks_rx_fut, close_rx_fut);
+                    tokio::pin!(datagram_rx_fut, close_rx_fut);
 
                     tokio::select! {
-                        socks_rx_result = socks_rx_fut => {
-                            match socks_rx_result {
+                        datagram_rx_result = datagram_rx_fut => {
+                            match datagram_rx_result {
                                 Ok((this_size, this_addr)) => {
                                     debug!(
                                         "receive from proxy: {}: {} -> {} ({} Bytes)",
@@ -630,7 +1251,7 @@ impl DatagramWorker {
                                 },
                                 Err(ref e) => {
                                     if e.kind() == io::ErrorKind::TimedOut {
-                                        time::sleep(Duration::from_millis(TIMEDOUT_WAIT)).await;
+                                        time::sleep(Duration::from_millis(timedout_wait)).await;
                                         continue;
                                     }
 
@@ -638,7 +1259,7 @@ impl DatagramWorker {
                                         "receive from proxy: {}: {} = {}: {}",
                                         "UDP",
                                         local_port,
-                                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                                        *a_src_cloned.lock().unwrap(),
                                         e
                                     );
 
@@ -654,7 +1275,7 @@ impl DatagramWorker {
                     // Send
                     if let Err(ref e) = tx.lock().unwrap().forward(
                         addr,
-                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                        *a_src_cloned.lock().unwrap(),
                         &buffer[..size],
                     ) {
                         warn!(
@@ -667,7 +1288,7 @@ impl DatagramWorker {
                     trace!(
                         "close datagram {} = {}",
                         local_port,
-                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed))
+                        *a_src_cloned.lock().unwrap()
                     );
 
                     break;
@@ -691,7 +1312,7 @@ impl DatagramWorker {
     }
 
     /// Sends data on the proxied datagram in UDP to the destination.
-    pub fn send_to(&mut self, payload: Vec<u8>, dst: SocketAddrV4) -> io::Result<()> {
+    pub fn send_to(&mut self, payload: Vec<u8>, dst: SocketAddr) -> io::Result<()> {
         // Send
         if let Err(_) = self.tx_tx.send((payload, dst)) {
             return Err(io::Error::from(io::ErrorKind::NotConnected));
@@ -701,15 +1322,14 @@ impl DatagramWorker {
     }
 
     /// Sets the source of the worker.
-    pub fn set_src(&mut self, src: &SocketAddrV4) {
-        self.src
-            .store(socket_addr_v4_to_u64(src), Ordering::Relaxed);
+    pub fn set_src(&mut self, src: &SocketAddr) {
+        *self.src.lock().unwrap() = src.clone();
         trace!("set datagram {} = {}", src, self.local_port);
     }
 
     /// Returns the source of the worker.
-    pub fn src(&self) -> SocketAddrV4 {
-        u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
+    pub fn src(&self) -> SocketAddr {
+        *self.src.lock().unwrap()
     }
 
     /// Returns if the worker is closed.
@@ -730,9 +1350,9 @@ impl Drop for DatagramWorker {
 /// `DatagramWorker2` do not require the ownership of the sent payload, but have to wait until the
 /// payload was sent.
 pub struct DatagramWorker2 {
-    src: Arc<AtomicU64>,
+    src: Arc<Mutex<SocketAddr>>,
     local_port: u16,
-    socks_tx: SocksSendHalf,
+    datagram_tx: DatagramSendHalf,
     is_closed: Arc<AtomicBool>,
     close_tx: Sender<()>,
 }
@@ -741,14 +1361,15 @@ impl DatagramWorker2 {
     /// Creates a new `DatagramWorker2`.
     pub async fn bind(
         tx: Arc<Mutex<dyn ForwardDatagram>>,
-        src: SocketAddrV4,
+        src: SocketAddr,
         proxy: &ProxyConfig,
+        config: &Config,
     ) -> io::Result<(DatagramWorker2, u16)> {
-        let (mut socks_rx, socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
-        };
+        let timedout_wait = config.timedout_wait;
+
+        let (mut datagram_rx, datagram_tx, local_port) = bind(src, proxy).await?;
 
-        let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
+        let a_src = Arc::new(Mutex::new(src));
         let a_src_cloned = Arc::clone(&a_src);
         let is_closed = Arc::new(AtomicBool::new(false));
         let is_closed_cloned = Arc::clone(&is_closed);
@@ -759,18 +1380,18 @@ impl DatagramWorker2 {
             let mut buffer = vec![0u8; u16::MAX as usize];
             loop {
                 let size;
-                let mut addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+                let mut addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
 
                 // Select
                 {
-                    let socks_rx_fut = socks_rx.recv_from(&mut buffer);
+                    let datagram_rx_fut = datagram_rx.recv_from(&mut buffer);
                     let close_rx_fut = close_rx.recv();
 
-                    tokio::pin!(socks_rx_fut, close_rx_fut);
+                    tokio::pin!(datagram_rx_fut, close_rx_fut);
 
                     tokio::select! {
-                        socks_rx_result = socks_rx_fut => {
-                            match socks_rx_result {
+                        datagram_rx_result = datagram_rx_fut => {
+                            match datagram_rx_result {
                                 Ok((this_size, this_addr)) => {
                                     debug!(
                                         "receive from proxy: {}: {} -> {} ({} Bytes)",
@@ -782,7 +1403,7 @@ impl DatagramWorker2 {
                                 },
                                 Err(ref e) => {
                                     if e.kind() == io::ErrorKind::TimedOut {
-                                        time::sleep(Duration::from_millis(TIMEDOUT_WAIT)).await;
+                                        time::sleep(Duration::from_millis(timedout_wait)).await;
                                         continue;
                                     }
 
@@ -790,7 +1411,7 @@ impl DatagramWorker2 {
                                         "receive from proxy: {}: {} = {}: {}",
                                         "UDP",
                                         local_port,
-                                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                                        *a_src_cloned.lock().unwrap(),
                                         e
                                     );
 
@@ -806,7 +1427,7 @@ impl DatagramWorker2 {
                     // Send
                     if let Err(ref e) = tx.lock().unwrap().forward(
                         addr,
-                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                        *a_src_cloned.lock().unwrap(),
                         &buffer[..size],
                     ) {
                         warn!(
@@ -819,7 +1440,7 @@ impl DatagramWorker2 {
                     trace!(
                         "close datagram {} = {}",
                         local_port,
-                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed))
+                        *a_src_cloned.lock().unwrap()
                     );
 
                     break;
@@ -833,7 +1454,7 @@ impl DatagramWorker2 {
             DatagramWorker2 {
                 src: a_src,
                 local_port,
-                socks_tx,
+                datagram_tx,
                 is_closed,
                 close_tx,
             },
@@ -842,9 +1463,9 @@ impl DatagramWorker2 {
     }
 
     /// Sends data on the proxied datagram in UDP to the destination.
-    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddr) -> io::Result<usize> {
         // Send
-        let size = self.socks_tx.send_to(payload, dst).await?;
+        let size = self.datagram_tx.send_to(payload, dst).await?;
         debug!(
             "send to proxy {}: {} -> {} ({} Bytes)",
             "UDP", self.local_port, dst, size
@@ -854,15 +1475,14 @@ impl DatagramWorker2 {
     }
 
     /// Sets the source of the worker.
-    pub fn set_src(&mut self, src: &SocketAddrV4) {
-        self.src
-            .store(socket_addr_v4_to_u64(src), Ordering::Relaxed);
+    pub fn set_src(&mut self, src: &SocketAddr) {
+        *self.src.lock().unwrap() = src.clone();
         trace!("set datagram {} = {}", src, self.local_port);
     }
 
     /// Returns the source of the worker.
-    pub fn src(&self) -> SocketAddrV4 {
-        u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
+    pub fn src(&self) -> SocketAddr {
+        *self.src.lock().unwrap()
     }
 
     /// Returns if the worker is closed.
@@ -878,16 +1498,257 @@ impl Drop for DatagramWorker2 {
     }
 }
 
-fn socket_addr_v4_to_u64(addr: &SocketAddrV4) -> u64 {
-    let ip = u32::from(addr.ip().clone());
+#[test]
+fn proxy_group_candidates() {
+    let group = ProxyGroup::new(vec![
+        ProxyConfig::new_socks4("127.0.0.1:1081".parse().unwrap(), None),
+        ProxyConfig::new_socks4("127.0.0.1:1082".parse().unwrap(), None),
+        ProxyConfig::new_socks4("127.0.0.1:1083".parse().unwrap(), None),
+    ]);
+    let candidates = |group: &ProxyGroup| {
+        group
+            .candidates()
+            .into_iter()
+            .map(|upstream| upstream.proxy.to_string())
+            .collect::<Vec<_>>()
+    };
+    let set_alive = |i: usize, is_alive: bool| {
+        group.upstreams[i]
+            .is_alive
+            .store(is_alive, Ordering::Relaxed)
+    };
+
+    assert_eq!(
+        candidates(&group),
+        vec![
+            "socks4://127.0.0.1:1081",
+            "socks4://127.0.0.1:1082",
+            "socks4://127.0.0.1:1083"
+        ]
+    );
+
+    // Down
+    set_alive(0, false);
+    assert_eq!(
+        candidates(&group),
+        vec!["socks4://127.0.0.1:1082", "socks4://127.0.0.1:1083"]
+    );
+    set_alive(2, false);
+    assert_eq!(candidates(&group), vec!["socks4://127.0.0.1:1082"]);
+
+    // All down
+    set_alive(1, false);
+    assert_eq!(
+        candidates(&group),
+        vec![
+            "socks4://127.0.0.1:1081",
+            "socks4://127.0.0.1:1082",
+            "socks4://127.0.0.1:1083"
+        ]
+    );
+
+    // Recover
+    set_alive(2, true);
+    assert_eq!(candidates(&group), vec!["socks4://127.0.0.1:1083"]);
+    set_alive(0, true);
+    assert_eq!(
+        candidates(&group),
+        vec!["socks4://127.0.0.1:1081", "socks4://127.0.0.1:1083"]
+    );
+}
 
-    ((ip as u64) << 16) + addr.port() as u64
+#[test]
+fn proxy_group_datagram_candidates() {
+    let group = ProxyGroup::new(vec![
+        ProxyConfig::new_socks4("127.0.0.1:1081".parse().unwrap(), None),
+        ProxyConfig::Discard,
+        ProxyConfig::Echo,
+        ProxyConfig::Direct,
+    ]);
+    let candidates = |group: &ProxyGroup| {
+        group
+            .datagram_candidates()
+            .into_iter()
+            .map(|upstream| upstream.proxy.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // Not measured, in order without the upstreams not supporting datagrams
+    assert_eq!(candidates(&group), vec!["discard", "echo", "direct"]);
+
+    // Measured upstreams go first in the order of latency
+    group.upstreams[2].latency.update(Duration::from_millis(30));
+    group.upstreams[3].latency.update(Duration::from_millis(10));
+    assert_eq!(candidates(&group), vec!["direct", "echo", "discard"]);
+    group.upstreams[1].latency.update(Duration::from_millis(20));
+    assert_eq!(candidates(&group), vec!["direct", "discard", "echo"]);
+
+    // Unavailable
+    group.upstreams[3].latency.reset();
+    group.upstreams[3].is_alive.store(false, Ordering::Relaxed);
+    assert_eq!(candidates(&group), vec!["discard", "echo"]);
 }
 
-fn u64_to_socket_addr_v4(v: u64) -> SocketAddrV4 {
-    let port = v as u16;
-    let ip = (v >> 16) as u32;
-    let ip = Ipv4Addr::from(ip);
+#[tokio::test]
+async fn stream_worker_window() {
+    struct Window {
+        opened: Vec<(SocketAddr, SocketAddr)>,
+        notify: Arc<Notify>,
+    }
+
+    impl ForwardStream for Window {
+        fn open(&mut self, _: SocketAddr, _: SocketAddr) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward(&mut self, _: SocketAddr, _: SocketAddr, _: Bytes) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self, _: SocketAddr, _: SocketAddr) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn check(&self, _: SocketAddr, _: SocketAddr) -> io::Result<usize> {
+            Ok(usize::MAX)
+        }
+
+        fn notify(&self, _: SocketAddr, _: SocketAddr) -> io::Result<Arc<Notify>> {
+            Ok(Arc::new(Notify::new()))
+        }
+
+        fn backpressure(&self) -> Backpressure {
+            Backpressure::new()
+        }
 
-    SocketAddrV4::new(ip, port)
+        fn open_window(&mut self, dst: SocketAddr, src: SocketAddr) {
+            self.opened.push((dst, src));
+            self.notify.notify_one();
+        }
+    }
+
+    let src = "10.6.0.1:10000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let notify = Arc::new(Notify::new());
+    let window = Arc::new(Mutex::new(Window {
+        opened: Vec::new(),
+        notify: Arc::clone(&notify),
+    }));
+    let mut config = Config::default();
+    config.max_proxy_queue = 1000;
+    let mut worker = StreamWorker::connect(
+        Arc::clone(&window) as Arc<Mutex<dyn ForwardStream>>,
+        src,
+        dst,
+        &ProxyConfig::Discard,
+        &config,
+    )
+    .await
+    .unwrap();
+
+    // The queue is not drained before the worker is polled
+    worker.send(Bytes::from(vec![0u8; 600])).unwrap();
+    assert_eq!(worker.tx_remaining(), 400);
+    assert_eq!(
+        worker.send(Bytes::from(vec![0u8; 500])).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    worker.send(Bytes::from(vec![0u8; 400])).unwrap();
+    assert_eq!(worker.tx_remaining(), 0);
+
+    // The window is opened once when the queue drops below the half
+    for _ in 0..2 {
+        time::timeout(Duration::from_secs(5), notify.notified())
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while worker.tx_remaining() < 1000 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        worker.send(Bytes::from(vec![0u8; 1000])).unwrap();
+        assert_eq!(worker.tx_remaining(), 0);
+    }
+    assert_eq!(window.lock().unwrap().opened, vec![(dst, src); 2]);
+}
+
+#[tokio::test]
+async fn proxy_direct() {
+    use tokio::net::TcpListener;
+
+    // Stream
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut stream_rx, mut stream_tx) = stream.split();
+        let _ = io::copy(&mut stream_rx, &mut stream_tx).await;
+    });
+
+    let (mut stream_rx, mut stream_tx) = connect(dst, &ProxyConfig::Direct, Duration::from_secs(1))
+        .await
+        .unwrap();
+    stream_tx.write_all(b"hello").await.unwrap();
+    let mut buffer = [0u8; 5];
+    let mut size = 0;
+    while size < buffer.len() {
+        size += stream_rx.read(&mut buffer[size..]).await.unwrap();
+    }
+    assert_eq!(&buffer, b"hello");
+
+    // Datagram
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dst = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        let (size, addr) = echo.recv_from(&mut buffer).await.unwrap();
+        echo.send_to(&buffer[..size], addr).await.unwrap();
+    });
+
+    let src = "10.6.0.1:10000".parse().unwrap();
+    let (mut datagram_rx, mut datagram_tx, local_port) =
+        bind(src, &ProxyConfig::Direct).await.unwrap();
+    assert_ne!(local_port, 0);
+    datagram_tx.send_to(b"hello", dst).await.unwrap();
+    let mut buffer = [0u8; 1500];
+    let (size, addr) = time::timeout(Duration::from_secs(5), datagram_rx.recv_from(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(addr, dst);
+    assert_eq!(&buffer[..size], b"hello");
+}
+
+#[tokio::test]
+async fn proxy_connect_timeout() {
+    use tokio::net::TcpListener;
+
+    // The upstream accepts the connection but never replies
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            streams.push(stream);
+        }
+    });
+
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+    let proxy = ProxyConfig::new_socks4(remote, None);
+    assert_eq!(
+        connect(dst, &proxy, timeout).await.err().unwrap().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    // Fail over to the next upstream after the timeout
+    let group = ProxyConfig::new_group(vec![
+        ProxyConfig::new_socks4(remote, None),
+        ProxyConfig::Echo,
+    ]);
+    assert!(connect(dst, &group, timeout).await.is_ok());
 }
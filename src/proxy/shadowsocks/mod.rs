@@ -0,0 +1,679 @@
+//! Support for handling Shadowsocks proxies.
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use log::trace;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+/// Represents the AEAD cipher method of a Shadowsocks server.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Method {
+    /// Represents the ChaCha20-Poly1305 cipher.
+    Chacha20IetfPoly1305,
+    /// Represents the AES-256-GCM cipher.
+    Aes256Gcm,
+}
+
+impl Method {
+    fn key_len(&self) -> usize {
+        match self {
+            Method::Chacha20IetfPoly1305 => 32,
+            Method::Aes256Gcm => 32,
+        }
+    }
+
+    fn salt_len(&self) -> usize {
+        match self {
+            Method::Chacha20IetfPoly1305 => 32,
+            Method::Aes256Gcm => 32,
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Method::Chacha20IetfPoly1305 => write!(f, "chacha20-ietf-poly1305"),
+            Method::Aes256Gcm => write!(f, "aes-256-gcm"),
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chacha20-ietf-poly1305" => Ok(Method::Chacha20IetfPoly1305),
+            "aes-256-gcm" => Ok(Method::Aes256Gcm),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "method not supported",
+            )),
+        }
+    }
+}
+
+/// Represents the options connecting to a Shadowsocks server.
+#[derive(Clone, Debug)]
+pub struct ShadowsocksOption {
+    method: Method,
+    key: Vec<u8>,
+}
+
+impl ShadowsocksOption {
+    /// Creates a `ShadowsocksOption`.
+    pub fn new(method: Method, password: &str) -> ShadowsocksOption {
+        ShadowsocksOption {
+            method,
+            key: derive_key(password.as_bytes(), method.key_len()),
+        }
+    }
+}
+
+/// Represents the length of the nonce of AEAD ciphers.
+const NONCE_LEN: usize = 12;
+/// Represents the length of the tag of AEAD ciphers.
+const TAG_LEN: usize = 16;
+/// Represents the length of the encrypted payload length.
+const LENGTH_LEN: usize = 2;
+/// Represents the maximum payload size of a chunk.
+const MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+/// Represents the wait time for the rejection from the server in a handshake in ms.
+const HANDSHAKE_WAIT: u64 = 500;
+
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// Derives the master key from the password, known as `EVP_BytesToKey` in OpenSSL.
+fn derive_key(password: &[u8], key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut prev: Vec<u8> = vec![];
+    while key.len() < key_len {
+        let mut hasher = Md5::new();
+        hasher.update(&prev);
+        hasher.update(password);
+        prev = hasher.finalize().to_vec();
+        key.extend_from_slice(&prev);
+    }
+    key.truncate(key_len);
+
+    key
+}
+
+fn new_salt(method: Method) -> Vec<u8> {
+    let mut salt = vec![0u8; method.salt_len()];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    salt
+}
+
+enum AeadCipherKind {
+    Chacha20IetfPoly1305(ChaCha20Poly1305),
+    Aes256Gcm(Aes256Gcm),
+}
+
+/// Represents an AEAD cipher with its session subkey and nonce.
+struct AeadCipher {
+    cipher: AeadCipherKind,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl AeadCipher {
+    /// Creates an `AeadCipher` using the subkey derived from the key and the salt.
+    fn new(method: Method, key: &[u8], salt: &[u8]) -> AeadCipher {
+        let mut subkey = vec![0u8; method.key_len()];
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(SUBKEY_INFO, &mut subkey)
+            .unwrap();
+
+        let cipher = match method {
+            Method::Chacha20IetfPoly1305 => AeadCipherKind::Chacha20IetfPoly1305(
+                ChaCha20Poly1305::new(GenericArray::from_slice(&subkey)),
+            ),
+            Method::Aes256Gcm => {
+                AeadCipherKind::Aes256Gcm(Aes256Gcm::new(GenericArray::from_slice(&subkey)))
+            }
+        };
+
+        AeadCipher {
+            cipher,
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = GenericArray::from_slice(&self.nonce);
+        let ciphertext = match &self.cipher {
+            AeadCipherKind::Chacha20IetfPoly1305(cipher) => cipher.encrypt(nonce, plaintext),
+            AeadCipherKind::Aes256Gcm(cipher) => cipher.encrypt(nonce, plaintext),
+        }
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "encryption failed"))?;
+        self.increase_nonce();
+
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = GenericArray::from_slice(&self.nonce);
+        let plaintext = match &self.cipher {
+            AeadCipherKind::Chacha20IetfPoly1305(cipher) => cipher.decrypt(nonce, ciphertext),
+            AeadCipherKind::Aes256Gcm(cipher) => cipher.decrypt(nonce, ciphertext),
+        }
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+        self.increase_nonce();
+
+        Ok(plaintext)
+    }
+
+    fn increase_nonce(&mut self) {
+        // Little endian
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+fn encode_addr(addr: SocketAddr, buffer: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip_addr) => {
+            buffer.push(ATYP_IPV4);
+            buffer.extend_from_slice(&ip_addr.octets());
+        }
+        IpAddr::V6(ip_addr) => {
+            buffer.push(ATYP_IPV6);
+            buffer.extend_from_slice(&ip_addr.octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn decode_addr(buffer: &[u8]) -> io::Result<(SocketAddr, usize)> {
+    let (ip_addr, size) = match buffer.first() {
+        Some(&ATYP_IPV4) if buffer.len() >= 7 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&buffer[1..5]);
+            (IpAddr::V4(Ipv4Addr::from(octets)), 7)
+        }
+        Some(&ATYP_IPV6) if buffer.len() >= 19 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buffer[1..17]);
+            (IpAddr::V6(Ipv6Addr::from(octets)), 19)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "address type not supported",
+            ))
+        }
+    };
+    let port = u16::from_be_bytes([buffer[size - 2], buffer[size - 1]]);
+
+    Ok((SocketAddr::new(ip_addr, port), size))
+}
+
+/// Represents the send half of a Shadowsocks TCP stream.
+pub struct ShadowsocksStreamSendHalf {
+    stream_tx: OwnedWriteHalf,
+    method: Method,
+    salt: Option<Vec<u8>>,
+    cipher: AeadCipher,
+}
+
+impl ShadowsocksStreamSendHalf {
+    /// Creates a new `ShadowsocksStreamSendHalf`.
+    pub fn new(
+        stream_tx: OwnedWriteHalf,
+        options: &ShadowsocksOption,
+    ) -> ShadowsocksStreamSendHalf {
+        let salt = new_salt(options.method);
+        let cipher = AeadCipher::new(options.method, &options.key, &salt);
+
+        ShadowsocksStreamSendHalf {
+            stream_tx,
+            method: options.method,
+            salt: Some(salt),
+            cipher,
+        }
+    }
+
+    /// Writes an entire buffer into the stream.
+    pub async fn write_all(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(
+            self.method.salt_len()
+                + payload.len()
+                + (payload.len() / MAX_PAYLOAD_SIZE + 1) * (LENGTH_LEN + 2 * TAG_LEN),
+        );
+        // Salt
+        if let Some(salt) = self.salt.take() {
+            buffer.extend_from_slice(&salt);
+        }
+        // Chunks
+        for chunk in payload.chunks(MAX_PAYLOAD_SIZE) {
+            let length = (chunk.len() as u16).to_be_bytes();
+            buffer.extend_from_slice(&self.cipher.encrypt(&length)?);
+            buffer.extend_from_slice(&self.cipher.encrypt(chunk)?);
+        }
+
+        self.stream_tx.write_all(buffer.as_slice()).await
+    }
+
+    /// Destroys the send half, but don't close the write half of the stream.
+    pub fn forget(self) {
+        self.stream_tx.forget();
+    }
+}
+
+/// Represents the receive half of a Shadowsocks TCP stream.
+pub struct ShadowsocksStreamRecvHalf {
+    stream_rx: OwnedReadHalf,
+    method: Method,
+    key: Vec<u8>,
+    cipher: Option<AeadCipher>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl ShadowsocksStreamRecvHalf {
+    /// Creates a new `ShadowsocksStreamRecvHalf`.
+    pub fn new(stream_rx: OwnedReadHalf, options: &ShadowsocksOption) -> ShadowsocksStreamRecvHalf {
+        ShadowsocksStreamRecvHalf {
+            stream_rx,
+            method: options.method,
+            key: options.key.clone(),
+            cipher: None,
+            buffer: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Pulls some bytes from the stream into the specified buffer, returning how many bytes were
+    /// read.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buffer.len() {
+            // Salt
+            if self.cipher.is_none() {
+                let mut salt = vec![0u8; self.method.salt_len()];
+                if !read_exact_or_eof(&mut self.stream_rx, &mut salt).await? {
+                    return Ok(0);
+                }
+                self.cipher = Some(AeadCipher::new(self.method, &self.key, &salt));
+            }
+            let cipher = self.cipher.as_mut().unwrap();
+
+            // Length
+            let mut length = [0u8; LENGTH_LEN + TAG_LEN];
+            if !read_exact_or_eof(&mut self.stream_rx, &mut length).await? {
+                return Ok(0);
+            }
+            let length = cipher.decrypt(&length)?;
+            let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+            if length > MAX_PAYLOAD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid chunk length",
+                ));
+            }
+
+            // Payload
+            let mut payload = vec![0u8; length + TAG_LEN];
+            self.stream_rx.read_exact(&mut payload).await?;
+            self.buffer = cipher.decrypt(&payload)?;
+            self.pos = 0;
+        }
+
+        let size = min(buffer.len(), self.buffer.len() - self.pos);
+        buffer[..size].copy_from_slice(&self.buffer[self.pos..self.pos + size]);
+        self.pos += size;
+
+        Ok(size)
+    }
+}
+
+/// Reads the exact number of bytes to fill the buffer. Returns `false` if the stream reaches EOF
+/// before any byte was read.
+async fn read_exact_or_eof(stream_rx: &mut OwnedReadHalf, buffer: &mut [u8]) -> io::Result<bool> {
+    let mut n = 0;
+    while n < buffer.len() {
+        let size = stream_rx.read(&mut buffer[n..]).await?;
+        if size == 0 {
+            if n == 0 {
+                return Ok(false);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        n += size;
+    }
+
+    Ok(true)
+}
+
+/// Sends an encrypted length chunk to a Shadowsocks server without connecting to any target
+/// server, and returns the time of establishing the connection. The server closes the connection
+/// if it fails to decrypt the chunk, or waits for the following payload otherwise, so the
+/// connection is accepted if it is not closed in the wait time. This method is used for checking
+/// if the server is available.
+pub async fn handshake(remote: SocketAddr, options: &ShadowsocksOption) -> io::Result<Duration> {
+    let instant = Instant::now();
+    let mut stream = TcpStream::connect(remote).await?;
+    let rtt = instant.elapsed();
+
+    // Salt and length
+    let salt = new_salt(options.method);
+    let mut cipher = AeadCipher::new(options.method, &options.key, &salt);
+    let mut buffer = salt;
+    buffer.extend_from_slice(&cipher.encrypt(&(MAX_PAYLOAD_SIZE as u16).to_be_bytes())?);
+    stream.write_all(buffer.as_slice()).await?;
+
+    // Wait for the rejection
+    let mut buffer = [0u8; 1];
+    match time::timeout(
+        Duration::from_millis(HANDSHAKE_WAIT),
+        stream.read(&mut buffer),
+    )
+    .await
+    {
+        Ok(Ok(0)) | Ok(Err(_)) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "Shadowsocks request rejected",
+        )),
+        Ok(Ok(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected Shadowsocks reply",
+        )),
+        Err(_) => Ok(rtt),
+    }
+}
+
+/// Connects to a target server through a Shadowsocks proxy.
+pub async fn connect(
+    remote: SocketAddr,
+    dst: SocketAddr,
+    options: &ShadowsocksOption,
+) -> io::Result<(ShadowsocksStreamRecvHalf, ShadowsocksStreamSendHalf)> {
+    let stream = TcpStream::connect(remote).await?;
+    let (stream_rx, stream_tx) = stream.into_split();
+
+    let stream_rx = ShadowsocksStreamRecvHalf::new(stream_rx, options);
+    let mut stream_tx = ShadowsocksStreamSendHalf::new(stream_tx, options);
+
+    // Target address
+    let mut addr = vec![];
+    encode_addr(dst, &mut addr);
+    stream_tx.write_all(addr.as_slice()).await?;
+
+    Ok((stream_rx, stream_tx))
+}
+
+fn encrypt_packet(options: &ShadowsocksOption, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let salt = new_salt(options.method);
+    let mut cipher = AeadCipher::new(options.method, &options.key, &salt);
+
+    let mut buffer = salt;
+    buffer.extend_from_slice(&cipher.encrypt(payload)?);
+
+    Ok(buffer)
+}
+
+fn decrypt_packet(options: &ShadowsocksOption, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let salt_len = options.method.salt_len();
+    if packet.len() < salt_len + TAG_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "datagram too short",
+        ));
+    }
+    let mut cipher = AeadCipher::new(options.method, &options.key, &packet[..salt_len]);
+
+    cipher.decrypt(&packet[salt_len..])
+}
+
+/// Represents the send half of a Shadowsocks UDP client.
+pub struct ShadowsocksSendHalf {
+    socket: Arc<UdpSocket>,
+    options: ShadowsocksOption,
+}
+
+impl ShadowsocksSendHalf {
+    /// Sends data on the socket to the given address.
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        let mut buffer = Vec::with_capacity(19 + payload.len());
+        encode_addr(dst, &mut buffer);
+        buffer.extend_from_slice(payload);
+
+        let packet = encrypt_packet(&self.options, buffer.as_slice())?;
+        self.socket.send(packet.as_slice()).await?;
+
+        Ok(payload.len())
+    }
+}
+
+/// Represents the receive half of a Shadowsocks UDP client.
+pub struct ShadowsocksRecvHalf {
+    socket: Arc<UdpSocket>,
+    options: ShadowsocksOption,
+    buffer: Vec<u8>,
+}
+
+impl ShadowsocksRecvHalf {
+    /// Receives a single datagram message on the socket. Datagrams which cannot be decrypted or
+    /// decoded are dropped.
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let n = self.socket.recv(&mut self.buffer).await?;
+            let (plaintext, addr, header_size) =
+                match decrypt_packet(&self.options, &self.buffer[..n]).and_then(|plaintext| {
+                    let (addr, header_size) = decode_addr(plaintext.as_slice())?;
+
+                    Ok((plaintext, addr, header_size))
+                }) {
+                    Ok(result) => result,
+                    Err(ref e) => {
+                        trace!("drop Shadowsocks datagram ({} Bytes): {}", n, e);
+                        continue;
+                    }
+                };
+
+            // Buffer
+            let size = min(plaintext.len() - header_size, buffer.len());
+            buffer[..size].copy_from_slice(&plaintext[header_size..header_size + size]);
+
+            return Ok((size, addr));
+        }
+    }
+}
+
+/// Binds a local address to a Shadowsocks proxy for relaying UDP.
+pub async fn bind(
+    remote: SocketAddr,
+    options: &ShadowsocksOption,
+) -> io::Result<(ShadowsocksRecvHalf, ShadowsocksSendHalf, u16)> {
+    let local = match remote {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let local_port = socket.local_addr()?.port();
+
+    let a_socket = Arc::new(socket);
+    let a_socket_cloned = Arc::clone(&a_socket);
+
+    Ok((
+        ShadowsocksRecvHalf {
+            socket: a_socket,
+            options: options.clone(),
+            buffer: vec![0u8; u16::MAX as usize],
+        },
+        ShadowsocksSendHalf {
+            socket: a_socket_cloned,
+            options: options.clone(),
+        },
+        local_port,
+    ))
+}
+
+#[test]
+fn aead_cipher_round_trip() {
+    for method in [Method::Chacha20IetfPoly1305, Method::Aes256Gcm].iter() {
+        let options = ShadowsocksOption::new(*method, "password");
+        let salt = new_salt(*method);
+        let mut encryptor = AeadCipher::new(*method, &options.key, &salt);
+        let mut decryptor = AeadCipher::new(*method, &options.key, &salt);
+
+        for payload in [&b"hello"[..], &b"world"[..]].iter() {
+            let ciphertext = encryptor.encrypt(payload).unwrap();
+            assert_eq!(ciphertext.len(), payload.len() + TAG_LEN);
+            assert_eq!(decryptor.decrypt(&ciphertext).unwrap(), payload.to_vec());
+        }
+    }
+}
+
+#[tokio::test]
+async fn stream_relay() {
+    use tokio::net::TcpListener;
+
+    let options = ShadowsocksOption::new(Method::Aes256Gcm, "password");
+    let options_cloned = options.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    // In-process server echoing the target address back
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (stream_rx, stream_tx) = stream.into_split();
+        let mut stream_rx = ShadowsocksStreamRecvHalf::new(stream_rx, &options_cloned);
+        let mut stream_tx = ShadowsocksStreamSendHalf::new(stream_tx, &options_cloned);
+
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let size = stream_rx.read(&mut buffer).await.unwrap();
+        let (addr, _) = decode_addr(&buffer[..size]).unwrap();
+        stream_tx
+            .write_all(addr.to_string().as_bytes())
+            .await
+            .unwrap();
+    });
+
+    let dst: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let (mut stream_rx, _stream_tx) = connect(remote, dst, &options).await.unwrap();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let size = stream_rx.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..size], dst.to_string().as_bytes());
+}
+
+#[tokio::test]
+async fn datagram_relay() {
+    let options = ShadowsocksOption::new(Method::Chacha20IetfPoly1305, "password");
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote = server.local_addr().unwrap();
+
+    let (mut rx, mut tx, _) = bind(remote, &options).await.unwrap();
+    let dst: SocketAddr = "1.2.3.4:53".parse().unwrap();
+    tx.send_to(b"ping", dst).await.unwrap();
+
+    // In-process server replying from the target address
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let (n, client) = server.recv_from(&mut buffer).await.unwrap();
+    let plaintext = decrypt_packet(&options, &buffer[..n]).unwrap();
+    let (addr, header_size) = decode_addr(&plaintext).unwrap();
+    assert_eq!(addr, dst);
+    assert_eq!(&plaintext[header_size..], b"ping");
+    let mut reply = vec![];
+    encode_addr(dst, &mut reply);
+    reply.extend_from_slice(b"pong");
+    let packet = encrypt_packet(&options, &reply).unwrap();
+    // A datagram failing the authentication is dropped without closing the association
+    let mut forged = packet.clone();
+    *forged.last_mut().unwrap() ^= 1;
+    server.send_to(&forged, client).await.unwrap();
+    server.send_to(&packet, client).await.unwrap();
+
+    let (size, addr) = rx.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(addr, dst);
+    assert_eq!(&buffer[..size], b"pong");
+}
+
+#[tokio::test]
+async fn stream_invalid_length() {
+    use tokio::net::TcpListener;
+
+    let options = ShadowsocksOption::new(Method::Chacha20IetfPoly1305, "password");
+    let options_cloned = options.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    // In-process server sending a chunk length over the maximum payload size
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let salt = new_salt(options_cloned.method);
+        let mut cipher = AeadCipher::new(options_cloned.method, &options_cloned.key, &salt);
+        let mut buffer = salt;
+        let length = (MAX_PAYLOAD_SIZE as u16 + 1).to_be_bytes();
+        buffer.extend_from_slice(&cipher.encrypt(&length).unwrap());
+        stream.write_all(&buffer).await.unwrap();
+
+        let mut buffer = [0u8; 1];
+        let _ = stream.read(&mut buffer).await;
+    });
+
+    let dst = "1.2.3.4:80".parse().unwrap();
+    let (mut stream_rx, _stream_tx) = connect(remote, dst, &options).await.unwrap();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    assert_eq!(
+        stream_rx.read(&mut buffer).await.unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+#[tokio::test]
+async fn handshake_password() {
+    use tokio::net::TcpListener;
+
+    let options = ShadowsocksOption::new(Method::Aes256Gcm, "password");
+    let options_cloned = options.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    // In-process server closing the connection if the length chunk cannot be decrypted
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let options = options_cloned.clone();
+            tokio::spawn(async move {
+                let mut salt = vec![0u8; options.method.salt_len()];
+                stream.read_exact(&mut salt).await.unwrap();
+                let mut length = [0u8; LENGTH_LEN + TAG_LEN];
+                stream.read_exact(&mut length).await.unwrap();
+                let mut cipher = AeadCipher::new(options.method, &options.key, &salt);
+                if cipher.decrypt(&length).is_ok() {
+                    let mut buffer = [0u8; 1];
+                    let _ = stream.read(&mut buffer).await;
+                }
+            });
+        }
+    });
+
+    assert!(handshake(remote, &options).await.is_ok());
+    let options = ShadowsocksOption::new(Method::Aes256Gcm, "wrong");
+    assert_eq!(
+        handshake(remote, &options).await.unwrap_err().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+}
@@ -2,9 +2,9 @@
 
 use async_socks5::{self, AddrKind, Auth};
 use log::trace;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{self, BufStream};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufStream};
 use tokio::net::{TcpStream, UdpSocket};
 
 /// Represents the username and the password of the authentication connecting to a SOCKS5 server.
@@ -53,8 +53,8 @@ impl SocksOption {
 
 /// Connects to a target server through a SOCKS5 proxy.
 pub async fn connect(
-    remote: SocketAddrV4,
-    dst: SocketAddrV4,
+    remote: SocketAddr,
+    dst: SocketAddr,
     options: &SocksOption,
 ) -> io::Result<BufStream<TcpStream>> {
     let stream = TcpStream::connect(remote).await?;
@@ -69,14 +69,129 @@ pub async fn connect(
     Ok(stream)
 }
 
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_METHOD_NO_AUTH: u8 = 0;
+const SOCKS5_METHOD_PASSWORD: u8 = 2;
+const SOCKS5_METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+/// Negotiates the authentication method with a SOCKS5 server without sending any request. This
+/// method is used for checking if the server is available.
+pub async fn handshake(remote: SocketAddr, options: &SocksOption) -> io::Result<()> {
+    let mut stream = TcpStream::connect(remote).await?;
+
+    // VER, NMETHODS and METHODS
+    let request = match options.auth {
+        Some(_) => vec![
+            SOCKS5_VERSION,
+            2,
+            SOCKS5_METHOD_NO_AUTH,
+            SOCKS5_METHOD_PASSWORD,
+        ],
+        None => vec![SOCKS5_VERSION, 1, SOCKS5_METHOD_NO_AUTH],
+    };
+    stream.write_all(request.as_slice()).await?;
+
+    // VER and METHOD
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected SOCKS5 reply",
+        ));
+    }
+    if reply[1] == SOCKS5_METHOD_NOT_ACCEPTABLE {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no acceptable SOCKS5 methods",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Represents the options connecting to a SOCKS4 server.
+#[derive(Clone, Debug)]
+pub struct Socks4Option {
+    user_id: Option<String>,
+}
+
+impl Socks4Option {
+    /// Creates a `Socks4Option`.
+    pub fn new(user_id: Option<String>) -> Socks4Option {
+        Socks4Option { user_id }
+    }
+}
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_COMMAND_CONNECT: u8 = 1;
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_REPLY_GRANTED: u8 = 90;
+const SOCKS4_REPLY_SIZE: usize = 8;
+
+/// Connects to a target server through a SOCKS4 proxy. Destinations in IPv6 are sent as domain
+/// names in SOCKS4a. The reply is read without buffering, so the data tunneled right after it is
+/// kept in the stream.
+pub async fn connect4(
+    remote: SocketAddr,
+    dst: SocketAddr,
+    options: &Socks4Option,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(remote).await?;
+
+    // VN and CD
+    let mut buf = vec![SOCKS4_VERSION, SOCKS4_COMMAND_CONNECT];
+    // DSTPORT
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    // DSTIP
+    match dst.ip() {
+        IpAddr::V4(ip_addr) => buf.extend_from_slice(&ip_addr.octets()),
+        // SOCKS4a uses an invalid IP address 0.0.0.x to indicate a domain name follows
+        IpAddr::V6(_) => buf.extend_from_slice(&[0, 0, 0, 1]),
+    }
+    // USERID
+    if let Some(ref user_id) = options.user_id {
+        buf.extend_from_slice(user_id.as_bytes());
+    }
+    buf.push(0);
+    // Domain name
+    if let IpAddr::V6(ip_addr) = dst.ip() {
+        buf.extend_from_slice(ip_addr.to_string().as_bytes());
+        buf.push(0);
+    }
+    stream.write_all(buf.as_slice()).await?;
+
+    // Reply
+    let mut reply = [0u8; SOCKS4_REPLY_SIZE];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS4_REPLY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected SOCKS4 reply",
+        ));
+    }
+    if reply[1] != SOCKS4_REPLY_GRANTED {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS4 request rejected ({})", reply[1]),
+        ));
+    }
+
+    Ok(stream)
+}
+
 const RSV_SIZE: usize = 2;
 const FRAG_SIZE: usize = 1;
 const ATYP_SIZE: usize = 1;
 const DST_ADDR_SIZE: usize = 4;
+const DST_ADDR_IPV6_SIZE: usize = 16;
 const DST_PORT_SIZE: usize = 2;
 const HEADER_SIZE: usize = RSV_SIZE + FRAG_SIZE + ATYP_SIZE + DST_ADDR_SIZE + DST_PORT_SIZE;
+const HEADER_IPV6_SIZE: usize =
+    RSV_SIZE + FRAG_SIZE + ATYP_SIZE + DST_ADDR_IPV6_SIZE + DST_PORT_SIZE;
 
 const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
 
 /// Represents the send half of a SOCKS5 UDP client.
 #[derive(Debug)]
@@ -92,19 +207,30 @@ impl SocksSendHalf {
     }
 
     /// Sends data on the socket to the given address.
-    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
-        let mut buf = vec![0u8; HEADER_SIZE + payload.len()];
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        let header_size = match dst {
+            SocketAddr::V4(_) => HEADER_SIZE,
+            SocketAddr::V6(_) => HEADER_IPV6_SIZE,
+        };
+        let mut buf = vec![0u8; header_size + payload.len()];
         // RSV
         // FRAG
-        // ATYP
-        buf[3] = ATYP_IPV4;
-        // DST.ADDR
-        &buf[4..8].copy_from_slice(&dst.ip().octets());
+        // ATYP and DST.ADDR
+        match dst.ip() {
+            IpAddr::V4(ip_addr) => {
+                buf[3] = ATYP_IPV4;
+                &buf[4..8].copy_from_slice(&ip_addr.octets());
+            }
+            IpAddr::V6(ip_addr) => {
+                buf[3] = ATYP_IPV6;
+                &buf[4..20].copy_from_slice(&ip_addr.octets());
+            }
+        }
         // DST.PORT
-        buf[8] = (dst.port() / 256) as u8;
-        buf[9] = (dst.port() % 256) as u8;
+        buf[header_size - 2] = (dst.port() / 256) as u8;
+        buf[header_size - 1] = (dst.port() % 256) as u8;
         // Data
-        &buf[10..].copy_from_slice(payload);
+        &buf[header_size..].copy_from_slice(payload);
 
         self.socket.send(buf.as_slice()).await
     }
@@ -129,25 +255,46 @@ impl SocksRecvHalf {
     }
 
     /// Receives a single datagram message on the socket.
-    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         let n = self.socket.recv(&mut self.buffer).await?;
-        // ATYP and address
-        match self.buffer[3] {
-            ATYP_IPV4 => {}
-            _ => unreachable!(),
+        if n < HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "datagram too short",
+            ));
         }
-        let addr = SocketAddrV4::new(
-            Ipv4Addr::new(
-                self.buffer[4],
-                self.buffer[5],
-                self.buffer[6],
-                self.buffer[7],
-            ),
-            self.buffer[8] as u16 * 256 + self.buffer[9] as u16,
+        // ATYP and address
+        let (ip_addr, header_size) = match self.buffer[3] {
+            ATYP_IPV4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&self.buffer[4..8]);
+                (IpAddr::V4(Ipv4Addr::from(octets)), HEADER_SIZE)
+            }
+            ATYP_IPV6 => {
+                if n < HEADER_IPV6_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "datagram too short",
+                    ));
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.buffer[4..20]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), HEADER_IPV6_SIZE)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "address type not supported",
+                ))
+            }
+        };
+        let addr = SocketAddr::new(
+            ip_addr,
+            self.buffer[header_size - 2] as u16 * 256 + self.buffer[header_size - 1] as u16,
         );
         // Buffer
-        let size = n - HEADER_SIZE;
-        &buffer[..size].copy_from_slice(&self.buffer[HEADER_SIZE..n]);
+        let size = n - header_size;
+        &buffer[..size].copy_from_slice(&self.buffer[header_size..n]);
 
         Ok((size, addr))
     }
@@ -155,17 +302,20 @@ impl SocksRecvHalf {
 
 /// Binds a local address to a target server through a SOCKS5 proxy.
 pub async fn bind(
-    remote: SocketAddrV4,
+    remote: SocketAddr,
     options: &SocksOption,
 ) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
     // Connect
     let stream = TcpStream::connect(remote).await?;
     let stream = BufStream::new(stream);
 
-    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let local = match remote {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
     let socket = UdpSocket::bind(local).await?;
     let local_port = socket.local_addr().unwrap().port();
-    let datagram = match async_socks5::SocksDatagram::associate::<SocketAddrV4>(
+    let datagram = match async_socks5::SocksDatagram::associate::<SocketAddr>(
         stream,
         socket,
         options.auth(),
@@ -188,18 +338,20 @@ pub async fn bind(
 
     // Rewrite ASSOCIATE address
     let is_rewrite = options.force_associate_remote
-        || match proxy_addr {
-            SocketAddr::V4(proxy_addr) => match options.force_associate_bind_addr {
-                true => false,
-                false => proxy_addr.ip().is_private(),
-            },
-            SocketAddr::V6(_) => match options.force_associate_bind_addr {
-                true => panic!("IPv6 is not supported yet"),
-                false => true,
+        || match options.force_associate_bind_addr {
+            true => false,
+            false => match (proxy_addr, remote) {
+                (SocketAddr::V4(proxy_addr), SocketAddr::V4(_)) => proxy_addr.ip().is_private(),
+                // Unique local address
+                (SocketAddr::V6(proxy_addr), SocketAddr::V6(_)) => {
+                    proxy_addr.ip().segments()[0] & 0xfe00 == 0xfc00
+                }
+                // The address family of the bound socket is different from the remote
+                _ => true,
             },
         };
     if is_rewrite {
-        let next_proxy_addr = SocketAddrV4::new(remote.ip().clone(), proxy_addr.port());
+        let next_proxy_addr = SocketAddr::new(remote.ip(), proxy_addr.port());
         socket.connect(next_proxy_addr).await?;
 
         trace!(
@@ -221,3 +373,49 @@ pub async fn bind(
         local_port,
     ))
 }
+
+#[tokio::test]
+async fn socks4_connect() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 13];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 1, 0, 80, 1, 2, 3, 4, b'u', b's', b'e', b'r', 0]);
+        stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    });
+
+    let dst = "1.2.3.4:80".parse().unwrap();
+    let options = Socks4Option::new(Some(String::from("user")));
+    assert!(connect4(remote, dst, &options).await.is_ok());
+}
+
+#[tokio::test]
+async fn socks4_connect_keeps_tunneled_data() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 9];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        // The reply and the tunneled data arrive in one segment
+        stream
+            .write_all(&[0, 90, 0, 0, 0, 0, 0, 0, b'h', b'e', b'l', b'l', b'o'])
+            .await
+            .unwrap();
+    });
+
+    let dst = "1.2.3.4:80".parse().unwrap();
+    let mut stream = connect4(remote, dst, &Socks4Option::new(None))
+        .await
+        .unwrap();
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+}
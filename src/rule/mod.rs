@@ -0,0 +1,280 @@
+//! Support for routing traffic with rules.
+
+use ipnetwork::IpNetwork;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::packet::layer::{LayerKind, LayerKinds};
+
+/// Represents the action of a rule.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Action {
+    /// Represents forwarding through the proxy with the given name, or the default proxy if the
+    /// name is not specified.
+    Proxy(Option<String>),
+    /// Represents connecting to the destination directly.
+    Direct,
+    /// Represents rejecting the traffic.
+    Block,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Action::Proxy(Some(name)) => write!(f, "proxy:{}", name),
+            Action::Proxy(None) => write!(f, "proxy"),
+            Action::Direct => write!(f, "direct"),
+            Action::Block => write!(f, "block"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proxy" => Ok(Action::Proxy(None)),
+            "direct" => Ok(Action::Direct),
+            "block" => Ok(Action::Block),
+            _ => match s.strip_prefix("proxy:") {
+                Some(name) if !name.is_empty() => Ok(Action::Proxy(Some(name.to_string()))),
+                _ => Err(format!("unknown action {}", s)),
+            },
+        }
+    }
+}
+
+/// Represents a routing rule. A rule matches the traffic only when all of its conditions are
+/// satisfied.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Rule {
+    dst: Option<IpNetwork>,
+    dst_ports: Option<(u16, u16)>,
+    protocol: Option<LayerKind>,
+    src: Option<IpNetwork>,
+    action: Action,
+}
+
+impl Rule {
+    /// Creates a `Rule` which matches all the traffic.
+    pub fn new(action: Action) -> Rule {
+        Rule {
+            dst: None,
+            dst_ports: None,
+            protocol: None,
+            src: None,
+            action,
+        }
+    }
+
+    /// Sets the destination network of the rule.
+    pub fn set_dst(&mut self, dst: IpNetwork) {
+        self.dst = Some(dst);
+    }
+
+    /// Sets the inclusive destination port range of the rule.
+    pub fn set_dst_ports(&mut self, start: u16, end: u16) {
+        self.dst_ports = Some((start, end));
+    }
+
+    /// Sets the protocol of the rule. Available values are `LayerKinds::Tcp` and
+    /// `LayerKinds::Udp`.
+    pub fn set_protocol(&mut self, protocol: LayerKind) {
+        self.protocol = Some(protocol);
+    }
+
+    /// Sets the source network of the rule.
+    pub fn set_src(&mut self, src: IpNetwork) {
+        self.src = Some(src);
+    }
+
+    /// Returns the action of the rule.
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// Returns if the rule matches the traffic.
+    pub fn is_match(&self, protocol: LayerKind, src: SocketAddr, dst: SocketAddr) -> bool {
+        if let Some(ref rule_dst) = self.dst {
+            if !rule_dst.contains(dst.ip()) {
+                return false;
+            }
+        }
+        if let Some((start, end)) = self.dst_ports {
+            if dst.port() < start || dst.port() > end {
+                return false;
+            }
+        }
+        if let Some(rule_protocol) = self.protocol {
+            if rule_protocol != protocol {
+                return false;
+            }
+        }
+        if let Some(ref rule_src) = self.src {
+            if !rule_src.contains(src.ip()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut conditions = vec![];
+        if let Some(ref dst) = self.dst {
+            conditions.push(format!("dst={}", dst));
+        }
+        if let Some((start, end)) = self.dst_ports {
+            match start == end {
+                true => conditions.push(format!("port={}", start)),
+                false => conditions.push(format!("port={}-{}", start, end)),
+            }
+        }
+        if let Some(protocol) = self.protocol {
+            conditions.push(format!("protocol={}", protocol.to_string().to_lowercase()));
+        }
+        if let Some(ref src) = self.src {
+            conditions.push(format!("src={}", src));
+        }
+        conditions.push(format!("action={}", self.action));
+
+        write!(f, "{}", conditions.join(","))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses a rule in the form of comma-separated conditions and an action like
+    /// `dst=203.0.113.0/24,port=80-443,protocol=tcp,src=10.6.0.1/32,action=direct`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = Rule::new(Action::Proxy(None));
+        let mut has_action = false;
+
+        for condition in s.split(',') {
+            let mut kv = condition.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            let value = kv
+                .next()
+                .ok_or(format!("invalid condition {}", condition))?
+                .trim();
+            match key {
+                "dst" => rule.set_dst(parse_network(value)?),
+                "port" => {
+                    let mut ports = value.splitn(2, '-');
+                    let start = parse_port(ports.next().unwrap())?;
+                    let end = match ports.next() {
+                        Some(end) => parse_port(end)?,
+                        None => start,
+                    };
+                    if start > end {
+                        return Err(format!("invalid port range {}", value));
+                    }
+                    rule.set_dst_ports(start, end);
+                }
+                "protocol" => match value {
+                    "tcp" => rule.set_protocol(LayerKinds::Tcp),
+                    "udp" => rule.set_protocol(LayerKinds::Udp),
+                    _ => return Err(format!("unknown protocol {}", value)),
+                },
+                "src" => rule.set_src(parse_network(value)?),
+                "action" => {
+                    rule.action = value.parse()?;
+                    has_action = true;
+                }
+                _ => return Err(format!("unknown condition {}", key)),
+            }
+        }
+        if !has_action {
+            return Err(String::from("missing action"));
+        }
+
+        Ok(rule)
+    }
+}
+
+fn parse_network(s: &str) -> Result<IpNetwork, String> {
+    s.parse().map_err(|_| format!("invalid network {}", s))
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    s.trim().parse().map_err(|_| format!("invalid port {}", s))
+}
+
+/// Represents a router which routes traffic with rules in order.
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+}
+
+impl Router {
+    /// Creates a new empty `Router`.
+    pub fn new() -> Router {
+        Router { rules: vec![] }
+    }
+
+    /// Appends a rule to the router.
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// Returns the rules of the router.
+    pub fn rules(&self) -> &Vec<Rule> {
+        &self.rules
+    }
+
+    /// Returns the action of the first rule matching the traffic, or forwarding through the
+    /// default proxy if no rule matches.
+    pub fn route(&self, protocol: LayerKind, src: SocketAddr, dst: SocketAddr) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.is_match(protocol, src, dst))
+            .map(|rule| rule.action().clone())
+            .unwrap_or(Action::Proxy(None))
+    }
+}
+
+#[test]
+fn rule_from_str() {
+    let rule: Rule = "dst=203.0.113.0/24,port=80-443,protocol=tcp,action=direct"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        rule.to_string(),
+        "dst=203.0.113.0/24,port=80-443,protocol=tcp,action=direct"
+    );
+    assert_eq!(
+        "src=10.6.0.1/32,action=proxy:game"
+            .parse::<Rule>()
+            .unwrap()
+            .action(),
+        &Action::Proxy(Some(String::from("game")))
+    );
+    assert!("dst=203.0.113.0/24".parse::<Rule>().is_err());
+    assert!("port=443-80,action=block".parse::<Rule>().is_err());
+}
+
+#[test]
+fn router_route() {
+    let mut router = Router::new();
+    router.push("protocol=udp,port=53,action=direct".parse().unwrap());
+    router.push("dst=198.51.100.0/24,action=block".parse().unwrap());
+
+    let src = "10.6.0.1:50000".parse().unwrap();
+    assert_eq!(
+        router.route(LayerKinds::Udp, src, "8.8.8.8:53".parse().unwrap()),
+        Action::Direct
+    );
+    assert_eq!(
+        router.route(LayerKinds::Tcp, src, "8.8.8.8:53".parse().unwrap()),
+        Action::Proxy(None)
+    );
+    assert_eq!(
+        router.route(LayerKinds::Tcp, src, "198.51.100.1:443".parse().unwrap()),
+        Action::Block
+    );
+}
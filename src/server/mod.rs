@@ -0,0 +1,606 @@
+//! Support for the embedded SOCKS5 server.
+
+use log::{debug, trace, warn};
+use std::collections::HashMap;
+use std::future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{self, TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_METHOD_NO_AUTH: u8 = 0;
+const SOCKS5_METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const SOCKS5_COMMAND_CONNECT: u8 = 1;
+const SOCKS5_COMMAND_UDP_ASSOCIATE: u8 = 3;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0;
+const SOCKS5_REPLY_HOST_UNREACHABLE: u8 = 4;
+const SOCKS5_REPLY_CONNECTION_REFUSED: u8 = 5;
+const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN_NAME: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Represents the buffer size of UDP relays.
+const RELAY_BUFFER_SIZE: usize = u16::MAX as usize;
+
+/// Represents an address in a SOCKS5 message, which may be a domain name to be resolved.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Addr {
+    SocketAddr(SocketAddr),
+    DomainName(String, u16),
+}
+
+impl Addr {
+    /// Resolves the address. Domain names are resolved by the host.
+    async fn resolve(self) -> io::Result<SocketAddr> {
+        match self {
+            Addr::SocketAddr(addr) => Ok(addr),
+            Addr::DomainName(name, port) => lookup(&name, port).await,
+        }
+    }
+}
+
+/// Represents an embedded SOCKS5 server. The server supports the CONNECT and the UDP ASSOCIATE
+/// commands without authentication, and connects to the destination from the host directly.
+#[derive(Debug)]
+pub struct SocksServer {
+    listener: TcpListener,
+}
+
+impl SocksServer {
+    /// Binds a new `SocksServer` to the given address.
+    pub async fn bind(addr: SocketAddr) -> io::Result<SocksServer> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(SocksServer { listener })
+    }
+
+    /// Returns the local address of the server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts clients and serves each of them in its own task. This method returns only if the
+    /// listener fails.
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(ref e) = serve(stream).await {
+                    debug!("serve SOCKS5 client {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Runs the server in a new task. Returns the local address of the server.
+    pub fn spawn(self) -> io::Result<SocketAddr> {
+        let addr = self.local_addr()?;
+        tokio::spawn(async move {
+            if let Err(ref e) = self.run().await {
+                warn!("embedded SOCKS5 server: {}", e);
+            }
+        });
+
+        Ok(addr)
+    }
+}
+
+async fn serve(mut stream: TcpStream) -> io::Result<()> {
+    // VER, NMETHODS and METHODS
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(invalid_data("unexpected SOCKS5 version"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS5_METHOD_NO_AUTH) {
+        stream
+            .write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NOT_ACCEPTABLE])
+            .await?;
+
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no acceptable SOCKS5 methods",
+        ));
+    }
+    stream
+        .write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NO_AUTH])
+        .await?;
+
+    // VER, CMD, RSV and ATYP
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(invalid_data("unexpected SOCKS5 version"));
+    }
+    // DST.ADDR and DST.PORT
+    let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let dst = match read_addr(&mut stream, header[3]).await {
+        Ok(dst) => dst,
+        Err(e) => {
+            let reply = match e.kind() {
+                io::ErrorKind::InvalidData => SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+                _ => SOCKS5_REPLY_HOST_UNREACHABLE,
+            };
+            write_reply(&mut stream, reply, unspecified).await?;
+
+            return Err(e);
+        }
+    };
+
+    match header[1] {
+        SOCKS5_COMMAND_CONNECT => serve_connect(stream, dst).await,
+        SOCKS5_COMMAND_UDP_ASSOCIATE => serve_associate(stream).await,
+        _ => {
+            write_reply(&mut stream, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED, unspecified).await?;
+
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "command not supported",
+            ))
+        }
+    }
+}
+
+async fn serve_connect(mut stream: TcpStream, dst: SocketAddr) -> io::Result<()> {
+    let mut remote = match TcpStream::connect(dst).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            let reply = match e.kind() {
+                io::ErrorKind::ConnectionRefused => SOCKS5_REPLY_CONNECTION_REFUSED,
+                _ => SOCKS5_REPLY_HOST_UNREACHABLE,
+            };
+            write_reply(&mut stream, reply, unspecified_addr(dst)).await?;
+
+            return Err(e);
+        }
+    };
+    write_reply(&mut stream, SOCKS5_REPLY_SUCCEEDED, remote.local_addr()?).await?;
+    trace!("connect to {}", dst);
+
+    io::copy_bidirectional(&mut stream, &mut remote).await?;
+
+    Ok(())
+}
+
+/// Relays datagrams of a client until the control connection is closed. Only datagrams from the
+/// IP address of the control connection are accepted, and the first one determines the port of
+/// the client.
+async fn serve_associate(mut stream: TcpStream) -> io::Result<()> {
+    let client_ip_addr = stream.peer_addr()?.ip();
+    // The relay is bound to the address the client connects to
+    let relay = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+    let outbound = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    // The host may not support IPv6
+    let outbound_ipv6 = UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+        .await
+        .ok();
+    write_reply(&mut stream, SOCKS5_REPLY_SUCCEEDED, relay.local_addr()?).await?;
+    trace!("associate {}", relay.local_addr()?);
+
+    // Domain names are resolved in other tasks so that the relay is not blocked, and the
+    // results are cached through the association
+    let (resolve_tx, mut resolve_rx) = mpsc::unbounded_channel();
+    let mut resolved = HashMap::new();
+
+    let mut client = None;
+    let mut control = [0u8; 1];
+    let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut outbound_buffer = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut outbound_ipv6_buffer = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let (payload, dst) = tokio::select! {
+            // The association terminates when the control connection is closed
+            result = stream.read(&mut control) => {
+                match result? {
+                    0 => return Ok(()),
+                    _ => continue,
+                }
+            }
+            result = relay.recv_from(&mut buffer) => {
+                let (size, addr) = result?;
+                if addr.ip() != client_ip_addr {
+                    continue;
+                }
+                client = Some(addr);
+
+                let (header_size, dst) = match parse_datagram(&buffer[..size]) {
+                    Ok(pair) => pair,
+                    Err(ref e) => {
+                        trace!("parse datagram from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                let payload = &buffer[header_size..size];
+                let resolved_dst = match dst {
+                    Addr::SocketAddr(dst) => Some(dst),
+                    Addr::DomainName(_, _) => resolved.get(&dst).copied(),
+                };
+
+                // Send
+                match resolved_dst {
+                    Some(dst) => send_to(&outbound, outbound_ipv6.as_ref(), payload, dst).await,
+                    None => {
+                        let payload = payload.to_vec();
+                        let resolve_tx = resolve_tx.clone();
+                        tokio::spawn(async move {
+                            let result = dst.clone().resolve().await;
+                            let _ = resolve_tx.send((dst, result, payload));
+                        });
+                    }
+                }
+
+                continue;
+            }
+            result = resolve_rx.recv() => {
+                // The channel is never closed since a sender is held by the loop
+                let (name, result, payload) = result.unwrap();
+
+                // Send
+                match result {
+                    Ok(dst) => {
+                        resolved.insert(name, dst);
+                        send_to(&outbound, outbound_ipv6.as_ref(), payload.as_slice(), dst).await;
+                    }
+                    Err(ref e) => trace!("resolve {:?}: {}", name, e),
+                }
+
+                continue;
+            }
+            result = outbound.recv_from(&mut outbound_buffer) => {
+                let (size, addr) = result?;
+                (&outbound_buffer[..size], addr)
+            }
+            result = recv_from(outbound_ipv6.as_ref(), &mut outbound_ipv6_buffer) => {
+                let (size, addr) = result?;
+                (&outbound_ipv6_buffer[..size], addr)
+            }
+        };
+
+        // Reply, RSV, FRAG, ATYP, DST.ADDR, DST.PORT and DATA
+        if let Some(client) = client {
+            let mut datagram = vec![0u8; 3];
+            put_addr(&mut datagram, dst);
+            datagram.extend_from_slice(payload);
+            relay.send_to(datagram.as_slice(), client).await?;
+        }
+    }
+}
+
+/// Sends a datagram to the destination through the outbound socket in the same IP version. The
+/// datagram is dropped if it cannot be sent.
+async fn send_to(
+    outbound: &UdpSocket,
+    outbound_ipv6: Option<&UdpSocket>,
+    payload: &[u8],
+    dst: SocketAddr,
+) {
+    let socket = match dst {
+        SocketAddr::V4(_) => Some(outbound),
+        SocketAddr::V6(_) => outbound_ipv6,
+    };
+    if let Some(socket) = socket {
+        if let Err(ref e) = socket.send_to(payload, dst).await {
+            trace!("send to {}: {}", dst, e);
+        }
+    }
+}
+
+/// Receives a single datagram message on the socket if any, or waits forever.
+async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => future::pending().await,
+    }
+}
+
+/// Parses the header of a datagram from the client. Returns the size of the header and the
+/// destination.
+fn parse_datagram(datagram: &[u8]) -> io::Result<(usize, Addr)> {
+    if datagram.len() < 4 {
+        return Err(invalid_data("datagram too short"));
+    }
+    // FRAG
+    if datagram[2] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "fragmentation not supported",
+        ));
+    }
+    // ATYP, DST.ADDR and DST.PORT
+    let rest = &datagram[4..];
+    let (len, dst) = match datagram[3] {
+        ATYP_IPV4 => {
+            if rest.len() < 6 {
+                return Err(invalid_data("datagram too short"));
+            }
+
+            let dst = SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3])),
+                u16::from_be_bytes([rest[4], rest[5]]),
+            );
+            (6, Addr::SocketAddr(dst))
+        }
+        ATYP_IPV6 => {
+            if rest.len() < 18 {
+                return Err(invalid_data("datagram too short"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rest[..16]);
+
+            let dst = SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                u16::from_be_bytes([rest[16], rest[17]]),
+            );
+            (18, Addr::SocketAddr(dst))
+        }
+        ATYP_DOMAIN_NAME => {
+            let len = *rest.first().ok_or(invalid_data("datagram too short"))? as usize;
+            if rest.len() < 1 + len + 2 {
+                return Err(invalid_data("datagram too short"));
+            }
+            let name = String::from_utf8_lossy(&rest[1..1 + len]).into_owned();
+            let port = u16::from_be_bytes([rest[1 + len], rest[2 + len]]);
+
+            (1 + len + 2, Addr::DomainName(name, port))
+        }
+        _ => return Err(invalid_data("address type not supported")),
+    };
+
+    Ok((4 + len, dst))
+}
+
+/// Reads an address in the given address type. Domain names are resolved by the host.
+async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> io::Result<SocketAddr> {
+    match atyp {
+        ATYP_IPV4 => {
+            let mut buf = [0u8; 6];
+            reader.read_exact(&mut buf).await?;
+
+            Ok(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])),
+                u16::from_be_bytes([buf[4], buf[5]]),
+            ))
+        }
+        ATYP_IPV6 => {
+            let mut buf = [0u8; 18];
+            reader.read_exact(&mut buf).await?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[..16]);
+
+            Ok(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                u16::from_be_bytes([buf[16], buf[17]]),
+            ))
+        }
+        ATYP_DOMAIN_NAME => {
+            let mut len = [0u8; 1];
+            reader.read_exact(&mut len).await?;
+            let len = len[0] as usize;
+            let mut buf = vec![0u8; len + 2];
+            reader.read_exact(&mut buf).await?;
+            let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+            let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+
+            lookup(&name, port).await
+        }
+        _ => Err(invalid_data("address type not supported")),
+    }
+}
+
+/// Resolves a domain name by the host.
+async fn lookup(name: &str, port: u16) -> io::Result<SocketAddr> {
+    net::lookup_host((name, port))
+        .await?
+        .next()
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "domain name not resolved",
+        ))
+}
+
+/// Writes a reply with the given bound address.
+async fn write_reply(stream: &mut TcpStream, reply: u8, addr: SocketAddr) -> io::Result<()> {
+    // VER, REP and RSV
+    let mut buf = vec![SOCKS5_VERSION, reply, 0];
+    // ATYP, BND.ADDR and BND.PORT
+    put_addr(&mut buf, addr);
+
+    stream.write_all(buf.as_slice()).await
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip_addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip_addr.octets());
+        }
+        IpAddr::V6(ip_addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip_addr.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn unspecified_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+fn invalid_data(error: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[tokio::test]
+async fn server_connect() {
+    use tokio::io::BufStream;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut stream_rx, mut stream_tx) = stream.split();
+        let _ = io::copy(&mut stream_rx, &mut stream_tx).await;
+    });
+
+    let server = SocksServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let remote = server.spawn().unwrap();
+
+    let stream = TcpStream::connect(remote).await.unwrap();
+    let mut stream = BufStream::new(stream);
+    async_socks5::connect(&mut stream, dst, None).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn server_associate() {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dst = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], addr).await.unwrap();
+        }
+    });
+
+    let server = SocksServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let remote = server.spawn().unwrap();
+
+    // Handshake and UDP ASSOCIATE
+    let mut stream = TcpStream::connect(remote).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [5, 0]);
+    stream
+        .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [5, 0, 0, 1]);
+    let relay = read_addr(&mut stream, ATYP_IPV4).await.unwrap();
+
+    // Relay
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut datagram = vec![0u8; 3];
+    put_addr(&mut datagram, dst);
+    datagram.extend_from_slice(b"hello");
+    socket.send_to(datagram.as_slice(), relay).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let size = socket.recv(&mut buf).await.unwrap();
+    assert_eq!(
+        parse_datagram(&buf[..size]).unwrap(),
+        (10, Addr::SocketAddr(dst))
+    );
+    assert_eq!(&buf[10..size], b"hello");
+}
+
+#[tokio::test]
+async fn server_associate_resolve() {
+    use tokio::time::{self, Duration};
+
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dst = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], addr).await.unwrap();
+        }
+    });
+
+    let server = SocksServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let remote = server.spawn().unwrap();
+
+    // Handshake and UDP ASSOCIATE
+    let mut stream = TcpStream::connect(remote).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    stream
+        .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    let relay = read_addr(&mut stream, ATYP_IPV4).await.unwrap();
+
+    // A domain name being resolved does not block the following datagrams
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut datagram = vec![0, 0, 0, ATYP_DOMAIN_NAME, 15];
+    datagram.extend_from_slice(b"pcap2socks.test");
+    datagram.extend_from_slice(&53u16.to_be_bytes());
+    datagram.extend_from_slice(b"world");
+    socket.send_to(datagram.as_slice(), relay).await.unwrap();
+    let mut datagram = vec![0u8; 3];
+    put_addr(&mut datagram, dst);
+    datagram.extend_from_slice(b"hello");
+    socket.send_to(datagram.as_slice(), relay).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let size = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        parse_datagram(&buf[..size]).unwrap(),
+        (10, Addr::SocketAddr(dst))
+    );
+    assert_eq!(&buf[10..size], b"hello");
+}
+
+#[test]
+fn server_parse_datagram() {
+    // IPv6
+    let dst: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+    let mut datagram = vec![0u8; 3];
+    put_addr(&mut datagram, dst);
+    datagram.extend_from_slice(b"hello");
+    assert_eq!(
+        parse_datagram(&datagram).unwrap(),
+        (22, Addr::SocketAddr(dst))
+    );
+
+    // Domain name
+    let mut datagram = vec![0, 0, 0, ATYP_DOMAIN_NAME, 9];
+    datagram.extend_from_slice(b"localhost");
+    datagram.extend_from_slice(&53u16.to_be_bytes());
+    datagram.extend_from_slice(b"hello");
+    assert_eq!(
+        parse_datagram(&datagram).unwrap(),
+        (16, Addr::DomainName(String::from("localhost"), 53))
+    );
+
+    // Truncated and fragmented
+    assert_eq!(
+        parse_datagram(&datagram[..15]).unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+    datagram[2] = 1;
+    assert_eq!(
+        parse_datagram(&datagram).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
@@ -1,7 +1,8 @@
 //! Support for statistics.
 
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Represents the traffic statistics.
 #[derive(Clone, Debug)]
@@ -29,3 +30,128 @@ impl Traffic {
         self.count.clone()
     }
 }
+
+/// Represents the statistics of reaped connections.
+#[derive(Clone, Debug)]
+pub struct Reaped {
+    keep_alive: Arc<AtomicUsize>,
+    idle: Arc<AtomicUsize>,
+}
+
+impl Reaped {
+    /// Creates a new `Reaped`.
+    pub fn new() -> Reaped {
+        Reaped {
+            keep_alive: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the count of connections reaped due to unanswered keep-alive probes.
+    pub fn keep_alive(&self) -> Arc<AtomicUsize> {
+        self.keep_alive.clone()
+    }
+
+    /// Returns the count of connections reaped due to the idle timeout.
+    pub fn idle(&self) -> Arc<AtomicUsize> {
+        self.idle.clone()
+    }
+}
+
+/// Represents the backpressure statistics of the proxied streams blocked by full TCP queues.
+#[derive(Clone, Debug)]
+pub struct Backpressure {
+    blocked: Arc<AtomicU64>,
+    count: Arc<AtomicUsize>,
+}
+
+impl Backpressure {
+    /// Creates a new `Backpressure`.
+    pub fn new() -> Backpressure {
+        Backpressure {
+            blocked: Arc::new(AtomicU64::new(0)),
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the total time the proxied streams spent blocked.
+    pub fn blocked(&self) -> Duration {
+        Duration::from_micros(self.blocked.load(Ordering::Relaxed))
+    }
+
+    /// Returns the count of times the proxied streams were blocked.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Adds a period the proxied stream was blocked.
+    pub fn add(&self, duration: Duration) {
+        self.blocked
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Represents the latency statistics. The round-trip time is smoothed over samples.
+#[derive(Clone, Debug)]
+pub struct Latency {
+    rtt: Arc<AtomicU64>,
+}
+
+impl Latency {
+    /// Creates a new `Latency`.
+    pub fn new() -> Latency {
+        Latency {
+            rtt: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the smoothed round-trip time, or `None` if it has not been measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    /// Updates the round-trip time with a sample.
+    pub fn update(&self, sample: Duration) {
+        let sample = (sample.as_micros() as u64).max(1);
+        let rtt = match self.rtt.load(Ordering::Relaxed) {
+            0 => sample,
+            rtt => (rtt * 7 + sample) / 8,
+        };
+        self.rtt.store(rtt.max(1), Ordering::Relaxed);
+    }
+
+    /// Resets the round-trip time as not measured.
+    pub fn reset(&self) {
+        self.rtt.store(0, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn backpressure_add() {
+    let backpressure = Backpressure::new();
+    assert_eq!(backpressure.blocked(), Duration::from_micros(0));
+
+    backpressure.add(Duration::from_millis(3));
+    backpressure.clone().add(Duration::from_millis(5));
+    assert_eq!(backpressure.blocked(), Duration::from_millis(8));
+    assert_eq!(backpressure.count(), 2);
+}
+
+#[test]
+fn latency_update() {
+    let latency = Latency::new();
+    assert_eq!(latency.rtt(), None);
+
+    latency.update(Duration::from_millis(80));
+    assert_eq!(latency.rtt(), Some(Duration::from_millis(80)));
+
+    latency.update(Duration::from_millis(160));
+    assert_eq!(latency.rtt(), Some(Duration::from_millis(90)));
+
+    latency.reset();
+    assert_eq!(latency.rtt(), None);
+}
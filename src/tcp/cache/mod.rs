@@ -1,11 +1,12 @@
 //! Support for caching and keeping send & receive window.
 
+use bytes::Bytes;
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::{self, Display};
 use std::io::{Error, ErrorKind, Result};
 use std::ops::Bound::Included;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::Timer;
 
@@ -16,13 +17,13 @@ const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 const ALLOC_IN_INITIAL: bool = false;
 
 /// Represents a queue cache. The `Queue` can hold continuos bytes constantly unless they are
-/// invalidated. The `Queue` can be used as a send window of a TCP connection.
+/// invalidated. The `Queue` can be used as a send window of a TCP connection. The bytes are held in
+/// the chunks they are appended in, so they are not copied in appending and can be sliced out.
 #[derive(Debug)]
 pub struct Queue {
-    buffer: Vec<u8>,
+    chunks: VecDeque<Bytes>,
     capacity: usize,
     sequence: u32,
-    head: usize,
     size: usize,
     clocks: VecDeque<(u32, Timer)>,
     retrans: Option<u32>,
@@ -38,13 +39,9 @@ impl Queue {
     /// Creates a new `Queue` with the specified capacity.
     pub fn with_capacity(capacity: usize, sequence: u32) -> Queue {
         Queue {
-            buffer: match ALLOC_IN_INITIAL {
-                true => Vec::with_capacity(capacity),
-                false => Vec::new(),
-            },
+            chunks: VecDeque::new(),
             capacity,
             sequence,
-            head: 0,
             size: 0,
             clocks: match ALLOC_IN_INITIAL {
                 true => VecDeque::with_capacity(capacity),
@@ -56,37 +53,20 @@ impl Queue {
 
     /// Appends some bytes to the end of the queue.
     pub fn append(&mut self, payload: &[u8], rto: u64) -> Result<()> {
-        if payload.len() > self.remaining() {
-            return Err(Error::new(ErrorKind::Other, "queue is full"));
-        }
-        if payload.len() > self.buffer.len() - self.size {
-            // Extend the buffer
-            let prev_len = self.buffer.len();
-            let prev_tail = self.tail();
-            let new_len = min(
-                self.capacity,
-                max(
-                    self.buffer
-                        .len()
-                        .checked_add(self.buffer.len() / 2)
-                        .unwrap_or(usize::MAX),
-                    self.size + payload.len(),
-                ),
-            );
-            self.buffer.resize(new_len, 0);
+        self.append_vectored(vec![Bytes::copy_from_slice(payload)], rto)
+    }
 
-            // From the begin of the buffer to the tail
-            if prev_tail <= self.head {
-                // From the begin to the mid of the buffer
-                let len_a = min(prev_tail, new_len - prev_len);
-                self.buffer.copy_within(..len_a, prev_len);
+    /// Appends some bytes to the end of the queue without copying them.
+    pub fn append_bytes(&mut self, payload: Bytes, rto: u64) -> Result<()> {
+        self.append_vectored(vec![payload], rto)
+    }
 
-                // From the mid to the tail of the buffer
-                let len_b = prev_tail - len_a;
-                if len_b > 0 {
-                    self.buffer.copy_within(len_a..len_a + len_b, 0);
-                }
-            }
+    /// Appends some bytes in multiple chunks to the end of the queue without copying them. The
+    /// bytes are recognized as a whole with a single clock.
+    pub fn append_vectored(&mut self, payloads: Vec<Bytes>, rto: u64) -> Result<()> {
+        let len = payloads.iter().map(|payload| payload.len()).sum::<usize>();
+        if len > self.remaining() {
+            return Err(Error::new(ErrorKind::Other, "queue is full"));
         }
 
         // Sequence and clock
@@ -96,18 +76,12 @@ impl Queue {
             .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
         self.clocks.push_back((sequence, Timer::new(rto)));
 
-        // From the tail to the end of the buffer
-        let tail = self.tail();
-        let len_a = min(self.buffer.len() - tail, payload.len());
-        self.buffer[tail..tail + len_a].copy_from_slice(&payload[..len_a]);
-
-        // From the begin of the buffer to the head
-        let len_b = payload.len() - len_a;
-        if len_b > 0 {
-            self.buffer[..len_b].copy_from_slice(&payload[len_a..]);
+        for payload in payloads {
+            if !payload.is_empty() {
+                self.chunks.push_back(payload);
+            }
         }
-
-        self.size += payload.len();
+        self.size += len;
 
         Ok(())
     }
@@ -121,10 +95,20 @@ impl Queue {
         if size <= MAX_U32_WINDOW_SIZE as usize {
             self.sequence = sequence;
             self.size = self.size.checked_sub(size).unwrap_or(0);
-            if self.size == 0 {
-                self.head = 0;
-            } else {
-                self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
+
+            // Pop chunks
+            let mut remaining = size;
+            while remaining > 0 {
+                let chunk = match self.chunks.front_mut() {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                if chunk.len() > remaining {
+                    *chunk = chunk.slice(remaining..);
+                    break;
+                }
+                remaining -= chunk.len();
+                self.chunks.pop_front();
             }
 
             let mut rtt = None;
@@ -198,9 +182,68 @@ impl Queue {
 
     /// Returns the payload from the certain sequence of the queue in the given size.
     pub fn get(&self, sequence: u32, size: usize) -> Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(size);
+        self.get_into(sequence, size, &mut payload)?;
+
+        Ok(payload)
+    }
+
+    /// Copies the payload from the certain sequence of the queue in the given size into the
+    /// buffer. The buffer is cleared before copying, and its allocation is reused.
+    pub fn get_into(&self, sequence: u32, size: usize, payload: &mut Vec<u8>) -> Result<()> {
+        payload.clear();
         if size == 0 {
-            return Ok(Vec::new());
+            return Ok(());
         }
+        let mut offset = self.distance(sequence, size)?;
+
+        let mut remaining = size;
+        for chunk in &self.chunks {
+            if offset >= chunk.len() {
+                offset -= chunk.len();
+                continue;
+            }
+
+            let len = min(remaining, chunk.len() - offset);
+            payload.extend_from_slice(&chunk[offset..offset + len]);
+            offset = 0;
+            remaining -= len;
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the payload from the certain sequence of the queue in the given size. The payload
+    /// is sliced out without being copied if it is held in a single chunk.
+    pub fn get_bytes(&self, sequence: u32, size: usize) -> Result<Bytes> {
+        if size == 0 {
+            return Ok(Bytes::new());
+        }
+        let mut offset = self.distance(sequence, size)?;
+
+        for chunk in &self.chunks {
+            if offset >= chunk.len() {
+                offset -= chunk.len();
+                continue;
+            }
+
+            if chunk.len() - offset >= size {
+                return Ok(chunk.slice(offset..offset + size));
+            }
+            break;
+        }
+
+        // The payload crosses chunks
+        let mut payload = Vec::with_capacity(size);
+        self.get_into(sequence, size, &mut payload)?;
+
+        Ok(Bytes::from(payload))
+    }
+
+    fn distance(&self, sequence: u32, size: usize) -> Result<usize> {
         let distance = sequence
             .checked_sub(self.sequence)
             .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
@@ -215,21 +258,7 @@ impl Queue {
             return Err(Error::new(ErrorKind::InvalidInput, "request size too big"));
         }
 
-        let mut payload = vec![0u8; size];
-
-        // From the head to the end of the buffer
-        let head = self.head + distance;
-        let head = head.checked_sub(self.buffer.len()).unwrap_or(head);
-        let length_a = min(size, self.buffer.len() - head);
-        payload[..length_a].copy_from_slice(&self.buffer[head..head + length_a]);
-
-        // From the begin of the buffer to the tail
-        let length_b = size - length_a;
-        if length_b > 0 {
-            payload[length_a..].copy_from_slice(&self.buffer[..length_b]);
-        }
-
-        Ok(payload)
+        Ok(distance)
     }
 
     /// Returns all the payload of the queue.
@@ -238,7 +267,7 @@ impl Queue {
     }
 
     /// Returns the payload which is timed out from the begin to the first byte which is not timed out.
-    #[deprecated = "use get_timed_out_and_update instead"]
+    #[deprecated = "use update_timed_out instead"]
     pub fn get_timed_out(&self) -> Vec<u8> {
         let mut recv_next = None;
         for clock in &self.clocks {
@@ -262,9 +291,9 @@ impl Queue {
         }
     }
 
-    /// Returns the payload which is timed out from the begin to the first byte which is not timed out
-    /// and update their timeout timer.
-    pub fn get_timed_out_and_update(&mut self, rto: u64) -> Vec<u8> {
+    /// Updates the timeout timer of the payload which is timed out from the begin to the first byte
+    /// which is not timed out, and returns its size.
+    pub fn update_timed_out(&mut self, rto: u64) -> usize {
         let mut recv_next = None;
         for clock in &self.clocks {
             let timer = clock.1;
@@ -299,7 +328,7 @@ impl Queue {
                 }
                 self.retrans = Some(recv_next);
 
-                self.get(self.sequence, size).unwrap()
+                size
             }
             None => {
                 // Update clock
@@ -307,11 +336,16 @@ impl Queue {
                 self.clocks.push_back((self.sequence, Timer::new(rto)));
                 self.retrans = Some(self.recv_next());
 
-                self.get_all()
+                self.size
             }
         }
     }
 
+    /// Returns the instant when the first byte in the queue will be timed out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.clocks.front().map(|clock| clock.1.deadline())
+    }
+
     /// Returns the capacity of the queue.
     pub fn capacity(&self) -> usize {
         self.capacity
@@ -332,14 +366,6 @@ impl Queue {
         self.capacity - self.size
     }
 
-    fn tail(&self) -> usize {
-        self.head
-            .checked_add(self.size)
-            .unwrap_or_else(|| self.size - (self.buffer.len() - self.head))
-            .checked_rem(self.buffer.len())
-            .unwrap_or(0)
-    }
-
     /// Returns the receive next of the queue.
     pub fn recv_next(&self) -> u32 {
         self.sequence
@@ -355,25 +381,23 @@ impl Queue {
 
 impl Display for Queue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let head = self.head;
-        // The second checked_sub will only fall on 0 if the length of the buffer is 0
-        let tail = self
-            .tail()
-            .checked_sub(1)
-            .unwrap_or(self.buffer.len().checked_sub(1).unwrap_or(0));
-
         write!(f, "[")?;
-        for i in 0..self.buffer.len() {
-            if i != 0 {
-                write!(f, ", ")?;
-            }
+        let mut i = 0;
+        for chunk in &self.chunks {
+            for byte in chunk.iter() {
+                if i != 0 {
+                    write!(f, ", ")?;
+                }
 
-            if i == head {
-                write!(f, "<")?;
-            }
-            write!(f, "{}", self.buffer[i])?;
-            if i == tail {
-                write!(f, ">")?;
+                if i == 0 {
+                    write!(f, "<")?;
+                }
+                write!(f, "{}", byte)?;
+                if i == self.size - 1 {
+                    write!(f, ">")?;
+                }
+
+                i += 1;
             }
         }
         write!(f, "]")
@@ -403,7 +427,7 @@ fn queue_append_overflow() {
     let v = (10..15).into_iter().collect::<Vec<_>>();
     q.append(v.as_slice(), 0).unwrap();
 
-    assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
+    assert_eq!(q.to_string(), "[<6, 7, 8, 9, 10, 11, 12, 13, 14>]");
 }
 
 #[test]
@@ -423,7 +447,7 @@ fn queue_append_overflow_overlapped() {
     let v = (11..15).into_iter().collect::<Vec<_>>();
     q.append(v.as_slice(), 0).unwrap();
 
-    assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
+    assert_eq!(q.to_string(), "[<6, 7, 8, 9, 10, 11, 12, 13, 14>]");
 }
 
 #[test]
@@ -441,7 +465,50 @@ fn queue_append_overflow_overlapped_2() {
     let v = (14..15).into_iter().collect::<Vec<_>>();
     q.append(v.as_slice(), 0).unwrap();
 
-    assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
+    assert_eq!(q.to_string(), "[<6, 7, 8, 9, 10, 11, 12, 13, 14>]");
+}
+
+#[test]
+fn queue_append_vectored() {
+    let mut q = Queue::with_capacity(9, 0);
+
+    q.append_vectored(
+        vec![Bytes::from_static(&[0, 1, 2]), Bytes::from_static(&[3, 4])],
+        0,
+    )
+    .unwrap();
+    assert_eq!(q.len(), 5);
+    assert_eq!(q.get(1, 3).unwrap(), vec![1, 2, 3]);
+
+    let mut v = vec![9];
+    q.get_into(3, 2, &mut v).unwrap();
+    assert_eq!(v, vec![3, 4]);
+}
+
+#[test]
+fn queue_get_bytes() {
+    let mut q = Queue::with_capacity(9, 0);
+
+    let b = Bytes::from(vec![0, 1, 2, 3]);
+    q.append_bytes(b.clone(), 0).unwrap();
+    q.append_bytes(Bytes::from_static(&[4, 5]), 0).unwrap();
+
+    // The payload in a single chunk is not copied
+    let r = q.get_bytes(1, 2).unwrap();
+    assert_eq!(r, Bytes::from_static(&[1, 2]));
+    assert_eq!(r.as_ptr(), b[1..].as_ptr());
+
+    // The payload crossing chunks is concatenated
+    assert_eq!(
+        q.get_bytes(2, 4).unwrap(),
+        Bytes::from_static(&[2, 3, 4, 5])
+    );
+
+    q.invalidate_to(3);
+    let r = q.get_bytes(3, 1).unwrap();
+    assert_eq!(r.as_ptr(), b[3..].as_ptr());
+    assert!(q.get_bytes(2, 1).is_err());
+    assert!(q.get_bytes(3, 4).is_err());
 }
 
 /// Represents a window cache. The `Window` can hold discontinuous bytes and pop out them when
@@ -685,6 +752,29 @@ impl Window {
         Ok(None)
     }
 
+    /// Appends some bytes to the window and returns continuous bytes from the beginning. If the
+    /// bytes are exactly the next ones and nothing is pending in the window, they are returned
+    /// without being copied.
+    pub fn append_bytes(&mut self, sequence: u32, payload: Bytes) -> Result<Option<Bytes>> {
+        if sequence == self.sequence && self.edges.is_empty() {
+            if payload.len() > self.capacity {
+                return Err(Error::new(ErrorKind::Other, "window is full"));
+            }
+            if payload.is_empty() {
+                return Ok(None);
+            }
+
+            self.sequence = self
+                .sequence
+                .checked_add(payload.len() as u32)
+                .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - self.sequence));
+
+            return Ok(Some(payload));
+        }
+
+        Ok(self.append(sequence, &payload)?.map(Bytes::from))
+    }
+
     /// Returns the sequence of the window.
     pub fn sequence(&self) -> u32 {
         self.sequence
@@ -892,3 +982,24 @@ fn window_append_prev_and_next() {
 
     assert_eq!(w.to_string(), "[0, 1, 2, <0, <4, 5>>]");
 }
+
+#[test]
+fn window_append_bytes() {
+    let mut w = Window::with_capacity(8, 0);
+
+    let b = Bytes::from_static(&[0, 1, 2]);
+    let r = w.append_bytes(0, b.clone()).unwrap().unwrap();
+    assert_eq!(r.as_ptr(), b.as_ptr());
+
+    assert!(w
+        .append_bytes(5, Bytes::from_static(&[5]))
+        .unwrap()
+        .is_none());
+
+    let r = w
+        .append_bytes(3, Bytes::from_static(&[3, 4]))
+        .unwrap()
+        .unwrap();
+    assert_eq!(r.as_ref(), &[3, 4, 5]);
+    assert_eq!(w.sequence(), 6);
+}
@@ -1,15 +1,24 @@
 //! Support for tracking TCP connections.
 
+use bytes::Bytes;
 use log::trace;
+use serde::Deserialize;
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::fmt::{self, Display};
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io;
+use tokio::sync::Notify;
+
+use crate::config::Config;
 
 mod cache;
 use cache::{Queue, Window};
+mod timer;
+pub use timer::TimerQueue;
 
 /// Represents a timer.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -32,16 +41,28 @@ impl Timer {
         self.instant.elapsed()
     }
 
-    /// Returns if the timer is timed out.
+    /// Returns the timeout of the timer.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns if the timer is timed out. The timer is timed out at its deadline, the same as
+    /// expiring in the `TimerQueue`.
     pub fn is_timedout(&self) -> bool {
-        self.instant.elapsed() > self.timeout
+        self.instant.elapsed() >= self.timeout
+    }
+
+    /// Returns the instant when the timer will be timed out.
+    pub fn deadline(&self) -> Instant {
+        self.instant + self.timeout
     }
 }
 
 /// Represents the max distance of `u32` values between packets in an `u32` window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
 /// Enumeration of congestion control algorithms.
 pub enum TcpCcAlgorithms {
     /// Represents the TCP Tahoe congestion control algorithm.
@@ -50,6 +71,33 @@ pub enum TcpCcAlgorithms {
     Reno,
     /// Represents the TCP CUBIC congestion control algorithm.
     Cubic,
+    /// Represents the TCP BBR congestion control algorithm.
+    Bbr,
+}
+
+impl Display for TcpCcAlgorithms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpCcAlgorithms::Tahoe => write!(f, "tahoe"),
+            TcpCcAlgorithms::Reno => write!(f, "reno"),
+            TcpCcAlgorithms::Cubic => write!(f, "cubic"),
+            TcpCcAlgorithms::Bbr => write!(f, "bbr"),
+        }
+    }
+}
+
+impl FromStr for TcpCcAlgorithms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tahoe" => Ok(TcpCcAlgorithms::Tahoe),
+            "reno" => Ok(TcpCcAlgorithms::Reno),
+            "cubic" => Ok(TcpCcAlgorithms::Cubic),
+            "bbr" => Ok(TcpCcAlgorithms::Bbr),
+            _ => Err(format!("unknown congestion control algorithm {}", s)),
+        }
+    }
 }
 
 /// Represents the initial slow start threshold rate for congestion window in a TCP connection.
@@ -60,8 +108,10 @@ pub trait TcpCc: Send + Sync {
     /// Indicates a TCP ACK event.
     fn ack(&mut self, size: usize);
 
-    /// Indicates a TCP ACK event with RTT.
-    fn ack_rtt(&mut self, size: usize, rtt: f64);
+    /// Indicates a TCP ACK event with the smoothed RTT in seconds.
+    fn ack_rtt(&mut self, size: usize, _: f64) {
+        self.ack(size);
+    }
 
     /// Indicates a TCP timed out event.
     fn timedout(&mut self);
@@ -69,15 +119,24 @@ pub trait TcpCc: Send + Sync {
     /// Indicates a TCP fast retransmission event.
     fn fast_retransmission(&mut self);
 
+    /// Indicates a RTT sample in seconds.
+    fn rtt(&mut self, _: f64) {}
+
     /// Returns the congestion window of the TCP connection.
     fn cwnd(&self) -> usize;
+
+    /// Returns the pacing rate in Bytes per second of the TCP connection, or `None` if the
+    /// sending is not paced.
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
 }
 
 /// Represents the TCP Tahoe congestion control state of a TCP connection.
 #[derive(Clone, Debug)]
 pub struct TcpTahoeCcState {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    dst: SocketAddr,
     mss: usize,
     cwnd: usize,
     ssthresh: usize,
@@ -86,7 +145,7 @@ pub struct TcpTahoeCcState {
 
 impl TcpTahoeCcState {
     /// Creates a new `TcpTahoeCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpTahoeCcState {
+    pub fn new(src: SocketAddr, dst: SocketAddr, mss: usize) -> TcpTahoeCcState {
         TcpTahoeCcState {
             src,
             dst,
@@ -197,8 +256,8 @@ impl Display for TcpTahoeCcState {
 /// Represents the TCP Reno congestion control state of a TCP connection.
 #[derive(Clone, Debug)]
 pub struct TcpRenoCcState {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    dst: SocketAddr,
     mss: usize,
     cwnd: usize,
     ssthresh: usize,
@@ -207,7 +266,7 @@ pub struct TcpRenoCcState {
 
 impl TcpRenoCcState {
     /// Creates a new `TcpRenoCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpRenoCcState {
+    pub fn new(src: SocketAddr, dst: SocketAddr, mss: usize) -> TcpRenoCcState {
         TcpRenoCcState {
             src,
             dst,
@@ -323,8 +382,8 @@ const CC_CUBIC_BETA: f64 = 0.7;
 /// Represents the TCP CUBIC congestion control state of a TCP connection.
 #[derive(Clone, Debug)]
 pub struct TcpCubicCcState {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    dst: SocketAddr,
     w_max: usize,
     w_last_max: usize,
     k: f64,
@@ -337,7 +396,7 @@ pub struct TcpCubicCcState {
 
 impl TcpCubicCcState {
     /// Creates a new `TcpCubicCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpCubicCcState {
+    pub fn new(src: SocketAddr, dst: SocketAddr, mss: usize) -> TcpCubicCcState {
         TcpCubicCcState {
             src,
             dst,
@@ -535,66 +594,426 @@ impl Display for TcpCubicCcState {
     }
 }
 
-/// Represents the receive window size.
-const RECV_WINDOW: u16 = u16::MAX;
+/// Represents the high gain of BBR in the startup, which is 2/ln(2).
+const CC_BBR_HIGH_GAIN: f64 = 2.885;
+/// Represents the congestion window gain of BBR in the bandwidth probing.
+const CC_BBR_CWND_GAIN: f64 = 2.0;
+/// Represents the pacing gain cycle of BBR in the bandwidth probing.
+const CC_BBR_PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Represents the length of the bottleneck bandwidth filter in rounds.
+const CC_BBR_BTL_BW_FILTER_LEN: u64 = 10;
+/// Represents the window of the minimum RTT filter in seconds.
+const CC_BBR_MIN_RTT_WINDOW: f64 = 10.0;
+/// Represents the duration of the RTT probing in seconds.
+const CC_BBR_PROBE_RTT_DURATION: f64 = 0.2;
+/// Represents the minimum congestion window rate of BBR.
+const CC_BBR_MIN_CWND_RATE: usize = 4;
+/// Represents the growth rate of the bottleneck bandwidth expected when the pipe is not full.
+const CC_BBR_FULL_BW_THRESH: f64 = 1.25;
+/// Represents the count of rounds without the expected growth before the pipe is full.
+const CC_BBR_FULL_BW_COUNT: usize = 3;
+
+/// Enumeration of BBR modes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TcpBbrModes {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+/// Represents the TCP BBR congestion control state of a TCP connection.
+#[derive(Clone, Debug)]
+pub struct TcpBbrCcState {
+    src: SocketAddr,
+    dst: SocketAddr,
+    mss: usize,
+    mode: TcpBbrModes,
+    cwnd: usize,
+    prior_cwnd: usize,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    btl_bw_filter: VecDeque<(u64, f64)>,
+    min_rtt: Option<f64>,
+    min_rtt_stamp: Instant,
+    probe_rtt_done: Option<Instant>,
+    delivered: usize,
+    round_count: u64,
+    next_round_delivered: usize,
+    sample_delivered: usize,
+    sample_stamp: Instant,
+    full_bw: f64,
+    full_bw_count: usize,
+    is_full_bw: bool,
+    cycle_index: usize,
+    cycle_stamp: Instant,
+}
+
+impl TcpBbrCcState {
+    /// Creates a new `TcpBbrCcState`.
+    pub fn new(src: SocketAddr, dst: SocketAddr, mss: usize) -> TcpBbrCcState {
+        let now = Instant::now();
+        TcpBbrCcState {
+            src,
+            dst,
+            mss,
+            mode: TcpBbrModes::Startup,
+            cwnd: mss.checked_mul(CC_BBR_MIN_CWND_RATE).unwrap_or(usize::MAX),
+            prior_cwnd: 0,
+            pacing_gain: CC_BBR_HIGH_GAIN,
+            cwnd_gain: CC_BBR_HIGH_GAIN,
+            btl_bw_filter: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: now,
+            probe_rtt_done: None,
+            delivered: 0,
+            round_count: 0,
+            next_round_delivered: 0,
+            sample_delivered: 0,
+            sample_stamp: now,
+            full_bw: 0.0,
+            full_bw_count: 0,
+            is_full_bw: false,
+            cycle_index: 0,
+            cycle_stamp: now,
+        }
+    }
+
+    fn min_cwnd(&self) -> usize {
+        self.mss
+            .checked_mul(CC_BBR_MIN_CWND_RATE)
+            .unwrap_or(usize::MAX)
+    }
+
+    fn set_cwnd(&mut self, cwnd: usize) {
+        self.cwnd = max(self.min_cwnd(), cwnd);
+        trace!(
+            "set TCP congestion window of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.cwnd
+        );
+    }
+
+    fn set_mode(&mut self, mode: TcpBbrModes, now: Instant) {
+        self.mode = mode;
+        match mode {
+            TcpBbrModes::Startup => {
+                self.pacing_gain = CC_BBR_HIGH_GAIN;
+                self.cwnd_gain = CC_BBR_HIGH_GAIN;
+            }
+            TcpBbrModes::Drain => {
+                self.pacing_gain = 1.0 / CC_BBR_HIGH_GAIN;
+                self.cwnd_gain = CC_BBR_HIGH_GAIN;
+            }
+            TcpBbrModes::ProbeBw => {
+                self.cycle_index = 0;
+                self.cycle_stamp = now;
+                self.pacing_gain = CC_BBR_PACING_GAIN_CYCLE[0];
+                self.cwnd_gain = CC_BBR_CWND_GAIN;
+            }
+            TcpBbrModes::ProbeRtt => {
+                self.pacing_gain = 1.0;
+                self.cwnd_gain = 1.0;
+            }
+        }
+        trace!(
+            "set TCP BBR mode of {} -> {} to {:?}",
+            self.dst,
+            self.src,
+            mode
+        );
+    }
+
+    /// Returns the estimated bottleneck bandwidth in Bytes per second.
+    fn btl_bw(&self) -> f64 {
+        match self.btl_bw_filter.front() {
+            Some((_, rate)) => *rate,
+            None => 0.0,
+        }
+    }
+
+    /// Returns the estimated bandwidth-delay product multiplied by the given gain, or `None` if
+    /// the model is not ready.
+    fn bdp(&self, gain: f64) -> Option<usize> {
+        let btl_bw = self.btl_bw();
+        match self.min_rtt {
+            Some(min_rtt) if btl_bw > 0.0 => {
+                Some((gain * btl_bw * min_rtt).min(usize::MAX as f64) as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Updates the delivery rate at the given instant, and returns if a new round is started.
+    fn update_delivery(&mut self, size: usize, now: Instant) -> bool {
+        self.delivered = self.delivered.checked_add(size).unwrap_or(usize::MAX);
+
+        // Round
+        let is_round_start = self.delivered >= self.next_round_delivered;
+        if is_round_start {
+            self.round_count += 1;
+            self.next_round_delivered = self.delivered.checked_add(self.cwnd).unwrap_or(usize::MAX);
+        }
+
+        // Delivery rate, sampled once in a minimum RTT
+        let elapsed = now
+            .saturating_duration_since(self.sample_stamp)
+            .as_secs_f64();
+        if elapsed > 0.0 && elapsed >= self.min_rtt.unwrap_or(0.0) {
+            let rate = (self.delivered - self.sample_delivered) as f64 / elapsed;
+            self.sample_delivered = self.delivered;
+            self.sample_stamp = now;
+
+            // Windowed max filter
+            while let Some((_, back_rate)) = self.btl_bw_filter.back() {
+                if *back_rate > rate {
+                    break;
+                }
+                self.btl_bw_filter.pop_back();
+            }
+            self.btl_bw_filter.push_back((self.round_count, rate));
+            while let Some((round, _)) = self.btl_bw_filter.front() {
+                if round + CC_BBR_BTL_BW_FILTER_LEN > self.round_count {
+                    break;
+                }
+                self.btl_bw_filter.pop_front();
+            }
+            trace!(
+                "update TCP BBR bottleneck bandwidth of {} -> {} to {}",
+                self.dst,
+                self.src,
+                self.btl_bw()
+            );
+        }
+
+        is_round_start
+    }
+
+    fn check_full_bw(&mut self, is_round_start: bool) {
+        if self.is_full_bw || !is_round_start {
+            return;
+        }
+
+        let btl_bw = self.btl_bw();
+        if btl_bw >= self.full_bw * CC_BBR_FULL_BW_THRESH {
+            self.full_bw = btl_bw;
+            self.full_bw_count = 0;
+            return;
+        }
+
+        self.full_bw_count += 1;
+        self.is_full_bw = self.full_bw_count >= CC_BBR_FULL_BW_COUNT;
+    }
+
+    fn update_mode(&mut self, is_round_start: bool, now: Instant) {
+        match self.mode {
+            TcpBbrModes::Startup => {
+                if self.is_full_bw {
+                    self.set_mode(TcpBbrModes::Drain, now);
+                }
+            }
+            TcpBbrModes::Drain => {
+                // Drain the queue built in the startup in a round
+                if is_round_start {
+                    self.set_mode(TcpBbrModes::ProbeBw, now);
+                }
+            }
+            TcpBbrModes::ProbeBw => {
+                let min_rtt = self.min_rtt.unwrap_or(0.0);
+                if now
+                    .saturating_duration_since(self.cycle_stamp)
+                    .as_secs_f64()
+                    > min_rtt
+                {
+                    self.cycle_index = (self.cycle_index + 1) % CC_BBR_PACING_GAIN_CYCLE.len();
+                    self.cycle_stamp = now;
+                    self.pacing_gain = CC_BBR_PACING_GAIN_CYCLE[self.cycle_index];
+                }
+            }
+            TcpBbrModes::ProbeRtt => {
+                if let Some(instant) = self.probe_rtt_done {
+                    if now >= instant {
+                        self.probe_rtt_done = None;
+                        self.set_cwnd(max(self.cwnd, self.prior_cwnd));
+                        match self.is_full_bw {
+                            true => self.set_mode(TcpBbrModes::ProbeBw, now),
+                            false => self.set_mode(TcpBbrModes::Startup, now),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates the model with the acknowledged size at the given instant.
+    fn ack_at(&mut self, size: usize, now: Instant) {
+        let is_round_start = self.update_delivery(size, now);
+        self.check_full_bw(is_round_start);
+        self.update_mode(is_round_start, now);
+
+        // Congestion window
+        let cwnd = self.cwnd.checked_add(size).unwrap_or(usize::MAX);
+        match self.bdp(self.cwnd_gain) {
+            Some(target) => {
+                if self.is_full_bw {
+                    self.set_cwnd(min(cwnd, target));
+                } else if self.cwnd < target {
+                    self.set_cwnd(cwnd);
+                }
+            }
+            None => self.set_cwnd(cwnd),
+        }
+    }
+
+    /// Updates the model with the RTT sample at the given instant.
+    fn rtt_at(&mut self, rtt: f64, now: Instant) {
+        let is_expired = now
+            .saturating_duration_since(self.min_rtt_stamp)
+            .as_secs_f64()
+            > CC_BBR_MIN_RTT_WINDOW;
+        if is_expired || self.min_rtt.map_or(true, |min_rtt| rtt <= min_rtt) {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = now;
+            trace!(
+                "update TCP BBR minimum RTT of {} -> {} to {}",
+                self.dst,
+                self.src,
+                rtt
+            );
+        }
+
+        // Probe RTT if the minimum RTT has not been refreshed for a while
+        if is_expired && self.mode != TcpBbrModes::ProbeRtt {
+            self.prior_cwnd = self.cwnd;
+            self.probe_rtt_done = Some(now + Duration::from_secs_f64(CC_BBR_PROBE_RTT_DURATION));
+            self.set_mode(TcpBbrModes::ProbeRtt, now);
+        }
+    }
+}
+
+impl TcpCc for TcpBbrCcState {
+    fn ack(&mut self, size: usize) {
+        self.ack_at(size, Instant::now());
+    }
+
+    fn rtt(&mut self, rtt: f64) {
+        self.rtt_at(rtt, Instant::now());
+    }
+
+    fn timedout(&mut self) {
+        self.prior_cwnd = self.cwnd;
+        self.set_cwnd(self.min_cwnd());
+    }
+
+    fn fast_retransmission(&mut self) {
+        // BBR does not treat losses as the signal of congestion
+    }
+
+    fn cwnd(&self) -> usize {
+        match self.mode {
+            TcpBbrModes::ProbeRtt => min(self.cwnd, self.min_cwnd()),
+            _ => self.cwnd,
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        let btl_bw = self.btl_bw();
+        match btl_bw > 0.0 {
+            true => Some(self.pacing_gain * btl_bw),
+            false => None,
+        }
+    }
+}
+
+impl Display for TcpBbrCcState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TCP BBR State: {} -> {}, cwnd = {}, btl_bw = {}, min_rtt = {}",
+            self.dst,
+            self.src,
+            self.cwnd,
+            self.btl_bw(),
+            self.min_rtt.unwrap_or(0.0)
+        )
+    }
+}
 
-/// Represents the maximum size of extra cache in a TCP connection.
-const MAX_QUEUE: usize = 16777216;
+/// Returns if the serial number `a` is before `b` in the modulo 2^32 space (RFC 1323 section
+/// 4.2.1, RFC 7323 section 5.3).
+fn is_serial_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
 
-/// Represents if the RTO computation is enabled.
-const ENABLE_RTO_COMPUTE: bool = true;
-/// Represents the initial timeout for a retransmission in a TCP connection.
-const INITIAL_RTO: u64 = 1000;
-/// Represents the minimum timeout for a retransmission in a TCP connection.
-const MIN_RTO: u64 = 1000;
-/// Represents the maximum timeout for a retransmission in a TCP connection.
-const MAX_RTO: u64 = 60000;
+/// Represents the receive window size.
+const RECV_WINDOW: u16 = u16::MAX;
 
 const RTO_K: f64 = 4.0;
 const RTO_ALPHA: f64 = 1.0 / 8.0;
 const RTO_BETA: f64 = 1.0 / 4.0;
 
-/// Represents if the congestion control is enabled.
-const ENABLE_CC: bool = true;
-/// Represents the congestion control algorithm.
-const CC_ALGORITHM: TcpCcAlgorithms = TcpCcAlgorithms::Reno;
-
 /// Represents the TX state of a TCP connection.
 pub struct TcpTxState {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    dst: SocketAddr,
     src_window: usize,
     src_wscale: Option<u8>,
     sack_perm: bool,
+    ts_recent: Option<u32>,
+    ts_offset: u32,
+    ts_origin: Instant,
     sequence: u32,
     acknowledgement: u32,
+    last_ack_sent: u32,
     window: u16,
     sacks: Option<Vec<(u32, u32)>>,
-    delayed_ack: bool,
+    delayed_ack: Option<Timer>,
+    delayed_ack_timeout: u64,
     cache: Queue,
     cache_syn: Option<Instant>,
     cache_fin: Option<Timer>,
     cache_fin_retrans: bool,
-    queue: VecDeque<u8>,
+    fin_acknowledged: bool,
+    persist: Option<Timer>,
+    last_recv: Instant,
+    last_active: Instant,
+    keep_alive_probes: usize,
+    enable_keep_alive: bool,
+    keep_alive_idle: u64,
+    keep_alive_interval: u64,
+    idle_timeout: u64,
+    queue: VecDeque<Bytes>,
+    queue_len: usize,
     queue_fin: bool,
+    max_queue: usize,
+    queue_notify: Arc<Notify>,
     rto: u64,
+    enable_rto_compute: bool,
+    min_rto: u64,
+    max_rto: u64,
     srtt: Option<f64>,
     rttvar: Option<f64>,
     cc: Option<Box<dyn TcpCc>>,
+    mss: usize,
+    pacing_tokens: f64,
+    pacing_stamp: Instant,
 }
 
 impl TcpTxState {
     /// Creates a new `TcpTxState`.
     pub fn new(
-        src: SocketAddrV4,
-        dst: SocketAddrV4,
+        src: SocketAddr,
+        dst: SocketAddr,
         sequence: u32,
         acknowledgement: u32,
         src_window: u16,
         src_wscale: Option<u8>,
         sack_perm: bool,
+        ts: Option<u32>,
         wscale: Option<u8>,
         mss: usize,
+        config: &Config,
     ) -> TcpTxState {
         TcpTxState {
             src,
@@ -602,11 +1021,16 @@ impl TcpTxState {
             src_window: (src_window as usize) << src_wscale.unwrap_or(0),
             src_wscale,
             sack_perm,
+            ts_recent: ts,
+            ts_offset: sequence,
+            ts_origin: Instant::now(),
             sequence,
             acknowledgement,
+            last_ack_sent: acknowledgement,
             window: RECV_WINDOW,
             sacks: None,
-            delayed_ack: false,
+            delayed_ack: None,
+            delayed_ack_timeout: config.delayed_ack_timeout,
             cache: Queue::with_capacity(
                 (RECV_WINDOW as usize) << wscale.unwrap_or(0) as usize,
                 sequence,
@@ -614,25 +1038,47 @@ impl TcpTxState {
             cache_syn: None,
             cache_fin: None,
             cache_fin_retrans: true,
+            fin_acknowledged: false,
+            persist: None,
+            last_recv: Instant::now(),
+            last_active: Instant::now(),
+            keep_alive_probes: 0,
+            enable_keep_alive: config.enable_keep_alive,
+            keep_alive_idle: config.keep_alive_idle,
+            keep_alive_interval: config.keep_alive_interval,
+            idle_timeout: config.idle_timeout,
             queue: VecDeque::new(),
+            queue_len: 0,
             queue_fin: false,
-            rto: INITIAL_RTO,
+            max_queue: config.max_queue,
+            queue_notify: Arc::new(Notify::new()),
+            rto: config.initial_rto,
+            enable_rto_compute: config.enable_rto_compute,
+            min_rto: config.min_rto,
+            max_rto: config.max_rto,
             srtt: None,
             rttvar: None,
-            cc: match ENABLE_CC {
-                true => match CC_ALGORITHM {
+            cc: match config.enable_cc {
+                true => match config.cc_algorithm(src.ip()) {
                     TcpCcAlgorithms::Tahoe => Some(Box::new(TcpTahoeCcState::new(src, dst, mss))),
                     TcpCcAlgorithms::Reno => Some(Box::new(TcpRenoCcState::new(src, dst, mss))),
                     TcpCcAlgorithms::Cubic => Some(Box::new(TcpCubicCcState::new(src, dst, mss))),
+                    TcpCcAlgorithms::Bbr => Some(Box::new(TcpBbrCcState::new(src, dst, mss))),
                 },
                 false => None,
             },
+            mss,
+            pacing_tokens: 0.0,
+            pacing_stamp: Instant::now(),
         }
     }
 
     /// Sets the source window of the TCP connection.
     pub fn set_src_window(&mut self, window: usize) {
         self.src_window = window;
+        if window > 0 {
+            self.clear_persist_timer();
+        }
         trace!(
             "set TCP source window of {} -> {} to {}",
             self.dst,
@@ -657,6 +1103,7 @@ impl TcpTxState {
 
     /// Adds acknowledgement to the TCP connection.
     pub fn add_acknowledgement(&mut self, n: u32) {
+        self.last_active = Instant::now();
         self.acknowledgement = self
             .acknowledgement
             .checked_add(n)
@@ -697,10 +1144,29 @@ impl TcpTxState {
         }
     }
 
-    /// Acknowledges to the given sequence of the TCP connection.
-    pub fn acknowledge(&mut self, sequence: u32) {
+    /// Sets the most recent timestamp of the source of the TCP connection, which will be echoed in
+    /// the TCP timestamps option.
+    pub fn set_ts_recent(&mut self, ts: u32) {
+        if self.ts_recent.is_some() {
+            self.ts_recent = Some(ts);
+            trace!(
+                "set TCP recent timestamp of {} -> {} to {}",
+                self.dst,
+                self.src,
+                ts
+            );
+        }
+    }
+
+    /// Acknowledges to the given sequence of the TCP connection. The timestamp echo reply is used
+    /// in measuring the RTT if the TCP timestamps option is negotiated.
+    pub fn acknowledge(&mut self, sequence: u32, ts_ecr: Option<u32>) {
         let mut rtt = None;
 
+        // Keep-alive
+        self.last_recv = Instant::now();
+        self.keep_alive_probes = 0;
+
         // SYN
         if let Some(instant) = self.cache_syn {
             let send_next = self.sequence;
@@ -726,8 +1192,14 @@ impl TcpTxState {
         if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
             // Invalidate cache
             let cache_rtt = self.cache.invalidate_to(sequence);
+
+            // RTTM, the timestamp echo reply gives samples even on retransmission
+            let ts_rtt = match self.ts_recent {
+                Some(_) => ts_ecr.and_then(|ts_ecr| self.ts_rtt(ts_ecr)),
+                None => None,
+            };
             if rtt.is_none() {
-                rtt = cache_rtt;
+                rtt = ts_rtt.or(cache_rtt);
             }
             trace!(
                 "acknowledge TCP cache of {} -> {} to sequence {}",
@@ -759,6 +1231,7 @@ impl TcpTxState {
 
                 self.cache_fin = None;
                 self.cache_fin_retrans = false;
+                self.fin_acknowledged = true;
                 trace!("acknowledge TCP FIN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -787,9 +1260,54 @@ impl TcpTxState {
         trace!("update TCP FIN timer of {} -> {}", self.dst, self.src);
     }
 
+    /// Updates the TCP persist timer of the TCP connection. The timeout starts from the RTO and is
+    /// doubled on each update, up to the maximum RTO.
+    pub fn update_persist_timer(&mut self) {
+        let timeout = match self.persist {
+            Some(timer) => min(
+                self.max_rto,
+                (timer.timeout().as_millis() as u64)
+                    .checked_mul(2)
+                    .unwrap_or(u64::MAX),
+            ),
+            None => self.rto,
+        };
+
+        self.persist = Some(Timer::new(timeout));
+        trace!(
+            "update TCP persist timer of {} -> {} to {}",
+            self.dst,
+            self.src,
+            timeout
+        );
+    }
+
+    /// Clears the TCP persist timer of the TCP connection.
+    pub fn clear_persist_timer(&mut self) {
+        if self.persist.take().is_some() {
+            trace!("clear TCP persist timer of {} -> {}", self.dst, self.src);
+        }
+    }
+
+    /// Adds a TCP keep-alive probe to the TCP connection.
+    pub fn add_keep_alive_probe(&mut self) {
+        self.keep_alive_probes = self.keep_alive_probes.checked_add(1).unwrap_or(usize::MAX);
+        trace!(
+            "add TCP keep-alive probe of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.keep_alive_probes
+        );
+    }
+
+    /// Records the acknowledgement of the TCP connection as the last one sent to the source.
+    pub fn set_ack_sent(&mut self) {
+        self.last_ack_sent = self.acknowledgement;
+    }
+
     /// Set the TCP delayed ACK to the cache of the TCP connection.
     pub fn set_delayed_ack(&mut self) {
-        self.delayed_ack = true;
+        self.delayed_ack = Some(Timer::new(self.delayed_ack_timeout));
 
         trace!(
             "set TCP delayed ACK to TCP cache of {} -> {}",
@@ -800,7 +1318,7 @@ impl TcpTxState {
 
     /// Clears the TCP delayed ACK from the cache of the TCP connection.
     pub fn clear_delayed_ack(&mut self) {
-        self.delayed_ack = false;
+        self.delayed_ack = None;
 
         trace!(
             "clear TCP delayed ACK to TCP cache of {} -> {}",
@@ -810,20 +1328,47 @@ impl TcpTxState {
     }
 
     /// Appends the payload from the queue to the cache of the TCP connection.
-    pub fn append_cache(&mut self, size: usize) -> io::Result<Vec<u8>> {
-        let payload = self.queue.drain(..size).collect::<Vec<_>>();
-
+    pub fn append_cache(&mut self, size: usize) -> io::Result<()> {
         // Append to cache
         trace!(
             "append {} Bytes to TCP cache of {} -> {}",
-            payload.len(),
+            size,
             self.dst,
             self.src
         );
-        // TODO: intermediate performance degradation
-        self.cache.append(&payload, self.rto)?;
+        if size > self.queue_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "queue is too short",
+            ));
+        }
+        if size > self.cache.remaining() {
+            return Err(io::Error::new(io::ErrorKind::Other, "queue is full"));
+        }
+        let mut payloads = Vec::new();
+        let mut remaining = size;
+        while remaining > 0 {
+            let mut chunk = self.queue.pop_front().unwrap();
+            if chunk.len() > remaining {
+                self.queue.push_front(chunk.split_off(remaining));
+            }
+            remaining -= chunk.len();
+            payloads.push(chunk);
+        }
+        self.queue_len -= size;
+        self.cache.append_vectored(payloads, self.rto)?;
+        if size > 0 {
+            self.queue_notify.notify_one();
+        }
+        self.last_active = Instant::now();
+
+        // Pacing
+        if self.cc.as_ref().and_then(|cc| cc.pacing_rate()).is_some() {
+            self.pacing_tokens = (self.pacing_window() as f64 - size as f64).max(0.0);
+            self.pacing_stamp = Instant::now();
+        }
 
-        Ok(payload)
+        Ok(())
     }
 
     /// Appends the TCP FIN from the queue to the cache of the TCP connection.
@@ -837,13 +1382,17 @@ impl TcpTxState {
         self.update_fin_timer();
     }
 
-    /// Appends the payload to the queue of the TCP connection.
-    pub fn append_queue(&mut self, payload: &[u8]) {
-        // TODO: major performance degradation
-        self.queue.extend(payload);
+    /// Appends the payload to the queue of the TCP connection. The payload is held without being
+    /// copied until it is sent.
+    pub fn append_queue(&mut self, payload: Bytes) {
+        let size = payload.len();
+        self.queue_len += size;
+        if size > 0 {
+            self.queue.push_back(payload);
+        }
         trace!(
             "append {} Bytes to TCP queue of {} -> {}",
-            payload.len(),
+            size,
             self.dst,
             self.src
         );
@@ -860,8 +1409,8 @@ impl TcpTxState {
     }
 
     fn set_rto(&mut self, rto: u64) {
-        if ENABLE_RTO_COMPUTE {
-            let rto = min(MAX_RTO, max(MIN_RTO, rto));
+        if self.enable_rto_compute {
+            let rto = min(self.max_rto, max(self.min_rto, rto));
 
             self.rto = rto;
             trace!("set TCP RTO of {} -> {} to {}", self.dst, self.src, rto);
@@ -877,6 +1426,11 @@ impl TcpTxState {
     pub fn update_rto(&mut self, rtt: Duration) {
         let rtt = rtt.as_secs_f64();
 
+        // Congestion control
+        if let Some(cc) = &mut self.cc {
+            cc.rtt(rtt);
+        }
+
         let srtt;
         let rttvar;
         match self.srtt {
@@ -942,6 +1496,11 @@ impl TcpTxState {
         self.acknowledgement
     }
 
+    /// Returns the last acknowledgement sent to the source of the TCP connection.
+    pub fn last_ack_sent(&self) -> u32 {
+        self.last_ack_sent
+    }
+
     /// Returns the window of the TCP connection.
     pub fn window(&self) -> u16 {
         self.window
@@ -959,7 +1518,7 @@ impl TcpTxState {
 
     /// Returns if the TCP delayed ACK exists of the TCP connection.
     pub fn delayed_ack(&self) -> bool {
-        self.delayed_ack
+        self.delayed_ack.is_some()
     }
 
     /// Returns the cache of the TCP connection.
@@ -982,9 +1541,48 @@ impl TcpTxState {
         self.cache_fin
     }
 
-    /// Returns the queue of the TCP connection.
-    pub fn queue(&self) -> &VecDeque<u8> {
-        &self.queue
+    /// Returns if the TCP FIN of the TCP connection is acknowledged.
+    pub fn fin_acknowledged(&self) -> bool {
+        self.fin_acknowledged
+    }
+
+    /// Returns the TCP persist timer of the TCP connection.
+    pub fn persist(&self) -> Option<Timer> {
+        self.persist
+    }
+
+    /// Returns the count of unanswered TCP keep-alive probes of the TCP connection.
+    pub fn keep_alive_probes(&self) -> usize {
+        self.keep_alive_probes
+    }
+
+    /// Returns the instant when the next TCP keep-alive probe of the TCP connection should be
+    /// sent. The first probe is sent after the source has been silent for the keep-alive idle
+    /// time, and the following ones are sent in every keep-alive interval.
+    pub fn keep_alive_deadline(&self) -> Option<Instant> {
+        match self.enable_keep_alive {
+            true => Some(
+                self.last_recv
+                    + Duration::from_millis(self.keep_alive_idle)
+                    + Duration::from_millis(self.keep_alive_interval)
+                        * self.keep_alive_probes as u32,
+            ),
+            false => None,
+        }
+    }
+
+    /// Returns the instant when the TCP connection should be recognized as idle, which is the idle
+    /// timeout after the last payload in either direction.
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        match self.idle_timeout {
+            0 => None,
+            idle_timeout => Some(self.last_active + Duration::from_millis(idle_timeout)),
+        }
+    }
+
+    /// Returns the length of the queue of the TCP connection.
+    pub fn queue_len(&self) -> usize {
+        self.queue_len
     }
 
     /// Returns if the TCP FIN is in the queue of the TCP connection.
@@ -994,7 +1592,35 @@ impl TcpTxState {
 
     /// Returns the remaining size of the queue of the TCP connection.
     pub fn queue_remaining(&self) -> usize {
-        MAX_QUEUE.checked_sub(self.queue().len()).unwrap_or(0)
+        self.max_queue.checked_sub(self.queue_len).unwrap_or(0)
+    }
+
+    /// Returns the notification of the queue of the TCP connection. It is notified when the queue
+    /// is drained into the cache, or the TCP connection is dropped.
+    pub fn queue_notify(&self) -> Arc<Notify> {
+        Arc::clone(&self.queue_notify)
+    }
+
+    /// Returns the timestamp value and the timestamp echo reply of the TCP connection if the TCP
+    /// timestamps option is negotiated.
+    pub fn ts(&self) -> Option<(u32, u32)> {
+        self.ts_recent.map(|ts_recent| (self.ts_val(), ts_recent))
+    }
+
+    fn ts_val(&self) -> u32 {
+        self.ts_offset
+            .wrapping_add(self.ts_origin.elapsed().as_millis() as u32)
+    }
+
+    fn ts_rtt(&self, ts_ecr: u32) -> Option<Duration> {
+        let rtt = self.ts_val().wrapping_sub(ts_ecr) as u64;
+
+        // Ignore the echo reply which is from the future or too old
+        if rtt > self.max_rto {
+            None
+        } else {
+            Some(Duration::from_millis(rtt))
+        }
     }
 
     /// Returns the RTO of the TCP connection.
@@ -1004,7 +1630,10 @@ impl TcpTxState {
 
     /// Returns the next RTO of the TCP connection.
     pub fn next_rto(&self) -> u64 {
-        max(MIN_RTO, self.rto.checked_mul(2).unwrap_or(MAX_RTO))
+        max(
+            self.min_rto,
+            self.rto.checked_mul(2).unwrap_or(self.max_rto),
+        )
     }
 
     /// Returns the congestion control state of the TCP connection.
@@ -1026,6 +1655,59 @@ impl TcpTxState {
             self.src_window
         }
     }
+
+    /// Returns the pacing window of the TCP connection. The pacing window indicates how much
+    /// payload can be sent next under the pacing rate, and is limited by the congestion window.
+    pub fn pacing_window(&self) -> usize {
+        if let Some(cc) = &self.cc {
+            if let Some(rate) = cc.pacing_rate() {
+                let tokens = self.pacing_tokens + rate * self.pacing_stamp.elapsed().as_secs_f64();
+
+                return tokens.min(cc.cwnd() as f64) as usize;
+            }
+        }
+
+        usize::MAX
+    }
+
+    /// Returns the instant when the pacing window allows sending the next segment from the queue,
+    /// or `None` if the sending is not limited by the pacing. The instant is derived from the
+    /// pacing tokens left at the last sending and the pacing rate.
+    pub fn pacing_deadline(&self) -> Option<Instant> {
+        let rate = self.cc.as_ref()?.pacing_rate()?;
+        let remaining = self
+            .send_window()
+            .checked_sub(self.cache.len())
+            .unwrap_or(0);
+        let size = min(min(remaining, self.queue_len), self.mss);
+        if size == 0 || self.pacing_window() >= size {
+            return None;
+        }
+
+        let tokens = size as f64 - self.pacing_tokens;
+        Some(self.pacing_stamp + Duration::from_secs_f64(tokens.max(0.0) / rate))
+    }
+
+    /// Returns the instant when the next timed event of the TCP connection should be triggered.
+    /// The timed events include retransmitting timed out data or FIN, probing the zero window,
+    /// sending the paced data, sending the delayed ACK, probing the keep-alive and reaping the
+    /// idle connection.
+    pub fn deadline(&self) -> Option<Instant> {
+        let retrans = match self.cache.is_empty() {
+            true => self.cache_fin.map(|timer| timer.deadline()),
+            false => self.cache.deadline(),
+        };
+        let persist = self.persist.map(|timer| timer.deadline());
+        let pacing = self.pacing_deadline();
+        let delayed_ack = self.delayed_ack.map(|timer| timer.deadline());
+        let keep_alive = self.keep_alive_deadline();
+        let idle = self.idle_deadline();
+
+        [retrans, persist, pacing, delayed_ack, keep_alive, idle]
+            .iter()
+            .filter_map(|deadline| *deadline)
+            .min()
+    }
 }
 
 impl Display for TcpTxState {
@@ -1034,11 +1716,18 @@ impl Display for TcpTxState {
     }
 }
 
+impl Drop for TcpTxState {
+    fn drop(&mut self) {
+        // Wake up the stream waiting for the queue
+        self.queue_notify.notify_one();
+    }
+}
+
 /// Represents the RX state of a TCP connection.
 #[derive(Debug)]
 pub struct TcpRxState {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    dst: SocketAddr,
     recv_next: u32,
     acknowledgement: u32,
     duplicate: usize,
@@ -1047,16 +1736,19 @@ pub struct TcpRxState {
     sack_perm: bool,
     cache: Window,
     fin_sequence: Option<u32>,
+    ts_recent: Option<u32>,
 }
 
 impl TcpRxState {
-    /// Creates a new `TcpRxState`, the sequence is the sequence in the TCP SYN packet.
+    /// Creates a new `TcpRxState`, the sequence and the timestamp are the ones in the TCP SYN
+    /// packet.
     pub fn new(
-        src: SocketAddrV4,
-        dst: SocketAddrV4,
+        src: SocketAddr,
+        dst: SocketAddr,
         sequence: u32,
         wscale: u8,
         sack_perm: bool,
+        ts: Option<u32>,
     ) -> TcpRxState {
         let recv_next = sequence.checked_add(1).unwrap_or(0);
 
@@ -1073,6 +1765,27 @@ impl TcpRxState {
             sack_perm,
             cache: Window::with_capacity((RECV_WINDOW as usize) << wscale as usize, recv_next),
             fin_sequence: None,
+            ts_recent: ts,
+        }
+    }
+
+    /// Updates the most recent timestamp of the source of the TCP connection. The timestamp is
+    /// only updated by the segment which covers the last acknowledgement sent (RFC 7323 section
+    /// 4.3).
+    pub fn update_ts_recent(&mut self, ts: u32, sequence: u32, last_ack_sent: u32) {
+        if let Some(ts_recent) = self.ts_recent {
+            if !is_serial_before(ts, ts_recent) && !is_serial_before(last_ack_sent, sequence) {
+                self.ts_recent = Some(ts);
+            }
+        }
+    }
+
+    /// Returns if the segment with the given timestamp should be rejected by PAWS (protection
+    /// against wrapped sequences), which means the timestamp is older than the most recent one.
+    pub fn is_paws_rejected(&self, ts: Option<u32>) -> bool {
+        match (self.ts_recent, ts) {
+            (Some(ts_recent), Some(ts)) => is_serial_before(ts, ts_recent),
+            _ => false,
         }
     }
 
@@ -1140,14 +1853,14 @@ impl TcpRxState {
     }
 
     /// Appends the payload to the cache of the TCP connection.
-    pub fn append_cache(&mut self, sequence: u32, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    pub fn append_cache(&mut self, sequence: u32, payload: Bytes) -> io::Result<Option<Bytes>> {
         trace!(
             "append {} Bytes to TCP cache of {} -> {}",
             payload.len(),
             self.src,
             self.dst
         );
-        self.cache.append(sequence, payload)
+        self.cache.append_bytes(sequence, payload)
     }
 
     /// Sets the TCP FIN sequence of the TCP connection.
@@ -1201,6 +1914,12 @@ impl TcpRxState {
     pub fn fin_sequence(&self) -> Option<u32> {
         self.fin_sequence
     }
+
+    /// Returns the most recent timestamp of the source of the TCP connection if the TCP
+    /// timestamps option is negotiated.
+    pub fn ts_recent(&self) -> Option<u32> {
+        self.ts_recent
+    }
 }
 
 impl Display for TcpRxState {
@@ -1208,3 +1927,209 @@ impl Display for TcpRxState {
         write!(f, "TCP RX State: {} -> {}", self.src, self.dst)
     }
 }
+
+#[test]
+fn timer_timedout_at_deadline() {
+    let timer = Timer::new(0);
+    assert!(timer.is_timedout());
+
+    let mut queue = TimerQueue::new();
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    queue.schedule(dst, src, timer.deadline());
+    assert_eq!(queue.pop_expired(timer.deadline()), vec![(dst, src)]);
+}
+
+#[test]
+fn bbr_model() {
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut cc = TcpBbrCcState::new(src, dst, 1000);
+    let now = cc.sample_stamp;
+    assert_eq!(cc.cwnd(), 4000);
+    assert_eq!(cc.pacing_rate(), None);
+
+    // Startup grows the window below the target
+    cc.rtt_at(0.05, now);
+    cc.ack_at(4000, now + Duration::from_millis(125));
+    assert_eq!(cc.mode, TcpBbrModes::Startup);
+    assert_eq!(cc.cwnd(), 8000);
+    assert_eq!(cc.btl_bw(), 32000.0);
+    assert_eq!(cc.pacing_rate(), Some(CC_BBR_HIGH_GAIN * 32000.0));
+
+    // Losses do not reduce the window
+    cc.fast_retransmission();
+    assert_eq!(cc.cwnd(), 8000);
+
+    // Probe RTT after the minimum RTT expires
+    let now = now + Duration::from_secs(11);
+    cc.rtt_at(0.1, now);
+    assert_eq!(cc.mode, TcpBbrModes::ProbeRtt);
+    assert_eq!(cc.min_rtt, Some(0.1));
+    assert_eq!(cc.cwnd(), 4000);
+
+    // Back to the startup after the RTT probing since the pipe is not full
+    cc.ack_at(1000, now + Duration::from_millis(100));
+    assert_eq!(cc.mode, TcpBbrModes::ProbeRtt);
+    cc.ack_at(1000, now + Duration::from_millis(300));
+    assert_eq!(cc.mode, TcpBbrModes::Startup);
+    assert_eq!(cc.cwnd(), 9000);
+}
+
+#[test]
+fn tcp_pacing_deadline() {
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut config = Config::default();
+    config.enable_keep_alive = false;
+    let mut state = TcpTxState::new(
+        src, dst, 0, 0, 65535, None, false, None, None, 1000, &config,
+    );
+    let mut cc = TcpBbrCcState::new(src, dst, 1000);
+    let now = cc.sample_stamp;
+    cc.rtt_at(0.05, now);
+    cc.ack_at(4000, now + Duration::from_millis(125));
+    let rate = cc.pacing_rate().unwrap();
+    state.cc = Some(Box::new(cc));
+
+    // Nothing is waiting for the pacing
+    assert_eq!(state.pacing_deadline(), None);
+
+    // The next segment is released after the pacing tokens are refilled
+    state.append_queue(Bytes::from(vec![0u8; 3000]));
+    state.pacing_tokens = 200.0;
+    state.pacing_stamp = Instant::now();
+    let deadline = state.pacing_stamp + Duration::from_secs_f64(800.0 / rate);
+    assert_eq!(state.pacing_deadline(), Some(deadline));
+    assert_eq!(state.deadline(), Some(deadline));
+
+    // The segment is not limited by the pacing
+    state.pacing_tokens = 1000.0;
+    assert_eq!(state.pacing_deadline(), None);
+    assert_eq!(state.deadline(), None);
+}
+
+#[test]
+fn tcp_paws() {
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut state = TcpRxState::new(src, dst, 0, 0, false, Some(u32::MAX - 1));
+    assert!(!state.is_paws_rejected(None));
+    assert!(!state.is_paws_rejected(Some(u32::MAX - 1)));
+    assert!(state.is_paws_rejected(Some(u32::MAX - 2)));
+
+    // The timestamp wraps around
+    state.update_ts_recent(1, 1, 1);
+    assert_eq!(state.ts_recent(), Some(1));
+    assert!(state.is_paws_rejected(Some(u32::MAX)));
+    assert!(!state.is_paws_rejected(Some(2)));
+
+    // Segments after the last acknowledgement sent do not update the timestamp
+    state.update_ts_recent(2, 100, 1);
+    assert_eq!(state.ts_recent(), Some(1));
+    state.update_ts_recent(2, 1, 0);
+    assert_eq!(state.ts_recent(), Some(1));
+
+    // Older timestamps do not update the timestamp
+    state.update_ts_recent(0, 1, 1);
+    assert_eq!(state.ts_recent(), Some(1));
+
+    // The sequence wraps around
+    let mut state = TcpRxState::new(src, dst, u32::MAX - 1, 0, false, Some(1));
+    state.update_ts_recent(2, u32::MAX, 0);
+    assert_eq!(state.ts_recent(), Some(2));
+
+    // The timestamps option is not negotiated
+    let state = TcpRxState::new(src, dst, 0, 0, false, None);
+    assert!(!state.is_paws_rejected(Some(0)));
+}
+
+#[test]
+fn tcp_persist_backoff() {
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut config = Config::default();
+    config.initial_rto = 1000;
+    config.max_rto = 5000;
+    let mut state = TcpTxState::new(src, dst, 0, 0, 0, None, false, None, None, 1460, &config);
+    assert!(state.persist().is_none());
+
+    // The timeout is doubled up to the maximum RTO
+    let mut timeouts = Vec::new();
+    for _ in 0..5 {
+        state.update_persist_timer();
+        timeouts.push(state.persist().unwrap().timeout().as_millis());
+    }
+    assert_eq!(timeouts, vec![1000, 2000, 4000, 5000, 5000]);
+    assert_eq!(
+        state.deadline(),
+        state.persist().map(|timer| timer.deadline())
+    );
+
+    // The window is opened
+    state.set_src_window(1460);
+    assert!(state.persist().is_none());
+
+    // The timeout starts from the RTO again
+    state.update_persist_timer();
+    assert_eq!(
+        state.persist().unwrap().timeout(),
+        Duration::from_millis(1000)
+    );
+}
+
+#[test]
+fn tcp_keep_alive_and_idle_deadline() {
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "203.0.113.1:80".parse().unwrap();
+    let mut config = Config::default();
+    config.keep_alive_idle = 60000;
+    config.keep_alive_interval = 10000;
+    config.idle_timeout = 300000;
+    let mut state = TcpTxState::new(src, dst, 0, 0, 0, None, false, None, None, 1460, &config);
+
+    // The first probe is sent after the keep-alive idle time
+    let last_recv = state.last_recv;
+    let last_active = state.last_active;
+    assert_eq!(
+        state.keep_alive_deadline(),
+        Some(last_recv + Duration::from_millis(60000))
+    );
+
+    // The following probes are sent in every keep-alive interval
+    for _ in 0..3 {
+        state.add_keep_alive_probe();
+    }
+    assert_eq!(state.keep_alive_probes(), 3);
+    assert_eq!(
+        state.keep_alive_deadline(),
+        Some(last_recv + Duration::from_millis(90000))
+    );
+
+    // Probes do not refresh the idle deadline
+    assert_eq!(
+        state.idle_deadline(),
+        Some(last_active + Duration::from_millis(300000))
+    );
+    assert_eq!(
+        state.deadline(),
+        Some(last_recv + Duration::from_millis(90000))
+    );
+
+    // Any segment from the source resets the probes
+    state.acknowledge(0, None);
+    assert_eq!(state.keep_alive_probes(), 0);
+    assert!(state.keep_alive_deadline().unwrap() < last_recv + Duration::from_millis(90000));
+
+    // Payload refreshes the idle deadline
+    state.add_acknowledgement(1);
+    assert!(state.idle_deadline().unwrap() >= last_active + Duration::from_millis(300000));
+
+    // Both are disabled
+    config.enable_keep_alive = false;
+    config.idle_timeout = 0;
+    let state = TcpTxState::new(src, dst, 0, 0, 0, None, false, None, None, 1460, &config);
+    assert_eq!(state.keep_alive_deadline(), None);
+    assert_eq!(state.idle_deadline(), None);
+    assert_eq!(state.deadline(), None);
+}
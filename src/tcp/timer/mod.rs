@@ -0,0 +1,111 @@
+//! Support for scheduling timed events of TCP connections.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Represents a deadline queue of TCP connections. Each TCP connection holds at most one deadline
+/// in the queue, and the deadlines are popped in order.
+#[derive(Debug, Default)]
+pub struct TimerQueue {
+    deadlines: BTreeSet<(Instant, SocketAddr, SocketAddr)>,
+    schedules: HashMap<(SocketAddr, SocketAddr), Instant>,
+}
+
+impl TimerQueue {
+    /// Creates a new `TimerQueue`.
+    pub fn new() -> TimerQueue {
+        TimerQueue {
+            deadlines: BTreeSet::new(),
+            schedules: HashMap::new(),
+        }
+    }
+
+    /// Schedules a deadline of a TCP connection. The deadline is ignored if the TCP connection
+    /// already has an earlier one. Returns if the deadline becomes the earliest one in the queue.
+    pub fn schedule(&mut self, dst: SocketAddr, src: SocketAddr, deadline: Instant) -> bool {
+        let key = (src, dst);
+
+        if let Some(prev) = self.schedules.get(&key) {
+            if *prev <= deadline {
+                return false;
+            }
+            self.deadlines.remove(&(*prev, src, dst));
+        }
+        self.schedules.insert(key, deadline);
+        self.deadlines.insert((deadline, src, dst));
+
+        self.next() == Some(deadline)
+    }
+
+    /// Cancels the deadline of a TCP connection.
+    pub fn cancel(&mut self, dst: SocketAddr, src: SocketAddr) {
+        let key = (src, dst);
+
+        if let Some(prev) = self.schedules.remove(&key) {
+            self.deadlines.remove(&(prev, src, dst));
+        }
+    }
+
+    /// Pops all the TCP connections whose deadline is not later than the given instant, in
+    /// `(dst, src)` pairs.
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<(SocketAddr, SocketAddr)> {
+        let mut expired = Vec::new();
+        while let Some(&(deadline, src, dst)) = self.deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.remove(&(deadline, src, dst));
+            self.schedules.remove(&(src, dst));
+            expired.push((dst, src));
+        }
+
+        expired
+    }
+
+    /// Returns the earliest deadline in the queue.
+    pub fn next(&self) -> Option<Instant> {
+        self.deadlines.iter().next().map(|deadline| deadline.0)
+    }
+
+    /// Returns the number of TCP connections in the queue.
+    pub fn len(&self) -> usize {
+        self.schedules.len()
+    }
+
+    /// Returns if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+}
+
+#[test]
+fn timer_queue_schedule() {
+    use std::time::Duration;
+
+    let now = Instant::now();
+    let dst: SocketAddr = "1.1.1.1:80".parse().unwrap();
+    let src_a: SocketAddr = "10.6.0.1:10000".parse().unwrap();
+    let src_b: SocketAddr = "10.6.0.1:10001".parse().unwrap();
+
+    let mut queue = TimerQueue::new();
+    assert!(queue.schedule(dst, src_a, now + Duration::from_millis(300)));
+    assert!(queue.schedule(dst, src_b, now + Duration::from_millis(200)));
+    // A later deadline is ignored
+    assert!(!queue.schedule(dst, src_b, now + Duration::from_millis(400)));
+    // An earlier deadline replaces the previous one
+    assert!(queue.schedule(dst, src_a, now + Duration::from_millis(100)));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.next(), Some(now + Duration::from_millis(100)));
+
+    assert!(queue.pop_expired(now).is_empty());
+    assert_eq!(
+        queue.pop_expired(now + Duration::from_millis(250)),
+        vec![(dst, src_a), (dst, src_b)]
+    );
+    assert!(queue.is_empty());
+
+    queue.schedule(dst, src_a, now);
+    queue.cancel(dst, src_a);
+    assert_eq!(queue.next(), None);
+}